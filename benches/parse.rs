@@ -0,0 +1,101 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Benchmarks for the parsers in `ffi::parse`, run on an environment of the size OpenVPN passes
+//! with each event on a server.
+
+use std::{ffi::CString, os::raw::c_char, ptr};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use openvpn_plugin::{env_keys, ffi::parse};
+
+/// Number of environment variables OpenVPN typically passes to a server side plugin.
+const ENV_SIZE: usize = 70;
+
+/// A null-terminated array of C strings, like the `argv` and `envp` arrays from OpenVPN.
+struct StringArray {
+    _strings: Vec<CString>,
+    ptrs: Vec<*const c_char>,
+}
+
+impl StringArray {
+    fn new(strings: Vec<String>) -> Self {
+        let strings: Vec<CString> = strings
+            .into_iter()
+            .map(|s| CString::new(s).unwrap())
+            .collect();
+        let mut ptrs: Vec<*const c_char> = strings.iter().map(|s| s.as_ptr()).collect();
+        ptrs.push(ptr::null());
+        StringArray {
+            _strings: strings,
+            ptrs,
+        }
+    }
+
+    fn as_ptr(&self) -> *const *const c_char {
+        self.ptrs.as_ptr()
+    }
+}
+
+fn env() -> StringArray {
+    let mut env = vec![
+        format!("{}=client1", env_keys::COMMON_NAME),
+        format!("{}=203.0.113.17", env_keys::TRUSTED_IP),
+        format!("{}=51820", env_keys::TRUSTED_PORT),
+        format!("{}=tun0", env_keys::DEV),
+        format!("{}=user-pass-verify", env_keys::SCRIPT_TYPE),
+        format!("{}=/tmp/openvpn_acf_1234.tmp", env_keys::AUTH_CONTROL_FILE),
+    ];
+    for i in env.len()..ENV_SIZE {
+        env.push(format!(
+            "{}={}",
+            env_keys::foreign_option(i),
+            "dhcp-option DNS 10.8.0.1"
+        ));
+    }
+    StringArray::new(env)
+}
+
+fn args() -> StringArray {
+    StringArray::new(vec![
+        "/usr/lib/openvpn/plugins/plugin.so".to_owned(),
+        "/etc/openvpn/plugin.conf".to_owned(),
+        "verbose".to_owned(),
+    ])
+}
+
+fn bench_string_array(c: &mut Criterion) {
+    let args = args();
+    c.bench_function("string_array", |b| {
+        b.iter(|| unsafe { parse::string_array(black_box(args.as_ptr())) })
+    });
+    let parsed = unsafe { parse::string_array(args.as_ptr()) }.unwrap();
+    c.bench_function("string_array_utf8", |b| {
+        b.iter(|| parse::string_array_utf8(black_box(&parsed)))
+    });
+}
+
+fn bench_env(c: &mut Criterion) {
+    let env = env();
+    c.bench_function("env", |b| {
+        b.iter(|| unsafe { parse::env(black_box(env.as_ptr())) })
+    });
+    c.bench_function("env_borrowed", |b| {
+        b.iter(|| unsafe { parse::env_borrowed(black_box(env.as_ptr())) })
+    });
+    c.bench_function("env_get", |b| {
+        b.iter(|| unsafe { parse::env_get(black_box(env.as_ptr()), env_keys::cstr::COMMON_NAME) })
+    });
+    let parsed = unsafe { parse::env(env.as_ptr()) }.unwrap();
+    c.bench_function("env_utf8", |b| {
+        b.iter(|| parse::env_utf8(black_box(&parsed)))
+    });
+}
+
+criterion_group!(benches, bench_string_array, bench_env);
+criterion_main!(benches);
@@ -0,0 +1,206 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! This example OpenVPN plugin publishes the addresses of connected clients in DNS, with RFC 2136
+//! dynamic updates.
+//!
+//! When OpenVPN learns the address of a client, the plugin points the name of the client in the
+//! zone at it, and removes the address again when OpenVPN forgets it. The name is the certificate
+//! common name of the client, made into a valid DNS label, so a client with the common name
+//! `Alice's Laptop` is reachable as `alice-s-laptop.vpn.example.com`. The updates are sent by a
+//! background thread, so a slow DNS server never holds up OpenVPN, and failed updates are only
+//! logged.
+//!
+//! ```text
+//! plugin /usr/lib/openvpn/libddns_plugin.so "--server 192.0.2.53 --zone vpn.example.com --tsig-key-name openvpn --tsig-secret c2VjcmV0"
+//! ```
+//!
+//! * `--server` is the address of the primary DNS server of the zone, with an optional port. The
+//!   port is 53 by default. Required.
+//! * `--zone` is the zone the names are added to. Required.
+//! * `--ttl` is the TTL of the records, in seconds. 60 by default.
+//! * `--tsig-key-name` is the name of the TSIG key signing the updates.
+//! * `--tsig-secret` is the base64 encoded secret of the TSIG key, an `hmac-sha256` key such as one
+//!   created by `tsig-keygen`. The updates are unsigned without a key.
+//!
+//! Only single addresses are published, the networks of `iroute`s are not. Clients sharing a
+//! common name with `duplicate-cn` share a name, which points at the client that connected last.
+
+mod update;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use openvpn_plugin::{
+    args::PluginArgs,
+    events::{EventArgs, LearnAddressOp, LearnedAddress},
+    workers::Workers,
+    EventResult, EventType,
+};
+use std::{
+    collections::HashMap,
+    error::Error,
+    ffi::CString,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::mpsc::{self, RecvTimeoutError, Sender},
+    time::Duration,
+};
+use update::{Change, TsigKey, Updater};
+
+openvpn_plugin::openvpn_plugin!(
+    crate::ddns_open,
+    crate::ddns_close,
+    crate::ddns_event,
+    crate::Handle
+);
+
+/// How often the background thread checks if the plugin is closing.
+const TICK: Duration = Duration::from_secs(1);
+
+/// How long to wait for the DNS server to answer an update.
+const UPDATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Handle {
+    zone: String,
+    /// The name each published address was published under.
+    names: HashMap<IpAddr, String>,
+    updates: Sender<Change>,
+    _workers: Workers,
+}
+
+fn option<T: FromStr>(args: &PluginArgs, key: &str, default: T) -> Result<T, Box<dyn Error>>
+where
+    T::Err: Error + 'static,
+{
+    match args.get(key) {
+        Some(value) => Ok(value.parse()?),
+        None => Ok(default),
+    }
+}
+
+fn required<'a>(args: &'a PluginArgs, key: &str) -> Result<&'a str, Box<dyn Error>> {
+    args.get(key)
+        .ok_or_else(|| format!("Missing --{}", key).into())
+}
+
+fn ddns_open(
+    args: Vec<CString>,
+    _env: HashMap<CString, CString>,
+) -> Result<(Vec<EventType>, Handle), Box<dyn Error>> {
+    let args = PluginArgs::parse(&args)?;
+    let server = required(&args, "server")?;
+    let server = match server.parse::<SocketAddr>() {
+        Ok(server) => server,
+        Err(_) => SocketAddr::new(server.parse()?, 53),
+    };
+    let key = match (args.get("tsig-key-name"), args.get("tsig-secret")) {
+        (Some(name), Some(secret)) => Some(TsigKey {
+            name: name.to_owned(),
+            secret: BASE64.decode(secret)?,
+        }),
+        (None, None) => None,
+        _ => return Err("--tsig-key-name and --tsig-secret must be given together".into()),
+    };
+    let updater = Updater {
+        server,
+        zone: required(&args, "zone")?.trim_end_matches('.').to_owned(),
+        ttl: option(&args, "ttl", 60)?,
+        key,
+        timeout: UPDATE_TIMEOUT,
+    };
+    let zone = updater.zone.clone();
+
+    let (updates, receiver) = mpsc::channel::<Change>();
+    let mut workers = Workers::new();
+    workers.spawn("ddns-update", move |shutdown| {
+        while !shutdown.is_requested() {
+            match receiver.recv_timeout(TICK) {
+                Ok(change) => {
+                    if let Err(e) = updater.send(&change) {
+                        eprintln!("DDNS-PLUGIN: unable to {}: {}", change, e);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    })?;
+    Ok((
+        vec![EventType::LearnAddress],
+        Handle {
+            zone,
+            names: HashMap::new(),
+            updates,
+            _workers: workers,
+        },
+    ))
+}
+
+fn ddns_close(_handle: Handle) {}
+
+fn ddns_event(
+    event: EventType,
+    args: Vec<CString>,
+    env: HashMap<CString, CString>,
+    handle: &mut Handle,
+) -> Result<EventResult, Box<dyn Error>> {
+    let (operation, address, common_name) = match EventArgs::parse(event, &args, &env)? {
+        EventArgs::LearnAddress {
+            operation,
+            address: LearnedAddress::Ip(address),
+            common_name,
+        } if address.is_host() => (operation, address.address(), common_name),
+        // Networks and MAC addresses are not published.
+        _ => return Ok(EventResult::Success),
+    };
+
+    // The address no longer belongs to the name it was published under.
+    let previous = handle.names.remove(&address);
+    let name = match (operation, common_name) {
+        (LearnAddressOp::Add, Some(common_name)) | (LearnAddressOp::Update, Some(common_name)) => {
+            hostname(&common_name).map(|label| format!("{}.{}", label, handle.zone))
+        }
+        _ => None,
+    };
+    if let Some(previous) = previous.filter(|previous| Some(previous) != name.as_ref()) {
+        handle.updates.send(Change::Remove {
+            name: previous,
+            address,
+        })?;
+    }
+    if let Some(name) = name {
+        handle.names.insert(address, name.clone());
+        handle.updates.send(Change::Replace { name, address })?;
+    }
+    Ok(EventResult::Success)
+}
+
+/// Makes `common_name` into a DNS label, with only lowercase letters, digits and hyphens. `None`
+/// if nothing is left of it.
+fn hostname(common_name: &str) -> Option<String> {
+    let mut label = String::new();
+    for c in common_name.chars() {
+        if c.is_ascii_alphanumeric() {
+            label.push(c.to_ascii_lowercase());
+        } else if !label.ends_with('-') {
+            label.push('-');
+        }
+    }
+    let label = label.trim_matches('-');
+    if label.is_empty() {
+        None
+    } else {
+        Some(
+            label
+                .chars()
+                .take(63)
+                .collect::<String>()
+                .trim_end_matches('-')
+                .to_owned(),
+        )
+    }
+}
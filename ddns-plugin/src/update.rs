@@ -0,0 +1,266 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! DNS UPDATE messages (RFC 2136), signed with TSIG (RFC 8945) when a key is given.
+
+use std::{
+    collections::hash_map::RandomState,
+    error::Error,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+const OPCODE_UPDATE: u16 = 5 << 11;
+const TYPE_A: u16 = 1;
+const TYPE_SOA: u16 = 6;
+const TYPE_AAAA: u16 = 28;
+const TYPE_TSIG: u16 = 250;
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+
+/// The only TSIG algorithm supported.
+const HMAC_SHA256: &str = "hmac-sha256";
+
+/// The allowed difference between the clocks of the plugin and the server, in seconds.
+const TSIG_FUDGE: u16 = 300;
+
+/// A change to the address records of a name.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Change {
+    /// Makes `address` the only address of its family for `name`.
+    Replace { name: String, address: IpAddr },
+    /// Removes `address` from `name`, if it is there.
+    Remove { name: String, address: IpAddr },
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Replace { name, address } => write!(f, "set {} to {}", name, address),
+            Change::Remove { name, address } => write!(f, "remove {} from {}", address, name),
+        }
+    }
+}
+
+/// A shared secret authenticating the updates.
+pub struct TsigKey {
+    pub name: String,
+    pub secret: Vec<u8>,
+}
+
+/// Sends updates of a zone to its primary server.
+pub struct Updater {
+    pub server: SocketAddr,
+    pub zone: String,
+    pub ttl: u32,
+    pub key: Option<TsigKey>,
+    pub timeout: Duration,
+}
+
+impl Updater {
+    /// Sends `change` and waits for the server to accept it.
+    pub fn send(&self, change: &Change) -> Result<(), UpdateError> {
+        let id = random_id();
+        let mut message = self.message(id, change)?;
+        if let Some(key) = &self.key {
+            sign(&mut message, key, SystemTime::now())?;
+        }
+
+        let local: SocketAddr = match self.server {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        socket.connect(self.server)?;
+        socket.send(&message)?;
+        let mut response = [0; 4096];
+        loop {
+            let len = socket.recv(&mut response)?;
+            if len < 12 || u16::from_be_bytes([response[0], response[1]]) != id {
+                // Not the answer to this update.
+                continue;
+            }
+            let flags = u16::from_be_bytes([response[2], response[3]]);
+            return match flags & 0xf {
+                0 => Ok(()),
+                rcode => Err(UpdateError::Rejected(rcode)),
+            };
+        }
+    }
+
+    fn message(&self, id: u16, change: &Change) -> Result<Vec<u8>, UpdateError> {
+        let (name, address, replace) = match change {
+            Change::Replace { name, address } => (name, address, true),
+            Change::Remove { name, address } => (name, address, false),
+        };
+        let (rtype, rdata) = match address {
+            IpAddr::V4(address) => (TYPE_A, address.octets().to_vec()),
+            IpAddr::V6(address) => (TYPE_AAAA, address.octets().to_vec()),
+        };
+
+        let mut message = Vec::with_capacity(512);
+        message.extend_from_slice(&id.to_be_bytes());
+        message.extend_from_slice(&OPCODE_UPDATE.to_be_bytes());
+        // One zone, no prerequisites, the updates and no additional records.
+        let updates: u16 = if replace { 2 } else { 1 };
+        for count in &[1, 0, updates, 0] {
+            message.extend_from_slice(&u16::to_be_bytes(*count));
+        }
+
+        write_name(&mut message, &self.zone)?;
+        message.extend_from_slice(&TYPE_SOA.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+        if replace {
+            // Deletes the records of the type, before adding the new one.
+            write_record(&mut message, name, rtype, CLASS_ANY, 0, &[])?;
+            write_record(&mut message, name, rtype, CLASS_IN, self.ttl, &rdata)?;
+        } else {
+            write_record(&mut message, name, rtype, CLASS_NONE, 0, &rdata)?;
+        }
+        Ok(message)
+    }
+}
+
+/// Appends a TSIG record to `message`, and counts it as an additional record.
+fn sign(message: &mut Vec<u8>, key: &TsigKey, now: SystemTime) -> Result<(), UpdateError> {
+    let time_signed = now
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or(0)
+        .to_be_bytes();
+    // The time is 48 bits.
+    let time_signed = &time_signed[2..];
+
+    // The MAC covers the message and the TSIG variables.
+    let mut variables = Vec::new();
+    write_name(&mut variables, &key.name)?;
+    variables.extend_from_slice(&CLASS_ANY.to_be_bytes());
+    variables.extend_from_slice(&0u32.to_be_bytes());
+    write_name(&mut variables, HMAC_SHA256)?;
+    variables.extend_from_slice(time_signed);
+    variables.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+    // No error and no other data.
+    variables.extend_from_slice(&[0, 0, 0, 0]);
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key.secret).expect("HMAC takes keys of any size");
+    mac.update(message);
+    mac.update(&variables);
+    let mac = mac.finalize().into_bytes();
+
+    let mut rdata = Vec::new();
+    write_name(&mut rdata, HMAC_SHA256)?;
+    rdata.extend_from_slice(time_signed);
+    rdata.extend_from_slice(&TSIG_FUDGE.to_be_bytes());
+    rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(&mac);
+    // The original id, no error and no other data.
+    rdata.extend_from_slice(&message[..2]);
+    rdata.extend_from_slice(&[0, 0, 0, 0]);
+    write_record(message, &key.name, TYPE_TSIG, CLASS_ANY, 0, &rdata)?;
+
+    let additional = u16::from_be_bytes([message[10], message[11]]) + 1;
+    message[10..12].copy_from_slice(&additional.to_be_bytes());
+    Ok(())
+}
+
+fn write_record(
+    message: &mut Vec<u8>,
+    name: &str,
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+    rdata: &[u8],
+) -> Result<(), UpdateError> {
+    write_name(message, name)?;
+    message.extend_from_slice(&rtype.to_be_bytes());
+    message.extend_from_slice(&class.to_be_bytes());
+    message.extend_from_slice(&ttl.to_be_bytes());
+    message.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    message.extend_from_slice(rdata);
+    Ok(())
+}
+
+/// Writes `name` in the lowercase wire format TSIG needs.
+fn write_name(message: &mut Vec<u8>, name: &str) -> Result<(), UpdateError> {
+    let start = message.len();
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(UpdateError::InvalidName(name.to_owned()));
+        }
+        message.push(label.len() as u8);
+        message.extend(label.bytes().map(|b| b.to_ascii_lowercase()));
+    }
+    message.push(0);
+    if message.len() - start > 255 {
+        return Err(UpdateError::InvalidName(name.to_owned()));
+    }
+    Ok(())
+}
+
+/// A message id that is hard to guess, from the random keys of the standard library.
+fn random_id() -> u16 {
+    RandomState::new().build_hasher().finish() as u16
+}
+
+#[derive(Debug)]
+pub enum UpdateError {
+    /// The name is not a valid domain name.
+    InvalidName(String),
+    /// Sending the update or receiving the answer failed.
+    Io(io::Error),
+    /// The server answered with this error code.
+    Rejected(u16),
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateError::InvalidName(name) => write!(f, "Invalid domain name \"{}\"", name),
+            UpdateError::Io(_) => "Unable to reach the DNS server".fmt(f),
+            UpdateError::Rejected(rcode) => {
+                let name = match rcode {
+                    1 => "FORMERR",
+                    2 => "SERVFAIL",
+                    5 => "REFUSED",
+                    8 => "NXRRSET",
+                    9 => "NOTAUTH",
+                    10 => "NOTZONE",
+                    _ => "error",
+                };
+                write!(
+                    f,
+                    "The DNS server rejected the update with {} ({})",
+                    name, rcode
+                )
+            }
+        }
+    }
+}
+
+impl Error for UpdateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            UpdateError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for UpdateError {
+    fn from(e: io::Error) -> Self {
+        UpdateError::Io(e)
+    }
+}
@@ -9,32 +9,18 @@
 //! This debug/example OpenVPN plugin listens for almost all events and prints the arguments
 //! for each event callback and returns success in every case.
 
-use openvpn_plugin::{EventResult, EventType};
+use openvpn_plugin::{EventArgs, EventResult, EventType, EventTypeSet};
 use std::collections::HashMap;
 use std::ffi::CString;
 
-/// The list of OpenVPN events we register for. The list contains all possible events, but some of
-/// them are commented out since they work slightly different, and will not work with the simple
-/// log-and-return-success implementation we have here.
-pub static INTERESTING_EVENTS: &[EventType] = &[
-    EventType::Up,
-    EventType::Down,
-    EventType::RouteUp,
-    EventType::IpChange,
-    // EventType::TlsVerify,
-    // EventType::AuthUserPassVerify,
-    EventType::ClientConnect,
-    EventType::ClientDisconnect,
-    EventType::LearnAddress,
-    EventType::ClientConnectV2,
-    EventType::TlsFinal,
-    EventType::EnablePf,
-    EventType::RoutePredown,
-    EventType::ClientConnectDefer,
-    EventType::ClientConnectDeferV2,
-    #[cfg(feature = "auth-failed-event")]
-    EventType::AuthFailed,
-];
+/// The OpenVPN events we register for. This is all possible events, except the ones that work
+/// slightly different, and will not work with the simple log-and-return-success implementation we
+/// have here.
+fn interesting_events() -> EventTypeSet {
+    EventType::iter()
+        .filter(|event| *event != EventType::TlsVerify && *event != EventType::AuthUserPassVerify)
+        .collect()
+}
 
 openvpn_plugin::openvpn_plugin!(
     crate::debug_open,
@@ -46,12 +32,12 @@ openvpn_plugin::openvpn_plugin!(
 fn debug_open(
     args: Vec<CString>,
     env: HashMap<CString, CString>,
-) -> Result<(Vec<EventType>, ()), ::std::io::Error> {
+) -> Result<(EventTypeSet, ()), ::std::io::Error> {
     println!(
         "DEBUG-PLUGIN: open called:\n\targs: {:?}\n\tenv: {:?}",
         args, env
     );
-    Ok((INTERESTING_EVENTS.to_vec(), ()))
+    Ok((interesting_events(), ()))
 }
 
 mod lol {
@@ -70,5 +56,9 @@ fn debug_event(
         "DEBUG-PLUGIN: event called:\n\tevent: {:?}\n\targs: {:?}\n\tenv: {:?}",
         event, args, env
     );
+    println!(
+        "DEBUG-PLUGIN: parsed event:\n\t{:?}",
+        EventArgs::parse(event, &args, &env)
+    );
     Ok(EventResult::Success)
 }
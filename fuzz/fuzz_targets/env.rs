@@ -0,0 +1,40 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use openvpn_plugin::ffi::parse::{self, ParseError};
+use openvpn_plugin_fuzz::StringArray;
+
+fuzz_target!(|data: &[u8]| {
+    let array = StringArray::from_fuzz_input(data);
+
+    let env = unsafe { parse::env(array.as_ptr()) };
+    let borrowed = unsafe { parse::env_borrowed(array.as_ptr()) };
+
+    let missing_equal = array.strings.iter().find(|s| !s.as_bytes().contains(&b'='));
+    match missing_equal {
+        Some(entry) => {
+            let expected = ParseError::NoEqual(entry.clone());
+            assert_eq!(Some(&expected), env.err().as_ref());
+            assert_eq!(Some(&expected), borrowed.err().as_ref());
+        }
+        None => {
+            let env = env.unwrap();
+            let borrowed = borrowed.unwrap();
+            assert_eq!(env.len(), borrowed.len());
+            for (key, value) in &env {
+                assert_eq!(Some(&value.as_c_str()), borrowed.get(key.as_bytes()));
+                let single = unsafe { parse::env_get(array.as_ptr(), key) };
+                assert_eq!(Some(value), single.as_ref());
+            }
+            let _ = parse::env_utf8(&env);
+        }
+    }
+});
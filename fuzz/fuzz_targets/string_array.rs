@@ -0,0 +1,33 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use openvpn_plugin::ffi::parse;
+use openvpn_plugin_fuzz::StringArray;
+
+fuzz_target!(|data: &[u8]| {
+    let array = StringArray::from_fuzz_input(data);
+
+    let parsed = unsafe { parse::string_array(array.as_ptr()) }.unwrap();
+    assert_eq!(array.strings, parsed);
+
+    let borrowed = unsafe { parse::string_array_borrowed(array.as_ptr()) }.unwrap();
+    assert!(borrowed
+        .iter()
+        .copied()
+        .eq(parsed.iter().map(|s| s.as_c_str())));
+
+    if let Ok(utf8) = parse::string_array_utf8(&parsed) {
+        assert!(utf8
+            .iter()
+            .map(|s| s.as_bytes())
+            .eq(parsed.iter().map(|s| s.as_bytes())));
+    }
+});
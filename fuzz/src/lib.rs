@@ -0,0 +1,36 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Helpers shared by the fuzz targets.
+
+use std::{ffi::CString, os::raw::c_char, ptr};
+
+/// A null-terminated array of C strings, laid out like the `argv` and `envp` arrays OpenVPN
+/// passes to a plugin.
+pub struct StringArray {
+    pub strings: Vec<CString>,
+    ptrs: Vec<*const c_char>,
+}
+
+impl StringArray {
+    /// Builds an array from fuzzer input. The input is split into strings at every null byte, so
+    /// the fuzzer controls both the number of strings and their content.
+    pub fn from_fuzz_input(data: &[u8]) -> Self {
+        let strings: Vec<CString> = data
+            .split(|&b| b == 0)
+            .map(|s| CString::new(s).expect("Split at all null bytes"))
+            .collect();
+        let mut ptrs: Vec<*const c_char> = strings.iter().map(|s| s.as_ptr()).collect();
+        ptrs.push(ptr::null());
+        StringArray { strings, ptrs }
+    }
+
+    pub fn as_ptr(&self) -> *const *const c_char {
+        self.ptrs.as_ptr()
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    env::consts::{DLL_PREFIX, DLL_SUFFIX},
+    path::PathBuf,
+    process::Command,
+};
+
+use libloading::{Library, Symbol};
+use openvpn_plugin::{
+    testing::{CloseFn, FuncFn, OpenFn, Plugin, PluginExports},
+    EventResult, EventType,
+};
+
+/// Builds the debug plugin and returns the path to the shared library.
+fn build_debug_plugin() -> PathBuf {
+    let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let plugin_dir = manifest_dir.parent().unwrap().join("debug-plugin");
+    let status = Command::new(env!("CARGO"))
+        .arg("build")
+        .arg("--manifest-path")
+        .arg(plugin_dir.join("Cargo.toml"))
+        .status()
+        .expect("Failed to run cargo");
+    assert!(status.success(), "Failed to build debug-plugin");
+    plugin_dir
+        .join("target")
+        .join("debug")
+        .join(format!("{}debug_plugin{}", DLL_PREFIX, DLL_SUFFIX))
+}
+
+/// Loads the plugin and resolves the functions OpenVPN looks for. The library must outlive the
+/// returned exports.
+unsafe fn load_exports(library: &Library) -> PluginExports {
+    let open: Symbol<'_, OpenFn> = library.get(b"openvpn_plugin_open_v3\0").unwrap();
+    let func: Symbol<'_, FuncFn> = library.get(b"openvpn_plugin_func_v3\0").unwrap();
+    let close: Symbol<'_, CloseFn> = library.get(b"openvpn_plugin_close_v1\0").unwrap();
+    PluginExports {
+        open: *open,
+        func: *func,
+        close: *close,
+    }
+}
+
+#[test]
+fn load_and_call_debug_plugin() {
+    let path = build_debug_plugin();
+    let library = unsafe { Library::new(&path) }.expect("Failed to load debug-plugin");
+    let exports = unsafe { load_exports(&library) };
+
+    let plugin_path = path.to_str().unwrap();
+    let mut plugin = Plugin::open(exports, vec![plugin_path], vec![("verb", "3")]).unwrap();
+
+    let events = plugin.events();
+    assert!(events.contains(EventType::Up));
+    assert!(events.contains(EventType::LearnAddress));
+    assert!(!events.contains(EventType::AuthUserPassVerify));
+
+    // The handle created by open must be given back on every event and finally to close.
+    let result = plugin.event(
+        EventType::Up,
+        vec![
+            plugin_path,
+            "tun0",
+            "1500",
+            "0",
+            "10.8.0.1",
+            "10.8.0.2",
+            "init",
+        ],
+        vec![("dev", "tun0"), ("script_context", "init")],
+    );
+    assert_eq!(EventResult::Success, result);
+    let result = plugin.event(
+        EventType::LearnAddress,
+        vec![plugin_path, "add", "10.8.0.6", "client1"],
+        vec![("common_name", "client1")],
+    );
+    assert_eq!(EventResult::Success, result);
+    plugin.close();
+}
@@ -0,0 +1,150 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The client side of the OAuth 2.0 device authorization grant (RFC 8628).
+
+use std::time::Duration;
+
+use reqwest::{blocking::Client, StatusCode, Url};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+/// The grant type of token requests for a device code.
+const DEVICE_CODE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+/// How long to wait between token requests if the provider does not say.
+const DEFAULT_INTERVAL: u64 = 5;
+
+/// The endpoints of the identity provider and the client registered with it.
+#[derive(Debug)]
+pub struct Provider {
+    pub device_url: Url,
+    pub token_url: Url,
+    pub userinfo_url: Option<Url>,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub scope: String,
+}
+
+/// A started device authorization, waiting for the user to sign in.
+#[derive(Debug, Deserialize)]
+pub struct Authorization {
+    pub device_code: String,
+    /// The page the user signs in on, with the user code filled in. Optional in RFC 8628, but
+    /// needed here since the client only opens a URL and can't show the user code.
+    pub verification_uri_complete: Option<String>,
+    /// Seconds until the device code expires.
+    pub expires_in: u64,
+    /// Seconds to wait between token requests.
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+}
+
+fn default_interval() -> u64 {
+    DEFAULT_INTERVAL
+}
+
+/// The answer to a token request.
+#[derive(Debug)]
+pub enum Poll {
+    /// The user has not signed in yet.
+    Pending,
+    /// Requests are too frequent, the interval must be increased by five seconds.
+    SlowDown,
+    /// The user signed in. Holds the access token.
+    Granted(String),
+    /// The user declined, the device code expired or the provider rejected the request. Holds the
+    /// OAuth error code.
+    Denied(String),
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+impl Provider {
+    /// Starts a device authorization.
+    pub fn start(&self, client: &Client) -> reqwest::Result<Authorization> {
+        let mut form = vec![
+            ("client_id", self.client_id.as_str()),
+            ("scope", &self.scope),
+        ];
+        if let Some(secret) = &self.client_secret {
+            form.push(("client_secret", secret));
+        }
+        client
+            .post(self.device_url.clone())
+            .form(&form)
+            .send()?
+            .error_for_status()?
+            .json()
+    }
+
+    /// Asks whether the user has signed in for `device_code`.
+    pub fn poll(&self, client: &Client, device_code: &str) -> reqwest::Result<Poll> {
+        let mut form = vec![
+            ("grant_type", DEVICE_CODE_GRANT),
+            ("device_code", device_code),
+            ("client_id", &self.client_id),
+        ];
+        if let Some(secret) = &self.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let response = client.post(self.token_url.clone()).form(&form).send()?;
+        if response.status().is_success() {
+            let token: TokenResponse = response.json()?;
+            return Ok(Poll::Granted(token.access_token));
+        }
+        // Errors are answered with 400, or 401 for client authentication errors.
+        if !matches!(
+            response.status(),
+            StatusCode::BAD_REQUEST | StatusCode::UNAUTHORIZED
+        ) {
+            response.error_for_status_ref()?;
+        }
+        let error: ErrorResponse = response.json()?;
+        Ok(match error.error.as_str() {
+            "authorization_pending" => Poll::Pending,
+            "slow_down" => Poll::SlowDown,
+            _ => Poll::Denied(error.error),
+        })
+    }
+
+    /// Fetches the claims about the user signed in with `access_token` from the userinfo endpoint.
+    /// Returns `None` if no userinfo endpoint is configured.
+    pub fn userinfo(
+        &self,
+        client: &Client,
+        access_token: &str,
+    ) -> reqwest::Result<Option<Map<String, Value>>> {
+        let url = match &self.userinfo_url {
+            Some(url) => url.clone(),
+            None => return Ok(None),
+        };
+        client
+            .get(url)
+            .bearer_auth(access_token)
+            .send()?
+            .error_for_status()?
+            .json()
+            .map(Some)
+    }
+}
+
+impl Authorization {
+    /// The time to wait between token requests.
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.max(1))
+    }
+}
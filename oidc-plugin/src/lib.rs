@@ -0,0 +1,283 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! This example OpenVPN plugin authenticates clients in a web browser, with the OAuth 2.0 device
+//! authorization grant of an OpenID Connect provider.
+//!
+//! When a client connects, the plugin starts a device authorization with the provider and writes
+//! the sign-in page to `auth_pending_file`, which makes the client open it in a browser. The
+//! authentication is deferred. A background thread asks the provider whether the user has signed
+//! in, and writes the result to the auth control file of the client. Authentications not completed
+//! within the timeout, or before the device code expires, are denied.
+//!
+//! ```text
+//! plugin /usr/lib/openvpn/liboidc_plugin.so "--device-url https://idp.example.com/device --token-url https://idp.example.com/token --client-id openvpn"
+//! ```
+//!
+//! * `--device-url` is the device authorization endpoint of the provider. Required.
+//! * `--token-url` is the token endpoint of the provider. Required.
+//! * `--client-id` is the id of the client registered with the provider. Required.
+//! * `--client-secret` is the secret of the client, for providers requiring one.
+//! * `--scope` is the requested scope. `openid profile` by default.
+//! * `--userinfo-url` is the userinfo endpoint of the provider. When given, the user must have
+//!   signed in as the username the client connected with.
+//! * `--username-claim` is the userinfo claim compared with the username. `preferred_username` by
+//!   default.
+//! * `--timeout` is the number of seconds after which an authentication is denied. 300 by default.
+//!
+//! The provider must give a `verification_uri_complete`, a sign-in page with the user code
+//! filled in, since the client has no way to show the user code. Only clients announcing support
+//! for opening URLs in `IV_SSO` can authenticate, which needs OpenVPN 2.6 on the server.
+
+mod device_flow;
+
+use device_flow::{Poll, Provider};
+use openvpn_plugin::{
+    args::PluginArgs,
+    auth::{Credentials, PendingAuth},
+    deferred_auth::{AuthWatchdog, TrackedAuth},
+    workers::Workers,
+    EventResult, EventType,
+};
+use reqwest::{blocking::Client, Url};
+use std::{
+    collections::HashMap,
+    error::Error,
+    ffi::CString,
+    mem,
+    panic::AssertUnwindSafe,
+    str::FromStr,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+openvpn_plugin::openvpn_plugin!(
+    crate::oidc_open,
+    crate::oidc_close,
+    crate::oidc_event,
+    crate::Handle
+);
+
+/// How often the background thread looks for authentications to ask the provider about.
+const TICK: Duration = Duration::from_secs(1);
+
+/// How long to wait for the provider to answer a request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sent by clients able to open a URL for `auth_pending_file`.
+const IV_SSO: &str = "IV_SSO";
+
+/// An authentication waiting for the user to sign in.
+struct Flow {
+    username: String,
+    device_code: String,
+    interval: Duration,
+    next_poll: Instant,
+    expires: Instant,
+    auth: TrackedAuth,
+}
+
+type Flows = Arc<Mutex<Vec<Flow>>>;
+
+/// Shared by the event callback and the background thread.
+struct Oidc {
+    provider: Provider,
+    // A panic can't leave the client in a broken state, it holds no state between requests.
+    client: AssertUnwindSafe<Client>,
+    username_claim: String,
+}
+
+struct Handle {
+    oidc: Arc<Oidc>,
+    flows: Flows,
+    watchdog: AuthWatchdog,
+    _workers: Workers,
+}
+
+fn option<T: FromStr>(args: &PluginArgs, key: &str, default: T) -> Result<T, Box<dyn Error>>
+where
+    T::Err: Error + 'static,
+{
+    match args.get(key) {
+        Some(value) => Ok(value.parse()?),
+        None => Ok(default),
+    }
+}
+
+fn required(args: &PluginArgs, key: &str) -> Result<String, Box<dyn Error>> {
+    args.get(key)
+        .map(str::to_owned)
+        .ok_or_else(|| format!("Missing --{}", key).into())
+}
+
+fn oidc_open(
+    args: Vec<CString>,
+    _env: HashMap<CString, CString>,
+) -> Result<(Vec<EventType>, Handle), Box<dyn Error>> {
+    let args = PluginArgs::parse(&args)?;
+    let provider = Provider {
+        device_url: Url::parse(&required(&args, "device-url")?)?,
+        token_url: Url::parse(&required(&args, "token-url")?)?,
+        userinfo_url: args.get("userinfo-url").map(Url::parse).transpose()?,
+        client_id: required(&args, "client-id")?,
+        client_secret: args.get("client-secret").map(str::to_owned),
+        scope: args.get("scope").unwrap_or("openid profile").to_owned(),
+    };
+    let oidc = Arc::new(Oidc {
+        provider,
+        client: AssertUnwindSafe(Client::builder().timeout(REQUEST_TIMEOUT).build()?),
+        username_claim: option(&args, "username-claim", "preferred_username".to_owned())?,
+    });
+    let watchdog = AuthWatchdog::new(Duration::from_secs(option(&args, "timeout", 300)?))?;
+    let flows = Flows::default();
+
+    let mut workers = Workers::new();
+    workers.spawn("oidc-poll", {
+        let oidc = oidc.clone();
+        let flows = flows.clone();
+        move |shutdown| {
+            while !shutdown.wait_timeout(TICK) {
+                poll_due(&oidc, &flows);
+            }
+        }
+    })?;
+    Ok((
+        vec![EventType::AuthUserPassVerify],
+        Handle {
+            oidc,
+            flows,
+            watchdog,
+            _workers: workers,
+        },
+    ))
+}
+
+fn oidc_close(_handle: Handle) {}
+
+fn oidc_event(
+    _event: EventType,
+    _args: Vec<CString>,
+    env: HashMap<CString, CString>,
+    handle: &mut Handle,
+) -> Result<EventResult, Box<dyn Error>> {
+    let sso = env
+        .get(&CString::new(IV_SSO)?)
+        .and_then(|sso| sso.to_str().ok())
+        .unwrap_or("");
+    let supports = |method: &str| sso.split(',').any(|m| m == method);
+    let web_auth = if supports("openurl") {
+        false
+    } else if supports("webauth") {
+        true
+    } else {
+        eprintln!("OIDC-PLUGIN: client can't open a URL, denying");
+        return Ok(EventResult::Failure);
+    };
+    let credentials = Credentials::from_env(&env)?;
+
+    let oidc = &handle.oidc;
+    let authorization = oidc.provider.start(&oidc.client)?;
+    let url = authorization
+        .verification_uri_complete
+        .as_deref()
+        .ok_or("The provider gave no verification_uri_complete")?;
+    let expires_in = Duration::from_secs(authorization.expires_in);
+    let timeout = expires_in.min(handle.watchdog.timeout());
+    let pending = if web_auth {
+        PendingAuth::web_auth(timeout, url)
+    } else {
+        PendingAuth::open_url(timeout, url)
+    };
+    let result = pending.write(&env)?;
+
+    let now = Instant::now();
+    let flow = Flow {
+        username: credentials.username().to_owned(),
+        interval: authorization.interval(),
+        next_poll: now + authorization.interval(),
+        expires: now + expires_in,
+        device_code: authorization.device_code,
+        auth: handle.watchdog.track(&env)?,
+    };
+    handle
+        .flows
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(flow);
+    Ok(result)
+}
+
+/// Asks the provider about the authentications due to be asked about, and decides those the
+/// provider has an answer for.
+fn poll_due(oidc: &Oidc, flows: &Mutex<Vec<Flow>>) {
+    let now = Instant::now();
+    // Taken out of the list, so new authentications are not blocked by the requests.
+    let due = {
+        let mut flows = flows.lock().unwrap_or_else(PoisonError::into_inner);
+        // The watchdog denies the authentications that expire.
+        flows.retain(|flow| !flow.auth.is_decided() && flow.expires > now);
+        let (due, waiting) = mem::take(&mut *flows)
+            .into_iter()
+            .partition::<Vec<_>, _>(|flow| flow.next_poll <= now);
+        *flows = waiting;
+        due
+    };
+    let mut waiting = Vec::new();
+    for mut flow in due {
+        match oidc.provider.poll(&oidc.client, &flow.device_code) {
+            Ok(Poll::Granted(access_token)) => {
+                decide(oidc, flow, &access_token);
+                continue;
+            }
+            Ok(Poll::Denied(error)) => {
+                eprintln!("OIDC-PLUGIN: {} was denied: {}", flow.username, error);
+                write(flow.auth.deny_with_reason("Sign-in was not completed"));
+                continue;
+            }
+            Ok(Poll::SlowDown) => flow.interval += Duration::from_secs(5),
+            Ok(Poll::Pending) => (),
+            // Retried, until the provider answers or the authentication times out.
+            Err(e) => eprintln!("OIDC-PLUGIN: token request failed: {}", e),
+        }
+        flow.next_poll = Instant::now() + flow.interval;
+        waiting.push(flow);
+    }
+    flows
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .extend(waiting);
+}
+
+/// Approves the authentication of a user who signed in, if the provider says they signed in as
+/// the username the client connected with.
+fn decide(oidc: &Oidc, flow: Flow, access_token: &str) {
+    let claims = match oidc.provider.userinfo(&oidc.client, access_token) {
+        Ok(Some(claims)) => claims,
+        Ok(None) => return write(flow.auth.approve()),
+        Err(e) => {
+            eprintln!("OIDC-PLUGIN: userinfo request failed: {}", e);
+            return write(flow.auth.deny());
+        }
+    };
+    let signed_in_as = claims.get(&oidc.username_claim).and_then(|v| v.as_str());
+    if signed_in_as == Some(flow.username.as_str()) {
+        write(flow.auth.approve());
+    } else {
+        eprintln!(
+            "OIDC-PLUGIN: {} signed in as {:?}, denying",
+            flow.username, signed_in_as
+        );
+        write(flow.auth.deny_with_reason("Signed in as a different user"));
+    }
+}
+
+fn write<E: Error>(result: Result<bool, E>) {
+    if let Err(e) = result {
+        eprintln!("OIDC-PLUGIN: unable to write auth result: {}", e);
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Derive macros for the `openvpn-plugin` crate. Use them through the `derive` feature of
+//! `openvpn-plugin` rather than depending on this crate directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{
+    parse_macro_input, spanned::Spanned, Data, DeriveInput, Field, Fields, GenericArgument, LitStr,
+    PathArguments, Type,
+};
+
+/// Derives `openvpn_plugin::from_env::FromEnv` for a struct with named fields. See the
+/// documentation of that trait.
+#[proc_macro_derive(FromEnv, attributes(env))]
+pub fn derive_from_env(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    from_env(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+fn from_env(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(unsupported(&input)),
+        },
+        _ => return Err(unsupported(&input)),
+    };
+    let field_values = fields
+        .iter()
+        .map(field_value)
+        .collect::<syn::Result<Vec<_>>>()?;
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::openvpn_plugin::from_env::FromEnv for #name #ty_generics
+        #where_clause
+        {
+            fn from_env(
+                env: &::std::collections::HashMap<::std::ffi::CString, ::std::ffi::CString>,
+            ) -> ::std::result::Result<Self, ::openvpn_plugin::events::EventArgsError> {
+                ::std::result::Result::Ok(#name {
+                    #(#field_values,)*
+                })
+            }
+        }
+    })
+}
+
+fn unsupported(input: &DeriveInput) -> syn::Error {
+    syn::Error::new(
+        input.ident.span(),
+        "FromEnv can only be derived for structs with named fields",
+    )
+}
+
+/// The attributes a field can have in `#[env(...)]`.
+#[derive(Default)]
+struct FieldAttrs {
+    rename: Option<LitStr>,
+    default: bool,
+}
+
+fn field_attrs(field: &Field) -> syn::Result<FieldAttrs> {
+    let mut attrs = FieldAttrs::default();
+    for attr in field
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("env"))
+    {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("rename") {
+                attrs.rename = Some(meta.value()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("default") {
+                attrs.default = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected `rename = \"...\"` or `default`"))
+            }
+        })?;
+    }
+    Ok(attrs)
+}
+
+/// The `field: value` initializer of a field, reading the variable with the name of the field.
+fn field_value(field: &Field) -> syn::Result<TokenStream2> {
+    let attrs = field_attrs(field)?;
+    let ident = field.ident.as_ref().expect("named field");
+    let key = attrs
+        .rename
+        .unwrap_or_else(|| LitStr::new(&ident.to_string(), ident.span()));
+    let private = quote!(::openvpn_plugin::from_env::__private);
+
+    let value = if let Some(inner) = option_inner(&field.ty) {
+        if attrs.default {
+            return Err(syn::Error::new(
+                field.span(),
+                "`default` has no effect on `Option` fields, they are `None` when missing",
+            ));
+        }
+        quote!(#private::parse_opt::<#inner>(env, #key)?)
+    } else if attrs.default {
+        let ty = &field.ty;
+        quote!(#private::parse_opt::<#ty>(env, #key)?.unwrap_or_default())
+    } else {
+        let ty = &field.ty;
+        quote!(#private::parse::<#ty>(env, #key)?)
+    };
+    Ok(quote!(#ident: #value))
+}
+
+/// Returns `T` if `ty` is `Option<T>`.
+fn option_inner(ty: &Type) -> Option<&Type> {
+    let path = match ty {
+        Type::Path(ty) if ty.qself.is_none() => &ty.path,
+        _ => return None,
+    };
+    let segment = path.segments.last()?;
+    if segment.ident != "Option" {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) if args.args.len() == 1 => match &args.args[0] {
+            GenericArgument::Type(inner) => Some(inner),
+            _ => None,
+        },
+        _ => None,
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! This example OpenVPN plugin authenticates clients against PAM, like `openvpn-auth-pam`.
+//!
+//! PAM modules may take long to answer, for example when they ask a remote server, so every
+//! authentication is deferred and run on a pool of threads. The result is written to the auth
+//! control file of the client when PAM is done. Authentications PAM has not answered within the
+//! timeout are denied. The password is removed from the environment as soon as the event arrives
+//! and is zeroed when dropped.
+//!
+//! ```text
+//! plugin /usr/lib/openvpn/libpam_plugin.so "--service openvpn --threads 4 --timeout 60"
+//! ```
+//!
+//! * `--service` is the PAM service, configured in `/etc/pam.d/<service>`. `openvpn` by default.
+//! * `--threads` is the number of authentications run at the same time. 4 by default.
+//! * `--queue` is the number of authentications that may wait for a thread. 64 by default.
+//! * `--timeout` is the number of seconds after which an authentication is denied. 60 by default.
+
+mod pam;
+
+use openvpn_plugin::{
+    args::PluginArgs, auth::Credentials, deferred_auth::DeferredAuthPool, env_keys, EventResult,
+    EventType,
+};
+use std::{collections::HashMap, error::Error, ffi::CString, str::FromStr, time::Duration};
+
+openvpn_plugin::openvpn_plugin!(
+    crate::pam_open,
+    crate::pam_close,
+    crate::pam_event,
+    crate::Handle
+);
+
+struct Handle {
+    service: String,
+    pool: DeferredAuthPool,
+}
+
+fn option<T: FromStr>(args: &PluginArgs, key: &str, default: T) -> Result<T, Box<dyn Error>>
+where
+    T::Err: Error + 'static,
+{
+    match args.get(key) {
+        Some(value) => Ok(value.parse()?),
+        None => Ok(default),
+    }
+}
+
+fn pam_open(
+    args: Vec<CString>,
+    _env: HashMap<CString, CString>,
+) -> Result<(Vec<EventType>, Handle), Box<dyn Error>> {
+    let args = PluginArgs::parse(&args)?;
+    let service = args.get("service").unwrap_or("openvpn").to_owned();
+    let pool = DeferredAuthPool::new(option(&args, "threads", 4)?, option(&args, "queue", 64)?)?
+        .timeout(Duration::from_secs(option(&args, "timeout", 60)?))?;
+    Ok((
+        vec![EventType::AuthUserPassVerify],
+        Handle { service, pool },
+    ))
+}
+
+fn pam_close(_handle: Handle) {}
+
+fn pam_event(
+    _event: EventType,
+    _args: Vec<CString>,
+    mut env: HashMap<CString, CString>,
+    handle: &mut Handle,
+) -> Result<EventResult, Box<dyn Error>> {
+    // Removed from the environment, so the password is not kept in it while PAM runs.
+    let credentials = Credentials::take_from_env(&mut env)?;
+    let remote_host = env
+        .get(env_keys::cstr::UNTRUSTED_IP)
+        .and_then(|ip| ip.to_str().ok())
+        .map(str::to_owned);
+    let service = handle.service.clone();
+    let result = handle.pool.defer(&env, move || {
+        let allowed = pam::authenticate(
+            &service,
+            credentials.username(),
+            credentials.password(),
+            remote_host.as_deref(),
+        )?;
+        Ok::<_, pam::PamError>(if allowed {
+            EventResult::Success
+        } else {
+            EventResult::Failure
+        })
+    })?;
+    Ok(result)
+}
@@ -0,0 +1,211 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The part of the Linux-PAM API needed to check a username and password.
+
+use std::{
+    error::Error,
+    ffi::{CStr, CString},
+    fmt,
+    os::raw::{c_char, c_int, c_void},
+    ptr,
+};
+
+use zeroize::Zeroizing;
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_BUF_ERR: c_int = 5;
+const PAM_CONV_ERR: c_int = 19;
+
+const PAM_SILENT: c_int = 0x8000;
+const PAM_DISALLOW_NULL_AUTHTOK: c_int = 0x0001;
+
+const PAM_RHOST: c_int = 4;
+
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_PROMPT_ECHO_ON: c_int = 2;
+
+#[repr(C)]
+struct PamHandle {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+type ConvFn = unsafe extern "C" fn(
+    num_msg: c_int,
+    msg: *mut *const PamMessage,
+    resp: *mut *mut PamResponse,
+    appdata_ptr: *mut c_void,
+) -> c_int;
+
+#[repr(C)]
+struct PamConv {
+    conv: ConvFn,
+    appdata_ptr: *mut c_void,
+}
+
+#[link(name = "pam")]
+extern "C" {
+    fn pam_start(
+        service_name: *const c_char,
+        user: *const c_char,
+        pam_conversation: *const PamConv,
+        pamh: *mut *mut PamHandle,
+    ) -> c_int;
+    fn pam_end(pamh: *mut PamHandle, pam_status: c_int) -> c_int;
+    fn pam_authenticate(pamh: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_acct_mgmt(pamh: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_set_item(pamh: *mut PamHandle, item_type: c_int, item: *const c_void) -> c_int;
+    fn pam_strerror(pamh: *mut PamHandle, errnum: c_int) -> *const c_char;
+}
+
+/// A PAM call that failed, with the message PAM gave for the status.
+#[derive(Debug)]
+pub struct PamError {
+    call: &'static str,
+    message: String,
+}
+
+impl fmt::Display for PamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed: {}", self.call, self.message)
+    }
+}
+
+impl Error for PamError {}
+
+/// What the conversation function answers PAM with.
+struct Conversation {
+    username: CString,
+    password: Zeroizing<CString>,
+}
+
+/// Answers prompts without echo with the password and prompts with echo with the username.
+/// Informational messages are ignored.
+unsafe extern "C" fn converse(
+    num_msg: c_int,
+    msg: *mut *const PamMessage,
+    resp: *mut *mut PamResponse,
+    appdata_ptr: *mut c_void,
+) -> c_int {
+    if num_msg <= 0 || msg.is_null() || resp.is_null() || appdata_ptr.is_null() {
+        return PAM_CONV_ERR;
+    }
+    let conversation = &*(appdata_ptr as *const Conversation);
+    let count = num_msg as usize;
+    // PAM frees the responses, and wipes them, so they are allocated with its allocator.
+    let responses = libc::calloc(count, std::mem::size_of::<PamResponse>()) as *mut PamResponse;
+    if responses.is_null() {
+        return PAM_BUF_ERR;
+    }
+    for i in 0..count {
+        // Linux-PAM passes an array of pointers to messages.
+        let message = &**msg.add(i);
+        let answer = match message.msg_style {
+            PAM_PROMPT_ECHO_OFF => Some(&*conversation.password),
+            PAM_PROMPT_ECHO_ON => Some(&conversation.username),
+            _ => None,
+        };
+        if let Some(answer) = answer {
+            let copy = libc::strdup(answer.as_ptr());
+            if copy.is_null() {
+                for j in 0..i {
+                    libc::free((*responses.add(j)).resp as *mut c_void);
+                }
+                libc::free(responses as *mut c_void);
+                return PAM_BUF_ERR;
+            }
+            (*responses.add(i)).resp = copy;
+        }
+    }
+    *resp = responses;
+    PAM_SUCCESS
+}
+
+/// Checks `username` and `password` with the PAM `service`, then checks that the account is
+/// allowed to log in. `remote_host` is given to the PAM modules as `PAM_RHOST`. Returns whether
+/// the user is allowed.
+pub fn authenticate(
+    service: &str,
+    username: &str,
+    password: &str,
+    remote_host: Option<&str>,
+) -> Result<bool, PamError> {
+    let invalid = |call| PamError {
+        call,
+        message: "argument contains a NUL byte".to_owned(),
+    };
+    let service = CString::new(service).map_err(|_| invalid("pam_start"))?;
+    let remote_host = remote_host
+        .map(CString::new)
+        .transpose()
+        .map_err(|_| invalid("pam_set_item"))?;
+    let conversation = Conversation {
+        username: CString::new(username).map_err(|_| invalid("pam_start"))?,
+        password: Zeroizing::new(CString::new(password).map_err(|_| invalid("pam_start"))?),
+    };
+    let conv = PamConv {
+        conv: converse,
+        appdata_ptr: &conversation as *const Conversation as *mut c_void,
+    };
+
+    let mut handle = ptr::null_mut();
+    // SAFETY: All pointers are valid for the duration of the transaction, which ends with
+    // `pam_end` before `conversation` and `conv` are dropped.
+    unsafe {
+        let status = pam_start(
+            service.as_ptr(),
+            conversation.username.as_ptr(),
+            &conv,
+            &mut handle,
+        );
+        if status != PAM_SUCCESS {
+            return Err(error(handle, "pam_start", status));
+        }
+        let mut status = PAM_SUCCESS;
+        if let Some(remote_host) = &remote_host {
+            status = pam_set_item(handle, PAM_RHOST, remote_host.as_ptr() as *const c_void);
+        }
+        let result = if status != PAM_SUCCESS {
+            Err(error(handle, "pam_set_item", status))
+        } else {
+            status = pam_authenticate(handle, PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK);
+            if status == PAM_SUCCESS {
+                status = pam_acct_mgmt(handle, PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK);
+            }
+            // Wrong passwords, unknown users and expired accounts are all denials.
+            Ok(status == PAM_SUCCESS)
+        };
+        pam_end(handle, status);
+        result
+    }
+}
+
+/// # Safety
+///
+/// `handle` must be null or a handle returned by `pam_start`.
+unsafe fn error(handle: *mut PamHandle, call: &'static str, status: c_int) -> PamError {
+    let message = pam_strerror(handle, status);
+    let message = if message.is_null() {
+        format!("status {}", status)
+    } else {
+        CStr::from_ptr(message).to_string_lossy().into_owned()
+    };
+    PamError { call, message }
+}
@@ -0,0 +1,439 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Parsing of the arguments given to the plugin in the OpenVPN config.
+//!
+//! OpenVPN splits the `plugin` line into arguments on whitespace, keeping quoted strings together.
+//! A line like
+//!
+//! ```text
+//! plugin /usr/lib/openvpn/my_plugin.so --auth-url=https://auth.example "--realm 'Main Office' --timeout 5" --verbose
+//! ```
+//!
+//! is parsed by [`PluginArgs`] into the options `auth-url`, `realm`, `timeout` and `verbose`.
+//! Options are written as `--key=value`, `--key value` or just `--key` for flags. An option
+//! argument that was quoted as a whole, such as `"--realm 'Main Office' --timeout 5"` above, is
+//! split again with the quoting rules of the OpenVPN config file. Arguments that are neither
+//! options nor option values are positional.
+//!
+//! ```rust
+//! # use std::ffi::CString;
+//! use openvpn_plugin::args::PluginArgs;
+//!
+//! # let args: Vec<CString> = ["/my_plugin.so", "--realm 'Main Office' --timeout 5", "--verbose"]
+//! #     .iter()
+//! #     .map(|arg| CString::new(*arg).unwrap())
+//! #     .collect();
+//! let args = PluginArgs::parse(&args)?;
+//! assert_eq!(Some("5"), args.get("timeout"));
+//! assert_eq!(Some("Main Office"), args.get("realm"));
+//! assert!(args.flag("verbose"));
+//! # Ok::<(), openvpn_plugin::args::ArgsError>(())
+//! ```
+//!
+//! With the `serde` feature the options can be deserialized into a struct with
+//! [`PluginArgs::deserialize`]. Dashes in the keys are replaced with underscores, so
+//! `--auth-url` sets the field `auth_url`.
+//!
+//! [`PluginArgs`]: struct.PluginArgs.html
+//! [`PluginArgs::deserialize`]: struct.PluginArgs.html#method.deserialize
+
+use std::{error::Error, ffi::CString, fmt};
+
+/// The options and positional arguments given to the plugin.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct PluginArgs {
+    options: Vec<(String, Option<String>)>,
+    positional: Vec<String>,
+}
+
+impl PluginArgs {
+    /// Parses the arguments given to the open callback. The first argument, the path to the plugin
+    /// itself, is skipped.
+    pub fn parse(args: &[CString]) -> Result<Self, ArgsError> {
+        let mut tokens = Vec::new();
+        for (index, arg) in args.iter().enumerate().skip(1) {
+            let arg = arg.to_str().map_err(|_| ArgsError::InvalidUtf8(index))?;
+            if arg.starts_with("--") && arg.contains(char::is_whitespace) {
+                tokens.extend(tokenize(arg)?);
+            } else {
+                tokens.push(arg.to_owned());
+            }
+        }
+        Ok(Self::from_tokens(tokens))
+    }
+
+    fn from_tokens(tokens: Vec<String>) -> Self {
+        let mut parsed = PluginArgs::default();
+        let mut tokens = tokens.into_iter().peekable();
+        while let Some(token) = tokens.next() {
+            match token.strip_prefix("--") {
+                Some(option) => {
+                    let (key, value) = match option.split_once('=') {
+                        Some((key, value)) => (key.to_owned(), Some(value.to_owned())),
+                        None => {
+                            let value = tokens.next_if(|next| !next.starts_with("--"));
+                            (option.to_owned(), value)
+                        }
+                    };
+                    parsed.options.push((key, value));
+                }
+                None => parsed.positional.push(token),
+            }
+        }
+        parsed
+    }
+
+    /// Returns the value of the last `--key` option, if it was given with a value.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .and_then(|(_, value)| value.as_deref())
+    }
+
+    /// Returns the values of every `--key` option, for options that can be given more than once.
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.options
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .filter_map(|(_, value)| value.as_deref())
+    }
+
+    /// Returns true if `--key` was given, with or without a value.
+    pub fn flag(&self, key: &str) -> bool {
+        self.options.iter().any(|(k, _)| k == key)
+    }
+
+    /// All options in the order they were given, with their values.
+    pub fn options(&self) -> &[(String, Option<String>)] {
+        &self.options
+    }
+
+    /// The arguments that are not options or option values.
+    pub fn positional(&self) -> &[String] {
+        &self.positional
+    }
+
+    /// Deserializes the options into `T`, with the dashes in the keys replaced by underscores.
+    /// Values are parsed into the type of the field, flags without a value deserialize as `true`
+    /// and options given several times can be collected into a `Vec`. Positional arguments are
+    /// ignored.
+    #[cfg(feature = "serde")]
+    pub fn deserialize<T: serde::de::DeserializeOwned>(&self) -> Result<T, ArgsError> {
+        let mut fields: Vec<(String, de::Values)> = Vec::new();
+        for (key, value) in &self.options {
+            let key = key.replace('-', "_");
+            match fields.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, values)) => values.values.push(value.clone()),
+                None => fields.push((
+                    key.clone(),
+                    de::Values {
+                        key,
+                        values: vec![value.clone()],
+                    },
+                )),
+            }
+        }
+        T::deserialize(serde::de::value::MapDeserializer::new(fields.into_iter()))
+    }
+}
+
+/// Splits `line` into arguments the way OpenVPN splits a line in its config file. Arguments are
+/// separated by whitespace. Double or single quotes keep whitespace in an argument. A backslash
+/// makes the next character literal, except inside single quotes.
+pub fn tokenize(line: &str) -> Result<Vec<String>, ArgsError> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut in_token = false;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), c) => token.push(c),
+            (_, '\\') => {
+                if let Some(escaped) = chars.next() {
+                    token.push(escaped);
+                }
+                in_token = true;
+            }
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                in_token = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut token));
+                    in_token = false;
+                }
+            }
+            (_, c) => {
+                token.push(c);
+                in_token = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err(ArgsError::UnterminatedQuote(line.to_owned()));
+    }
+    if in_token {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Error returned when the plugin arguments can't be parsed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ArgsError {
+    /// The argument with the given index is not valid UTF-8.
+    InvalidUtf8(usize),
+    /// The argument has a quote that is never closed.
+    UnterminatedQuote(String),
+    /// The options could not be deserialized into the requested type.
+    Deserialize(String),
+}
+
+impl fmt::Display for ArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArgsError::InvalidUtf8(index) => {
+                write!(f, "Plugin argument {} is not valid UTF-8", index)
+            }
+            ArgsError::UnterminatedQuote(arg) => {
+                write!(f, "Unterminated quote in plugin argument \"{}\"", arg)
+            }
+            ArgsError::Deserialize(msg) => write!(f, "Invalid plugin arguments: {}", msg),
+        }
+    }
+}
+
+impl Error for ArgsError {}
+
+#[cfg(feature = "serde")]
+mod de {
+    use std::str::FromStr;
+
+    use serde::de::{self, value::SeqDeserializer, IntoDeserializer, Visitor};
+
+    use super::ArgsError;
+
+    impl de::Error for ArgsError {
+        fn custom<T: std::fmt::Display>(msg: T) -> Self {
+            ArgsError::Deserialize(msg.to_string())
+        }
+    }
+
+    /// The values of every occurrence of one option.
+    pub(super) struct Values {
+        pub(super) key: String,
+        pub(super) values: Vec<Option<String>>,
+    }
+
+    impl Values {
+        fn last(&self) -> Option<&str> {
+            self.values.last().and_then(Option::as_deref)
+        }
+
+        fn parse<T: FromStr>(&self) -> Result<T, ArgsError> {
+            let value = self.last().ok_or_else(|| {
+                ArgsError::Deserialize(format!("--{} needs a value", self.key.replace('_', "-")))
+            })?;
+            value.parse().map_err(|_| {
+                ArgsError::Deserialize(format!(
+                    "invalid value for --{}: \"{}\"",
+                    self.key.replace('_', "-"),
+                    value
+                ))
+            })
+        }
+    }
+
+    impl<'de> IntoDeserializer<'de, ArgsError> for Values {
+        type Deserializer = Self;
+
+        fn into_deserializer(self) -> Self {
+            self
+        }
+    }
+
+    macro_rules! deserialize_parsed {
+        ($($method:ident => $visit:ident,)*) => {
+            $(
+                fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ArgsError> {
+                    visitor.$visit(self.parse()?)
+                }
+            )*
+        };
+    }
+
+    impl<'de> de::Deserializer<'de> for Values {
+        type Error = ArgsError;
+
+        fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ArgsError> {
+            match self.last() {
+                Some(value) => visitor.visit_string(value.to_owned()),
+                None => visitor.visit_bool(true),
+            }
+        }
+
+        fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ArgsError> {
+            match self.last() {
+                Some(_) => visitor.visit_bool(self.parse()?),
+                None => visitor.visit_bool(true),
+            }
+        }
+
+        deserialize_parsed! {
+            deserialize_i8 => visit_i8,
+            deserialize_i16 => visit_i16,
+            deserialize_i32 => visit_i32,
+            deserialize_i64 => visit_i64,
+            deserialize_u8 => visit_u8,
+            deserialize_u16 => visit_u16,
+            deserialize_u32 => visit_u32,
+            deserialize_u64 => visit_u64,
+            deserialize_f32 => visit_f32,
+            deserialize_f64 => visit_f64,
+            deserialize_char => visit_char,
+        }
+
+        fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ArgsError> {
+            visitor.visit_some(self)
+        }
+
+        fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, ArgsError> {
+            let key = self.key;
+            let values = self.values.into_iter().map(move |value| Values {
+                key: key.clone(),
+                values: vec![value],
+            });
+            visitor.visit_seq(SeqDeserializer::new(values))
+        }
+
+        fn deserialize_newtype_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            visitor: V,
+        ) -> Result<V::Value, ArgsError> {
+            visitor.visit_newtype_struct(self)
+        }
+
+        fn deserialize_enum<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            _variants: &'static [&'static str],
+            visitor: V,
+        ) -> Result<V::Value, ArgsError> {
+            let value: String = self.parse()?;
+            visitor.visit_enum(value.into_deserializer())
+        }
+
+        serde::forward_to_deserialize_any! {
+            i128 u128 str string bytes byte_buf unit unit_struct tuple tuple_struct map struct
+            identifier ignored_any
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<CString> {
+        args.iter().map(|a| CString::new(*a).unwrap()).collect()
+    }
+
+    #[test]
+    fn tokenize_quoting() {
+        assert_eq!(
+            vec!["--realm", "Main Office", "it's", "a\"b", "c d", ""],
+            tokenize(r#"  --realm "Main Office" 'it'\''s' "a\"b" c\ d '' "#).unwrap()
+        );
+        assert_eq!(
+            Err(ArgsError::UnterminatedQuote("--a \"b".to_owned())),
+            tokenize("--a \"b")
+        );
+    }
+
+    #[test]
+    fn parse_options() {
+        let parsed = PluginArgs::parse(&args(&[
+            "/plugin.so",
+            "/etc/plugin.toml",
+            "--url=https://a?b=c",
+            "--timeout",
+            "5",
+            "--realm \"Main Office\"",
+            "--verbose",
+            "--server",
+            "a",
+            "--server=b",
+        ]))
+        .unwrap();
+        assert_eq!(Some("https://a?b=c"), parsed.get("url"));
+        assert_eq!(Some("5"), parsed.get("timeout"));
+        assert_eq!(Some("Main Office"), parsed.get("realm"));
+        assert_eq!(None, parsed.get("verbose"));
+        assert!(parsed.flag("verbose"));
+        assert!(!parsed.flag("quiet"));
+        assert_eq!(Some("b"), parsed.get("server"));
+        assert_eq!(vec!["a", "b"], parsed.get_all("server").collect::<Vec<_>>());
+        assert_eq!(&["/etc/plugin.toml".to_owned()], parsed.positional());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn deserialize_options() {
+        #[derive(Debug, serde::Deserialize, PartialEq)]
+        struct Options {
+            auth_url: String,
+            timeout: u16,
+            verbose: bool,
+            debug: Option<bool>,
+            server: Vec<String>,
+            realm: Option<String>,
+        }
+
+        let parsed = PluginArgs::parse(&args(&[
+            "/plugin.so",
+            "--auth-url=https://auth",
+            "--timeout 5",
+            "--verbose",
+            "--server",
+            "a",
+            "--server",
+            "b",
+        ]))
+        .unwrap();
+        assert_eq!(
+            Options {
+                auth_url: "https://auth".to_owned(),
+                timeout: 5,
+                verbose: true,
+                debug: None,
+                server: vec!["a".to_owned(), "b".to_owned()],
+                realm: None,
+            },
+            parsed.deserialize().unwrap()
+        );
+
+        let parsed = PluginArgs::parse(&args(&["/plugin.so", "--timeout=five"])).unwrap();
+        assert_eq!(
+            "Invalid plugin arguments: invalid value for --timeout: \"five\"",
+            parsed.deserialize::<Options>().unwrap_err().to_string()
+        );
+        let parsed = PluginArgs::parse(&args(&["/plugin.so", "--timeout=5"])).unwrap();
+        assert_eq!(
+            "Invalid plugin arguments: missing field `auth_url`",
+            parsed.deserialize::<Options>().unwrap_err().to_string()
+        );
+    }
+}
@@ -0,0 +1,504 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    future::Future,
+    io,
+    os::raw::{c_int, c_void},
+    panic,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::runtime::{Builder, Runtime};
+
+use crate::{
+    auth::{ControlFile, ControlFileError, FailedReasonFile},
+    callbacks::CloseResult,
+    ffi, logging,
+    redact::Secrets,
+    Error, EventResult, EventType, EventTypeSet,
+};
+
+/// Generates the same FFI functions as [`openvpn_plugin!`], but for a plugin with an `async`
+/// event callback. Requires the `tokio` feature.
+///
+/// The crate creates a multi threaded tokio runtime when the plugin is loaded and keeps it for the
+/// entire lifetime of the plugin. The runtime is shut down right before `$close_fn` is called.
+/// Authentications still in progress then are denied, so their clients don't wait for a result
+/// that never comes.
+///
+/// `EventType::AuthUserPassVerify` events are spawned onto the runtime and
+/// `OPENVPN_PLUGIN_FUNC_DEFERRED` is returned to OpenVPN immediately. When the future completes,
+/// the result is written to the file given in the `auth_control_file` environment variable:
+///
+/// * `Ok(EventResult::Success)` writes `1`, approving the authentication.
+/// * `Ok(EventResult::Failure)`, `Err(e)` or a panic writes `0`, denying the authentication. Errors
+///   and panics are logged.
+/// * `Ok(EventResult::FailureWithReason(reason))` also writes `0`, after writing `reason` to
+///   `auth_failed_reason_file` if OpenVPN provided one.
+/// * `Ok(EventResult::Deferred)` writes nothing. The plugin has then taken responsibility for
+///   writing to the control file itself.
+///
+/// All other events are driven to completion on the runtime before the result is returned to
+/// OpenVPN, just like with the synchronous [`openvpn_plugin!`] macro.
+///
+/// ## `$open_fn` - The plugin load callback
+///
+/// Identical to the `$open_fn` of [`openvpn_plugin!`].
+///
+/// ## `$close_fn` - The plugin unload callback
+///
+/// Should be a function with the following signature:
+///
+/// ```rust,no_run
+/// # use std::sync::Arc;
+/// # struct Handle {}
+/// fn foo_close(handle: Arc<Handle>) {
+///     /// ...
+/// #    unimplemented!();
+/// }
+/// # fn main() {}
+/// ```
+///
+/// It can also return `Result<(), E>`, the same as the `$close_fn` given to [`openvpn_plugin!`].
+///
+/// Since the handle is shared with the spawned event futures it is given as an `Arc`. All
+/// futures have been dropped when this function is called, so unless the plugin has cloned the
+/// `Arc` elsewhere it will be the only reference left.
+///
+/// ## `$event_fn` - The event callback function
+///
+/// Should be an `async` function with the following signature:
+///
+/// ```rust,no_run
+/// # use openvpn_plugin::{EventResult, EventType};
+/// # use std::ffi::CString;
+/// # use std::collections::HashMap;
+/// # use std::sync::Arc;
+/// # struct Handle {}
+/// # struct Error {}
+/// async fn foo_event(
+///     event: EventType,
+///     args: Vec<CString>,
+///     env: HashMap<CString, CString>,
+///     handle: Arc<Handle>,
+/// ) -> Result<EventResult, Error> {
+///     /// ...
+/// #    unimplemented!();
+/// }
+/// # fn main() {}
+/// ```
+///
+/// The returned future must be `Send + 'static` and the handle type must be `Send + Sync` since
+/// the future can be executed on any of the runtime's worker threads.
+///
+/// [`openvpn_plugin!`]: macro.openvpn_plugin.html
+#[macro_export]
+macro_rules! openvpn_plugin_async {
+    ($open_fn:expr, $close_fn:expr, $event_fn:expr, $handle_ty:ty) => {
+        /// Called by OpenVPN when the plugin is first loaded on OpenVPN start.
+        ///
+        /// Will parse the data from OpenVPN, start the async runtime and call the function given
+        /// as `$open_fn` to the `openvpn_plugin_async` macro.
+        #[no_mangle]
+        pub unsafe extern "C" fn openvpn_plugin_open_v3(
+            version: ::std::os::raw::c_int,
+            args: *const $crate::ffi::openvpn_plugin_args_open_in,
+            retptr: *mut $crate::ffi::openvpn_plugin_args_open_return,
+        ) -> ::std::os::raw::c_int {
+            unsafe {
+                $crate::async_plugin::openvpn_plugin_open::<$handle_ty, _, _, _>(
+                    version, args, retptr, $open_fn,
+                )
+            }
+        }
+
+        /// Called by OpenVPN when the plugin is unloaded, just before OpenVPN shuts down.
+        /// Will stop the async runtime and call the function given as `$close_fn` to the
+        /// `openvpn_plugin_async` macro.
+        #[no_mangle]
+        pub unsafe extern "C" fn openvpn_plugin_close_v1(handle: *const ::std::os::raw::c_void) {
+            unsafe {
+                $crate::async_plugin::openvpn_plugin_close::<$handle_ty, _, _>(handle, $close_fn)
+            }
+        }
+
+        /// Called by OpenVPN for each `OPENVPN_PLUGIN_*` event that it registered for in
+        /// the open function.
+        ///
+        /// Will parse the data from OpenVPN and run the future returned by the function given as
+        /// `$event_fn` to the `openvpn_plugin_async` macro on the async runtime.
+        #[no_mangle]
+        pub unsafe extern "C" fn openvpn_plugin_func_v3(
+            version: ::std::os::raw::c_int,
+            args: *const $crate::ffi::openvpn_plugin_args_func_in,
+            _retptr: *const $crate::ffi::openvpn_plugin_args_func_return,
+        ) -> ::std::os::raw::c_int {
+            unsafe {
+                $crate::async_plugin::openvpn_plugin_func::<$handle_ty, _, _, _>(
+                    version, args, $event_fn,
+                )
+            }
+        }
+    };
+}
+
+
+/// How long shutting down the runtime waits for the tasks on it to stop.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The handle the async plugin gives to OpenVPN. Owns the runtime the event futures execute on
+/// together with the handle created by the plugin.
+pub(crate) struct AsyncHandle<H> {
+    runtime: Runtime,
+    handle: Arc<H>,
+}
+
+impl<H> AsyncHandle<H> {
+    /// Creates a new multi threaded runtime and wraps `handle` together with it.
+    pub(crate) fn new(handle: H) -> io::Result<Self> {
+        let runtime = Builder::new_multi_thread().enable_all().build()?;
+        Ok(AsyncHandle {
+            runtime,
+            handle: Arc::new(handle),
+        })
+    }
+
+    /// Shuts down the runtime, dropping all futures still executing on it, and returns the
+    /// plugin handle. Dropping a deferred authentication denies it, see `PendingControlFile`.
+    pub(crate) fn into_inner(self) -> Arc<H> {
+        self.runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+        self.handle
+    }
+}
+
+impl<H: Send + Sync + 'static> AsyncHandle<H> {
+    /// Dispatches one event to `event_fn`. See the [`openvpn_plugin_async!`] documentation for
+    /// how the different events are handled.
+    ///
+    /// [`openvpn_plugin_async!`]: ../macro.openvpn_plugin_async.html
+    pub(crate) fn dispatch<E, F, Fut>(
+        &self,
+        event: EventType,
+        args: Vec<CString>,
+        env: HashMap<CString, CString>,
+        event_fn: &mut F,
+    ) -> Result<EventResult, Error>
+    where
+        E: Into<Box<dyn std::error::Error>> + Send + 'static,
+        F: FnMut(EventType, Vec<CString>, HashMap<CString, CString>, Arc<H>) -> Fut,
+        Fut: Future<Output = Result<EventResult, E>> + Send + 'static,
+    {
+        if event != EventType::AuthUserPassVerify {
+            return self
+                .runtime
+                .block_on(event_fn(event, args, env, self.handle.clone()))
+                .map_err(|e| Error::callback_failed("Event callback failed", e));
+        }
+
+        let control_file = ControlFile::from_env(&env).map_err(|e| Error::Other {
+            msg: "Unable to defer authentication",
+            source: Box::new(e),
+        })?;
+        let mut control_file = PendingControlFile::new(control_file);
+        let failed_reason_file = FailedReasonFile::from_env(&env).ok();
+        // The errors of the spawned task are logged on a runtime thread, where the secrets
+        // entered for this event are not visible.
+        let secrets = Secrets::from_env(&env);
+        let future = event_fn(event, args, env, self.handle.clone());
+        // The callback and the writing of its result run in a span of their own, in the event
+        // span. It keeps the fields of the event span on errors logged after the callback returns.
+        #[cfg(feature = "tracing")]
+        let span = tracing::error_span!("deferred_auth");
+        #[cfg(feature = "tracing")]
+        let future = tracing::Instrument::instrument(future, span.clone());
+        let task = self.runtime.spawn(future);
+        let completion = async move {
+            let result = task.await;
+            let redacting = secrets.enter();
+            let approved = match result {
+                Ok(Ok(EventResult::Success)) => true,
+                Ok(Ok(EventResult::Deferred)) => return control_file.release(),
+                Ok(Ok(EventResult::Failure)) => false,
+                Ok(Ok(EventResult::FailureWithReason(reason))) => {
+                    if let Some(failed_reason_file) = failed_reason_file {
+                        if let Err(e) = failed_reason_file.write(&reason) {
+                            logging::log_error(&e);
+                        }
+                    }
+                    false
+                }
+                Ok(Err(e)) => {
+                    logging::log_error(&Error::callback_failed("Deferred auth callback failed", e));
+                    false
+                }
+                Err(e) => {
+                    if let Ok(panic_payload) = e.try_into_panic() {
+                        logging::log_panic("deferred auth", &panic_payload);
+                    }
+                    false
+                }
+            };
+            // Restores the secrets of the thread before yielding to other tasks.
+            drop(redacting);
+            control_file.approved = approved;
+            #[cfg(feature = "tracing")]
+            let span = tracing::Span::current();
+            let write = tokio::task::spawn_blocking(move || {
+                #[cfg(feature = "tracing")]
+                let _span = span.enter();
+                control_file.write()
+            });
+            if let Ok(Err(e)) = write.await {
+                logging::log_error(&e);
+            }
+        };
+        #[cfg(feature = "tracing")]
+        let completion = tracing::Instrument::instrument(completion, span);
+        self.runtime.spawn(completion);
+        Ok(EventResult::Deferred)
+    }
+}
+
+/// The control file of an authentication running on the runtime. Writes the result when dropped
+/// without being written, which happens to the tasks still running when the runtime shuts down.
+struct PendingControlFile {
+    control_file: Option<ControlFile>,
+    /// The result to write. Denied until the callback has decided.
+    approved: bool,
+}
+
+impl PendingControlFile {
+    fn new(control_file: ControlFile) -> Self {
+        PendingControlFile {
+            control_file: Some(control_file),
+            approved: false,
+        }
+    }
+
+    fn write(mut self) -> Result<(), ControlFileError> {
+        match self.control_file.take() {
+            Some(control_file) => control_file.write(self.approved),
+            None => Ok(()),
+        }
+    }
+
+    /// Leaves the control file to the plugin, which returned `EventResult::Deferred`.
+    fn release(mut self) {
+        self.control_file = None;
+    }
+}
+
+impl Drop for PendingControlFile {
+    fn drop(&mut self) {
+        if let Some(control_file) = self.control_file.take() {
+            if let Err(e) = control_file.write(self.approved) {
+                logging::log_error(&e);
+            }
+        }
+    }
+}
+
+/// Internal helper function. This function should never be called manually, only by code generated
+/// by the [`openvpn_plugin_async!`] macro.
+///
+/// # Safety
+///
+/// Same requirements as for [`crate::openvpn_plugin_open`].
+///
+/// [`openvpn_plugin_async!`]: ../macro.openvpn_plugin_async.html
+#[doc(hidden)]
+pub unsafe fn openvpn_plugin_open<H, S, E, F>(
+    version: c_int,
+    args: *const ffi::openvpn_plugin_args_open_in,
+    retptr: *mut ffi::openvpn_plugin_args_open_return,
+    mut open_fn: F,
+) -> c_int
+where
+    S: Into<EventTypeSet>,
+    E: Into<Box<dyn std::error::Error>>,
+    F: panic::UnwindSafe,
+    F: FnMut(Vec<CString>, HashMap<CString, CString>) -> Result<(S, H), E>,
+{
+    crate::openvpn_plugin_open::<AsyncHandle<H>, S, Error, _>(
+        version,
+        args,
+        retptr,
+        move |args, env| {
+            let (events, handle) =
+                open_fn(args, env).map_err(|e| Error::callback_failed("Plugin open failed", e))?;
+            let handle = AsyncHandle::new(handle).map_err(|e| Error::Other {
+                msg: "Unable to create async runtime",
+                source: Box::new(e),
+            })?;
+            Ok((events, handle))
+        },
+    )
+}
+
+
+/// Internal helper function. This function should never be called manually, only by code generated
+/// by the [`openvpn_plugin_async!`] macro.
+///
+/// # Safety
+///
+/// `handle` must be the pointer given to OpenVPN by [`openvpn_plugin_open`] and must not be used
+/// again after this call.
+///
+/// [`openvpn_plugin_async!`]: ../macro.openvpn_plugin_async.html
+#[doc(hidden)]
+pub unsafe fn openvpn_plugin_close<H, R, F>(handle: *const c_void, mut close_fn: F)
+where
+    R: CloseResult,
+    F: FnMut(Arc<H>) -> R,
+{
+    // The runtime is not unwind safe, but it is shut down before `close_fn` is called and is
+    // never observed again after a panic.
+    if !crate::check_close_handle(handle) {
+        return;
+    }
+    let handle = *Box::from_raw(handle as *mut AsyncHandle<H>);
+    #[cfg(feature = "tracing")]
+    let _span = tracing::error_span!("plugin_close").entered();
+    match crate::catch_unwind(panic::AssertUnwindSafe(|| close_fn(handle.into_inner()))) {
+        Ok(result) => result.log_error(),
+        Err(e) => logging::log_panic("plugin close", &e),
+    }
+    #[cfg(feature = "metrics")]
+    crate::metrics::log_summary();
+}
+
+
+/// Internal helper function. This function should never be called manually, only by code generated
+/// by the [`openvpn_plugin_async!`] macro.
+///
+/// # Safety
+///
+/// Same requirements as for [`crate::openvpn_plugin_func`].
+///
+/// [`openvpn_plugin_async!`]: ../macro.openvpn_plugin_async.html
+#[doc(hidden)]
+pub unsafe fn openvpn_plugin_func<H, E, F, Fut>(
+    version: c_int,
+    args: *const ffi::openvpn_plugin_args_func_in,
+    mut event_fn: F,
+) -> c_int
+where
+    H: Send + Sync + 'static,
+    E: Into<Box<dyn std::error::Error>> + Send + 'static,
+    F: panic::UnwindSafe,
+    F: FnMut(EventType, Vec<CString>, HashMap<CString, CString>, Arc<H>) -> Fut,
+    Fut: Future<Output = Result<EventResult, E>> + Send + 'static,
+{
+    crate::openvpn_plugin_func::<AsyncHandle<H>, Error, _>(
+        version,
+        args,
+        move |event, args, env, handle| handle.dispatch(event, args, env, &mut event_fn),
+    )
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AUTH_CONTROL_FILE;
+    use crate::testing::fixtures::temp_path;
+    use std::{fs, path::PathBuf, time::Duration};
+
+    fn control_file_env(name: &str) -> (PathBuf, HashMap<CString, CString>) {
+        let path = temp_path(&format!("async-{}", name));
+        let _ = fs::remove_file(&path);
+        let mut env = HashMap::new();
+        env.insert(
+            CString::new(AUTH_CONTROL_FILE).unwrap(),
+            CString::new(path.to_str().unwrap()).unwrap(),
+        );
+        (path, env)
+    }
+
+    fn wait_for_file(path: &PathBuf) -> String {
+        for _ in 0..100 {
+            if let Ok(content) = fs::read_to_string(path) {
+                if !content.is_empty() {
+                    let _ = fs::remove_file(path);
+                    return content;
+                }
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("{} was never written", path.display());
+    }
+
+    async fn respond(
+        _event: EventType,
+        _args: Vec<CString>,
+        _env: HashMap<CString, CString>,
+        handle: Arc<Result<EventResult, io::Error>>,
+    ) -> Result<EventResult, io::Error> {
+        match &*handle {
+            Ok(result) => Ok(result.clone()),
+            Err(e) => Err(io::Error::new(e.kind(), e.to_string())),
+        }
+    }
+
+    #[test]
+    fn auth_success_writes_1() {
+        let (path, env) = control_file_env("success");
+        let handle = AsyncHandle::new(Ok(EventResult::Success)).unwrap();
+        let result = handle.dispatch(EventType::AuthUserPassVerify, vec![], env, &mut respond);
+        assert_eq!(EventResult::Deferred, result.unwrap());
+        assert_eq!("1", wait_for_file(&path));
+    }
+
+    #[test]
+    fn auth_error_writes_0() {
+        let (path, env) = control_file_env("error");
+        let handle = AsyncHandle::new(Err(io::Error::from(io::ErrorKind::Other))).unwrap();
+        let result = handle.dispatch(EventType::AuthUserPassVerify, vec![], env, &mut respond);
+        assert_eq!(EventResult::Deferred, result.unwrap());
+        assert_eq!("0", wait_for_file(&path));
+    }
+
+    #[test]
+    fn shutdown_denies_pending_auths() {
+        let (path, env) = control_file_env("shutdown");
+        let handle = AsyncHandle::new(()).unwrap();
+        let result = handle.dispatch(
+            EventType::AuthUserPassVerify,
+            vec![],
+            env,
+            &mut |_, _, _, _| std::future::pending::<Result<EventResult, io::Error>>(),
+        );
+        assert_eq!(EventResult::Deferred, result.unwrap());
+        handle.into_inner();
+        assert_eq!("0", fs::read_to_string(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn auth_without_control_file_fails() {
+        let handle = AsyncHandle::new(Ok(EventResult::Success)).unwrap();
+        let result = handle.dispatch(
+            EventType::AuthUserPassVerify,
+            vec![],
+            HashMap::new(),
+            &mut respond,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn other_events_are_not_deferred() {
+        let handle = AsyncHandle::new(Ok(EventResult::Success)).unwrap();
+        let result = handle.dispatch(EventType::Up, vec![], HashMap::new(), &mut respond);
+        assert_eq!(EventResult::Success, result.unwrap());
+    }
+}
@@ -0,0 +1,506 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    ffi::{CStr, CString, OsString},
+    fmt,
+    fs::{self, File},
+    io::{self, Write},
+    os::raw::c_char,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{events::EventArgsError, EventResult};
+
+pub use crate::env_keys::{
+    AUTH_CONTROL_FILE, AUTH_FAILED_REASON_FILE, AUTH_PENDING_FILE, PASSWORD, USERNAME,
+};
+
+#[cfg(feature = "zeroize")]
+use zeroize::{Zeroize, Zeroizing};
+
+/// String type used for secrets. Zeroed on drop when the `zeroize` feature is enabled.
+#[cfg(feature = "zeroize")]
+pub(crate) type SecretString = Zeroizing<String>;
+#[cfg(not(feature = "zeroize"))]
+pub(crate) type SecretString = String;
+
+/// Error type returned when an auth control file can't be located or written.
+#[derive(Debug)]
+pub enum ControlFileError {
+    /// The variable with the given name is not present in the environment.
+    MissingEnv(&'static str),
+    /// The path given by OpenVPN is not a valid path to a file in an existing directory.
+    InvalidPath(PathBuf),
+    /// A value to be written contains a newline, which would corrupt the line based format.
+    InvalidValue(String),
+    /// Writing the result to the file failed.
+    Write(PathBuf, io::Error),
+}
+
+impl fmt::Display for ControlFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlFileError::MissingEnv(name) => write!(f, "No \"{}\" in env", name),
+            ControlFileError::InvalidPath(path) => {
+                write!(f, "Invalid control file path \"{}\"", path.display())
+            }
+            ControlFileError::InvalidValue(value) => {
+                write!(f, "Value \"{}\" contains a newline", value.escape_debug())
+            }
+            ControlFileError::Write(path, _) => {
+                write!(f, "Unable to write to \"{}\"", path.display())
+            }
+        }
+    }
+}
+
+impl Error for ControlFileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ControlFileError::Write(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+
+/// The file OpenVPN reads the result of a deferred `EventType::AuthUserPassVerify` from.
+///
+/// A plugin that returns `EventResult::Deferred` from an authentication event must later call
+/// either [`approve`] or [`deny`] on the control file from that event's environment.
+///
+/// [`approve`]: #method.approve
+/// [`deny`]: #method.deny
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ControlFile {
+    path: PathBuf,
+}
+
+impl ControlFile {
+    /// Locates the control file from the `auth_control_file` variable in the environment of an
+    /// `EventType::AuthUserPassVerify` event.
+    pub fn from_env(env: &HashMap<CString, CString>) -> Result<Self, ControlFileError> {
+        let path = env_path(env, AUTH_CONTROL_FILE)?;
+        Self::new(path)
+    }
+
+    /// Creates a control file handle for the given path. The path must point to a file in an
+    /// existing directory.
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self, ControlFileError> {
+        let path = path.into();
+        validate_path(&path)?;
+        Ok(ControlFile { path })
+    }
+
+    /// Returns the path to the control file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Tells OpenVPN that the authentication succeeded by writing `1` to the control file.
+    pub fn approve(self) -> Result<(), ControlFileError> {
+        self.write(true)
+    }
+
+    /// Tells OpenVPN that the authentication failed by writing `0` to the control file.
+    pub fn deny(self) -> Result<(), ControlFileError> {
+        self.write(false)
+    }
+
+    /// Writes `1` if `approved` is true, otherwise `0`.
+    pub fn write(self, approved: bool) -> Result<(), ControlFileError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::error_span!("write_control_file", approved).entered();
+        let content = if approved { "1" } else { "0" };
+        write_atomic(&self.path, content.as_bytes())
+    }
+}
+
+
+/// The username and password from the environment of an `EventType::AuthUserPassVerify` event.
+///
+/// With the `zeroize` feature enabled the credentials are kept in buffers that are zeroed when
+/// dropped. The `Debug` implementation never prints the password.
+#[derive(Clone, Eq, PartialEq)]
+pub struct Credentials {
+    username: String,
+    password: SecretString,
+}
+
+impl Credentials {
+    /// Copies the credentials from `env`. The password is left in `env`, see
+    /// [`take_from_env`] for a way to avoid that.
+    ///
+    /// [`take_from_env`]: #method.take_from_env
+    pub fn from_env(env: &HashMap<CString, CString>) -> Result<Self, EventArgsError> {
+        let username = env_string(env, USERNAME)?;
+        let password = env_string(env, PASSWORD)?;
+        Ok(Credentials {
+            username: username.to_owned(),
+            password: SecretString::from(password.to_owned()),
+        })
+    }
+
+    /// Moves the credentials out of `env`, removing the password from it. This makes the
+    /// remaining environment safe to log or keep around. With the `zeroize` feature enabled the
+    /// removed password buffer is zeroed.
+    pub fn take_from_env(env: &mut HashMap<CString, CString>) -> Result<Self, EventArgsError> {
+        let credentials = Self::from_env(env)?;
+        if let Some(password) = env.remove(&CString::new(PASSWORD).unwrap()) {
+            wipe(password);
+        }
+        Ok(credentials)
+    }
+
+    /// Returns the username.
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Returns the password.
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &crate::redact::REDACTED)
+            .finish()
+    }
+}
+
+/// Zeroes the buffer of `value` before freeing it.
+#[cfg(feature = "zeroize")]
+fn wipe(value: CString) {
+    value.into_bytes().zeroize();
+}
+
+#[cfg(not(feature = "zeroize"))]
+fn wipe(_value: CString) {}
+
+fn env_string<'a>(
+    env: &'a HashMap<CString, CString>,
+    name: &'static str,
+) -> Result<&'a str, EventArgsError> {
+    env.get(&CString::new(name).unwrap())
+        .ok_or(EventArgsError::MissingEnv(name))?
+        .to_str()
+        .map_err(|e| EventArgsError::InvalidUtf8(name.to_owned(), e))
+}
+
+
+/// The file OpenVPN reads the reason for a failed authentication from. The reason is sent to the
+/// client together with the authentication failure. Requires OpenVPN 2.6 or later.
+///
+/// The crate writes this file automatically when a plugin returns
+/// `EventResult::FailureWithReason`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct FailedReasonFile {
+    path: PathBuf,
+}
+
+impl FailedReasonFile {
+    /// Locates the file from the `auth_failed_reason_file` variable in the environment of an
+    /// `EventType::AuthUserPassVerify` event.
+    pub fn from_env(env: &HashMap<CString, CString>) -> Result<Self, ControlFileError> {
+        Self::from_path(env_path(env, AUTH_FAILED_REASON_FILE)?)
+    }
+
+    pub(crate) fn from_path(path: PathBuf) -> Result<Self, ControlFileError> {
+        validate_path(&path)?;
+        Ok(FailedReasonFile { path })
+    }
+
+    /// Locates the file in the environment array OpenVPN gives the event callback, without
+    /// parsing the rest of it.
+    ///
+    /// # Safety
+    ///
+    /// Same requirements as `ffi::parse::env_get`.
+    pub(crate) unsafe fn from_envp(envp: *const *const c_char) -> Option<Self> {
+        let path =
+            crate::ffi::parse::env_get(envp, crate::env_keys::cstr::AUTH_FAILED_REASON_FILE)?;
+        Self::from_path(PathBuf::from(cstr_to_os_string(&path))).ok()
+    }
+
+    /// Returns the path to the reason file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes `reason` to the file.
+    pub fn write(&self, reason: &str) -> Result<(), ControlFileError> {
+        write_atomic(&self.path, reason.as_bytes())
+    }
+}
+
+
+/// Information about a pending authentication, written to `auth_pending_file`. Tells a client
+/// supporting the given method how to continue the authentication, for example by opening a web
+/// page for a second factor. Requires OpenVPN 2.5 or later.
+///
+/// ```rust,no_run
+/// # use openvpn_plugin::{auth::PendingAuth, EventResult};
+/// # use std::{collections::HashMap, ffi::CString, time::Duration};
+/// # fn event(env: HashMap<CString, CString>) -> Result<EventResult, Box<dyn std::error::Error>> {
+/// let pending = PendingAuth::open_url(Duration::from_secs(300), "https://example.com/login");
+/// // Writes the file and returns `EventResult::Deferred`. The final result must later be
+/// // written to the `ControlFile`.
+/// Ok(pending.write(&env)?)
+/// # }
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct PendingAuth {
+    /// How long OpenVPN should wait for the authentication to complete.
+    pub timeout: Duration,
+    /// The method the client must support to continue the authentication, e.g. `openurl`. The
+    /// client lists the methods it supports in `IV_SSO`.
+    pub method: String,
+    /// Method specific data, e.g. `OPEN_URL:https://...` for `openurl` or `WEB_AUTH::https://...`
+    /// for `webauth`.
+    pub extra: String,
+}
+
+impl PendingAuth {
+    /// Creates a new pending authentication description.
+    pub fn new(timeout: Duration, method: impl Into<String>, extra: impl Into<String>) -> Self {
+        PendingAuth {
+            timeout,
+            method: method.into(),
+            extra: extra.into(),
+        }
+    }
+
+    /// Creates a pending authentication asking the client to open `url` in a web browser, with
+    /// the `openurl` method.
+    pub fn open_url(timeout: Duration, url: impl AsRef<str>) -> Self {
+        Self::new(timeout, "openurl", format!("OPEN_URL:{}", url.as_ref()))
+    }
+
+    /// Creates a pending authentication asking the client to open `url` in a web browser, with
+    /// the `webauth` method. The flags between the colons are left empty.
+    pub fn web_auth(timeout: Duration, url: impl AsRef<str>) -> Self {
+        Self::new(timeout, "webauth", format!("WEB_AUTH::{}", url.as_ref()))
+    }
+
+    /// Sets the timeout.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the method.
+    pub fn method(mut self, method: impl Into<String>) -> Self {
+        self.method = method.into();
+        self
+    }
+
+    /// Sets the method specific data.
+    pub fn extra(mut self, extra: impl Into<String>) -> Self {
+        self.extra = extra.into();
+        self
+    }
+
+    /// Formats the file content in the format OpenVPN expects in `auth_pending_file`.
+    pub fn to_file_content(&self) -> Result<String, ControlFileError> {
+        for value in &[&self.method, &self.extra] {
+            if value.contains(&['\n', '\r'][..]) {
+                return Err(ControlFileError::InvalidValue((*value).clone()));
+            }
+        }
+        Ok(format!(
+            "{}\n{}\n{}\n",
+            self.timeout.as_secs(),
+            self.method,
+            self.extra
+        ))
+    }
+
+    /// Writes the pending authentication to the `auth_pending_file` given in `env` and returns
+    /// `EventResult::Deferred`, which must be returned to OpenVPN for the pending authentication
+    /// to take effect.
+    pub fn write(&self, env: &HashMap<CString, CString>) -> Result<EventResult, ControlFileError> {
+        let content = self.to_file_content()?;
+        let path = env_path(env, AUTH_PENDING_FILE)?;
+        validate_path(&path)?;
+        write_atomic(&path, content.as_bytes())?;
+        Ok(EventResult::Deferred)
+    }
+}
+
+
+/// Reads the variable `name` from `env` and converts it into a path.
+pub(crate) fn env_path(
+    env: &HashMap<CString, CString>,
+    name: &'static str,
+) -> Result<PathBuf, ControlFileError> {
+    let value = env
+        .get(&CString::new(name).unwrap())
+        .ok_or(ControlFileError::MissingEnv(name))?;
+    Ok(PathBuf::from(cstr_to_os_string(value)))
+}
+
+/// Checks that `path` points to a file inside an existing directory.
+pub(crate) fn validate_path(path: &Path) -> Result<(), ControlFileError> {
+    let parent = match path.parent() {
+        Some(parent) if parent.as_os_str().is_empty() => Path::new("."),
+        Some(parent) => parent,
+        None => return Err(ControlFileError::InvalidPath(path.to_owned())),
+    };
+    if path.file_name().is_none() || !parent.is_dir() {
+        return Err(ControlFileError::InvalidPath(path.to_owned()));
+    }
+    Ok(())
+}
+
+/// Writes `content` to a temporary file next to `path`, syncs it to disk and then renames it over
+/// `path`. This way OpenVPN never observes a partially written file.
+pub(crate) fn write_atomic(path: &Path, content: &[u8]) -> Result<(), ControlFileError> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let result = (|| {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    })();
+    result.map_err(|e| {
+        let _ = fs::remove_file(&tmp_path);
+        ControlFileError::Write(path.to_owned(), e)
+    })
+}
+
+#[cfg(unix)]
+pub(crate) fn cstr_to_os_string(s: &CStr) -> OsString {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::OsStr::from_bytes(s.to_bytes()).to_owned()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn cstr_to_os_string(s: &CStr) -> OsString {
+    OsString::from(s.to_string_lossy().into_owned())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::temp_path;
+
+    fn env_with(path: &Path) -> HashMap<CString, CString> {
+        let mut env = HashMap::new();
+        env.insert(
+            CString::new(AUTH_CONTROL_FILE).unwrap(),
+            CString::new(path.to_str().unwrap()).unwrap(),
+        );
+        env
+    }
+
+    #[test]
+    fn from_env_missing() {
+        match ControlFile::from_env(&HashMap::new()) {
+            Err(ControlFileError::MissingEnv(AUTH_CONTROL_FILE)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn from_env_nonexistent_dir() {
+        let path = temp_path("auth-no-such-dir").join("control");
+        match ControlFile::from_env(&env_with(&path)) {
+            Err(ControlFileError::InvalidPath(p)) => assert_eq!(path, p),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn approve_writes_1() {
+        let path = temp_path("auth-approve");
+        ControlFile::from_env(&env_with(&path))
+            .unwrap()
+            .approve()
+            .unwrap();
+        assert_eq!("1", fs::read_to_string(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn take_credentials() {
+        let mut env = HashMap::new();
+        env.insert(
+            CString::new(USERNAME).unwrap(),
+            CString::new("foo").unwrap(),
+        );
+        env.insert(
+            CString::new(PASSWORD).unwrap(),
+            CString::new("hunter2").unwrap(),
+        );
+        let credentials = Credentials::take_from_env(&mut env).unwrap();
+        assert_eq!("foo", credentials.username());
+        assert_eq!("hunter2", credentials.password());
+        assert!(!format!("{:?}", credentials).contains("hunter2"));
+        assert_eq!(1, env.len());
+    }
+
+    #[test]
+    fn failed_reason_written() {
+        let path = temp_path("auth-reason");
+        let mut env = HashMap::new();
+        env.insert(
+            CString::new(AUTH_FAILED_REASON_FILE).unwrap(),
+            CString::new(path.to_str().unwrap()).unwrap(),
+        );
+        FailedReasonFile::from_env(&env)
+            .unwrap()
+            .write("Subscription expired")
+            .unwrap();
+        assert_eq!("Subscription expired", fs::read_to_string(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pending_auth_content() {
+        let pending = PendingAuth::open_url(Duration::from_secs(60), "https://example.com");
+        assert_eq!(
+            "60\nopenurl\nOPEN_URL:https://example.com\n",
+            pending.to_file_content().unwrap()
+        );
+        let pending = PendingAuth::web_auth(Duration::from_secs(60), "https://example.com");
+        assert_eq!(
+            "60\nwebauth\nWEB_AUTH::https://example.com\n",
+            pending.to_file_content().unwrap()
+        );
+    }
+
+    #[test]
+    fn pending_auth_newline() {
+        let pending = PendingAuth::new(Duration::from_secs(60), "crtext", "foo\nbar");
+        match pending.to_file_content() {
+            Err(ControlFileError::InvalidValue(value)) => assert_eq!("foo\nbar", value),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn deny_overwrites_existing() {
+        let path = temp_path("auth-deny");
+        fs::write(&path, "").unwrap();
+        ControlFile::new(&path).unwrap().deny().unwrap();
+        assert_eq!("0", fs::read_to_string(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,362 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A cache of recent successful authentications. Requires the `auth-cache` feature.
+//!
+//! OpenVPN verifies the credentials again on every TLS renegotiation, once an hour by default,
+//! and every client reconnecting after a server restart does so at the same time. An
+//! [`AuthCache`] remembers the credentials that were recently allowed, so those authentications
+//! are answered without asking the authentication backend:
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, ffi::CString, io, time::Duration};
+//! # use openvpn_plugin::{auth_cache::AuthCache, deferred_auth::DeferredAuthPool, EventResult};
+//! # fn verify(env: &HashMap<CString, CString>) -> Result<bool, io::Error> { unimplemented!() }
+//! struct Handle {
+//!     cache: AuthCache,
+//!     pool: DeferredAuthPool,
+//! }
+//!
+//! fn auth_user_pass_verify(
+//!     env: HashMap<CString, CString>,
+//!     handle: &mut Handle,
+//! ) -> Result<EventResult, Box<dyn std::error::Error>> {
+//!     let result = handle.cache.defer(&handle.pool, &env, {
+//!         let env = env.clone();
+//!         move || match verify(&env)? {
+//!             true => Ok::<_, io::Error>(EventResult::Success),
+//!             false => Ok(EventResult::Failure),
+//!         }
+//!     })?;
+//!     Ok(result)
+//! }
+//! ```
+//!
+//! Only successful authentications are cached. Entries are keyed by the common name of the client
+//! certificate, the username and a salted hash of the password, so changing any of them asks the
+//! backend again. The password itself is never stored. When access is revoked, remove the entries
+//! of the user with [`AuthCache::invalidate_user`] so the revocation takes effect before the entry
+//! expires.
+//!
+//! [`AuthCache`]: struct.AuthCache.html
+//! [`AuthCache::invalidate_user`]: struct.AuthCache.html#method.invalidate_user
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fmt, io,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    auth::{ControlFileError, Credentials},
+    deferred_auth::DeferredAuthPool,
+    env_keys,
+    events::Env,
+    EventResult,
+};
+
+/// The entries of one set of credentials.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+struct Key {
+    common_name: String,
+    username: String,
+    password_hash: [u8; 32],
+}
+
+#[derive(Default)]
+struct Entries {
+    /// When the entries expire, `None` for never.
+    expires: HashMap<Key, Option<Instant>>,
+    /// The number of entries at which expired entries are removed next.
+    next_prune: usize,
+}
+
+struct Inner {
+    ttl: Duration,
+    salt: [u8; 16],
+    entries: Mutex<Entries>,
+}
+
+/// Successful authentications, remembered for a fixed time. Clones share the same entries.
+#[derive(Clone)]
+pub struct AuthCache {
+    inner: Arc<Inner>,
+}
+
+impl AuthCache {
+    /// Creates an empty cache remembering successful authentications for `ttl`. A `ttl` too long
+    /// to add to the current time, such as `Duration::MAX`, never expires. Fails if no random salt
+    /// could be generated.
+    pub fn new(ttl: Duration) -> io::Result<Self> {
+        let mut salt = [0; 16];
+        getrandom::fill(&mut salt).map_err(io::Error::from)?;
+        Ok(AuthCache {
+            inner: Arc::new(Inner {
+                ttl,
+                salt,
+                entries: Mutex::default(),
+            }),
+        })
+    }
+
+    /// How long successful authentications are remembered.
+    pub fn ttl(&self) -> Duration {
+        self.inner.ttl
+    }
+
+    /// Returns whether the credentials in `env`, the environment of an
+    /// `EventType::AuthUserPassVerify` event, were allowed within the TTL.
+    pub fn is_allowed(&self, env: &HashMap<CString, CString>) -> bool {
+        match self.key(env) {
+            Some(key) => self.contains(&key),
+            None => false,
+        }
+    }
+
+    /// Remembers that the credentials in `env` were allowed. Does nothing if `env` has no
+    /// credentials.
+    pub fn allow(&self, env: &HashMap<CString, CString>) {
+        if let Some(key) = self.key(env) {
+            self.insert(key);
+        }
+    }
+
+    /// Returns `EventResult::Success` if the credentials in `env` were allowed within the TTL.
+    /// Otherwise runs `verify` and remembers the credentials if it returns `EventResult::Success`.
+    pub fn verify<F, E>(&self, env: &HashMap<CString, CString>, verify: F) -> Result<EventResult, E>
+    where
+        F: FnOnce() -> Result<EventResult, E>,
+    {
+        let key = match self.key(env) {
+            Some(key) if self.contains(&key) => return Ok(EventResult::Success),
+            key => key,
+        };
+        let result = verify()?;
+        if let (EventResult::Success, Some(key)) = (&result, key) {
+            self.insert(key);
+        }
+        Ok(result)
+    }
+
+    /// Returns `EventResult::Success` if the credentials in `env` were allowed within the TTL.
+    /// Otherwise runs `verify` on `pool` with [`DeferredAuthPool::defer`], and remembers the
+    /// credentials if it returns `EventResult::Success`.
+    ///
+    /// [`DeferredAuthPool::defer`]: ../deferred_auth/struct.DeferredAuthPool.html#method.defer
+    pub fn defer<F, E>(
+        &self,
+        pool: &DeferredAuthPool,
+        env: &HashMap<CString, CString>,
+        verify: F,
+    ) -> Result<EventResult, ControlFileError>
+    where
+        F: FnOnce() -> Result<EventResult, E> + Send + 'static,
+        E: Into<Box<dyn std::error::Error>>,
+    {
+        let key = match self.key(env) {
+            Some(key) if self.contains(&key) => return Ok(EventResult::Success),
+            key => key,
+        };
+        let cache = self.clone();
+        pool.defer(env, move || {
+            let result = verify()?;
+            if let (EventResult::Success, Some(key)) = (&result, key) {
+                cache.insert(key);
+            }
+            Ok::<_, E>(result)
+        })
+    }
+
+    /// Forgets the authentications of clients with the certificate common name `common_name`.
+    pub fn invalidate(&self, common_name: &str) {
+        self.lock()
+            .expires
+            .retain(|key, _| key.common_name != common_name);
+    }
+
+    /// Forgets the authentications of `username`.
+    pub fn invalidate_user(&self, username: &str) {
+        self.lock()
+            .expires
+            .retain(|key, _| key.username != username);
+    }
+
+    /// Forgets all authentications.
+    pub fn clear(&self) {
+        self.lock().expires.clear();
+    }
+
+    /// The number of remembered authentications, including expired ones not yet removed.
+    pub fn len(&self) -> usize {
+        self.lock().expires.len()
+    }
+
+    /// Returns true if no authentications are remembered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn key(&self, env: &HashMap<CString, CString>) -> Option<Key> {
+        let credentials = Credentials::from_env(env).ok()?;
+        // Clients without a certificate have no common name.
+        let common_name = Env(env)
+            .string_opt(env_keys::COMMON_NAME)
+            .ok()?
+            .unwrap_or_default();
+        let password_hash = Sha256::new()
+            .chain_update(self.inner.salt)
+            .chain_update(credentials.password())
+            .finalize()
+            .into();
+        Some(Key {
+            common_name,
+            username: credentials.username().to_owned(),
+            password_hash,
+        })
+    }
+
+    fn contains(&self, key: &Key) -> bool {
+        let mut entries = self.lock();
+        match entries.expires.get(key) {
+            Some(expires) if is_live(*expires, Instant::now()) => true,
+            Some(_) => {
+                entries.expires.remove(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&self, key: Key) {
+        let now = Instant::now();
+        let mut entries = self.lock();
+        entries.expires.insert(key, now.checked_add(self.inner.ttl));
+        // Entries that are never looked up again are removed once the cache has doubled in size.
+        if entries.expires.len() >= entries.next_prune {
+            entries.expires.retain(|_, expires| is_live(*expires, now));
+            entries.next_prune = (entries.expires.len() * 2).max(64);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Entries> {
+        self.inner
+            .entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Never prints the cached entries.
+impl fmt::Debug for AuthCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthCache")
+            .field("ttl", &self.inner.ttl)
+            .field("len", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Returns true if an entry expiring at `expires` has not expired at `now`.
+fn is_live(expires: Option<Instant>, now: Instant) -> bool {
+    expires.map_or(true, |expires| expires > now)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::env;
+    use std::convert::Infallible;
+
+    fn client(common_name: &str, username: &str, password: &str) -> HashMap<CString, CString> {
+        env(&[
+            ("common_name", common_name),
+            ("username", username),
+            ("password", password),
+        ])
+    }
+
+    #[test]
+    fn caches_successes() {
+        let cache = AuthCache::new(Duration::from_secs(60)).unwrap();
+        let alice = client("laptop", "alice", "hunter2");
+        let mut calls = 0;
+        let mut verify = |env, result| {
+            cache.verify(env, || {
+                calls += 1;
+                Ok::<_, Infallible>(result)
+            })
+        };
+
+        assert_eq!(
+            Ok(EventResult::Success),
+            verify(&alice, EventResult::Success)
+        );
+        assert_eq!(
+            Ok(EventResult::Success),
+            verify(&alice, EventResult::Failure)
+        );
+        // Another password, common name or username is not cached.
+        let wrong_password = client("laptop", "alice", "hunter3");
+        assert_eq!(
+            Ok(EventResult::Failure),
+            verify(&wrong_password, EventResult::Failure)
+        );
+        assert_eq!(
+            Ok(EventResult::Failure),
+            verify(&wrong_password, EventResult::Failure)
+        );
+        let other_cert = client("phone", "alice", "hunter2");
+        assert_eq!(
+            Ok(EventResult::Failure),
+            verify(&other_cert, EventResult::Failure)
+        );
+        let other_user = client("laptop", "bob", "hunter2");
+        assert_eq!(
+            Ok(EventResult::Failure),
+            verify(&other_user, EventResult::Failure)
+        );
+        assert_eq!(5, calls);
+        assert_eq!(1, cache.len());
+    }
+
+    #[test]
+    fn expires_and_invalidates() {
+        let cache = AuthCache::new(Duration::from_secs(60)).unwrap();
+        let alice = client("laptop", "alice", "hunter2");
+        let bob = client("laptop", "bob", "correct horse");
+        let no_cert = env(&[("username", "carol"), ("password", "pw")]);
+        cache.allow(&alice);
+        cache.allow(&bob);
+        cache.allow(&no_cert);
+        cache.allow(&env(&[("common_name", "laptop")]));
+        assert_eq!(3, cache.len());
+        assert!(cache.is_allowed(&no_cert));
+
+        cache.invalidate_user("alice");
+        assert!(!cache.is_allowed(&alice));
+        assert!(cache.is_allowed(&bob));
+        cache.invalidate("laptop");
+        assert!(!cache.is_allowed(&bob));
+        cache.clear();
+        assert!(cache.is_empty());
+
+        let expired = AuthCache::new(Duration::ZERO).unwrap();
+        expired.allow(&alice);
+        assert!(!expired.is_allowed(&alice));
+        assert!(expired.is_empty());
+
+        let forever = AuthCache::new(Duration::MAX).unwrap();
+        forever.allow(&alice);
+        assert!(forever.is_allowed(&alice));
+    }
+}
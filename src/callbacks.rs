@@ -0,0 +1,252 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The signatures of the callbacks given to the [`openvpn_plugin!`] macro, as function pointer
+//! types.
+//!
+//! The macro coerces each callback to these types before using it. So a callback with the wrong
+//! signature gives a type mismatch error showing the expected and the actual signature, instead of
+//! unsatisfied `Fn` bounds on functions inside the generated code.
+//!
+//! It also has the [`PluginHandle`] trait, for keeping the callbacks as methods on the handle
+//! type.
+//!
+//! [`openvpn_plugin!`]: ../macro.openvpn_plugin.html
+//! [`PluginHandle`]: trait.PluginHandle.html
+
+use std::{collections::HashMap, error::Error, ffi::CString};
+
+use crate::{logging, EventResult, EventType, EventTypeSet};
+
+/// Signature of `$open_fn`. `S` is the events to register for, `H` the handle type and `E` the
+/// error type.
+pub type OpenFn<S, H, E> = fn(Vec<CString>, HashMap<CString, CString>) -> Result<(S, H), E>;
+
+/// Signature of `$close_fn`. `R` is either `()` or `Result<(), E>`, see [`CloseResult`].
+///
+/// [`CloseResult`]: trait.CloseResult.html
+pub type CloseFn<H, R = ()> = fn(H) -> R;
+
+/// Signature of `$event_fn`.
+pub type EventFn<H, E> =
+    fn(EventType, Vec<CString>, HashMap<CString, CString>, &mut H) -> Result<EventResult, E>;
+
+/// Signature of the optional `before_event` hook.
+pub type BeforeEventFn = fn(EventType);
+
+/// Signature of the optional `after_event` hook. Gets the result of `$event_fn`.
+pub type AfterEventFn = fn(EventType, Result<&EventResult, &dyn Error>);
+
+/// The hooks given to [`openvpn_plugin!`]. Used by the code generated by the macro.
+///
+/// [`openvpn_plugin!`]: ../macro.openvpn_plugin.html
+#[doc(hidden)]
+#[derive(Debug, Clone, Copy)]
+pub struct EventHooks {
+    pub before_event: Option<BeforeEventFn>,
+    pub after_event: Option<AfterEventFn>,
+}
+
+impl EventHooks {
+    /// Calls `event_fn` between the hooks.
+    pub fn call<H, E: Into<Box<dyn Error>>>(
+        self,
+        event_fn: EventFn<H, E>,
+        event: EventType,
+        args: Vec<CString>,
+        env: HashMap<CString, CString>,
+        handle: &mut H,
+    ) -> Result<EventResult, Box<dyn Error>> {
+        if let Some(before_event) = self.before_event {
+            before_event(event);
+        }
+        let result = event_fn(event, args, env, handle).map_err(Into::into);
+        if let Some(after_event) = self.after_event {
+            after_event(event, result.as_ref().map_err(|e| &**e));
+        }
+        result
+    }
+}
+
+
+/// The plugin callbacks as methods on the handle type. Give only the handle type to
+/// [`openvpn_plugin!`] to use them, so the state and the code using it live on one type:
+///
+/// ```rust,no_run
+/// # use openvpn_plugin::{openvpn_plugin, EventResult, EventType, EventTypeSet, PluginHandle};
+/// # use std::{collections::HashMap, ffi::CString, io};
+/// struct Handle {
+///     connected: usize,
+/// }
+///
+/// impl PluginHandle for Handle {
+///     type Error = io::Error;
+///
+///     fn open(
+///         _args: Vec<CString>,
+///         _env: HashMap<CString, CString>,
+///     ) -> Result<(EventTypeSet, Self), io::Error> {
+///         let events = EventType::ClientConnectV2 | EventType::ClientDisconnect;
+///         Ok((events, Handle { connected: 0 }))
+///     }
+///
+///     fn event(
+///         &mut self,
+///         event: EventType,
+///         _args: Vec<CString>,
+///         _env: HashMap<CString, CString>,
+///     ) -> Result<EventResult, io::Error> {
+///         match event {
+///             EventType::ClientConnectV2 => self.connected += 1,
+///             EventType::ClientDisconnect => self.connected -= 1,
+///             _ => (),
+///         }
+///         Ok(EventResult::Success)
+///     }
+/// }
+///
+/// openvpn_plugin!(Handle);
+/// # fn main() {}
+/// ```
+///
+/// [`openvpn_plugin!`]: ../macro.openvpn_plugin.html
+pub trait PluginHandle: Sized {
+    /// The error type returned by the callbacks. Any type that converts into `Box<dyn Error>`,
+    /// such as an error type, `Box<dyn Error>` itself or `anyhow::Error`.
+    type Error: Into<Box<dyn Error>>;
+
+    /// Called when the plugin is loaded. The same as `$open_fn` of [`openvpn_plugin!`].
+    ///
+    /// [`openvpn_plugin!`]: ../macro.openvpn_plugin.html
+    fn open(
+        args: Vec<CString>,
+        env: HashMap<CString, CString>,
+    ) -> Result<(EventTypeSet, Self), Self::Error>;
+
+    /// Called for every event registered for in `open`. The same as `$event_fn` of
+    /// [`openvpn_plugin!`].
+    ///
+    /// [`openvpn_plugin!`]: ../macro.openvpn_plugin.html
+    fn event(
+        &mut self,
+        event: EventType,
+        args: Vec<CString>,
+        env: HashMap<CString, CString>,
+    ) -> Result<EventResult, Self::Error>;
+
+    /// Called when the plugin is unloaded. The same as `$close_fn` of [`openvpn_plugin!`]. Does
+    /// nothing by default, other than dropping the handle.
+    ///
+    /// [`openvpn_plugin!`]: ../macro.openvpn_plugin.html
+    fn close(self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+
+/// The types `$close_fn` can return. Either `()`, or `Result<(), E>` for any error type `E`. An
+/// error is logged the same way as errors from the other callbacks. OpenVPN ignores the outcome
+/// of unloading a plugin, so there is nothing more to do with it.
+pub trait CloseResult {
+    #[doc(hidden)]
+    fn log_error(self);
+}
+
+impl CloseResult for () {
+    fn log_error(self) {}
+}
+
+impl<E: Into<Box<dyn Error>>> CloseResult for Result<(), E> {
+    fn log_error(self) {
+        if let Err(e) = self {
+            logging::log_error(&*e.into());
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io,
+        os::raw::c_void,
+        sync::{
+            atomic::{AtomicU32, Ordering},
+            Arc,
+        },
+    };
+
+    static CLOSED_HANDLE: AtomicU32 = AtomicU32::new(0);
+
+    fn close(handle: u32) -> Result<(), io::Error> {
+        CLOSED_HANDLE.store(handle, Ordering::SeqCst);
+        Err(io::Error::other("Unable to remove firewall rules"))
+    }
+
+    #[test]
+    fn close_returning_error() {
+        let close_fn: CloseFn<u32, _> = close;
+        let handle = Box::into_raw(Box::new(5u32)) as *const c_void;
+        unsafe { crate::openvpn_plugin_close::<u32, _, _>(handle, close_fn) };
+        assert_eq!(5, CLOSED_HANDLE.load(Ordering::SeqCst));
+    }
+
+    static HOOK_CALLS: AtomicU32 = AtomicU32::new(0);
+
+    fn failing_event(
+        _event: EventType,
+        _args: Vec<CString>,
+        _env: HashMap<CString, CString>,
+        handle: &mut u32,
+    ) -> Result<EventResult, io::Error> {
+        *handle += 1;
+        Err(io::Error::other("Unable to reach server"))
+    }
+
+    #[test]
+    fn event_hooks() {
+        let hooks = EventHooks {
+            before_event: Some(|_event| {
+                HOOK_CALLS.fetch_add(1, Ordering::SeqCst);
+            }),
+            after_event: Some(|event, result| {
+                assert_eq!(EventType::Up, event);
+                assert_eq!("Unable to reach server", result.unwrap_err().to_string());
+                HOOK_CALLS.fetch_add(10, Ordering::SeqCst);
+            }),
+        };
+        let mut handle = 0;
+        let result = hooks.call(
+            failing_event,
+            EventType::Up,
+            Vec::new(),
+            HashMap::new(),
+            &mut handle,
+        );
+        assert_eq!("Unable to reach server", result.unwrap_err().to_string());
+        assert_eq!(1, handle);
+        assert_eq!(11, HOOK_CALLS.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn fn_mut_close() {
+        let closed = Arc::new(AtomicU32::new(0));
+        let mut calls = 0;
+        let close_fn = {
+            let closed = closed.clone();
+            move |handle: u32| {
+                calls += 1;
+                closed.store(handle + calls, Ordering::SeqCst);
+            }
+        };
+        let handle = Box::into_raw(Box::new(5u32)) as *const c_void;
+        unsafe { crate::openvpn_plugin_close::<u32, _, _>(handle, close_fn) };
+        assert_eq!(6, closed.load(Ordering::SeqCst));
+    }
+}
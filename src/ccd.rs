@@ -0,0 +1,612 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Building of client specific config, the options a client connect event gives OpenVPN for the
+//! connecting client, like a file in the `--client-config-dir`.
+//!
+//! A [`ConfigBuilder`] writes each option in the syntax OpenVPN expects and checks the addresses
+//! it is given, so a typo in a plugin doesn't give a client an unusable config:
+//!
+//! ```rust
+//! # use openvpn_plugin::ccd::ConfigBuilder;
+//! let config = ConfigBuilder::new()
+//!     .ifconfig_push("10.8.0.6/24".parse().unwrap())
+//!     .iroute("192.168.1.0/24".parse().unwrap())
+//!     .push_route("10.0.0.0/8".parse().unwrap())
+//!     .push_dns_server(0, &["10.8.0.1".parse().unwrap()])
+//!     .build()
+//!     .unwrap();
+//! assert_eq!(
+//!     "ifconfig-push 10.8.0.6 255.255.255.0\n\
+//!      iroute 192.168.1.0 255.255.255.0\n\
+//!      push \"route 10.0.0.0 255.0.0.0\"\n\
+//!      push \"dns server 0 address 10.8.0.1\"\n",
+//!     config.to_string()
+//! );
+//! ```
+//!
+//! The text of a [`ClientConfig`] is the config OpenVPN reads from a `config` entry of the return
+//! list of a client connect event, and its [`lines`] are what
+//! [`DeferredClientConnect::approve`] writes for deferred client connect events.
+//!
+//! Plugins that keep their own files per client, like OpenVPN does in `--client-config-dir`,
+//! should name them with [`config_path`], which filters the common name the way OpenVPN does, so
+//! a certificate with the common name `../../etc/passwd` can't reach outside the directory.
+//!
+//! [`ConfigBuilder`]: struct.ConfigBuilder.html
+//! [`ClientConfig`]: struct.ClientConfig.html
+//! [`lines`]: struct.ClientConfig.html#method.lines
+//! [`DeferredClientConnect::approve`]:
+//!     ../client_connect/struct.DeferredClientConnect.html#method.approve
+//! [`config_path`]: fn.config_path.html
+
+use std::{
+    error::Error,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::{Path, PathBuf},
+};
+
+use crate::events::IpNetwork;
+
+/// Error building a client config.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ConfigError {
+    /// The address can't be given to a client or routed, such as a multicast or loopback address.
+    InvalidAddress(IpAddr),
+    /// The network is of the wrong address family for the option, such as an IPv6 network given
+    /// to `ifconfig-push`.
+    WrongFamily(IpNetwork),
+    /// The network has bits set after the prefix, such as `10.0.0.1/8`.
+    NotNetworkAddress(IpNetwork),
+    /// The address is the network or broadcast address of its subnet.
+    NotHostAddress(IpNetwork),
+    /// The option is given twice, but OpenVPN only uses one of them.
+    Duplicate(&'static str),
+    /// The domain name is not valid.
+    InvalidDomain(String),
+    /// The option is empty, or has a quote or line break in it.
+    InvalidOption(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidAddress(address) => write!(f, "Invalid address {}", address),
+            ConfigError::WrongFamily(network) => {
+                write!(f, "{} is of the wrong address family", network)
+            }
+            ConfigError::NotNetworkAddress(network) => {
+                write!(f, "{} has host bits set", network)
+            }
+            ConfigError::NotHostAddress(network) => {
+                write!(f, "{} is not a host address of its subnet", network)
+            }
+            ConfigError::Duplicate(option) => write!(f, "{} is given more than once", option),
+            ConfigError::InvalidDomain(domain) => write!(f, "Invalid domain \"{}\"", domain),
+            ConfigError::InvalidOption(option) => write!(f, "Invalid option \"{}\"", option),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// Client specific config, one option per line.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct ClientConfig {
+    lines: Vec<String>,
+}
+
+impl ClientConfig {
+    /// The options of the config.
+    pub fn lines(&self) -> impl Iterator<Item = &str> {
+        self.lines.iter().map(String::as_str)
+    }
+
+    /// Returns true if the config has no options.
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+}
+
+/// Formats the config the way OpenVPN reads it, each option on a line of its own.
+impl fmt::Display for ClientConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            writeln!(f, "{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Builds a [`ClientConfig`]. The first invalid option is returned as an error from [`build`].
+///
+/// [`ClientConfig`]: struct.ClientConfig.html
+/// [`build`]: #method.build
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    lines: Vec<String>,
+    ifconfig: bool,
+    ifconfig_ipv6: bool,
+    error: Option<ConfigError>,
+}
+
+impl ConfigBuilder {
+    /// Creates a builder of an empty config.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gives the client `address` in a subnet topology, with `ifconfig-push <address> <netmask>`.
+    pub fn ifconfig_push(self, address: IpNetwork) -> Self {
+        self.with(|builder| {
+            let (host, _) = ipv4(address)?;
+            check_host(address)?;
+            once(&mut builder.ifconfig, "ifconfig-push")?;
+            Ok(format!(
+                "ifconfig-push {} {}",
+                host,
+                netmask(address.prefix())
+            ))
+        })
+    }
+
+    /// Gives the client `local` in a point-to-point topology, with the server end at `remote`,
+    /// with `ifconfig-push <local> <remote>`.
+    pub fn ifconfig_push_p2p(self, local: Ipv4Addr, remote: Ipv4Addr) -> Self {
+        self.with(|builder| {
+            check_address(local.into())?;
+            check_address(remote.into())?;
+            once(&mut builder.ifconfig, "ifconfig-push")?;
+            Ok(format!("ifconfig-push {} {}", local, remote))
+        })
+    }
+
+    /// Gives the client the IPv6 `address`, with the server end at `remote` if given, with
+    /// `ifconfig-ipv6-push`.
+    pub fn ifconfig_ipv6_push(self, address: IpNetwork, remote: Option<Ipv6Addr>) -> Self {
+        self.with(|builder| {
+            if !address.address().is_ipv6() {
+                return Err(ConfigError::WrongFamily(address));
+            }
+            check_address(address.address())?;
+            if let Some(remote) = remote {
+                check_address(remote.into())?;
+            }
+            once(&mut builder.ifconfig_ipv6, "ifconfig-ipv6-push")?;
+            Ok(match remote {
+                Some(remote) => format!("ifconfig-ipv6-push {} {}", address, remote),
+                None => format!("ifconfig-ipv6-push {}", address),
+            })
+        })
+    }
+
+    /// Routes `network` to the client, with `iroute` or `iroute-ipv6`. The server also needs a
+    /// route of the network to the tunnel.
+    pub fn iroute(self, network: IpNetwork) -> Self {
+        self.with(|_| {
+            check_network(network)?;
+            Ok(match network.address() {
+                IpAddr::V4(address) => {
+                    format!("iroute {} {}", address, netmask(network.prefix()))
+                }
+                IpAddr::V6(_) => format!("iroute-ipv6 {}", network),
+            })
+        })
+    }
+
+    /// Pushes a route of `network` through the tunnel to the client, with `route` or `route-ipv6`.
+    pub fn push_route(self, network: IpNetwork) -> Self {
+        self.with(|_| {
+            check_network(network)?;
+            Ok(match network.address() {
+                IpAddr::V4(address) => {
+                    format!("push \"route {} {}\"", address, netmask(network.prefix()))
+                }
+                IpAddr::V6(_) => format!("push \"route-ipv6 {}\"", network),
+            })
+        })
+    }
+
+    /// Pushes a route of `network` through `gateway` with `metric` to the client. The gateway
+    /// must be of the same address family as the network. OpenVPN only supports metrics of IPv4
+    /// routes, so `metric` is ignored for IPv6 networks.
+    pub fn push_route_via(self, network: IpNetwork, gateway: IpAddr, metric: Option<u32>) -> Self {
+        self.with(|_| {
+            check_network(network)?;
+            check_address(gateway)?;
+            Ok(match (network.address(), gateway) {
+                (IpAddr::V4(address), IpAddr::V4(gateway)) => {
+                    let mut route = format!(
+                        "route {} {} {}",
+                        address,
+                        netmask(network.prefix()),
+                        gateway
+                    );
+                    if let Some(metric) = metric {
+                        route.push_str(&format!(" {}", metric));
+                    }
+                    format!("push \"{}\"", route)
+                }
+                (IpAddr::V6(_), IpAddr::V6(gateway)) => {
+                    format!("push \"route-ipv6 {} {}\"", network, gateway)
+                }
+                _ => return Err(ConfigError::WrongFamily(network)),
+            })
+        })
+    }
+
+    /// Pushes a DNS server with the addresses `addresses` to the client, with
+    /// `dns server <priority> address`. Servers with a lower priority are preferred. Needs
+    /// OpenVPN 2.6 on the client.
+    pub fn push_dns_server(self, priority: i8, addresses: &[IpAddr]) -> Self {
+        self.with(|_| {
+            if addresses.is_empty() {
+                return Err(ConfigError::InvalidOption(format!(
+                    "dns server {} address",
+                    priority
+                )));
+            }
+            let mut option = format!("push \"dns server {} address", priority);
+            for address in addresses {
+                check_address(*address)?;
+                option.push_str(&format!(" {}", address));
+            }
+            option.push('"');
+            Ok(option)
+        })
+    }
+
+    /// Pushes the domains the client looks up unqualified names in, with
+    /// `dns search-domains`. Needs OpenVPN 2.6 on the client.
+    pub fn push_dns_search_domains(self, domains: &[&str]) -> Self {
+        self.with(|_| {
+            if domains.is_empty() {
+                return Err(ConfigError::InvalidOption("dns search-domains".to_owned()));
+            }
+            for domain in domains {
+                check_domain(domain)?;
+            }
+            Ok(format!("push \"dns search-domains {}\"", domains.join(" ")))
+        })
+    }
+
+    /// Pushes any other `option` to the client, such as `redirect-gateway def1`. The option is
+    /// checked to fit in the quotes of `push`, nothing else.
+    pub fn push(self, option: &str) -> Self {
+        self.with(|_| {
+            check_option(option)?;
+            Ok(format!("push \"{}\"", option))
+        })
+    }
+
+    /// Adds any other option for the server, such as `push-reset`. The option is checked to be
+    /// on a single line, nothing else.
+    pub fn option(self, option: &str) -> Self {
+        self.with(|_| {
+            if option.trim().is_empty() || option.contains(&['\n', '\r'][..]) {
+                return Err(ConfigError::InvalidOption(option.to_owned()));
+            }
+            Ok(option.to_owned())
+        })
+    }
+
+    /// Returns the config, or the first invalid option.
+    pub fn build(self) -> Result<ClientConfig, ConfigError> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(ClientConfig { lines: self.lines }),
+        }
+    }
+
+    /// Adds the line `line` returns, unless an earlier option was invalid.
+    fn with<F>(mut self, line: F) -> Self
+    where
+        F: FnOnce(&mut Self) -> Result<String, ConfigError>,
+    {
+        if self.error.is_none() {
+            match line(&mut self) {
+                Ok(line) => self.lines.push(line),
+                Err(error) => self.error = Some(error),
+            }
+        }
+        self
+    }
+}
+
+/// Replaces the ASCII control characters in `common_name` with `_`, like OpenVPN does before it
+/// puts the common name of a client in the environment and in its logs. Non-ASCII characters are
+/// kept, since OpenVPN allows UTF-8 in common names.
+pub fn sanitize_common_name(common_name: &str) -> String {
+    common_name
+        .chars()
+        .map(|c| if c.is_ascii_control() { '_' } else { c })
+        .collect()
+}
+
+/// The path of the file named after the client with `common_name` in `dir`, like OpenVPN finds
+/// the file of a client in `--client-config-dir`. ASCII control characters and path separators
+/// in the common name are replaced with `_`, and `None` is returned for the names `.` and `..`, so
+/// the path is always directly in `dir`.
+///
+/// On Windows, the characters Windows doesn't allow in file names are replaced too, and `None` is
+/// returned for reserved device names such as `NUL`.
+pub fn config_path(dir: impl AsRef<Path>, common_name: &str) -> Option<PathBuf> {
+    let file_name: String = sanitize_common_name(common_name)
+        .chars()
+        .map(|c| if is_path_reserved(c) { '_' } else { c })
+        .collect();
+    if file_name.is_empty() || file_name == "." || file_name == ".." || is_device_name(&file_name) {
+        return None;
+    }
+    Some(dir.as_ref().join(file_name))
+}
+
+#[cfg(windows)]
+fn is_path_reserved(c: char) -> bool {
+    matches!(c, '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*')
+}
+
+#[cfg(not(windows))]
+fn is_path_reserved(c: char) -> bool {
+    c == '/'
+}
+
+#[cfg(windows)]
+fn is_device_name(file_name: &str) -> bool {
+    const DEVICES: &[&str] = &[
+        "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+        "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+    let stem = file_name.split('.').next().unwrap_or("").trim_end();
+    DEVICES
+        .iter()
+        .any(|device| device.eq_ignore_ascii_case(stem))
+}
+
+#[cfg(not(windows))]
+fn is_device_name(_file_name: &str) -> bool {
+    false
+}
+
+/// Fails if `option` was already given, and marks it as given otherwise.
+fn once(given: &mut bool, option: &'static str) -> Result<(), ConfigError> {
+    if *given {
+        return Err(ConfigError::Duplicate(option));
+    }
+    *given = true;
+    Ok(())
+}
+
+fn ipv4(network: IpNetwork) -> Result<(Ipv4Addr, u8), ConfigError> {
+    match network.address() {
+        IpAddr::V4(address) => Ok((address, network.prefix())),
+        IpAddr::V6(_) => Err(ConfigError::WrongFamily(network)),
+    }
+}
+
+fn netmask(prefix: u8) -> Ipv4Addr {
+    Ipv4Addr::from(u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0))
+}
+
+/// Fails for addresses that can't be used for a client or a gateway.
+fn check_address(address: IpAddr) -> Result<(), ConfigError> {
+    let invalid = address.is_unspecified()
+        || address.is_multicast()
+        || address.is_loopback()
+        || address == IpAddr::V4(Ipv4Addr::BROADCAST);
+    if invalid {
+        Err(ConfigError::InvalidAddress(address))
+    } else {
+        Ok(())
+    }
+}
+
+/// Fails for IPv4 addresses that are the network or broadcast address of their subnet.
+fn check_host(address: IpNetwork) -> Result<(), ConfigError> {
+    let (host, prefix) = ipv4(address)?;
+    check_address(host.into())?;
+    let host_bits = !u32::from(netmask(prefix));
+    let host = u32::from(host) & host_bits;
+    // Subnets of one or two addresses have no network or broadcast address.
+    if prefix < 31 && (host == 0 || host == host_bits) {
+        return Err(ConfigError::NotHostAddress(address));
+    }
+    Ok(())
+}
+
+/// Fails for networks with host bits set, and for networks of invalid addresses other than the
+/// default route.
+fn check_network(network: IpNetwork) -> Result<(), ConfigError> {
+    let host_bits = match network.address() {
+        IpAddr::V4(address) => {
+            u128::from(u32::from(address) & !u32::from(netmask(network.prefix())))
+        }
+        IpAddr::V6(address) => {
+            u128::from(address)
+                & u128::MAX
+                    .checked_shr(u32::from(network.prefix()))
+                    .unwrap_or(0)
+        }
+    };
+    if host_bits != 0 {
+        return Err(ConfigError::NotNetworkAddress(network));
+    }
+    if network.prefix() > 0 && (network.address().is_loopback() || network.address().is_multicast())
+    {
+        return Err(ConfigError::InvalidAddress(network.address()));
+    }
+    Ok(())
+}
+
+fn check_domain(domain: &str) -> Result<(), ConfigError> {
+    let valid = domain.len() <= 253
+        && domain.trim_end_matches('.').split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    if valid {
+        Ok(())
+    } else {
+        Err(ConfigError::InvalidDomain(domain.to_owned()))
+    }
+}
+
+fn check_option(option: &str) -> Result<(), ConfigError> {
+    if option.trim().is_empty() || option.contains(&['"', '\n', '\r'][..]) {
+        Err(ConfigError::InvalidOption(option.to_owned()))
+    } else {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(network: &str) -> IpNetwork {
+        network.parse().unwrap()
+    }
+
+    #[test]
+    fn builds_options() {
+        let config = ConfigBuilder::new()
+            .ifconfig_push_p2p("10.8.0.6".parse().unwrap(), "10.8.0.5".parse().unwrap())
+            .ifconfig_ipv6_push(net("fd00::1000/64"), Some("fd00::1".parse().unwrap()))
+            .iroute(net("fd00:1::/48"))
+            .push_route(net("0.0.0.0/0"))
+            .push_route(net("2000::/3"))
+            .push_route_via(net("10.0.0.0/8"), "10.8.0.1".parse().unwrap(), Some(100))
+            .push_dns_server(
+                -1,
+                &["10.8.0.1".parse().unwrap(), "fd00::1".parse().unwrap()],
+            )
+            .push_dns_search_domains(&["vpn.example.com", "example.com."])
+            .push("redirect-gateway def1")
+            .option("push-reset")
+            .build()
+            .unwrap();
+        assert_eq!(
+            vec![
+                "ifconfig-push 10.8.0.6 10.8.0.5",
+                "ifconfig-ipv6-push fd00::1000/64 fd00::1",
+                "iroute-ipv6 fd00:1::/48",
+                "push \"route 0.0.0.0 0.0.0.0\"",
+                "push \"route-ipv6 2000::/3\"",
+                "push \"route 10.0.0.0 255.0.0.0 10.8.0.1 100\"",
+                "push \"dns server -1 address 10.8.0.1 fd00::1\"",
+                "push \"dns search-domains vpn.example.com example.com.\"",
+                "push \"redirect-gateway def1\"",
+                "push-reset",
+            ],
+            config.lines().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        let error = |builder: ConfigBuilder| builder.build().unwrap_err();
+        assert_eq!(
+            ConfigError::NotHostAddress(net("10.8.0.0/24")),
+            error(ConfigBuilder::new().ifconfig_push(net("10.8.0.0/24")))
+        );
+        assert_eq!(
+            ConfigError::NotHostAddress(net("10.8.0.255/24")),
+            error(ConfigBuilder::new().ifconfig_push(net("10.8.0.255/24")))
+        );
+        assert_eq!(
+            ConfigError::WrongFamily(net("fd00::1/64")),
+            error(ConfigBuilder::new().ifconfig_push(net("fd00::1/64")))
+        );
+        assert_eq!(
+            ConfigError::Duplicate("ifconfig-push"),
+            error(
+                ConfigBuilder::new()
+                    .ifconfig_push(net("10.8.0.6/24"))
+                    .ifconfig_push(net("10.8.0.7/24"))
+            )
+        );
+        assert_eq!(
+            ConfigError::NotNetworkAddress(net("10.0.0.1/8")),
+            error(ConfigBuilder::new().push_route(net("10.0.0.1/8")))
+        );
+        assert_eq!(
+            ConfigError::NotNetworkAddress(net("fd00::1/64")),
+            error(ConfigBuilder::new().iroute(net("fd00::1/64")))
+        );
+        assert_eq!(
+            ConfigError::InvalidAddress("224.0.0.0".parse().unwrap()),
+            error(ConfigBuilder::new().iroute(net("224.0.0.0/4")))
+        );
+        assert_eq!(
+            ConfigError::WrongFamily(net("10.0.0.0/8")),
+            error(ConfigBuilder::new().push_route_via(
+                net("10.0.0.0/8"),
+                "fd00::1".parse().unwrap(),
+                None
+            ))
+        );
+        assert_eq!(
+            ConfigError::InvalidAddress("127.0.0.1".parse().unwrap()),
+            error(ConfigBuilder::new().push_dns_server(0, &["127.0.0.1".parse().unwrap()]))
+        );
+        assert_eq!(
+            ConfigError::InvalidDomain("-bad.example.com".to_owned()),
+            error(ConfigBuilder::new().push_dns_search_domains(&["-bad.example.com"]))
+        );
+        assert_eq!(
+            ConfigError::InvalidOption("route \"x\"".to_owned()),
+            error(ConfigBuilder::new().push("route \"x\""))
+        );
+        assert_eq!(
+            ConfigError::InvalidOption("a\nb".to_owned()),
+            error(ConfigBuilder::new().option("a\nb"))
+        );
+    }
+
+    #[test]
+    fn formats_config() {
+        let config = ConfigBuilder::new()
+            .ifconfig_push(net("10.8.0.6/31"))
+            .iroute(net("192.168.1.0/24"))
+            .build()
+            .unwrap();
+        assert_eq!(
+            "ifconfig-push 10.8.0.6 255.255.255.254\niroute 192.168.1.0 255.255.255.0\n",
+            config.to_string()
+        );
+        assert!(ConfigBuilder::new().build().unwrap().is_empty());
+    }
+
+    #[test]
+    fn sanitizes_common_name() {
+        assert_eq!("alice_bob", sanitize_common_name("alice\nbob"));
+        assert_eq!("Ålice_Ö", sanitize_common_name("Ålice\u{7f}Ö"));
+        assert_eq!("alice\u{202e}bob", sanitize_common_name("alice\u{202e}bob"));
+        assert_eq!("alice bob", sanitize_common_name("alice bob"));
+        assert_eq!(
+            Some(Path::new("/etc/openvpn/ccd").join(".._.._etc_passwd")),
+            config_path("/etc/openvpn/ccd", "../../etc/passwd")
+        );
+        assert_eq!(
+            Some(Path::new("ccd").join("alice_")),
+            config_path("ccd", "alice\r")
+        );
+        assert_eq!(
+            Some(Path::new("ccd").join("Ålice")),
+            config_path("ccd", "Ålice")
+        );
+        assert_eq!(None, config_path("ccd", ".."));
+        assert_eq!(None, config_path("ccd", "."));
+        assert_eq!(None, config_path("ccd", ""));
+    }
+}
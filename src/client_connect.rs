@@ -0,0 +1,147 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    path::{Path, PathBuf},
+};
+
+use crate::auth::{env_path, validate_path, write_atomic, ControlFileError};
+
+pub use crate::env_keys::{CLIENT_CONNECT_CONFIG_FILE, CLIENT_CONNECT_DEFERRED_FILE};
+
+
+/// The files OpenVPN reads the result of a deferred `EventType::ClientConnect` or
+/// `EventType::ClientConnectV2` from. OpenVPN 2.5 and later gives them in the environment of
+/// these events.
+///
+/// A plugin that returns `EventResult::Deferred` from one of these events must later call
+/// either [`approve`] or [`deny`] on the instance created from that event's environment. Once the
+/// result is written, OpenVPN calls the `EventType::ClientConnectDefer` or
+/// `EventType::ClientConnectDeferV2` event.
+///
+/// [`approve`]: #method.approve
+/// [`deny`]: #method.deny
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct DeferredClientConnect {
+    deferred_file: PathBuf,
+    config_file: PathBuf,
+}
+
+impl DeferredClientConnect {
+    /// Locates the control files from the `client_connect_deferred_file` and
+    /// `client_connect_config_file` variables in the environment of a client connect event.
+    pub fn from_env(env: &HashMap<CString, CString>) -> Result<Self, ControlFileError> {
+        let deferred_file = env_path(env, CLIENT_CONNECT_DEFERRED_FILE)?;
+        let config_file = env_path(env, CLIENT_CONNECT_CONFIG_FILE)?;
+        validate_path(&deferred_file)?;
+        validate_path(&config_file)?;
+        Ok(DeferredClientConnect {
+            deferred_file,
+            config_file,
+        })
+    }
+
+    /// Returns the path to the file the result is written to.
+    pub fn deferred_file(&self) -> &Path {
+        &self.deferred_file
+    }
+
+    /// Returns the path to the file the client config is written to.
+    pub fn config_file(&self) -> &Path {
+        &self.config_file
+    }
+
+    /// Accepts the client. Writes `config`, one option per line, to the config file and then
+    /// `1` to the deferred file. The config can be empty.
+    pub fn approve<I, S>(self, config: I) -> Result<(), ControlFileError>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut content = String::new();
+        for option in config {
+            let option = option.as_ref();
+            if option.contains(&['\n', '\r'][..]) {
+                return Err(ControlFileError::InvalidValue(option.to_owned()));
+            }
+            content.push_str(option);
+            content.push('\n');
+        }
+        write_atomic(&self.config_file, content.as_bytes())?;
+        write_atomic(&self.deferred_file, b"1")
+    }
+
+    /// Rejects the client by writing `0` to the deferred file.
+    pub fn deny(self) -> Result<(), ControlFileError> {
+        write_atomic(&self.deferred_file, b"0")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::temp_path;
+    use std::fs;
+
+    fn test_env(name: &str) -> (PathBuf, PathBuf, HashMap<CString, CString>) {
+        let deferred = temp_path(&format!("cc-{}", name));
+        let config = temp_path(&format!("ccc-{}", name));
+        let mut env = HashMap::new();
+        env.insert(
+            CString::new(CLIENT_CONNECT_DEFERRED_FILE).unwrap(),
+            CString::new(deferred.to_str().unwrap()).unwrap(),
+        );
+        env.insert(
+            CString::new(CLIENT_CONNECT_CONFIG_FILE).unwrap(),
+            CString::new(config.to_str().unwrap()).unwrap(),
+        );
+        (deferred, config, env)
+    }
+
+    #[test]
+    fn approve_writes_config_and_result() {
+        let (deferred, config, env) = test_env("approve");
+        DeferredClientConnect::from_env(&env)
+            .unwrap()
+            .approve([
+                "push \"route 10.0.0.0 255.0.0.0\"",
+                "ifconfig-push 10.8.0.5 10.8.0.6",
+            ])
+            .unwrap();
+        assert_eq!(
+            "push \"route 10.0.0.0 255.0.0.0\"\nifconfig-push 10.8.0.5 10.8.0.6\n",
+            fs::read_to_string(&config).unwrap()
+        );
+        assert_eq!("1", fs::read_to_string(&deferred).unwrap());
+        fs::remove_file(&config).unwrap();
+        fs::remove_file(&deferred).unwrap();
+    }
+
+    #[test]
+    fn approve_rejects_newline() {
+        let (deferred, _config, env) = test_env("newline");
+        let result = DeferredClientConnect::from_env(&env)
+            .unwrap()
+            .approve(["push \"route\nfoo\""]);
+        assert!(matches!(result, Err(ControlFileError::InvalidValue(_))));
+        assert!(!deferred.exists());
+    }
+
+    #[test]
+    fn missing_config_file() {
+        let (_, _, mut env) = test_env("missing");
+        env.remove(&CString::new(CLIENT_CONNECT_CONFIG_FILE).unwrap());
+        match DeferredClientConnect::from_env(&env) {
+            Err(ControlFileError::MissingEnv(CLIENT_CONNECT_CONFIG_FILE)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! State OpenVPN keeps for each client, for server plugins.
+//!
+//! With `exports = [client_context(T)]` given to [`openvpn_plugin!`], the plugin exports the
+//! client constructor and destructor of the plugin API. OpenVPN then creates a `T::default()`
+//! when a client connects, passes it with every event of that client, and drops it when the
+//! client is gone. The event callback reaches the context of the client of the current event with
+//! [`with_current`]:
+//!
+//! ```rust,no_run
+//! # use openvpn_plugin::{client_context, openvpn_plugin, EventResult, EventType};
+//! # use std::{collections::HashMap, ffi::CString, io};
+//! #[derive(Default)]
+//! struct Client {
+//!     events: u64,
+//! }
+//!
+//! fn event(
+//!     event: EventType,
+//!     _args: Vec<CString>,
+//!     _env: HashMap<CString, CString>,
+//!     _handle: &mut (),
+//! ) -> Result<EventResult, io::Error> {
+//!     client_context::with_current(|client: Option<&mut Client>| {
+//!         if let Some(client) = client {
+//!             client.events += 1;
+//!         }
+//!     });
+//!     Ok(EventResult::Success)
+//! }
+//! # fn open(_: Vec<CString>, _: HashMap<CString, CString>) -> Result<(Vec<EventType>, ()), io::Error> {
+//! #     unimplemented!();
+//! # }
+//! # fn close(_: ()) {}
+//!
+//! openvpn_plugin!(
+//!     crate::open,
+//!     crate::close,
+//!     crate::event,
+//!     (),
+//!     exports = [abort, client_context(crate::Client)]
+//! );
+//! # fn main() {}
+//! ```
+//!
+//! The context is only available on the thread OpenVPN calls the plugin on, and only for events
+//! that belong to a client.
+//!
+//! [`openvpn_plugin!`]: ../macro.openvpn_plugin.html
+//! [`with_current`]: fn.with_current.html
+
+use std::{any::Any, cell::Cell, os::raw::c_void, panic, ptr};
+
+use crate::logging;
+
+/// The context as given to OpenVPN. Boxed twice, since OpenVPN keeps a thin pointer.
+type Context = Box<dyn Any + Send>;
+
+thread_local! {
+    /// The context of the client of the event handled on this thread.
+    static CURRENT: Cell<*mut c_void> = const { Cell::new(ptr::null_mut()) };
+}
+
+/// Calls `f` with the context of the client of the event being handled on this thread. `None` if
+/// there is no such client, if its context is not a `T`, or if this is called from within another
+/// `with_current`.
+pub fn with_current<T: 'static, R>(f: impl FnOnce(Option<&mut T>) -> R) -> R {
+    // Taken out while `f` runs, so nested calls can't get a second reference to it.
+    let context = CURRENT.with(|current| current.replace(ptr::null_mut()));
+    let _restore = Guard(context);
+    // Safe since `enter` only sets pointers created by `construct`, for the duration of an event.
+    let context = unsafe { (context as *mut Context).as_mut() };
+    f(context.and_then(|context| context.downcast_mut()))
+}
+
+/// Makes `context` the context `with_current` gives on this thread, until the guard is dropped.
+pub(crate) fn enter(context: *const c_void) -> Guard {
+    Guard(CURRENT.with(|current| current.replace(context as *mut c_void)))
+}
+
+/// Restores the previous context of the thread when dropped.
+pub(crate) struct Guard(*mut c_void);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.0));
+    }
+}
+
+/// Internal helper function. This function should never be called manually, only by code generated
+/// by the [`openvpn_plugin!`] macro.
+///
+/// [`openvpn_plugin!`]: ../macro.openvpn_plugin.html
+#[doc(hidden)]
+pub fn construct<T: Default + Send + 'static>() -> *mut c_void {
+    match crate::catch_unwind(|| Box::new(T::default()) as Context) {
+        Ok(context) => Box::into_raw(Box::new(context)) as *mut c_void,
+        Err(e) => {
+            logging::log_panic("client constructor", &e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Internal helper function. This function should never be called manually, only by code generated
+/// by the [`openvpn_plugin!`] macro.
+///
+/// # Safety
+///
+/// `context` must be null or a pointer returned by `construct`, and must not be used again after
+/// this call.
+///
+/// [`openvpn_plugin!`]: ../macro.openvpn_plugin.html
+#[doc(hidden)]
+pub unsafe fn destruct(context: *mut c_void) {
+    if context.is_null() {
+        return;
+    }
+    let context = panic::AssertUnwindSafe(Box::from_raw(context as *mut Context));
+    if let Err(e) = crate::catch_unwind(move || drop(context)) {
+        logging::log_panic("client destructor", &e);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn current_context() {
+        let context = construct::<u32>();
+        assert_eq!(None, with_current(|n: Option<&mut u32>| n.copied()));
+        {
+            let _current = enter(context);
+            with_current(|n: Option<&mut u32>| *n.unwrap() += 2);
+            assert_eq!(Some(2), with_current(|n: Option<&mut u32>| n.copied()));
+            // Wrong type, and nested.
+            assert!(with_current(|n: Option<&mut String>| n.is_none()));
+            assert!(with_current(|_: Option<&mut u32>| {
+                with_current(|n: Option<&mut u32>| n.is_none())
+            }));
+        }
+        assert_eq!(None, with_current(|n: Option<&mut u32>| n.copied()));
+        unsafe { destruct(context) };
+    }
+}
@@ -0,0 +1,485 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Loading of the plugin config file. Requires at least one of the `config-toml`,
+//! `config-json` and `config-yaml` features.
+//!
+//! The path to the config file is given with the `--config` option to the plugin in the OpenVPN
+//! config:
+//!
+//! ```text
+//! plugin /usr/lib/openvpn/my_plugin.so --config /etc/openvpn/my_plugin.toml
+//! ```
+//!
+//! The format is picked from the file extension. Any type implementing `Deserialize` can be
+//! loaded by implementing [`Config`] for it, optionally with extra validation:
+//!
+//! ```rust,no_run
+//! # #[cfg(feature = "config-toml")] {
+//! use std::{collections::HashMap, ffi::CString};
+//! use openvpn_plugin::{args::PluginArgs, config::{self, Config}, EventType};
+//! use serde::Deserialize;
+//!
+//! #[derive(Deserialize)]
+//! struct Settings {
+//!     auth_url: String,
+//!     timeout_secs: u64,
+//! }
+//!
+//! impl Config for Settings {
+//!     fn validate(&self) -> Result<(), String> {
+//!         if self.timeout_secs == 0 {
+//!             return Err("timeout_secs must be positive".to_owned());
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//!
+//! fn open(
+//!     args: Vec<CString>,
+//!     _env: HashMap<CString, CString>,
+//! ) -> Result<(Vec<EventType>, Settings), Box<dyn std::error::Error>> {
+//!     let args = PluginArgs::parse(&args)?;
+//!     let settings: Settings = config::from_args(&args)?;
+//!     Ok((vec![EventType::AuthUserPassVerify], settings))
+//! }
+//! # }
+//! ```
+//!
+//! Errors in the file are reported with the offending key and the line it is on, e.g.
+//! `Invalid config in /etc/openvpn/my_plugin.toml at line 3, column 16, key "timeout_secs":
+//! invalid type: string "ten", expected u64`.
+//!
+//! ## Reloading
+//!
+//! With the `config-reload` feature, [`SharedConfig::from_args`] loads the config and then
+//! reloads it every time the file changes, so for example authentication policies can be changed
+//! without restarting OpenVPN. The callbacks get the current config from the [`SharedConfig`]. A
+//! changed file that fails to load is logged and the previous config is kept. Plugins that want to
+//! handle reloads themselves use [`ConfigWatcher::watch`] instead.
+//!
+//! [`Config`]: trait.Config.html
+//! [`SharedConfig`]: struct.SharedConfig.html
+//! [`SharedConfig::from_args`]: struct.SharedConfig.html#method.from_args
+//! [`ConfigWatcher::watch`]: struct.ConfigWatcher.html#method.watch
+
+use std::{
+    error::Error,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::de::DeserializeOwned;
+
+use crate::args::PluginArgs;
+
+#[cfg(feature = "config-reload")]
+mod reload;
+#[cfg(feature = "config-reload")]
+pub use self::reload::{ConfigWatcher, SharedConfig};
+
+/// A plugin config that can be loaded from a file.
+pub trait Config: DeserializeOwned {
+    /// Checks the values in the config after it has been parsed. The returned message is included
+    /// in the error. Accepts everything unless overridden.
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// The formats config files can be written in.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum Format {
+    /// TOML, for files ending in `.toml`. Requires the `config-toml` feature.
+    #[cfg(feature = "config-toml")]
+    Toml,
+    /// JSON, for files ending in `.json`. Requires the `config-json` feature.
+    #[cfg(feature = "config-json")]
+    Json,
+    /// YAML, for files ending in `.yaml` or `.yml`. Requires the `config-yaml` feature.
+    #[cfg(feature = "config-yaml")]
+    Yaml,
+}
+
+impl Format {
+    /// Picks the format from the extension of `path`. Returns `None` for extensions of formats
+    /// that are not enabled.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            #[cfg(feature = "config-toml")]
+            "toml" => Some(Format::Toml),
+            #[cfg(feature = "config-json")]
+            "json" => Some(Format::Json),
+            #[cfg(feature = "config-yaml")]
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+}
+
+/// The plugin option the path to the config file is given in, as `--config PATH`.
+pub const PATH_ARG: &str = "config";
+
+/// Returns the path given in the `--config PATH` plugin option.
+pub fn path_from_args(args: &PluginArgs) -> Result<PathBuf, ConfigError> {
+    let path = args.get(PATH_ARG).ok_or(ConfigError::MissingPath)?;
+    Ok(PathBuf::from(path))
+}
+
+/// Loads the config file given in the `--config PATH` plugin option.
+pub fn from_args<T: Config>(args: &PluginArgs) -> Result<T, ConfigError> {
+    load(path_from_args(args)?)
+}
+
+/// Loads and validates the config file at `path`, in the format its extension tells.
+pub fn load<T: Config>(path: impl AsRef<Path>) -> Result<T, ConfigError> {
+    let path = path.as_ref();
+    let format =
+        Format::from_path(path).ok_or_else(|| ConfigError::UnknownFormat(path.to_owned()))?;
+    let text = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.to_owned(),
+        source,
+    })?;
+    let config: T = parse(&text, format).map_err(|error| ConfigError::Parse {
+        path: path.to_owned(),
+        error,
+    })?;
+    config.validate().map_err(|message| ConfigError::Invalid {
+        path: path.to_owned(),
+        message,
+    })?;
+    Ok(config)
+}
+
+/// Parses a config from `text` in `format`, without validating it.
+pub fn parse<T: DeserializeOwned>(text: &str, format: Format) -> Result<T, ParseError> {
+    match format {
+        #[cfg(feature = "config-toml")]
+        Format::Toml => {
+            serde_path_to_error::deserialize(toml::Deserializer::new(text)).map_err(|e| {
+                let key = key(e.path());
+                let e = e.into_inner();
+                let position = e.span().map(|span| position(text, span.start));
+                ParseError::new(key, position, e.message().to_owned())
+            })
+        }
+        #[cfg(feature = "config-json")]
+        Format::Json => {
+            let mut deserializer = serde_json::Deserializer::from_str(text);
+            serde_path_to_error::deserialize(&mut deserializer)
+                .map_err(|e| {
+                    let key = key(e.path());
+                    (key, e.into_inner())
+                })
+                .and_then(|config| deserializer.end().map(|()| config).map_err(|e| (None, e)))
+                .map_err(|(key, e)| {
+                    let position = Some((e.line(), e.column())).filter(|(line, _)| *line > 0);
+                    let message = strip_location(e.to_string(), position);
+                    ParseError::new(key, position, message)
+                })
+        }
+        #[cfg(feature = "config-yaml")]
+        Format::Yaml => serde_path_to_error::deserialize(serde_yaml::Deserializer::from_str(text))
+            .map_err(|e| {
+                let key = key(e.path());
+                let e = e.into_inner();
+                let position = e
+                    .location()
+                    .map(|location| (location.line(), location.column()));
+                let mut message = strip_location(e.to_string(), position);
+                if let Some(key) = &key {
+                    // serde_yaml prefixes the message with the key it failed at.
+                    if let Some(stripped) = message.strip_prefix(&format!("{}: ", key)) {
+                        message = stripped.to_owned();
+                    }
+                }
+                ParseError::new(key, position, message)
+            }),
+    }
+}
+
+/// The key path of an error, or `None` for errors at the top level.
+fn key(path: &serde_path_to_error::Path) -> Option<String> {
+    Some(path.to_string()).filter(|path| path != ".")
+}
+
+/// The one-based line and column of the byte at `offset` in `text`.
+#[cfg(feature = "config-toml")]
+fn position(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// Removes the ` at line L column C` suffix that serde_json and serde_yaml add to their messages,
+/// since the position is reported separately.
+#[cfg(any(feature = "config-json", feature = "config-yaml"))]
+fn strip_location(message: String, position: Option<(usize, usize)>) -> String {
+    match position {
+        Some((line, column)) => {
+            let suffix = format!(" at line {} column {}", line, column);
+            message
+                .strip_suffix(&suffix)
+                .map(str::to_owned)
+                .unwrap_or(message)
+        }
+        None => message,
+    }
+}
+
+/// A syntax or type error in a config.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseError {
+    key: Option<String>,
+    position: Option<(usize, usize)>,
+    message: String,
+}
+
+impl ParseError {
+    fn new(key: Option<String>, position: Option<(usize, usize)>, message: String) -> Self {
+        ParseError {
+            key,
+            position,
+            message,
+        }
+    }
+
+    /// The path to the key the error is at, such as `auth.servers[1].url`, if the error is not in
+    /// the syntax or at the top level of the config.
+    pub fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    /// The one-based line the error is at, if the parser reported it.
+    pub fn line(&self) -> Option<usize> {
+        self.position.map(|(line, _)| line)
+    }
+
+    /// The one-based column the error is at, if the parser reported it.
+    pub fn column(&self) -> Option<usize> {
+        self.position.map(|(_, column)| column)
+    }
+
+    /// The description of the error, without the key or position.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+/// Formats the error as e.g. `at line 3, column 16, key "timeout_secs": invalid type`.
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut context = Vec::new();
+        if let Some((line, column)) = self.position {
+            context.push(format!("at line {}, column {}", line, column));
+        }
+        if let Some(key) = &self.key {
+            context.push(format!("key \"{}\"", key));
+        }
+        if !context.is_empty() {
+            write!(f, "{}: ", context.join(", "))?;
+        }
+        self.message.fmt(f)
+    }
+}
+
+impl Error for ParseError {}
+
+/// Error returned when the config file can't be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The plugin was not given the `--config` option with the path to the config file.
+    MissingPath,
+    /// The file extension is not one of an enabled format.
+    UnknownFormat(PathBuf),
+    /// The file could not be read.
+    Read { path: PathBuf, source: io::Error },
+    /// The file is not valid in its format, or does not match the config type.
+    Parse { path: PathBuf, error: ParseError },
+    /// The config was rejected by `Config::validate`.
+    Invalid { path: PathBuf, message: String },
+    /// The file could not be watched for changes.
+    #[cfg(feature = "config-reload")]
+    Watch {
+        path: PathBuf,
+        source: notify::Error,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::MissingPath => "No --config plugin option given".fmt(f),
+            ConfigError::UnknownFormat(path) => {
+                write!(f, "Unsupported config file format: {}", path.display())
+            }
+            ConfigError::Read { path, .. } => {
+                write!(f, "Unable to read config file {}", path.display())
+            }
+            ConfigError::Parse { path, error } => {
+                let separator = if error.line().is_some() || error.key().is_some() {
+                    " "
+                } else {
+                    ": "
+                };
+                write!(
+                    f,
+                    "Invalid config in {}{}{}",
+                    path.display(),
+                    separator,
+                    error
+                )
+            }
+            ConfigError::Invalid { path, message } => {
+                write!(f, "Invalid config in {}: {}", path.display(), message)
+            }
+            #[cfg(feature = "config-reload")]
+            ConfigError::Watch { path, .. } => {
+                write!(f, "Unable to watch config file {}", path.display())
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::Read { source, .. } => Some(source),
+            #[cfg(feature = "config-reload")]
+            ConfigError::Watch { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::temp_path;
+    use std::ffi::CString;
+
+    #[derive(Debug, serde::Deserialize, Eq, PartialEq)]
+    struct Settings {
+        auth: Auth,
+    }
+
+    #[derive(Debug, serde::Deserialize, Eq, PartialEq)]
+    struct Auth {
+        url: String,
+        timeout_secs: u64,
+    }
+
+    impl Config for Settings {
+        fn validate(&self) -> Result<(), String> {
+            if self.auth.timeout_secs == 0 {
+                return Err("auth.timeout_secs must be positive".to_owned());
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "config-toml")]
+    #[test]
+    fn toml_errors() {
+        let error = parse::<Settings>(
+            "[auth]\nurl = \"x\"\ntimeout_secs = \"ten\"\n",
+            Format::Toml,
+        )
+        .unwrap_err();
+        assert_eq!(Some("auth.timeout_secs"), error.key());
+        assert_eq!((Some(3), Some(16)), (error.line(), error.column()));
+        assert_eq!(
+            "at line 3, column 16, key \"auth.timeout_secs\": invalid type: string \"ten\", \
+             expected u64",
+            error.to_string()
+        );
+
+        let error = parse::<Settings>("[auth\n", Format::Toml).unwrap_err();
+        assert_eq!(None, error.key());
+        assert_eq!(Some(1), error.line());
+    }
+
+    #[cfg(feature = "config-json")]
+    #[test]
+    fn json_errors() {
+        let error = parse::<Settings>(
+            "{\"auth\": {\n  \"url\": \"x\",\n  \"timeout_secs\": -1\n}}",
+            Format::Json,
+        )
+        .unwrap_err();
+        assert_eq!(Some("auth.timeout_secs"), error.key());
+        assert_eq!(Some(3), error.line());
+        assert!(error.message().starts_with("invalid value: integer `-1`"));
+
+        let error = parse::<Settings>(
+            "{\"auth\": {\"url\": \"x\", \"timeout_secs\": 1}} x",
+            Format::Json,
+        )
+        .unwrap_err();
+        assert_eq!(None, error.key());
+        assert_eq!("trailing characters", error.message());
+    }
+
+    #[cfg(feature = "config-yaml")]
+    #[test]
+    fn yaml_errors() {
+        let error = parse::<Settings>("auth:\n  url: x\n", Format::Yaml).unwrap_err();
+        assert_eq!(Some("auth"), error.key());
+        assert_eq!(Some(2), error.line());
+        assert_eq!("missing field `timeout_secs`", error.message());
+    }
+
+    #[cfg(feature = "config-toml")]
+    #[test]
+    fn load_from_args() {
+        let dir = temp_path("config");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("plugin.toml");
+        let args = |path: &Path| {
+            let args = [
+                CString::new("/plugin.so").unwrap(),
+                CString::new("--config").unwrap(),
+                CString::new(path.to_str().unwrap()).unwrap(),
+            ];
+            PluginArgs::parse(&args).unwrap()
+        };
+
+        fs::write(&path, "[auth]\nurl = \"https://auth\"\ntimeout_secs = 5\n").unwrap();
+        let settings: Settings = from_args(&args(&path)).unwrap();
+        assert_eq!(5, settings.auth.timeout_secs);
+
+        fs::write(&path, "[auth]\nurl = \"https://auth\"\ntimeout_secs = 0\n").unwrap();
+        let error = from_args::<Settings>(&args(&path)).unwrap_err();
+        assert_eq!(
+            format!(
+                "Invalid config in {}: auth.timeout_secs must be positive",
+                path.display()
+            ),
+            error.to_string()
+        );
+
+        assert!(matches!(
+            from_args::<Settings>(&args(&dir.join("missing.toml"))),
+            Err(ConfigError::Read { .. })
+        ));
+        assert!(matches!(
+            from_args::<Settings>(&args(&dir.join("plugin.ini"))),
+            Err(ConfigError::UnknownFormat(_))
+        ));
+        assert!(matches!(
+            from_args::<Settings>(&PluginArgs::default()),
+            Err(ConfigError::MissingPath)
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    ffi::OsString,
+    fmt,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, PoisonError, RwLock},
+    thread,
+    time::Duration,
+};
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use super::{load, path_from_args, Config, ConfigError};
+use crate::{args::PluginArgs, logging, Error};
+
+/// How long the file has to stay unchanged before it is reloaded. Editors often write a file in
+/// several steps.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// A config that is replaced when it is reloaded. Clones share the same config.
+pub struct SharedConfig<T> {
+    current: Arc<RwLock<Arc<T>>>,
+}
+
+impl<T> SharedConfig<T> {
+    /// Creates a shared config starting out as `config`.
+    pub fn new(config: T) -> Self {
+        SharedConfig {
+            current: Arc::new(RwLock::new(Arc::new(config))),
+        }
+    }
+
+    /// Returns the current config. The returned config is not changed by later reloads, so a
+    /// callback sees the same config from start to end.
+    pub fn get(&self) -> Arc<T> {
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Replaces the config.
+    pub fn set(&self, config: T) {
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = Arc::new(config);
+    }
+}
+
+impl<T: Config + Send + Sync + 'static> SharedConfig<T> {
+    /// Loads the config file given in the `--config PATH` plugin option and keeps it up to date
+    /// with the file. The file is watched for as long as the returned `ConfigWatcher` is kept.
+    pub fn from_args(args: &PluginArgs) -> Result<(Self, ConfigWatcher), ConfigError> {
+        let path = path_from_args(args)?;
+        let shared = SharedConfig::new(load(&path)?);
+        let watcher = ConfigWatcher::watch_shared(path, &shared)?;
+        Ok((shared, watcher))
+    }
+}
+
+impl<T> Clone for SharedConfig<T> {
+    fn clone(&self) -> Self {
+        SharedConfig {
+            current: self.current.clone(),
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for SharedConfig<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SharedConfig").field(&self.get()).finish()
+    }
+}
+
+/// Watches a config file and reloads it when it changes. Stops watching when dropped.
+pub struct ConfigWatcher {
+    watcher: Option<RecommendedWatcher>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ConfigWatcher {
+    /// Calls `on_change` with the result of loading and validating the file at `path` every time
+    /// it changes. `on_change` runs on a background thread.
+    pub fn watch<T, F>(path: impl Into<PathBuf>, mut on_change: F) -> Result<Self, ConfigError>
+    where
+        T: Config,
+        F: FnMut(Result<T, ConfigError>) + Send + 'static,
+    {
+        let path = path.into();
+        let watch_error = |source| ConfigError::Watch {
+            path: path.clone(),
+            source,
+        };
+        let file_name = path
+            .file_name()
+            .map(OsString::from)
+            .ok_or_else(|| watch_error(notify::Error::path_not_found()))?;
+        // Watches the directory rather than the file, since editors and config management tools
+        // often replace the file instead of writing to it.
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir.to_owned(),
+            _ => PathBuf::from("."),
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            if let Ok(event) = event {
+                if is_change_of(&event, &file_name) {
+                    let _ = tx.send(());
+                }
+            }
+        })
+        .map_err(watch_error)?;
+        watcher
+            .watch(&dir, RecursiveMode::NonRecursive)
+            .map_err(watch_error)?;
+
+        let reload_path = path.clone();
+        let thread = thread::Builder::new()
+            .name("config-reload".to_owned())
+            .spawn(move || {
+                while rx.recv().is_ok() {
+                    loop {
+                        match rx.recv_timeout(DEBOUNCE) {
+                            Ok(()) => continue,
+                            Err(mpsc::RecvTimeoutError::Timeout) => break,
+                            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                        }
+                    }
+                    on_change(load(&reload_path));
+                }
+            })
+            .map_err(|e| watch_error(notify::Error::io(e)))?;
+        Ok(ConfigWatcher {
+            watcher: Some(watcher),
+            thread: Some(thread),
+        })
+    }
+
+    /// Stores every successfully reloaded config in `shared`. A config that fails to load is
+    /// logged as a warning and the previous config is kept.
+    pub fn watch_shared<T>(
+        path: impl Into<PathBuf>,
+        shared: &SharedConfig<T>,
+    ) -> Result<Self, ConfigError>
+    where
+        T: Config + Send + Sync + 'static,
+    {
+        let shared = shared.clone();
+        Self::watch(path, move |result| match result {
+            Ok(config) => {
+                shared.set(config);
+                logging::log_info("Reloaded the config file");
+            }
+            Err(e) => logging::log_warning(&Error::Other {
+                msg: "Unable to reload the config file, keeping the previous config",
+                source: Box::new(e),
+            }),
+        })
+    }
+}
+
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) {
+        // Dropping the watcher drops the sending end of the channel, which stops the thread.
+        drop(self.watcher.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl fmt::Debug for ConfigWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ConfigWatcher").finish_non_exhaustive()
+    }
+}
+
+fn is_change_of(event: &Event, file_name: &OsString) -> bool {
+    matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+        && event
+            .paths
+            .iter()
+            .any(|path| path.file_name() == Some(file_name.as_os_str()))
+}
+
+
+#[cfg(all(test, feature = "config-toml"))]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::temp_path;
+    use std::{ffi::CString, fs, time::Instant};
+
+    #[derive(Debug, serde::Deserialize)]
+    struct Settings {
+        timeout_secs: u64,
+    }
+
+    impl Config for Settings {}
+
+    fn wait_for(mut condition: impl FnMut() -> bool) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(10), "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn reloads_on_change() {
+        let dir = temp_path("reload");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("plugin.toml");
+        fs::write(&path, "timeout_secs = 1").unwrap();
+        let args = [
+            CString::new("/plugin.so").unwrap(),
+            CString::new("--config").unwrap(),
+            CString::new(path.to_str().unwrap()).unwrap(),
+        ];
+        let args = PluginArgs::parse(&args).unwrap();
+
+        let (shared, watcher) = SharedConfig::<Settings>::from_args(&args).unwrap();
+        assert_eq!(1, shared.get().timeout_secs);
+
+        fs::write(&path, "timeout_secs = 2").unwrap();
+        wait_for(|| shared.get().timeout_secs == 2);
+
+        // A broken file keeps the previous config. Replacing the file is picked up as well.
+        fs::write(&path, "timeout_secs = \"three\"").unwrap();
+        thread::sleep(DEBOUNCE * 3);
+        let replacement = dir.join("plugin.toml.new");
+        fs::write(&replacement, "timeout_secs = 4").unwrap();
+        fs::rename(&replacement, &path).unwrap();
+        wait_for(|| shared.get().timeout_secs == 4);
+
+        drop(watcher);
+        fs::write(&path, "timeout_secs = 5").unwrap();
+        thread::sleep(DEBOUNCE * 3);
+        assert_eq!(4, shared.get().timeout_secs);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
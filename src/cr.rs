@@ -0,0 +1,231 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{collections::HashMap, error::Error, ffi::CString, fmt};
+
+use crate::base64;
+
+pub use crate::env_keys::CRRESPONSE;
+
+const STATIC_PREFIX: &str = "SCRV1:";
+const DYNAMIC_PREFIX: &str = "CRV1:";
+
+
+/// Error returned when a challenge/response encoded password can't be parsed.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum CrError {
+    /// The password has a challenge/response prefix but the wrong number of fields.
+    Malformed,
+    /// A field that should be base64 encoded is not.
+    InvalidBase64,
+    /// A base64 encoded field does not contain valid UTF-8.
+    InvalidUtf8,
+    /// The `crresponse` variable is not present in the environment.
+    MissingResponse,
+}
+
+impl fmt::Display for CrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CrError::Malformed => "Malformed challenge/response password".fmt(f),
+            CrError::InvalidBase64 => "Invalid base64 in challenge/response password".fmt(f),
+            CrError::InvalidUtf8 => "Invalid UTF-8 in challenge/response password".fmt(f),
+            CrError::MissingResponse => write!(f, "No \"{}\" in env", CRRESPONSE),
+        }
+    }
+}
+
+impl Error for CrError {}
+
+
+/// The password field of an `EventType::AuthUserPassVerify` event, decoded according to the
+/// OpenVPN challenge/response protocols.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum Password {
+    /// A regular password.
+    Plain(String),
+    /// A static challenge (`--static-challenge`) answer, sent as
+    /// `SCRV1:<base64 password>:<base64 response>`.
+    StaticChallenge { password: String, response: String },
+    /// An answer to a dynamic challenge previously sent with [`DynamicChallenge`], sent as
+    /// `CRV1::<state id>::<response>`.
+    ///
+    /// [`DynamicChallenge`]: struct.DynamicChallenge.html
+    DynamicChallenge { state_id: String, response: String },
+}
+
+impl Password {
+    /// Decodes a password. Passwords without a challenge/response prefix are returned as
+    /// `Password::Plain`.
+    pub fn parse(password: &str) -> Result<Self, CrError> {
+        if let Some(rest) = password.strip_prefix(STATIC_PREFIX) {
+            let mut parts = rest.splitn(2, ':');
+            let password = decode_field(parts.next().ok_or(CrError::Malformed)?)?;
+            let response = decode_field(parts.next().ok_or(CrError::Malformed)?)?;
+            Ok(Password::StaticChallenge { password, response })
+        } else if let Some(rest) = password.strip_prefix(DYNAMIC_PREFIX) {
+            let parts: Vec<&str> = rest.splitn(4, ':').collect();
+            match parts[..] {
+                ["", state_id, "", response] => Ok(Password::DynamicChallenge {
+                    state_id: state_id.to_owned(),
+                    response: response.to_owned(),
+                }),
+                _ => Err(CrError::Malformed),
+            }
+        } else {
+            Ok(Password::Plain(password.to_owned()))
+        }
+    }
+}
+
+/// Reads and decodes the response the client sent with a `CR_RESPONSE` message, from the
+/// environment of an `EventType::ClientCrresponse` event. Requires OpenVPN 2.6 or later.
+pub fn client_response(env: &HashMap<CString, CString>) -> Result<Vec<u8>, CrError> {
+    let response = env
+        .get(&CString::new(CRRESPONSE).unwrap())
+        .ok_or(CrError::MissingResponse)?;
+    let response = response.to_str().map_err(|_| CrError::InvalidBase64)?;
+    base64::decode(response.trim()).ok_or(CrError::InvalidBase64)
+}
+
+fn decode_field(field: &str) -> Result<String, CrError> {
+    let bytes = base64::decode(field).ok_or(CrError::InvalidBase64)?;
+    String::from_utf8(bytes).map_err(|_| CrError::InvalidUtf8)
+}
+
+
+/// A dynamic challenge to send to the client. Its `Display` implementation formats it as the
+/// `CRV1:<flags>:<state id>:<base64 username>:<text>` string OpenVPN clients expect as the
+/// authentication failure reason. Send it with `EventResult::FailureWithReason`.
+///
+/// The client answers by reconnecting with a password that parses into
+/// `Password::DynamicChallenge` with the same state id.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct DynamicChallenge {
+    /// Opaque id the plugin uses to tie the answer to this challenge. Must not contain `:`.
+    pub state_id: String,
+    /// The username the client should send the answer with.
+    pub username: String,
+    /// The challenge text shown to the user.
+    pub text: String,
+    /// If the client should echo the response as the user types it.
+    pub echo: bool,
+    /// If the user must give a response.
+    pub response_required: bool,
+}
+
+impl DynamicChallenge {
+    /// Creates a new challenge requiring a response that is not echoed.
+    pub fn new(
+        state_id: impl Into<String>,
+        username: impl Into<String>,
+        text: impl Into<String>,
+    ) -> Self {
+        DynamicChallenge {
+            state_id: state_id.into(),
+            username: username.into(),
+            text: text.into(),
+            echo: false,
+            response_required: true,
+        }
+    }
+
+    /// Sets if the client should echo the response.
+    pub fn echo(mut self, echo: bool) -> Self {
+        self.echo = echo;
+        self
+    }
+
+    /// Sets if the user must give a response.
+    pub fn response_required(mut self, response_required: bool) -> Self {
+        self.response_required = response_required;
+        self
+    }
+}
+
+impl fmt::Display for DynamicChallenge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let flags = match (self.response_required, self.echo) {
+            (true, true) => "R,E",
+            (true, false) => "R",
+            (false, true) => "E",
+            (false, false) => "",
+        };
+        write!(
+            f,
+            "{}{}:{}:{}:{}",
+            DYNAMIC_PREFIX,
+            flags,
+            self.state_id,
+            base64::encode(self.username.as_bytes()),
+            self.text
+        )
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_plain() {
+        assert_eq!(
+            Ok(Password::Plain("hunter2".to_owned())),
+            Password::parse("hunter2")
+        );
+    }
+
+    #[test]
+    fn parse_static() {
+        assert_eq!(
+            Ok(Password::StaticChallenge {
+                password: "hunter2".to_owned(),
+                response: "123456".to_owned(),
+            }),
+            Password::parse("SCRV1:aHVudGVyMg==:MTIzNDU2")
+        );
+        assert_eq!(
+            Err(CrError::Malformed),
+            Password::parse("SCRV1:aHVudGVyMg==")
+        );
+        assert_eq!(
+            Err(CrError::InvalidBase64),
+            Password::parse("SCRV1:!:MTIzNDU2")
+        );
+    }
+
+    #[test]
+    fn parse_dynamic() {
+        assert_eq!(
+            Ok(Password::DynamicChallenge {
+                state_id: "abc".to_owned(),
+                response: "12:34".to_owned(),
+            }),
+            Password::parse("CRV1::abc::12:34")
+        );
+        assert_eq!(Err(CrError::Malformed), Password::parse("CRV1:abc:12"));
+    }
+
+    #[test]
+    fn decode_client_response() {
+        let mut env = HashMap::new();
+        assert_eq!(Err(CrError::MissingResponse), client_response(&env));
+        env.insert(
+            CString::new(CRRESPONSE).unwrap(),
+            CString::new("MTIzNDU2").unwrap(),
+        );
+        assert_eq!(Ok(b"123456".to_vec()), client_response(&env));
+    }
+
+    #[test]
+    fn format_challenge() {
+        let challenge = DynamicChallenge::new("abc", "foo", "Enter code").echo(true);
+        assert_eq!("CRV1:R,E:abc:Zm9v:Enter code", challenge.to_string());
+    }
+}
@@ -0,0 +1,408 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Emits D-Bus signals for tunnel and client state changes. Requires the `dbus` feature.
+//!
+//! Desktop applications and network managers can watch the state of OpenVPN by subscribing to
+//! these signals on the system bus, instead of parsing its logs. [`DbusSignals::emit`] emits the
+//! [`Signal`] of an event, if it has one, from the object [`DEFAULT_PATH`] with the interface
+//! [`DEFAULT_INTERFACE`]:
+//!
+//! * `TunnelUp(device: s, local_ip: s, remote_ip: s)` on `Up`.
+//! * `TunnelDown(device: s, local_ip: s, remote_ip: s)` on `Down`.
+//! * `ClientConnected(common_name: s, real_ip: s, real_port: q, virtual_ip: s)` on `ClientConnect`
+//!   or `ClientConnectV2`.
+//! * `ClientDisconnected(common_name: s, real_ip: s, bytes_received: t, bytes_sent: t, duration:
+//!   t)` on `ClientDisconnect`.
+//!
+//! Values OpenVPN did not give are sent as empty strings and zero, since D-Bus has no optional
+//! arguments. `duration` is in seconds. Register only one of `ClientConnect` and
+//! `ClientConnectV2`, or every client is announced twice.
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, ffi::CString};
+//! # use openvpn_plugin::{dbus::DbusSignals, EventResult, EventType};
+//! # fn event(
+//! #     event: EventType,
+//! #     env: HashMap<CString, CString>,
+//! #     signals: &DbusSignals,
+//! # ) -> Result<EventResult, Box<dyn std::error::Error>> {
+//! // `signals` is created with `DbusSignals::system()` in the open callback.
+//! signals.emit(event, &env)?;
+//! Ok(EventResult::Success)
+//! # }
+//! ```
+//!
+//! Emitting needs a policy in `/etc/dbus-1/system.d` allowing the user OpenVPN runs as to own a
+//! name or send signals on the interface.
+//!
+//! [`DbusSignals::emit`]: struct.DbusSignals.html#method.emit
+//! [`Signal`]: enum.Signal.html
+//! [`DEFAULT_PATH`]: constant.DEFAULT_PATH.html
+//! [`DEFAULT_INTERFACE`]: constant.DEFAULT_INTERFACE.html
+
+use std::{collections::HashMap, convert::TryFrom, error::Error, ffi::CString, fmt, net::IpAddr};
+
+use zbus::{
+    blocking::Connection,
+    names::{BusName, InterfaceName},
+    zvariant::ObjectPath,
+};
+
+use crate::{
+    env_keys,
+    events::{self, DisconnectStats, Env, EventArgsError},
+    EventType,
+};
+
+/// The object path the signals are emitted from, by default.
+pub const DEFAULT_PATH: &str = "/net/openvpn/Plugin1";
+
+/// The interface of the signals, by default.
+pub const DEFAULT_INTERFACE: &str = "net.openvpn.Plugin1";
+
+/// Error emitting a signal.
+#[derive(Debug)]
+pub enum DbusError {
+    /// The environment of the event could not be parsed into the arguments of its signal.
+    EventArgs(EventArgsError),
+    /// Connecting to the bus or sending the signal failed.
+    Dbus(zbus::Error),
+}
+
+impl fmt::Display for DbusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbusError::EventArgs(_) => f.write_str("Invalid event for a D-Bus signal"),
+            DbusError::Dbus(_) => f.write_str("Unable to emit D-Bus signal"),
+        }
+    }
+}
+
+impl Error for DbusError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            DbusError::EventArgs(e) => Some(e),
+            DbusError::Dbus(e) => Some(e),
+        }
+    }
+}
+
+impl From<EventArgsError> for DbusError {
+    fn from(e: EventArgsError) -> Self {
+        DbusError::EventArgs(e)
+    }
+}
+
+impl From<zbus::Error> for DbusError {
+    fn from(e: zbus::Error) -> Self {
+        DbusError::Dbus(e)
+    }
+}
+
+/// A signal and its arguments.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Signal {
+    /// The tunnel device is up.
+    TunnelUp {
+        device: String,
+        local_ip: Option<IpAddr>,
+        remote_ip: Option<IpAddr>,
+    },
+    /// The tunnel device is about to go down.
+    TunnelDown {
+        device: String,
+        local_ip: Option<IpAddr>,
+        remote_ip: Option<IpAddr>,
+    },
+    /// A client connected to the server.
+    ClientConnected {
+        common_name: Option<String>,
+        real_ip: Option<IpAddr>,
+        real_port: Option<u16>,
+        virtual_ip: Option<IpAddr>,
+    },
+    /// A client disconnected from the server.
+    ClientDisconnected {
+        common_name: Option<String>,
+        stats: DisconnectStats,
+    },
+}
+
+impl Signal {
+    /// Returns the signal of `event`, or `None` if it has none.
+    pub fn from_event(
+        event: EventType,
+        env: &HashMap<CString, CString>,
+    ) -> Result<Option<Self>, EventArgsError> {
+        let env = Env(env);
+        Ok(Some(match event {
+            EventType::Up => Signal::TunnelUp {
+                device: env.string(env_keys::DEV)?,
+                local_ip: env.parse_opt(env_keys::IFCONFIG_LOCAL)?,
+                remote_ip: env.parse_opt(env_keys::IFCONFIG_REMOTE)?,
+            },
+            EventType::Down => Signal::TunnelDown {
+                device: env.string(env_keys::DEV)?,
+                local_ip: env.parse_opt(env_keys::IFCONFIG_LOCAL)?,
+                remote_ip: env.parse_opt(env_keys::IFCONFIG_REMOTE)?,
+            },
+            EventType::ClientConnect | EventType::ClientConnectV2 => {
+                let real = events::trusted_addr(env.0)?;
+                Signal::ClientConnected {
+                    common_name: env.string_opt(env_keys::COMMON_NAME)?,
+                    real_ip: real.map(|addr| addr.ip()),
+                    real_port: real.map(|addr| addr.port()),
+                    virtual_ip: env.ip_opt(
+                        env_keys::IFCONFIG_POOL_REMOTE_IP,
+                        env_keys::IFCONFIG_POOL_REMOTE_IP6,
+                    )?,
+                }
+            }
+            EventType::ClientDisconnect => Signal::ClientDisconnected {
+                common_name: env.string_opt(env_keys::COMMON_NAME)?,
+                stats: DisconnectStats::from_env(env.0)?,
+            },
+            _ => return Ok(None),
+        }))
+    }
+
+    /// The name of the signal, such as `TunnelUp`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Signal::TunnelUp { .. } => "TunnelUp",
+            Signal::TunnelDown { .. } => "TunnelDown",
+            Signal::ClientConnected { .. } => "ClientConnected",
+            Signal::ClientDisconnected { .. } => "ClientDisconnected",
+        }
+    }
+}
+
+
+/// Emits signals on a D-Bus connection.
+#[derive(Debug)]
+pub struct DbusSignals {
+    connection: Connection,
+    path: ObjectPath<'static>,
+    interface: InterfaceName<'static>,
+}
+
+impl DbusSignals {
+    /// Connects to the system bus.
+    pub fn system() -> Result<Self, DbusError> {
+        Ok(Self::new(Connection::system()?))
+    }
+
+    /// Emits signals on `connection`.
+    pub fn new(connection: Connection) -> Self {
+        DbusSignals {
+            connection,
+            path: ObjectPath::from_static_str_unchecked(DEFAULT_PATH),
+            interface: InterfaceName::from_static_str_unchecked(DEFAULT_INTERFACE),
+        }
+    }
+
+    /// Sets the object path the signals are emitted from. [`DEFAULT_PATH`] by default.
+    ///
+    /// [`DEFAULT_PATH`]: constant.DEFAULT_PATH.html
+    pub fn path(mut self, path: &str) -> Result<Self, DbusError> {
+        self.path = ObjectPath::try_from(path)
+            .map_err(zbus::Error::from)?
+            .into_owned();
+        Ok(self)
+    }
+
+    /// Sets the interface of the signals. [`DEFAULT_INTERFACE`] by default.
+    ///
+    /// [`DEFAULT_INTERFACE`]: constant.DEFAULT_INTERFACE.html
+    pub fn interface(mut self, interface: &str) -> Result<Self, DbusError> {
+        self.interface = InterfaceName::try_from(interface)
+            .map_err(zbus::Error::from)?
+            .into_owned();
+        Ok(self)
+    }
+
+    /// The connection the signals are emitted on.
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Emits the signal of `event`, if it has one.
+    pub fn emit(&self, event: EventType, env: &HashMap<CString, CString>) -> Result<(), DbusError> {
+        match Signal::from_event(event, env)? {
+            Some(signal) => self.emit_signal(&signal),
+            None => Ok(()),
+        }
+    }
+
+    /// Emits `signal`.
+    pub fn emit_signal(&self, signal: &Signal) -> Result<(), DbusError> {
+        match signal {
+            Signal::TunnelUp {
+                device,
+                local_ip,
+                remote_ip,
+            }
+            | Signal::TunnelDown {
+                device,
+                local_ip,
+                remote_ip,
+            } => self.send(signal, &(device, ip_string(local_ip), ip_string(remote_ip))),
+            Signal::ClientConnected {
+                common_name,
+                real_ip,
+                real_port,
+                virtual_ip,
+            } => self.send(
+                signal,
+                &(
+                    common_name.as_deref().unwrap_or(""),
+                    ip_string(real_ip),
+                    real_port.unwrap_or(0),
+                    ip_string(virtual_ip),
+                ),
+            ),
+            Signal::ClientDisconnected { common_name, stats } => self.send(
+                signal,
+                &(
+                    common_name.as_deref().unwrap_or(""),
+                    ip_string(&stats.trusted_ip),
+                    stats.bytes_received,
+                    stats.bytes_sent,
+                    stats.duration.as_secs(),
+                ),
+            ),
+        }
+    }
+
+    fn send<B>(&self, signal: &Signal, body: &B) -> Result<(), DbusError>
+    where
+        B: serde::Serialize + zbus::zvariant::DynamicType,
+    {
+        self.connection.emit_signal(
+            None::<BusName<'_>>,
+            &self.path,
+            &self.interface,
+            signal.name(),
+            body,
+        )?;
+        Ok(())
+    }
+}
+
+fn ip_string(ip: &Option<IpAddr>) -> String {
+    ip.map(|ip| ip.to_string()).unwrap_or_default()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::env;
+    use std::time::Duration;
+
+    #[test]
+    fn signals_of_events() {
+        assert_eq!(
+            Some(Signal::ClientConnected {
+                common_name: Some("alice".to_owned()),
+                real_ip: Some("192.0.2.1".parse().unwrap()),
+                real_port: Some(1194),
+                virtual_ip: Some("10.8.0.6".parse().unwrap()),
+            }),
+            Signal::from_event(
+                EventType::ClientConnectV2,
+                &env(&[
+                    ("common_name", "alice"),
+                    ("trusted_ip", "192.0.2.1"),
+                    ("trusted_port", "1194"),
+                    ("ifconfig_pool_remote_ip", "10.8.0.6"),
+                ])
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            Some(Signal::ClientDisconnected {
+                common_name: None,
+                stats: DisconnectStats {
+                    bytes_received: 10,
+                    bytes_sent: 20,
+                    duration: Duration::from_secs(30),
+                    trusted_ip: None,
+                    trusted_port: None,
+                },
+            }),
+            Signal::from_event(
+                EventType::ClientDisconnect,
+                &env(&[
+                    ("bytes_received", "10"),
+                    ("bytes_sent", "20"),
+                    ("time_duration", "30"),
+                ])
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            Err(EventArgsError::MissingEnv("dev")),
+            Signal::from_event(EventType::Down, &env(&[]))
+        );
+        assert_eq!(
+            None,
+            Signal::from_event(EventType::TlsVerify, &env(&[])).unwrap()
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn emits_signals() {
+        use std::{os::unix::net::UnixStream, thread};
+        use zbus::{blocking::MessageIterator, Guid};
+
+        let (server, client) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            zbus::blocking::connection::Builder::async_io_unix_stream(server)
+                .server(Guid::generate())
+                .unwrap()
+                .p2p()
+                .build()
+                .unwrap()
+        });
+        let client = zbus::blocking::connection::Builder::async_io_unix_stream(client)
+            .p2p()
+            .build()
+            .unwrap();
+        let server = server.join().unwrap();
+
+        // Created before emitting, since messages arriving before it are not kept for it.
+        let mut messages = MessageIterator::from(&server);
+        let signals = DbusSignals::new(client).path("/com/example/Vpn").unwrap();
+        signals
+            .emit(
+                EventType::Up,
+                &env(&[("dev", "tun0"), ("ifconfig_local", "10.8.0.1")]),
+            )
+            .unwrap();
+        signals.emit(EventType::TlsFinal, &env(&[])).unwrap();
+
+        let message = messages.next().unwrap().unwrap();
+        let header = message.header();
+        assert_eq!("TunnelUp", header.member().unwrap().as_str());
+        assert_eq!("/com/example/Vpn", header.path().unwrap().as_str());
+        assert_eq!(DEFAULT_INTERFACE, header.interface().unwrap().as_str());
+        assert_eq!(
+            ("tun0".to_owned(), "10.8.0.1".to_owned(), String::new()),
+            message
+                .body()
+                .deserialize::<(String, String, String)>()
+                .unwrap()
+        );
+        assert!(DbusSignals::new(server)
+            .interface("not an interface")
+            .is_err());
+    }
+}
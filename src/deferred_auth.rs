@@ -0,0 +1,612 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Concurrent deferred authentication on a pool of threads.
+//!
+//! Verifying credentials against a remote service in the event callback blocks OpenVPN, and every
+//! other client, until the service answers. A [`DeferredAuthPool`] instead runs the verification
+//! of each `EventType::AuthUserPassVerify` event on one of a fixed number of threads, and writes
+//! the result to the `auth_control_file` of the event when it is done:
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, ffi::CString, io, time::Duration};
+//! # use openvpn_plugin::{auth::Credentials, deferred_auth::DeferredAuthPool, EventResult, EventType};
+//! struct Handle {
+//!     pool: DeferredAuthPool,
+//! }
+//!
+//! fn verify(credentials: &Credentials) -> Result<bool, io::Error> {
+//!     // Asks the authentication server.
+//! #   unimplemented!()
+//! }
+//!
+//! fn event(
+//!     event: EventType,
+//!     _args: Vec<CString>,
+//!     env: HashMap<CString, CString>,
+//!     handle: &mut Handle,
+//! ) -> Result<EventResult, Box<dyn std::error::Error>> {
+//!     let credentials = Credentials::from_env(&env)?;
+//!     let result = handle.pool.defer(&env, move || match verify(&credentials)? {
+//!         true => Ok::<_, io::Error>(EventResult::Success),
+//!         false => Ok(EventResult::Failure),
+//!     })?;
+//!     Ok(result)
+//! }
+//! ```
+//!
+//! The result of the verification is handled the same way as the result of a deferred event in
+//! [`openvpn_plugin_async!`]: `EventResult::Success` approves the authentication, errors, panics
+//! and the failure results deny it, and `EventResult::Deferred` leaves the control file to the
+//! plugin.
+//!
+//! When all threads are busy, verifications wait in a queue of limited length. When the queue is
+//! full, new authentications are denied right away instead of waiting.
+//!
+//! ## Timeouts
+//!
+//! A deferred authentication that is never decided keeps the client waiting until OpenVPN gives
+//! up on it. An [`AuthWatchdog`] tracks the control files of deferred authentications and denies
+//! those not decided within its timeout, logging why. The result of a verification finishing
+//! after that is ignored. Plugins deferring on their own write the result through the
+//! [`TrackedAuth`] returned by [`AuthWatchdog::track`]:
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, ffi::CString, io, thread, time::Duration};
+//! # use openvpn_plugin::{deferred_auth::AuthWatchdog, EventResult};
+//! # fn event(env: HashMap<CString, CString>, watchdog: &AuthWatchdog) -> Result<EventResult, Box<dyn std::error::Error>> {
+//! let auth = watchdog.track(&env)?;
+//! thread::spawn(move || {
+//!     // Waits for the second factor, possibly forever.
+//!     auth.approve()
+//! });
+//! Ok(EventResult::Deferred)
+//! # }
+//! ```
+//!
+//! A [`DeferredAuthPool`] with a [`timeout`] has a watchdog of its own. Verifications still
+//! waiting in the queue when their authentication is denied are not run. A verification
+//! returning `EventResult::Deferred` stops the timeout, since the control file is left to the
+//! plugin.
+//!
+//! [`DeferredAuthPool`]: struct.DeferredAuthPool.html
+//! [`AuthWatchdog`]: struct.AuthWatchdog.html
+//! [`TrackedAuth`]: struct.TrackedAuth.html
+//! [`AuthWatchdog::track`]: struct.AuthWatchdog.html#method.track
+//! [`timeout`]: struct.DeferredAuthPool.html#method.timeout
+//! [`openvpn_plugin_async!`]: ../macro.openvpn_plugin_async.html
+
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error as StdError,
+    ffi::CString,
+    fmt, io,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    auth::{ControlFile, ControlFileError, FailedReasonFile},
+    logging,
+    redact::Secrets,
+    workers::Workers,
+    Error, EventResult,
+};
+
+type Verify = Box<dyn FnOnce() -> Result<EventResult, Box<dyn StdError>> + Send>;
+
+/// The longest the watchdog waits before checking for expired authentications.
+const WATCHDOG_TICK: Duration = Duration::from_millis(100);
+
+/// The reason given to the client when its authentication is denied by the watchdog.
+const TIMED_OUT_REASON: &str = "Authentication timed out";
+
+/// A fixed number of threads running deferred authentications.
+pub struct DeferredAuthPool {
+    // Declared before `workers`, so the queue is closed before the threads are joined.
+    tx: SyncSender<Job>,
+    queued: Arc<AtomicUsize>,
+    max_queue: usize,
+    watchdog: Option<AuthWatchdog>,
+    _workers: Workers,
+}
+
+impl DeferredAuthPool {
+    /// Starts `threads` threads running verifications, with room for `max_queue` verifications
+    /// waiting for a thread. With `max_queue` zero, authentications are denied unless a thread is
+    /// idle.
+    pub fn new(threads: usize, max_queue: usize) -> io::Result<Self> {
+        let (tx, rx) = mpsc::sync_channel(max_queue);
+        let rx = Arc::new(Mutex::new(rx));
+        let queued = Arc::new(AtomicUsize::new(0));
+        let mut workers = Workers::new();
+        for i in 0..threads.max(1) {
+            let rx = rx.clone();
+            let queued = queued.clone();
+            workers.spawn(format!("deferred-auth-{}", i), move |_shutdown| {
+                // Stops when the pool, holding the only sender, is dropped.
+                while let Some(job) = next_job(&rx) {
+                    queued.fetch_sub(1, Ordering::SeqCst);
+                    job.run();
+                }
+            })?;
+        }
+        Ok(DeferredAuthPool {
+            tx,
+            queued,
+            max_queue,
+            watchdog: None,
+            _workers: workers,
+        })
+    }
+
+    /// Sets the time from `defer` until the authentication must be decided. Authentications not
+    /// decided in time are denied by an [`AuthWatchdog`], started here.
+    ///
+    /// [`AuthWatchdog`]: struct.AuthWatchdog.html
+    pub fn timeout(mut self, timeout: Duration) -> io::Result<Self> {
+        self.watchdog = Some(AuthWatchdog::new(timeout)?);
+        Ok(self)
+    }
+
+    /// Runs `verify` on the pool and writes its result to the control file of the event with the
+    /// environment `env`. Returns `EventResult::Deferred`, to be returned from the event callback,
+    /// or `EventResult::Failure` if the queue is full.
+    pub fn defer<F, E>(
+        &self,
+        env: &HashMap<CString, CString>,
+        verify: F,
+    ) -> Result<EventResult, ControlFileError>
+    where
+        F: FnOnce() -> Result<EventResult, E> + Send + 'static,
+        E: Into<Box<dyn StdError>>,
+    {
+        let auth = match &self.watchdog {
+            Some(watchdog) => watchdog.track(env)?,
+            None => TrackedAuth::untracked(env)?,
+        };
+        let job = Job {
+            auth,
+            // The verification is logged on a pool thread, where the secrets entered for this
+            // event are not visible.
+            secrets: Secrets::from_env(env),
+            verify: Box::new(move || verify().map_err(Into::into)),
+        };
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        match self.tx.try_send(job) {
+            Ok(()) => Ok(EventResult::Deferred),
+            Err(TrySendError::Full(job)) | Err(TrySendError::Disconnected(job)) => {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+                // Keeps the watchdog from denying it again later.
+                job.auth.forget();
+                logging::log_warning(&QueueFull);
+                Ok(EventResult::Failure)
+            }
+        }
+    }
+
+    /// The number of verifications waiting for a thread.
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// The number of verifications that can wait for a thread.
+    pub fn max_queue(&self) -> usize {
+        self.max_queue
+    }
+}
+
+impl fmt::Debug for DeferredAuthPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeferredAuthPool")
+            .field("queue_depth", &self.queue_depth())
+            .field("max_queue", &self.max_queue)
+            .field("watchdog", &self.watchdog)
+            .finish_non_exhaustive()
+    }
+}
+
+fn next_job(rx: &Mutex<Receiver<Job>>) -> Option<Job> {
+    rx.lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .recv()
+        .ok()
+}
+
+/// A verification waiting for a thread.
+struct Job {
+    auth: TrackedAuth,
+    secrets: Secrets,
+    verify: Verify,
+}
+
+impl Job {
+    fn run(self) {
+        let _redacting = self.secrets.enter();
+        if self.auth.is_decided() {
+            // Denied by the watchdog while waiting in the queue.
+            return;
+        }
+        let written = match crate::catch_unwind(AssertUnwindSafe(self.verify)) {
+            Ok(Ok(EventResult::Success)) => self.auth.approve(),
+            Ok(Ok(EventResult::Deferred)) => {
+                // The control file is the plugin's now, so the watchdog must not deny it.
+                self.auth.forget();
+                return;
+            }
+            Ok(Ok(EventResult::Failure)) => self.auth.deny(),
+            Ok(Ok(EventResult::FailureWithReason(reason))) => self.auth.deny_with_reason(&reason),
+            Ok(Err(e)) => {
+                logging::log_error(&Error::callback_failed("Deferred auth callback failed", e));
+                self.auth.deny()
+            }
+            Err(panic_payload) => {
+                logging::log_panic("deferred auth", &panic_payload);
+                self.auth.deny()
+            }
+        };
+        if let Err(e) = written {
+            logging::log_error(&e);
+        }
+    }
+}
+
+
+/// Where the result of a deferred authentication is written.
+#[derive(Debug)]
+struct Decision {
+    control_file: ControlFile,
+    failed_reason_file: Option<FailedReasonFile>,
+}
+
+impl Decision {
+    fn write(self, approved: bool, reason: Option<&str>) -> Result<(), ControlFileError> {
+        if let (Some(failed_reason_file), Some(reason)) = (&self.failed_reason_file, reason) {
+            failed_reason_file.write(reason)?;
+        }
+        self.control_file.write(approved)
+    }
+}
+
+/// The control file of a deferred authentication, until it is written. Shared with the watchdog.
+type Slot = Mutex<Option<Decision>>;
+
+/// The authentications tracked by a watchdog, with their deadlines.
+type Tracked = Mutex<VecDeque<(Instant, Arc<Slot>)>>;
+
+fn lock(slot: &Slot) -> MutexGuard<'_, Option<Decision>> {
+    slot.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Denies deferred authentications not decided within a timeout.
+pub struct AuthWatchdog {
+    timeout: Duration,
+    tracked: Arc<Tracked>,
+    _workers: Workers,
+}
+
+impl AuthWatchdog {
+    /// Starts a thread denying the tracked authentications that are not decided within `timeout`.
+    pub fn new(timeout: Duration) -> io::Result<Self> {
+        let tracked = Arc::new(Tracked::default());
+        let mut workers = Workers::new();
+        workers.spawn("auth-watchdog", {
+            let tracked = tracked.clone();
+            move |shutdown| loop {
+                let next_deadline = expire(&tracked, timeout);
+                let wait = next_deadline.map_or(WATCHDOG_TICK, |deadline| {
+                    deadline
+                        .saturating_duration_since(Instant::now())
+                        .min(WATCHDOG_TICK)
+                });
+                if shutdown.wait_timeout(wait) {
+                    return;
+                }
+            }
+        })?;
+        Ok(AuthWatchdog {
+            timeout,
+            tracked,
+            _workers: workers,
+        })
+    }
+
+    /// The time from `track` until an authentication is denied.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Starts tracking the authentication with the control file in `env`, the environment of an
+    /// `EventType::AuthUserPassVerify` event. Unless decided through the returned `TrackedAuth`
+    /// within the timeout, the authentication is denied.
+    pub fn track(&self, env: &HashMap<CString, CString>) -> Result<TrackedAuth, ControlFileError> {
+        let auth = TrackedAuth::untracked(env)?;
+        self.tracked
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push_back((Instant::now() + self.timeout, auth.slot.clone()));
+        Ok(auth)
+    }
+
+    /// The number of tracked authentications not yet decided or denied.
+    pub fn outstanding(&self) -> usize {
+        self.tracked
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|(_, slot)| lock(slot).is_some())
+            .count()
+    }
+}
+
+impl fmt::Debug for AuthWatchdog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthWatchdog")
+            .field("timeout", &self.timeout)
+            .field("outstanding", &self.outstanding())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Denies the expired authentications. Returns the deadline of the next tracked authentication.
+fn expire(tracked: &Tracked, timeout: Duration) -> Option<Instant> {
+    let now = Instant::now();
+    let mut expired = Vec::new();
+    let next_deadline = {
+        let mut tracked = tracked.lock().unwrap_or_else(PoisonError::into_inner);
+        // Every authentication has the same timeout, so the queue is sorted by deadline.
+        while let Some((deadline, _)) = tracked.front() {
+            if *deadline > now {
+                break;
+            }
+            if let Some((_, slot)) = tracked.pop_front() {
+                expired.extend(lock(&slot).take());
+            }
+        }
+        tracked.retain(|(_, slot)| lock(slot).is_some());
+        tracked.front().map(|(deadline, _)| *deadline)
+    };
+    for decision in expired {
+        logging::log_warning(&Error::AuthTimedOut(timeout));
+        if let Err(e) = decision.write(false, Some(TIMED_OUT_REASON)) {
+            logging::log_error(&e);
+        }
+    }
+    next_deadline
+}
+
+/// A deferred authentication tracked by an [`AuthWatchdog`]. Its result is written through this,
+/// unless the watchdog has already denied it.
+///
+/// [`AuthWatchdog`]: struct.AuthWatchdog.html
+#[derive(Debug)]
+pub struct TrackedAuth {
+    slot: Arc<Slot>,
+}
+
+impl TrackedAuth {
+    /// An authentication no watchdog tracks.
+    fn untracked(env: &HashMap<CString, CString>) -> Result<Self, ControlFileError> {
+        let decision = Decision {
+            control_file: ControlFile::from_env(env)?,
+            failed_reason_file: FailedReasonFile::from_env(env).ok(),
+        };
+        Ok(TrackedAuth {
+            slot: Arc::new(Mutex::new(Some(decision))),
+        })
+    }
+
+    /// Returns whether the authentication has been decided, which for a `TrackedAuth` that is
+    /// not yet written means it was denied by the watchdog.
+    pub fn is_decided(&self) -> bool {
+        lock(&self.slot).is_none()
+    }
+
+    /// Approves the authentication. Returns `false` if the watchdog already denied it.
+    pub fn approve(self) -> Result<bool, ControlFileError> {
+        self.write(true)
+    }
+
+    /// Denies the authentication. Returns `false` if the watchdog already denied it.
+    pub fn deny(self) -> Result<bool, ControlFileError> {
+        self.write(false)
+    }
+
+    /// Denies the authentication, writing `reason` to `auth_failed_reason_file` if OpenVPN gave
+    /// one. Returns `false` if the watchdog already denied it.
+    pub fn deny_with_reason(self, reason: &str) -> Result<bool, ControlFileError> {
+        self.decide(false, Some(reason))
+    }
+
+    /// Writes `1` if `approved` is true, otherwise `0`. Returns `false` if the watchdog already
+    /// denied the authentication.
+    pub fn write(self, approved: bool) -> Result<bool, ControlFileError> {
+        self.decide(approved, None)
+    }
+
+    fn decide(self, approved: bool, reason: Option<&str>) -> Result<bool, ControlFileError> {
+        let decision = lock(&self.slot).take();
+        match decision {
+            Some(decision) => decision.write(approved, reason).map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Stops tracking the authentication without writing anything.
+    fn forget(self) {
+        lock(&self.slot).take();
+    }
+}
+
+/// The queue of a `DeferredAuthPool` was full, so an authentication was denied. Logged as a
+/// warning.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct QueueFull;
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "The authentication queue is full, denying the authentication".fmt(f)
+    }
+}
+
+impl StdError for QueueFull {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AUTH_CONTROL_FILE;
+    use crate::testing::fixtures::temp_path;
+    use std::{fs, path::PathBuf, sync::mpsc::channel, thread};
+
+    fn control_file_env(name: &str) -> (PathBuf, HashMap<CString, CString>) {
+        let path = temp_path(&format!("pool-{}", name));
+        let _ = fs::remove_file(&path);
+        let mut env = HashMap::new();
+        env.insert(
+            CString::new(AUTH_CONTROL_FILE).unwrap(),
+            CString::new(path.to_str().unwrap()).unwrap(),
+        );
+        (path, env)
+    }
+
+    fn wait_for_file(path: &PathBuf) -> String {
+        for _ in 0..500 {
+            if let Ok(content) = fs::read_to_string(path) {
+                if !content.is_empty() {
+                    let _ = fs::remove_file(path);
+                    return content;
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("{} was never written", path.display());
+    }
+
+    #[test]
+    fn writes_results() {
+        let pool = DeferredAuthPool::new(2, 4).unwrap();
+        let (approved, approved_env) = control_file_env("approved");
+        let (denied, denied_env) = control_file_env("denied");
+        let (failed, failed_env) = control_file_env("failed");
+
+        let defer =
+            |env, result: Result<EventResult, io::Error>| pool.defer(env, move || result).unwrap();
+        assert_eq!(
+            EventResult::Deferred,
+            defer(&approved_env, Ok(EventResult::Success))
+        );
+        assert_eq!(
+            EventResult::Deferred,
+            defer(&denied_env, Ok(EventResult::Failure))
+        );
+        assert_eq!(
+            EventResult::Deferred,
+            defer(&failed_env, Err(io::Error::other("Server unreachable")))
+        );
+        assert_eq!("1", wait_for_file(&approved));
+        assert_eq!("0", wait_for_file(&denied));
+        assert_eq!("0", wait_for_file(&failed));
+
+        assert!(pool
+            .defer(&HashMap::new(), || Ok::<_, io::Error>(EventResult::Success))
+            .is_err());
+    }
+
+    #[test]
+    fn denies_when_full() {
+        let pool = DeferredAuthPool::new(1, 1).unwrap();
+        let (started_tx, started_rx) = channel();
+        let (release_tx, release_rx) = channel::<()>();
+        let (running, running_env) = control_file_env("running");
+        let (queued, queued_env) = control_file_env("queued");
+        let (_, rejected_env) = control_file_env("rejected");
+
+        let result = pool.defer(&running_env, move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+            Ok::<_, io::Error>(EventResult::Success)
+        });
+        assert_eq!(EventResult::Deferred, result.unwrap());
+        started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let approve = || Ok::<_, io::Error>(EventResult::Success);
+        assert_eq!(
+            EventResult::Deferred,
+            pool.defer(&queued_env, approve).unwrap()
+        );
+        assert_eq!(1, pool.queue_depth());
+        assert_eq!(
+            EventResult::Failure,
+            pool.defer(&rejected_env, approve).unwrap()
+        );
+
+        drop(release_tx);
+        assert_eq!("1", wait_for_file(&running));
+        assert_eq!("1", wait_for_file(&queued));
+        assert_eq!(0, pool.queue_depth());
+    }
+
+    #[test]
+    fn denies_after_timeout() {
+        let pool = DeferredAuthPool::new(1, 1)
+            .unwrap()
+            .timeout(Duration::from_millis(20))
+            .unwrap();
+        let (path, env) = control_file_env("timeout");
+        let (release_tx, release_rx) = channel::<()>();
+        let result = pool.defer(&env, move || {
+            let _ = release_rx.recv();
+            Ok::<_, io::Error>(EventResult::Success)
+        });
+        assert_eq!(EventResult::Deferred, result.unwrap());
+        // Denied while the verification is still running.
+        assert_eq!("0", wait_for_file(&path));
+        drop(release_tx);
+    }
+
+    #[test]
+    fn deferred_result_stops_timeout() {
+        let pool = DeferredAuthPool::new(1, 1)
+            .unwrap()
+            .timeout(Duration::from_millis(20))
+            .unwrap();
+        let (path, env) = control_file_env("deferred-timeout");
+        let (done_tx, done_rx) = channel();
+        let result = pool.defer(&env, move || {
+            done_tx.send(()).unwrap();
+            Ok::<_, io::Error>(EventResult::Deferred)
+        });
+        assert_eq!(EventResult::Deferred, result.unwrap());
+        done_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(fs::read_to_string(&path).unwrap_or_default().is_empty());
+    }
+
+    #[test]
+    fn watchdog() {
+        let watchdog = AuthWatchdog::new(Duration::from_millis(50)).unwrap();
+        let (decided, decided_env) = control_file_env("watchdog-decided");
+        let (expired, expired_env) = control_file_env("watchdog-expired");
+
+        let decided_auth = watchdog.track(&decided_env).unwrap();
+        let expired_auth = watchdog.track(&expired_env).unwrap();
+        assert_eq!(2, watchdog.outstanding());
+        assert!(decided_auth.approve().unwrap());
+        assert_eq!("1", wait_for_file(&decided));
+
+        assert_eq!("0", wait_for_file(&expired));
+        assert!(expired_auth.is_decided());
+        assert!(!expired_auth.approve().unwrap());
+        assert_eq!(0, watchdog.outstanding());
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Routing of events to one handler per event type.
+//!
+//! Instead of one event callback matching on the event type, the plugin registers a closure per
+//! event type on an [`EventDispatcher`] in its open callback. The dispatcher is used as the handle
+//! and [`dispatch::event`] as the event callback:
+//!
+//! ```rust,no_run
+//! use std::{collections::HashMap, convert::Infallible, ffi::CString};
+//! use openvpn_plugin::{
+//!     dispatch::{self, EventDispatcher},
+//!     openvpn_plugin, EventResult, EventType, EventTypeSet,
+//! };
+//!
+//! #[derive(Default)]
+//! struct State {
+//!     connected: usize,
+//! }
+//!
+//! fn open(
+//!     _args: Vec<CString>,
+//!     _env: HashMap<CString, CString>,
+//! ) -> Result<(EventTypeSet, EventDispatcher<State>), Infallible> {
+//!     let dispatcher = EventDispatcher::new(State::default())
+//!         .on(EventType::ClientConnectV2, |ctx| {
+//!             ctx.state.connected += 1;
+//!             Ok::<_, Infallible>(EventResult::Success)
+//!         })
+//!         .on(EventType::ClientDisconnect, |ctx| {
+//!             ctx.state.connected -= 1;
+//!             Ok::<_, Infallible>(EventResult::Success)
+//!         });
+//!     Ok((dispatcher.events(), dispatcher))
+//! }
+//!
+//! fn close(_handle: EventDispatcher<State>) {}
+//!
+//! openvpn_plugin!(crate::open, crate::close, dispatch::event, EventDispatcher<State>);
+//! # fn main() {}
+//! ```
+//!
+//! [`EventDispatcher`]: struct.EventDispatcher.html
+//! [`dispatch::event`]: fn.event.html
+
+use std::{collections::HashMap, error::Error, ffi::CString, fmt, panic::UnwindSafe};
+
+use crate::{EventResult, EventType, EventTypeSet};
+
+type Handler<S> =
+    Box<dyn FnMut(&mut EventContext<'_, S>) -> Result<EventResult, Box<dyn Error>> + UnwindSafe>;
+
+/// The data of one event, given to its handler.
+#[derive(Debug)]
+pub struct EventContext<'a, S> {
+    /// The type of the event.
+    pub event: EventType,
+    /// The arguments OpenVPN gave with the event, starting with the path to the plugin.
+    pub args: Vec<CString>,
+    /// The environment OpenVPN gave with the event.
+    pub env: HashMap<CString, CString>,
+    /// The state of the plugin, kept in the dispatcher.
+    pub state: &'a mut S,
+}
+
+/// Routes every event to the handler registered for its type. `S` is the state of the plugin that
+/// the handlers share.
+pub struct EventDispatcher<S = ()> {
+    state: S,
+    handlers: HashMap<EventType, Handler<S>>,
+    unhandled: EventResult,
+}
+
+impl<S> EventDispatcher<S> {
+    /// Creates a dispatcher without any handlers.
+    pub fn new(state: S) -> Self {
+        EventDispatcher {
+            state,
+            handlers: HashMap::new(),
+            unhandled: EventResult::Success,
+        }
+    }
+
+    /// Registers `handler` for `event`, replacing any handler already registered for it.
+    pub fn on<F, E>(mut self, event: EventType, mut handler: F) -> Self
+    where
+        F: FnMut(&mut EventContext<'_, S>) -> Result<EventResult, E> + UnwindSafe + 'static,
+        E: Into<Box<dyn Error>>,
+    {
+        self.handlers.insert(
+            event,
+            Box::new(move |ctx: &mut EventContext<'_, S>| handler(ctx).map_err(Into::into)),
+        );
+        self
+    }
+
+    /// Sets the result returned for events without a handler. `EventResult::Success` by default.
+    pub fn unhandled(mut self, result: EventResult) -> Self {
+        self.unhandled = result;
+        self
+    }
+
+    /// The events with a handler, to return from the open callback.
+    pub fn events(&self) -> EventTypeSet {
+        self.handlers.keys().copied().collect()
+    }
+
+    /// The state of the plugin.
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// The state of the plugin, mutably.
+    pub fn state_mut(&mut self) -> &mut S {
+        &mut self.state
+    }
+
+    /// Consumes the dispatcher and returns the state, for example in the close callback.
+    pub fn into_state(self) -> S {
+        self.state
+    }
+
+    /// Calls the handler of `event`, or returns the result for unhandled events if there is none.
+    pub fn dispatch(
+        &mut self,
+        event: EventType,
+        args: Vec<CString>,
+        env: HashMap<CString, CString>,
+    ) -> Result<EventResult, Box<dyn Error>> {
+        match self.handlers.get_mut(&event) {
+            Some(handler) => handler(&mut EventContext {
+                event,
+                args,
+                env,
+                state: &mut self.state,
+            }),
+            None => Ok(self.unhandled.clone()),
+        }
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for EventDispatcher<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventDispatcher")
+            .field("state", &self.state)
+            .field("events", &self.events())
+            .field("unhandled", &self.unhandled)
+            .finish()
+    }
+}
+
+/// An event callback dispatching with the handle. Give it as `$event_fn` to
+/// [`openvpn_plugin!`] when the handle is an `EventDispatcher`.
+///
+/// [`openvpn_plugin!`]: ../macro.openvpn_plugin.html
+pub fn event<S>(
+    event: EventType,
+    args: Vec<CString>,
+    env: HashMap<CString, CString>,
+    handle: &mut EventDispatcher<S>,
+) -> Result<EventResult, Box<dyn Error>> {
+    handle.dispatch(event, args, env)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn dispatches_by_event() {
+        let mut dispatcher = EventDispatcher::new(Vec::new())
+            .on(EventType::Up, |ctx| {
+                ctx.state.push(ctx.event);
+                Ok::<_, io::Error>(EventResult::Success)
+            })
+            .on(EventType::AuthUserPassVerify, |ctx| {
+                ctx.state.push(ctx.event);
+                match ctx.args.len() {
+                    1 => Ok(EventResult::Failure),
+                    _ => Err(io::Error::other("Unexpected arguments")),
+                }
+            })
+            .unhandled(EventResult::Failure);
+        assert_eq!(
+            EventType::Up | EventType::AuthUserPassVerify,
+            dispatcher.events()
+        );
+
+        let args = vec![CString::new("/plugin.so").unwrap()];
+        assert_eq!(
+            EventResult::Success,
+            event(EventType::Up, args.clone(), HashMap::new(), &mut dispatcher).unwrap()
+        );
+        assert_eq!(
+            EventResult::Failure,
+            dispatcher
+                .dispatch(EventType::AuthUserPassVerify, args.clone(), HashMap::new())
+                .unwrap()
+        );
+        assert_eq!(
+            "Unexpected arguments",
+            dispatcher
+                .dispatch(EventType::AuthUserPassVerify, Vec::new(), HashMap::new())
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            EventResult::Failure,
+            dispatcher
+                .dispatch(EventType::Down, args, HashMap::new())
+                .unwrap()
+        );
+        assert_eq!(
+            vec![
+                EventType::Up,
+                EventType::AuthUserPassVerify,
+                EventType::AuthUserPassVerify
+            ],
+            dispatcher.into_state()
+        );
+    }
+}
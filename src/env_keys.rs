@@ -0,0 +1,251 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Names of the environment variables OpenVPN sets. Taken from the "Environmental Variables"
+//! section of the OpenVPN manual.
+//!
+//! The constants in this module are `&str`. The same names are available as `&CStr` in the
+//! [`cstr`] module, which can be used to look up values in the raw environment without
+//! allocating:
+//!
+//! ```rust
+//! # use std::{collections::HashMap, ffi::CString};
+//! use openvpn_plugin::env_keys;
+//!
+//! # let env: HashMap<CString, CString> = HashMap::new();
+//! let common_name = env.get(env_keys::cstr::COMMON_NAME);
+//! ```
+//!
+//! Variables that OpenVPN numbers, such as `tls_id_{n}` and `route_network_{n}`, are built with
+//! the functions in this module.
+//!
+//! [`cstr`]: cstr/index.html
+
+macro_rules! env_keys {
+    ($($(#[$meta:meta])* $name:ident = $value:literal;)*) => {
+        $(
+            $(#[$meta])*
+            pub const $name: &str = $value;
+        )*
+
+        /// The environment variable names as `&CStr`.
+        pub mod cstr {
+            use std::ffi::CStr;
+
+            $(
+                #[doc = concat!("`", $value, "`")]
+                pub const $name: &CStr = unsafe {
+                    CStr::from_bytes_with_nul_unchecked(concat!($value, "\0").as_bytes())
+                };
+            )*
+        }
+    };
+}
+
+env_keys! {
+    /// Path to the file the result of a deferred authentication is written to.
+    AUTH_CONTROL_FILE = "auth_control_file";
+    /// Path to the file the reason for a failed authentication is written to. OpenVPN 2.6+.
+    AUTH_FAILED_REASON_FILE = "auth_failed_reason_file";
+    /// Path to the file pending authentication information is written to. OpenVPN 2.5+.
+    AUTH_PENDING_FILE = "auth_pending_file";
+    /// Total number of bytes received from the client or server during the session.
+    BYTES_RECEIVED = "bytes_received";
+    /// Total number of bytes sent to the client or server during the session.
+    BYTES_SENT = "bytes_sent";
+    /// Path to the file client specific config is written to by deferred client connects.
+    CLIENT_CONNECT_CONFIG_FILE = "client_connect_config_file";
+    /// Path to the file the result of a deferred client connect is written to.
+    CLIENT_CONNECT_DEFERRED_FILE = "client_connect_deferred_file";
+    /// The common name of the client certificate, or the username with `--username-as-common-name`.
+    COMMON_NAME = "common_name";
+    /// Name of the first `--config` file.
+    CONFIG = "config";
+    /// Base64 encoded response of a `CR_RESPONSE` message. OpenVPN 2.6+.
+    CRRESPONSE = "crresponse";
+    /// Set to `1` if `--daemon` is specified.
+    DAEMON = "daemon";
+    /// Set to `1` if `--log` or `--log-append` redirects the output.
+    DAEMON_LOG_REDIRECT = "daemon_log_redirect";
+    /// Process id of the daemon.
+    DAEMON_PID = "daemon_pid";
+    /// Unix time the daemon started.
+    DAEMON_START_TIME = "daemon_start_time";
+    /// Name of the tun/tap device.
+    DEV = "dev";
+    /// Interface index of the device. Windows only.
+    DEV_IDX = "dev_idx";
+    /// Type of the device, `tun` or `tap`.
+    DEV_TYPE = "dev_type";
+    /// Broadcast address of the local interface in `tap` mode.
+    IFCONFIG_BROADCAST = "ifconfig_broadcast";
+    /// Local IPv6 address of the tunnel.
+    IFCONFIG_IPV6_LOCAL = "ifconfig_ipv6_local";
+    /// Prefix length of the local IPv6 address of the tunnel.
+    IFCONFIG_IPV6_NETBITS = "ifconfig_ipv6_netbits";
+    /// Remote IPv6 address of the tunnel.
+    IFCONFIG_IPV6_REMOTE = "ifconfig_ipv6_remote";
+    /// Local IPv4 address of the tunnel.
+    IFCONFIG_LOCAL = "ifconfig_local";
+    /// Netmask of the tunnel in `tap` mode.
+    IFCONFIG_NETMASK = "ifconfig_netmask";
+    /// Local endpoint of the IPv4 address assigned from the pool.
+    IFCONFIG_POOL_LOCAL_IP = "ifconfig_pool_local_ip";
+    /// Netmask of the IPv4 address assigned from the pool.
+    IFCONFIG_POOL_NETMASK = "ifconfig_pool_netmask";
+    /// IPv4 address assigned to the client from the pool.
+    IFCONFIG_POOL_REMOTE_IP = "ifconfig_pool_remote_ip";
+    /// IPv6 address assigned to the client from the pool.
+    IFCONFIG_POOL_REMOTE_IP6 = "ifconfig_pool_remote_ip6";
+    /// Prefix length of the IPv6 address assigned to the client from the pool.
+    IFCONFIG_POOL_IP6_NETBITS = "ifconfig_pool_ip6_netbits";
+    /// Remote IPv4 address of the tunnel in `tun` mode.
+    IFCONFIG_REMOTE = "ifconfig_remote";
+    /// Maximum packet size on the link.
+    LINK_MTU = "link_mtu";
+    /// The `--local` address.
+    LOCAL = "local";
+    /// The `--lport` port.
+    LOCAL_PORT = "local_port";
+    /// Pre-existing default IPv6 gateway, in `Up` and `RouteUp` events.
+    NET_GATEWAY_IPV6 = "net_gateway_ipv6";
+    /// The password of the client, in `EventType::AuthUserPassVerify` events.
+    PASSWORD = "password";
+    /// Path to the temporary file holding the client certificate in PEM format, with
+    /// `--tls-export-cert`.
+    PEER_CERT = "peer_cert";
+    /// The `--proto` in use.
+    PROTO = "proto";
+    /// Set if `--redirect-gateway` is active.
+    REDIRECT_GATEWAY = "redirect_gateway";
+    /// Gateway of the IPv6 routes pushed or configured with `--route-ipv6`, the remote IPv6
+    /// address of the tunnel by default.
+    ROUTE_IPV6_GATEWAY = "route_ipv6_gateway";
+    /// Pre-existing default IPv4 gateway.
+    ROUTE_NET_GATEWAY = "route_net_gateway";
+    /// Default gateway for `--route` options.
+    ROUTE_VPN_GATEWAY = "route_vpn_gateway";
+    /// Either `init` or `restart`, telling if the `Up` or `Down` event is part of a restart.
+    SCRIPT_CONTEXT = "script_context";
+    /// The script type, such as `up` or `client-connect`, the event corresponds to.
+    SCRIPT_TYPE = "script_type";
+    /// The reason OpenVPN is exiting or restarting, in `Down` events.
+    SIGNAL = "signal";
+    /// Session id of the client, used in the auth token. OpenVPN 2.5+.
+    SESSION_ID = "session_id";
+    /// State of the client auth token session. OpenVPN 2.5+.
+    SESSION_STATE = "session_state";
+    /// Time the client connected, in human readable form.
+    TIME_ASCII = "time_ascii";
+    /// Number of seconds the client was connected.
+    TIME_DURATION = "time_duration";
+    /// Time the client connected, as a unix timestamp.
+    TIME_UNIX = "time_unix";
+    /// The authenticated real IPv4 address of the peer.
+    TRUSTED_IP = "trusted_ip";
+    /// The authenticated real IPv6 address of the peer.
+    TRUSTED_IP6 = "trusted_ip6";
+    /// The authenticated real port of the peer.
+    TRUSTED_PORT = "trusted_port";
+    /// Maximum size of packets on the tunnel.
+    TUN_MTU = "tun_mtu";
+    /// The not yet authenticated real IPv4 address of the peer.
+    UNTRUSTED_IP = "untrusted_ip";
+    /// The not yet authenticated real IPv6 address of the peer.
+    UNTRUSTED_IP6 = "untrusted_ip6";
+    /// The not yet authenticated real port of the peer.
+    UNTRUSTED_PORT = "untrusted_port";
+    /// The username of the client, in `EventType::AuthUserPassVerify` events.
+    USERNAME = "username";
+    /// The `--verb` level.
+    VERB = "verb";
+    /// Version of the client. Sent by the client as peer info.
+    IV_VER = "IV_VER";
+    /// Platform of the client. Sent by the client as peer info.
+    IV_PLAT = "IV_PLAT";
+    /// Protocol feature bits of the client. Sent by the client as peer info.
+    IV_PROTO = "IV_PROTO";
+    /// Data channel ciphers the client supports. Sent by the client as peer info.
+    IV_CIPHERS = "IV_CIPHERS";
+}
+
+/// The `n`:th `--route` network, counting from 1.
+pub fn route_network(n: usize) -> String {
+    format!("route_network_{}", n)
+}
+
+/// The netmask of the `n`:th `--route`, counting from 1.
+pub fn route_netmask(n: usize) -> String {
+    format!("route_netmask_{}", n)
+}
+
+/// The gateway of the `n`:th `--route`, counting from 1.
+pub fn route_gateway(n: usize) -> String {
+    format!("route_gateway_{}", n)
+}
+
+/// The metric of the `n`:th `--route`, counting from 1.
+pub fn route_metric(n: usize) -> String {
+    format!("route_metric_{}", n)
+}
+
+/// The `n`:th `--route-ipv6` network, counting from 1.
+pub fn route_ipv6_network(n: usize) -> String {
+    format!("route_ipv6_network_{}", n)
+}
+
+/// The gateway of the `n`:th `--route-ipv6`, counting from 1.
+pub fn route_ipv6_gateway(n: usize) -> String {
+    format!("route_ipv6_gateway_{}", n)
+}
+
+/// The `n`:th option pushed from the server that the client does not handle itself, counting
+/// from 1.
+pub fn foreign_option(n: usize) -> String {
+    format!("foreign_option_{}", n)
+}
+
+/// The `n`:th `--remote` address, counting from 1.
+pub fn remote(n: usize) -> String {
+    format!("remote_{}", n)
+}
+
+/// The port of the `n`:th `--remote`, counting from 1.
+pub fn remote_port(n: usize) -> String {
+    format!("remote_port_{}", n)
+}
+
+/// The subject of the certificate at chain depth `depth`, where 0 is the peer certificate.
+pub fn tls_id(depth: usize) -> String {
+    format!("tls_id_{}", depth)
+}
+
+/// The serial number, in decimal, of the certificate at chain depth `depth`.
+pub fn tls_serial(depth: usize) -> String {
+    format!("tls_serial_{}", depth)
+}
+
+/// The serial number, in hex, of the certificate at chain depth `depth`.
+pub fn tls_serial_hex(depth: usize) -> String {
+    format!("tls_serial_hex_{}", depth)
+}
+
+/// The SHA1 fingerprint of the certificate at chain depth `depth`.
+pub fn tls_digest(depth: usize) -> String {
+    format!("tls_digest_{}", depth)
+}
+
+/// The SHA256 fingerprint of the certificate at chain depth `depth`.
+pub fn tls_digest_sha256(depth: usize) -> String {
+    format!("tls_digest_sha256_{}", depth)
+}
+
+/// The subject field `field`, such as `CN`, of the certificate at chain depth `depth`.
+pub fn x509_field(depth: usize, field: &str) -> String {
+    format!("X509_{}_{}", depth, field)
+}
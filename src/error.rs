@@ -0,0 +1,147 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{fmt, os::raw::c_int, time::Duration};
+
+use crate::{ffi::parse::ParseError, EventType};
+
+/// The errors this crate logs when a callback from OpenVPN can't be completed. The error returned
+/// by a plugin callback is available through `source()`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The arguments or environment OpenVPN gave the plugin could not be parsed.
+    ParseFailed {
+        /// What was being parsed.
+        msg: &'static str,
+        /// Why parsing failed.
+        source: ParseError,
+    },
+    /// One of the plugin's callbacks returned an error.
+    CallbackFailed {
+        /// Which callback failed.
+        msg: &'static str,
+        /// The error returned by the callback.
+        source: Box<dyn std::error::Error>,
+    },
+    /// OpenVPN sent an event type this crate does not know about. Logged as a warning, since the
+    /// event is answered with success.
+    InvalidEvent(c_int),
+    /// The plugin returned `EventResult::Deferred` from an event that can't be deferred.
+    IllegalDeferral(EventType),
+    /// OpenVPN passed plugin structs of an older version than this crate can read, given here.
+    UnsupportedVersion(c_int),
+    /// OpenVPN passed a null pointer for a value the plugin needs, named here.
+    NullPointer(&'static str),
+    /// A deferred authentication was not decided within its timeout, and was denied. Logged as a
+    /// warning.
+    AuthTimedOut(Duration),
+    /// Handling an event forwarded to a background thread failed. Logged as a warning, since the
+    /// event was already answered.
+    ForwardFailed {
+        /// The event that was forwarded.
+        event: EventType,
+        /// Why handling it failed.
+        source: Box<dyn std::error::Error>,
+    },
+    /// Any other failure in this crate, such as setting up the runtime of an async plugin.
+    Other {
+        /// What failed.
+        msg: &'static str,
+        /// Why it failed.
+        source: Box<dyn std::error::Error>,
+    },
+}
+
+impl Error {
+    pub(crate) fn parse_failed(msg: &'static str, source: ParseError) -> Error {
+        Error::ParseFailed { msg, source }
+    }
+
+    pub(crate) fn callback_failed(
+        msg: &'static str,
+        source: impl Into<Box<dyn std::error::Error>>,
+    ) -> Error {
+        Error::CallbackFailed {
+            msg,
+            source: source.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ParseFailed { msg, .. }
+            | Error::CallbackFailed { msg, .. }
+            | Error::Other { msg, .. } => msg.fmt(f),
+            Error::InvalidEvent(event_type) => write!(
+                f,
+                "Ignoring unknown event {}, not a valid OPENVPN_PLUGIN_* constant",
+                event_type
+            ),
+            Error::IllegalDeferral(event) => write!(f, "{:?} events can not be deferred", event),
+            Error::UnsupportedVersion(version) => write!(
+                f,
+                "OpenVPN passed plugin structs of version {}, at least version {} is required",
+                version,
+                crate::ffi::OPENVPN_PLUGIN_STRUCTVER_MIN
+            ),
+            Error::NullPointer(what) => write!(f, "OpenVPN passed a null pointer as the {}", what),
+            Error::ForwardFailed { event, .. } => {
+                write!(f, "Unable to handle the forwarded {} event", event)
+            }
+            Error::AuthTimedOut(timeout) => write!(
+                f,
+                "The deferred authentication was not decided within {:?}, denying it",
+                timeout
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::ParseFailed { source, .. } => Some(source),
+            Error::CallbackFailed { source, .. }
+            | Error::ForwardFailed { source, .. }
+            | Error::Other { source, .. } => Some(source.as_ref()),
+            Error::InvalidEvent(_)
+            | Error::IllegalDeferral(_)
+            | Error::UnsupportedVersion(_)
+            | Error::NullPointer(_)
+            | Error::AuthTimedOut(_) => None,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{error::Error as _, io};
+
+    #[test]
+    fn source_chain() {
+        let error = Error::callback_failed("Plugin open failed", io::Error::other("no config"));
+        assert_eq!("Plugin open failed", error.to_string());
+        assert_eq!("no config", error.source().unwrap().to_string());
+        assert!(matches!(error, Error::CallbackFailed { .. }));
+
+        let error = Error::parse_failed("Malformed env from OpenVPN", ParseError::NullPtr);
+        assert_eq!(
+            Some(&ParseError::NullPtr),
+            error.source().unwrap().downcast_ref::<ParseError>()
+        );
+
+        let error = Error::IllegalDeferral(EventType::Up);
+        assert_eq!("Up events can not be deferred", error.to_string());
+        assert!(error.source().is_none());
+    }
+}
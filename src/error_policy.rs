@@ -0,0 +1,97 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! What OpenVPN is told when the event callback returns an error.
+//!
+//! By default an error is logged and [`OPENVPN_PLUGIN_FUNC_ERROR`] is returned to OpenVPN. For
+//! events that only inform the plugin, such as `Up`, `Down` and `ClientDisconnect`, that can do
+//! more harm than good. A failure to push metrics in the `Up` event should not take down the
+//! tunnel. Errors in such events can instead be logged as warnings and answered with success:
+//!
+//! ```rust
+//! use openvpn_plugin::{error_policy, EventType};
+//!
+//! error_policy::ignore_errors(EventType::Up | EventType::Down | EventType::ClientDisconnect);
+//! assert!(error_policy::ignores_errors(EventType::Down));
+//! ```
+//!
+//! Events where an error denies a client, such as `AuthUserPassVerify` and `TlsVerify`, are always
+//! strict. Answering them with success would let the client in. See [`can_ignore_errors`].
+//!
+//! The policy applies to the whole plugin. Call [`ignore_errors`] from `$open_fn`.
+//!
+//! [`OPENVPN_PLUGIN_FUNC_ERROR`]: ../ffi/constant.OPENVPN_PLUGIN_FUNC_ERROR.html
+//! [`can_ignore_errors`]: fn.can_ignore_errors.html
+//! [`ignore_errors`]: fn.ignore_errors.html
+
+use std::sync::atomic::{AtomicI32, Ordering};
+
+use crate::{EventType, EventTypeSet};
+
+/// The `type_mask` of the events whose errors are ignored.
+static IGNORED: AtomicI32 = AtomicI32::new(0);
+
+/// Logs errors returned from `events` as warnings and answers them with success instead of
+/// `OPENVPN_PLUGIN_FUNC_ERROR`. Events that can't ignore errors are left strict. Returns the
+/// events that were made lenient.
+pub fn ignore_errors(events: impl Into<EventTypeSet>) -> EventTypeSet {
+    let lenient: EventTypeSet = events
+        .into()
+        .iter()
+        .filter(|event| can_ignore_errors(*event))
+        .collect();
+    IGNORED.fetch_or(lenient.bits(), Ordering::Relaxed);
+    lenient
+}
+
+/// Makes errors from `events` fail the event again, undoing `ignore_errors`.
+pub fn fail_on_errors(events: impl Into<EventTypeSet>) {
+    IGNORED.fetch_and(!events.into().bits(), Ordering::Relaxed);
+}
+
+/// Returns true if errors from `event` are logged as warnings and answered with success.
+pub fn ignores_errors(event: EventType) -> bool {
+    EventTypeSet::from_bits_truncate(IGNORED.load(Ordering::Relaxed)).contains(event)
+}
+
+/// Returns true if errors in `event` are allowed to be ignored. False for the events where
+/// OpenVPN takes an error as denying the client access, or as failing to set up its connection.
+pub fn can_ignore_errors(event: EventType) -> bool {
+    match event {
+        EventType::TlsVerify
+        | EventType::AuthUserPassVerify
+        | EventType::ClientConnect
+        | EventType::ClientConnectV2
+        | EventType::LearnAddress
+        | EventType::TlsFinal => false,
+        #[cfg(any(feature = "openvpn-2-4", feature = "openvpn-2-5"))]
+        EventType::EnablePf => false,
+        #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+        EventType::ClientConnectDefer | EventType::ClientConnectDeferV2 => false,
+        #[cfg(feature = "openvpn-2-6")]
+        EventType::ClientCrresponse => false,
+        _ => true,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auth_events_stay_strict() {
+        let lenient = ignore_errors(EventType::RouteUp | EventType::AuthUserPassVerify);
+        assert_eq!(EventTypeSet::from(EventType::RouteUp), lenient);
+        assert!(ignores_errors(EventType::RouteUp));
+        assert!(!ignores_errors(EventType::AuthUserPassVerify));
+
+        fail_on_errors(EventType::RouteUp);
+        assert!(!ignores_errors(EventType::RouteUp));
+    }
+}
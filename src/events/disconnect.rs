@@ -0,0 +1,83 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{collections::HashMap, ffi::CString, net::IpAddr, time::Duration};
+
+use super::{Env, EventArgsError};
+use crate::env_keys;
+
+/// Statistics about a client session, given in the environment of an
+/// `EventType::ClientDisconnect` event.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct DisconnectStats {
+    /// Number of bytes received from the client during the session.
+    pub bytes_received: u64,
+    /// Number of bytes sent to the client during the session.
+    pub bytes_sent: u64,
+    /// How long the client was connected.
+    pub duration: Duration,
+    /// The real address of the client. Read from `trusted_ip`, or `trusted_ip6` for IPv6 clients.
+    pub trusted_ip: Option<IpAddr>,
+    /// The real port of the client.
+    pub trusted_port: Option<u16>,
+}
+
+impl DisconnectStats {
+    /// Parses the statistics from the environment of a client disconnect event. The byte counters
+    /// and the duration are required and an error is returned if they are missing.
+    pub fn from_env(env: &HashMap<CString, CString>) -> Result<Self, EventArgsError> {
+        let env = Env(env);
+        let trusted_ip = env.ip_opt(env_keys::TRUSTED_IP, env_keys::TRUSTED_IP6)?;
+        Ok(DisconnectStats {
+            bytes_received: env.parse(env_keys::BYTES_RECEIVED)?,
+            bytes_sent: env.parse(env_keys::BYTES_SENT)?,
+            duration: env.duration(env_keys::TIME_DURATION)?,
+            trusted_ip,
+            trusted_port: env.parse_opt(env_keys::TRUSTED_PORT)?,
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::env;
+    use std::net::Ipv6Addr;
+
+    #[test]
+    fn parse_stats() {
+        let env = env(&[
+            ("bytes_received", "1234"),
+            ("bytes_sent", "5678"),
+            ("time_duration", "60"),
+            ("trusted_ip6", "2001:db8::1"),
+            ("trusted_port", "1194"),
+        ]);
+        assert_eq!(
+            DisconnectStats {
+                bytes_received: 1234,
+                bytes_sent: 5678,
+                duration: Duration::from_secs(60),
+                trusted_ip: Some(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))),
+                trusted_port: Some(1194),
+            },
+            DisconnectStats::from_env(&env).unwrap()
+        );
+    }
+
+    #[test]
+    fn missing_duration() {
+        let env = env(&[("bytes_received", "1234"), ("bytes_sent", "5678")]);
+        assert_eq!(
+            Err(EventArgsError::MissingEnv("time_duration")),
+            DisconnectStats::from_env(&env)
+        );
+    }
+}
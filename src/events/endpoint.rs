@@ -0,0 +1,130 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    net::{IpAddr, SocketAddr},
+};
+
+use super::{Env, EventArgsError};
+use crate::env_keys;
+
+/// The real address of the client, not yet authenticated, from `untrusted_ip`, or
+/// `untrusted_ip6` for clients connecting over IPv6. `None` if neither is set.
+pub fn untrusted_ip(env: &HashMap<CString, CString>) -> Result<Option<IpAddr>, EventArgsError> {
+    Env(env).ip_opt(env_keys::UNTRUSTED_IP, env_keys::UNTRUSTED_IP6)
+}
+
+/// The real address and port of the client, not yet authenticated, from `untrusted_ip` or
+/// `untrusted_ip6` and `untrusted_port`. `None` if no address is set, and an error if the port is
+/// missing.
+pub fn untrusted_addr(
+    env: &HashMap<CString, CString>,
+) -> Result<Option<SocketAddr>, EventArgsError> {
+    Env(env).socket_addr_opt(
+        env_keys::UNTRUSTED_IP,
+        env_keys::UNTRUSTED_IP6,
+        env_keys::UNTRUSTED_PORT,
+    )
+}
+
+/// The authenticated real address of the client, from `trusted_ip`, or `trusted_ip6` for clients
+/// connecting over IPv6. `None` if neither is set.
+pub fn trusted_ip(env: &HashMap<CString, CString>) -> Result<Option<IpAddr>, EventArgsError> {
+    Env(env).ip_opt(env_keys::TRUSTED_IP, env_keys::TRUSTED_IP6)
+}
+
+/// The authenticated real address and port of the client, from `trusted_ip` or `trusted_ip6` and
+/// `trusted_port`. `None` if no address is set, and an error if the port is missing.
+pub fn trusted_addr(env: &HashMap<CString, CString>) -> Result<Option<SocketAddr>, EventArgsError> {
+    Env(env).socket_addr_opt(
+        env_keys::TRUSTED_IP,
+        env_keys::TRUSTED_IP6,
+        env_keys::TRUSTED_PORT,
+    )
+}
+
+impl Env<'_> {
+    /// Reads an address from `v4`, or from `v6` if `v4` is not set.
+    pub(crate) fn ip_opt(
+        &self,
+        v4: &'static str,
+        v6: &'static str,
+    ) -> Result<Option<IpAddr>, EventArgsError> {
+        match self.parse_opt(v4)? {
+            Some(ip) => Ok(Some(ip)),
+            None => self.parse_opt(v6),
+        }
+    }
+
+    /// Reads an address from `v4` or `v6`, with the port in `port`.
+    pub(crate) fn socket_addr_opt(
+        &self,
+        v4: &'static str,
+        v6: &'static str,
+        port: &'static str,
+    ) -> Result<Option<SocketAddr>, EventArgsError> {
+        match self.ip_opt(v4, v6)? {
+            Some(ip) => Ok(Some(SocketAddr::new(ip, self.parse(port)?))),
+            None => Ok(None),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::env;
+
+    #[test]
+    fn combines_address_and_port() {
+        let ipv4 = env(&[
+            ("untrusted_ip", "192.0.2.1"),
+            ("untrusted_port", "1194"),
+            ("trusted_ip", "192.0.2.1"),
+            ("trusted_port", "1194"),
+        ]);
+        assert_eq!(
+            Some("192.0.2.1:1194".parse().unwrap()),
+            untrusted_addr(&ipv4).unwrap()
+        );
+        assert_eq!(
+            Some("192.0.2.1:1194".parse().unwrap()),
+            trusted_addr(&ipv4).unwrap()
+        );
+
+        let ipv6 = env(&[("untrusted_ip6", "2001:db8::1"), ("untrusted_port", "443")]);
+        assert_eq!(
+            Some("[2001:db8::1]:443".parse().unwrap()),
+            untrusted_addr(&ipv6).unwrap()
+        );
+        assert_eq!(
+            Some("2001:db8::1".parse().unwrap()),
+            untrusted_ip(&ipv6).unwrap()
+        );
+        assert_eq!(None, trusted_ip(&ipv6).unwrap());
+        assert_eq!(None, trusted_addr(&ipv6).unwrap());
+    }
+
+    #[test]
+    fn invalid_endpoints() {
+        assert_eq!(
+            Err(EventArgsError::MissingEnv("untrusted_port")),
+            untrusted_addr(&env(&[("untrusted_ip", "192.0.2.1")]))
+        );
+        assert_eq!(
+            Err(EventArgsError::InvalidValue(
+                "trusted_ip6".to_owned(),
+                "192.0.2.1:1194".to_owned()
+            )),
+            trusted_ip(&env(&[("trusted_ip6", "192.0.2.1:1194")]))
+        );
+    }
+}
@@ -0,0 +1,243 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{fmt, net::IpAddr, str::FromStr};
+
+/// Error returned when a learn-address operation or address can't be parsed.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ParseLearnAddressError(String);
+
+impl fmt::Display for ParseLearnAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid learn-address value \"{}\"", self.0)
+    }
+}
+
+impl std::error::Error for ParseLearnAddressError {}
+
+
+/// The operation OpenVPN performs on the address in an `EventType::LearnAddress` event.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum LearnAddressOp {
+    /// A new address was assigned to a client.
+    Add,
+    /// An existing address was moved to a different client.
+    Update,
+    /// The address is no longer in use.
+    Delete,
+}
+
+impl FromStr for LearnAddressOp {
+    type Err = ParseLearnAddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "add" => Ok(LearnAddressOp::Add),
+            "update" => Ok(LearnAddressOp::Update),
+            "delete" => Ok(LearnAddressOp::Delete),
+            _ => Err(ParseLearnAddressError(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for LearnAddressOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LearnAddressOp::Add => "add",
+            LearnAddressOp::Update => "update",
+            LearnAddressOp::Delete => "delete",
+        }
+        .fmt(f)
+    }
+}
+
+
+/// An IP address together with a prefix length, such as `10.8.0.0/24`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    /// Creates a new network. Returns `None` if `prefix` is longer than the address.
+    pub fn new(address: IpAddr, prefix: u8) -> Option<Self> {
+        if prefix > max_prefix(address) {
+            None
+        } else {
+            Some(IpNetwork { address, prefix })
+        }
+    }
+
+    /// Returns the address part of the network.
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    /// Returns the prefix length.
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// Returns true if this network is a single host, i.e. the prefix covers the entire address.
+    pub fn is_host(&self) -> bool {
+        self.prefix == max_prefix(self.address)
+    }
+}
+
+fn max_prefix(address: IpAddr) -> u8 {
+    match address {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    }
+}
+
+impl From<IpAddr> for IpNetwork {
+    fn from(address: IpAddr) -> Self {
+        IpNetwork {
+            address,
+            prefix: max_prefix(address),
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = ParseLearnAddressError;
+
+    /// Parses an address with an optional `/prefix` suffix. Without a suffix the network is a
+    /// single host.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseLearnAddressError(s.to_owned());
+        let mut parts = s.splitn(2, '/');
+        let address: IpAddr = parts.next().unwrap().parse().map_err(|_| error())?;
+        match parts.next() {
+            Some(prefix) => {
+                let prefix = prefix.parse().map_err(|_| error())?;
+                IpNetwork::new(address, prefix).ok_or_else(error)
+            }
+            None => Ok(IpNetwork::from(address)),
+        }
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+#[cfg(feature = "ipnet")]
+impl From<IpNetwork> for ipnet::IpNet {
+    fn from(network: IpNetwork) -> Self {
+        // The prefix is checked to fit the address when the network is created.
+        ipnet::IpNet::new(network.address, network.prefix).unwrap()
+    }
+}
+
+#[cfg(feature = "ipnet")]
+impl From<ipnet::IpNet> for IpNetwork {
+    fn from(network: ipnet::IpNet) -> Self {
+        IpNetwork {
+            address: network.addr(),
+            prefix: network.prefix_len(),
+        }
+    }
+}
+
+
+/// The address given in an `EventType::LearnAddress` event. Is an IP network in routed (`tun`)
+/// mode and a MAC address in bridged (`tap`) mode.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum LearnedAddress {
+    Ip(IpNetwork),
+    Mac([u8; 6]),
+}
+
+impl FromStr for LearnedAddress {
+    type Err = ParseLearnAddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(network) = s.parse() {
+            return Ok(LearnedAddress::Ip(network));
+        }
+        let mut mac = [0u8; 6];
+        let mut parts = s.split(':');
+        for byte in mac.iter_mut() {
+            let part = parts
+                .next()
+                .filter(|part| part.len() == 2)
+                .ok_or_else(|| ParseLearnAddressError(s.to_owned()))?;
+            *byte =
+                u8::from_str_radix(part, 16).map_err(|_| ParseLearnAddressError(s.to_owned()))?;
+        }
+        if parts.next().is_some() {
+            return Err(ParseLearnAddressError(s.to_owned()));
+        }
+        Ok(LearnedAddress::Mac(mac))
+    }
+}
+
+impl fmt::Display for LearnedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LearnedAddress::Ip(network) if network.is_host() => network.address().fmt(f),
+            LearnedAddress::Ip(network) => network.fmt(f),
+            LearnedAddress::Mac(mac) => write!(
+                f,
+                "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+                mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+            ),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn parse_op() {
+        assert_eq!(Ok(LearnAddressOp::Update), "update".parse());
+        assert!("remove".parse::<LearnAddressOp>().is_err());
+    }
+
+    #[test]
+    fn parse_host() {
+        let address: LearnedAddress = "10.8.0.6".parse().unwrap();
+        let network = IpNetwork::from(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 6)));
+        assert_eq!(LearnedAddress::Ip(network), address);
+        assert_eq!("10.8.0.6", address.to_string());
+    }
+
+    #[test]
+    fn parse_network() {
+        let address: LearnedAddress = "fd00::/64".parse().unwrap();
+        match address {
+            LearnedAddress::Ip(network) => assert_eq!(64, network.prefix()),
+            _ => panic!("Not an IP network"),
+        }
+        assert!("10.0.0.0/33".parse::<LearnedAddress>().is_err());
+    }
+
+    #[test]
+    fn parse_mac() {
+        let address: LearnedAddress = "0a:1b:2c:3d:4e:5f".parse().unwrap();
+        assert_eq!(
+            LearnedAddress::Mac([0x0a, 0x1b, 0x2c, 0x3d, 0x4e, 0x5f]),
+            address
+        );
+        assert_eq!("0a:1b:2c:3d:4e:5f", address.to_string());
+        assert!("0a:1b:2c:3d:4e".parse::<LearnedAddress>().is_err());
+        assert!("0a:1b:2c:3d:4e:5f:00".parse::<LearnedAddress>().is_err());
+    }
+}
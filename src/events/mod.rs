@@ -0,0 +1,453 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    ffi::CString,
+    fmt,
+    net::IpAddr,
+    str::{FromStr, Utf8Error},
+};
+
+#[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+use crate::client_connect::DeferredClientConnect;
+#[cfg(feature = "openvpn-2-6")]
+use crate::cr;
+use crate::{
+    auth::{ControlFile, Credentials},
+    env_keys, EventType,
+};
+
+mod disconnect;
+pub use self::disconnect::DisconnectStats;
+
+mod endpoint;
+pub use self::endpoint::{trusted_addr, trusted_ip, untrusted_addr, untrusted_ip};
+
+mod learn_address;
+pub use self::learn_address::{IpNetwork, LearnAddressOp, LearnedAddress, ParseLearnAddressError};
+
+mod peer_info;
+pub use self::peer_info::{PeerInfo, ProtoFlags};
+
+mod routes;
+#[cfg(feature = "ipnet")]
+pub(crate) use self::routes::{netmask_prefix, parse_numbered};
+pub use self::routes::{Route, Routes};
+
+mod times;
+pub use self::times::{daemon_start_time, time_duration, time_unix};
+
+/// Error type returned when the arguments or environment of an event can't be parsed into
+/// [`EventArgs`].
+///
+/// [`EventArgs`]: enum.EventArgs.html
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum EventArgsError {
+    /// OpenVPN did not give the argument with the given index.
+    MissingArg(usize),
+    /// The variable with the given name is not present in the environment.
+    MissingEnv(&'static str),
+    /// The argument or variable with the given name is not valid UTF-8.
+    InvalidUtf8(String, Utf8Error),
+    /// The argument or variable with the given name could not be parsed into the expected type.
+    InvalidValue(String, String),
+}
+
+impl fmt::Display for EventArgsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EventArgsError::MissingArg(index) => write!(f, "No argument at index {}", index),
+            EventArgsError::MissingEnv(name) => write!(f, "No \"{}\" in env", name),
+            EventArgsError::InvalidUtf8(name, _) => write!(f, "\"{}\" is not valid UTF-8", name),
+            EventArgsError::InvalidValue(name, value) => {
+                write!(f, "Invalid value for \"{}\": \"{}\"", name, value)
+            }
+        }
+    }
+}
+
+impl Error for EventArgsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EventArgsError::InvalidUtf8(_, e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+
+/// Typed representation of the data OpenVPN passes with each event. Created from the raw
+/// arguments and environment given to the event callback with [`EventArgs::parse`].
+///
+/// Only the most commonly used values are extracted. Everything else is still available in the
+/// raw arguments and environment.
+///
+/// [`EventArgs::parse`]: #method.parse
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum EventArgs {
+    Up {
+        device: String,
+        local_ip: Option<IpAddr>,
+        remote_ip: Option<IpAddr>,
+    },
+    Down {
+        device: String,
+        local_ip: Option<IpAddr>,
+        remote_ip: Option<IpAddr>,
+    },
+    RouteUp {
+        routes: Routes,
+    },
+    IpChange {
+        address: IpAddr,
+        port: u16,
+    },
+    TlsVerify {
+        depth: u32,
+        subject: String,
+    },
+    AuthUserPassVerify {
+        credentials: Credentials,
+        control_file: Option<ControlFile>,
+    },
+    ClientConnect {
+        common_name: Option<String>,
+        #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+        deferred: Option<DeferredClientConnect>,
+    },
+    ClientDisconnect {
+        common_name: Option<String>,
+        stats: DisconnectStats,
+    },
+    LearnAddress {
+        operation: LearnAddressOp,
+        address: LearnedAddress,
+        common_name: Option<String>,
+    },
+    ClientConnectV2 {
+        common_name: Option<String>,
+        #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+        deferred: Option<DeferredClientConnect>,
+    },
+    TlsFinal,
+    #[cfg(any(feature = "openvpn-2-4", feature = "openvpn-2-5"))]
+    EnablePf,
+    RoutePredown {
+        routes: Routes,
+    },
+    #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+    ClientConnectDefer {
+        common_name: Option<String>,
+        deferred: Option<DeferredClientConnect>,
+    },
+    #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+    ClientConnectDeferV2 {
+        common_name: Option<String>,
+        deferred: Option<DeferredClientConnect>,
+    },
+    #[cfg(feature = "openvpn-2-6")]
+    ClientCrresponse {
+        response: Vec<u8>,
+    },
+    #[cfg(feature = "auth-failed-event")]
+    AuthFailed,
+}
+
+impl EventArgs {
+    /// Parses the arguments and environment OpenVPN gave for `event`.
+    ///
+    /// `args` should be given exactly as received by the event callback. The first argument is the
+    /// path to the plugin itself and the event specific arguments follow after it.
+    pub fn parse(
+        event: EventType,
+        args: &[CString],
+        env: &HashMap<CString, CString>,
+    ) -> Result<Self, EventArgsError> {
+        let args = Args(args);
+        let env = Env(env);
+        Ok(match event {
+            EventType::Up => EventArgs::Up {
+                device: env.string(env_keys::DEV)?,
+                local_ip: env.parse_opt(env_keys::IFCONFIG_LOCAL)?,
+                remote_ip: env.parse_opt(env_keys::IFCONFIG_REMOTE)?,
+            },
+            EventType::Down => EventArgs::Down {
+                device: env.string(env_keys::DEV)?,
+                local_ip: env.parse_opt(env_keys::IFCONFIG_LOCAL)?,
+                remote_ip: env.parse_opt(env_keys::IFCONFIG_REMOTE)?,
+            },
+            EventType::RouteUp => EventArgs::RouteUp {
+                routes: Routes::from_env(env.0)?,
+            },
+            EventType::IpChange => EventArgs::IpChange {
+                address: args.parse(1)?,
+                port: args.parse(2)?,
+            },
+            EventType::TlsVerify => EventArgs::TlsVerify {
+                depth: args.parse(1)?,
+                subject: args.string(2)?,
+            },
+            EventType::AuthUserPassVerify => EventArgs::AuthUserPassVerify {
+                credentials: Credentials::from_env(env.0)?,
+                control_file: ControlFile::from_env(env.0).ok(),
+            },
+            EventType::ClientConnect => EventArgs::ClientConnect {
+                common_name: env.string_opt(env_keys::COMMON_NAME)?,
+                #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+                deferred: DeferredClientConnect::from_env(env.0).ok(),
+            },
+            EventType::ClientDisconnect => EventArgs::ClientDisconnect {
+                common_name: env.string_opt(env_keys::COMMON_NAME)?,
+                stats: DisconnectStats::from_env(env.0)?,
+            },
+            EventType::LearnAddress => EventArgs::LearnAddress {
+                operation: args.parse(1)?,
+                address: args.parse(2)?,
+                common_name: args.string_opt(3)?,
+            },
+            EventType::ClientConnectV2 => EventArgs::ClientConnectV2 {
+                common_name: env.string_opt(env_keys::COMMON_NAME)?,
+                #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+                deferred: DeferredClientConnect::from_env(env.0).ok(),
+            },
+            EventType::TlsFinal => EventArgs::TlsFinal,
+            #[cfg(any(feature = "openvpn-2-4", feature = "openvpn-2-5"))]
+            EventType::EnablePf => EventArgs::EnablePf,
+            EventType::RoutePredown => EventArgs::RoutePredown {
+                routes: Routes::from_env(env.0)?,
+            },
+            #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+            EventType::ClientConnectDefer => EventArgs::ClientConnectDefer {
+                common_name: env.string_opt(env_keys::COMMON_NAME)?,
+                deferred: DeferredClientConnect::from_env(env.0).ok(),
+            },
+            #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+            EventType::ClientConnectDeferV2 => EventArgs::ClientConnectDeferV2 {
+                common_name: env.string_opt(env_keys::COMMON_NAME)?,
+                deferred: DeferredClientConnect::from_env(env.0).ok(),
+            },
+            #[cfg(feature = "openvpn-2-6")]
+            EventType::ClientCrresponse => {
+                // Looked up again on failure for the error to tell a missing variable from an
+                // invalid one.
+                let response =
+                    cr::client_response(env.0).map_err(|_| match env.string(cr::CRRESPONSE) {
+                        Ok(value) => EventArgsError::InvalidValue(cr::CRRESPONSE.to_owned(), value),
+                        Err(e) => e,
+                    })?;
+                EventArgs::ClientCrresponse { response }
+            }
+            #[cfg(feature = "auth-failed-event")]
+            EventType::AuthFailed => EventArgs::AuthFailed,
+        })
+    }
+
+    /// Returns the type of the event these arguments belong to.
+    pub fn event_type(&self) -> EventType {
+        match self {
+            EventArgs::Up { .. } => EventType::Up,
+            EventArgs::Down { .. } => EventType::Down,
+            EventArgs::RouteUp { .. } => EventType::RouteUp,
+            EventArgs::IpChange { .. } => EventType::IpChange,
+            EventArgs::TlsVerify { .. } => EventType::TlsVerify,
+            EventArgs::AuthUserPassVerify { .. } => EventType::AuthUserPassVerify,
+            EventArgs::ClientConnect { .. } => EventType::ClientConnect,
+            EventArgs::ClientDisconnect { .. } => EventType::ClientDisconnect,
+            EventArgs::LearnAddress { .. } => EventType::LearnAddress,
+            EventArgs::ClientConnectV2 { .. } => EventType::ClientConnectV2,
+            EventArgs::TlsFinal => EventType::TlsFinal,
+            #[cfg(any(feature = "openvpn-2-4", feature = "openvpn-2-5"))]
+            EventArgs::EnablePf => EventType::EnablePf,
+            EventArgs::RoutePredown { .. } => EventType::RoutePredown,
+            #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+            EventArgs::ClientConnectDefer { .. } => EventType::ClientConnectDefer,
+            #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+            EventArgs::ClientConnectDeferV2 { .. } => EventType::ClientConnectDeferV2,
+            #[cfg(feature = "openvpn-2-6")]
+            EventArgs::ClientCrresponse { .. } => EventType::ClientCrresponse,
+            #[cfg(feature = "auth-failed-event")]
+            EventArgs::AuthFailed => EventType::AuthFailed,
+        }
+    }
+}
+
+
+/// Accessors for the raw event arguments.
+struct Args<'a>(&'a [CString]);
+
+impl Args<'_> {
+    fn string_opt(&self, index: usize) -> Result<Option<String>, EventArgsError> {
+        self.0
+            .get(index)
+            .map(|arg| {
+                arg.to_str()
+                    .map(str::to_owned)
+                    .map_err(|e| EventArgsError::InvalidUtf8(format!("argument {}", index), e))
+            })
+            .transpose()
+    }
+
+    fn string(&self, index: usize) -> Result<String, EventArgsError> {
+        self.string_opt(index)?
+            .ok_or(EventArgsError::MissingArg(index))
+    }
+
+    fn parse<T: FromStr>(&self, index: usize) -> Result<T, EventArgsError> {
+        let value = self.string(index)?;
+        value
+            .parse()
+            .map_err(|_| EventArgsError::InvalidValue(format!("argument {}", index), value))
+    }
+}
+
+/// Accessors for the raw event environment.
+pub(crate) struct Env<'a>(pub(crate) &'a HashMap<CString, CString>);
+
+impl Env<'_> {
+    pub(crate) fn string_opt(&self, name: &'static str) -> Result<Option<String>, EventArgsError> {
+        self.0
+            .get(&CString::new(name).unwrap())
+            .map(|value| {
+                value
+                    .to_str()
+                    .map(str::to_owned)
+                    .map_err(|e| EventArgsError::InvalidUtf8(name.to_owned(), e))
+            })
+            .transpose()
+    }
+
+    pub(crate) fn string(&self, name: &'static str) -> Result<String, EventArgsError> {
+        self.string_opt(name)?
+            .ok_or(EventArgsError::MissingEnv(name))
+    }
+
+    pub(crate) fn parse<T: FromStr>(&self, name: &'static str) -> Result<T, EventArgsError> {
+        self.parse_opt(name)?
+            .ok_or(EventArgsError::MissingEnv(name))
+    }
+
+    pub(crate) fn parse_opt<T: FromStr>(
+        &self,
+        name: &'static str,
+    ) -> Result<Option<T>, EventArgsError> {
+        self.string_opt(name)?
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| EventArgsError::InvalidValue(name.to_owned(), value))
+            })
+            .transpose()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::env;
+    use std::net::Ipv4Addr;
+
+    fn args(args: &[&str]) -> Vec<CString> {
+        args.iter().map(|a| CString::new(*a).unwrap()).collect()
+    }
+
+    #[test]
+    fn parse_up() {
+        let env = env(&[
+            ("dev", "tun0"),
+            ("ifconfig_local", "10.8.0.2"),
+            ("ifconfig_remote", "10.8.0.1"),
+        ]);
+        let result = EventArgs::parse(EventType::Up, &args(&["plugin.so"]), &env).unwrap();
+        assert_eq!(
+            EventArgs::Up {
+                device: "tun0".to_owned(),
+                local_ip: Some(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 2))),
+                remote_ip: Some(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 1))),
+            },
+            result
+        );
+        assert_eq!(EventType::Up, result.event_type());
+    }
+
+    #[test]
+    fn parse_learn_address() {
+        let args = args(&["plugin.so", "add", "10.8.0.6", "client1"]);
+        let result = EventArgs::parse(EventType::LearnAddress, &args, &HashMap::new()).unwrap();
+        assert_eq!(
+            EventArgs::LearnAddress {
+                operation: LearnAddressOp::Add,
+                address: LearnedAddress::Ip(IpNetwork::from(IpAddr::V4(Ipv4Addr::new(
+                    10, 8, 0, 6
+                )))),
+                common_name: Some("client1".to_owned()),
+            },
+            result
+        );
+    }
+
+    #[test]
+    fn parse_auth_missing_password() {
+        let env = env(&[("username", "foo")]);
+        let result = EventArgs::parse(EventType::AuthUserPassVerify, &[], &env);
+        assert_eq!(Err(EventArgsError::MissingEnv("password")), result);
+    }
+
+    #[test]
+    fn parse_ip_change_invalid_port() {
+        let args = args(&["plugin.so", "192.0.2.1", "foo"]);
+        let result = EventArgs::parse(EventType::IpChange, &args, &HashMap::new());
+        assert_eq!(
+            Err(EventArgsError::InvalidValue(
+                "argument 2".to_owned(),
+                "foo".to_owned()
+            )),
+            result
+        );
+    }
+
+    #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+    #[test]
+    fn parse_client_connect_v2_deferred() {
+        let fixture = crate::testing::fixtures::client_connect_v2();
+        let result = EventArgs::parse(fixture.event, &fixture.args, &fixture.env).unwrap();
+        let expected = DeferredClientConnect::from_env(&fixture.env).unwrap();
+        match result {
+            EventArgs::ClientConnectV2 { deferred, .. } => assert_eq!(Some(expected), deferred),
+            other => panic!("Unexpected event args: {:?}", other),
+        }
+    }
+
+    #[cfg(feature = "openvpn-2-6")]
+    #[test]
+    fn parse_client_crresponse() {
+        let parse = |env: &HashMap<CString, CString>| {
+            EventArgs::parse(EventType::ClientCrresponse, &[], env)
+        };
+        assert_eq!(
+            Ok(EventArgs::ClientCrresponse {
+                response: b"123456".to_vec()
+            }),
+            parse(&env(&[("crresponse", "MTIzNDU2\n")]))
+        );
+        assert_eq!(
+            Err(EventArgsError::InvalidValue(
+                "crresponse".to_owned(),
+                "MTI*".to_owned()
+            )),
+            parse(&env(&[("crresponse", "MTI*")]))
+        );
+        assert_eq!(
+            Err(EventArgsError::MissingEnv("crresponse")),
+            parse(&HashMap::new())
+        );
+    }
+}
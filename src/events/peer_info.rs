@@ -0,0 +1,169 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::CString,
+    ops::BitOr,
+};
+
+use super::EventArgsError;
+use crate::env_keys;
+
+/// Prefix of the peer info variables a client sends to the server.
+const PEER_INFO_PREFIX: &str = "IV_";
+
+
+/// The feature bits a client announces in `IV_PROTO`.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ProtoFlags(u32);
+
+impl ProtoFlags {
+    pub const DATA_V2: ProtoFlags = ProtoFlags(1 << 1);
+    pub const REQUEST_PUSH: ProtoFlags = ProtoFlags(1 << 2);
+    pub const TLS_KEY_EXPORT: ProtoFlags = ProtoFlags(1 << 3);
+    pub const AUTH_PENDING_KW: ProtoFlags = ProtoFlags(1 << 4);
+    pub const NCP_P2P: ProtoFlags = ProtoFlags(1 << 5);
+    pub const DNS_OPTION: ProtoFlags = ProtoFlags(1 << 6);
+    pub const CC_EXIT_NOTIFY: ProtoFlags = ProtoFlags(1 << 7);
+    pub const AUTH_FAIL_TEMP: ProtoFlags = ProtoFlags(1 << 8);
+    pub const DYN_TLS_CRYPT: ProtoFlags = ProtoFlags(1 << 9);
+
+    /// Creates flags from the raw `IV_PROTO` value. Unknown bits are kept.
+    pub fn from_bits(bits: u32) -> Self {
+        ProtoFlags(bits)
+    }
+
+    /// Returns the raw `IV_PROTO` value.
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns true if all bits in `other` are set.
+    pub fn contains(self, other: ProtoFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for ProtoFlags {
+    type Output = ProtoFlags;
+
+    fn bitor(self, other: ProtoFlags) -> ProtoFlags {
+        ProtoFlags(self.0 | other.0)
+    }
+}
+
+
+/// The `IV_*` peer info variables a client sends to the server, available in the environment of
+/// the client connect events.
+#[derive(Debug, Default, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PeerInfo {
+    /// The client version, from `IV_VER`.
+    pub version: Option<String>,
+    /// The client platform, such as `linux` or `win`, from `IV_PLAT`.
+    pub platform: Option<String>,
+    /// The protocol features the client supports, from `IV_PROTO`.
+    pub proto: ProtoFlags,
+    /// The data channel ciphers the client supports, from `IV_CIPHERS`.
+    pub ciphers: Vec<String>,
+    /// All `IV_*` variables, including the ones above, keyed by name.
+    pub values: BTreeMap<String, String>,
+}
+
+impl PeerInfo {
+    /// Collects all `IV_*` variables from `env`. Variables that are not valid UTF-8 results in an
+    /// error, as does an `IV_PROTO` that is not a number.
+    pub fn from_env(env: &HashMap<CString, CString>) -> Result<Self, EventArgsError> {
+        let mut values = BTreeMap::new();
+        for (key, value) in env {
+            if !key.as_bytes().starts_with(PEER_INFO_PREFIX.as_bytes()) {
+                continue;
+            }
+            let key = key
+                .to_str()
+                .map_err(|e| EventArgsError::InvalidUtf8(key.to_string_lossy().into_owned(), e))?;
+            let value = value
+                .to_str()
+                .map_err(|e| EventArgsError::InvalidUtf8(key.to_owned(), e))?;
+            values.insert(key.to_owned(), value.to_owned());
+        }
+
+        let proto = match values.get(env_keys::IV_PROTO) {
+            Some(proto) => proto.parse().map(ProtoFlags).map_err(|_| {
+                EventArgsError::InvalidValue(env_keys::IV_PROTO.to_owned(), proto.clone())
+            })?,
+            None => ProtoFlags::default(),
+        };
+        let ciphers = values
+            .get("IV_CIPHERS")
+            .map(|ciphers| {
+                ciphers
+                    .split(':')
+                    .filter(|cipher| !cipher.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(PeerInfo {
+            version: values.get(env_keys::IV_VER).cloned(),
+            platform: values.get(env_keys::IV_PLAT).cloned(),
+            proto,
+            ciphers,
+            values,
+        })
+    }
+
+    /// Returns the value of the peer info variable `key`, such as `IV_GUI_VER`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::env;
+
+    #[test]
+    fn parse_peer_info() {
+        let env = env(&[
+            ("IV_VER", "2.6.0"),
+            ("IV_PLAT", "linux"),
+            ("IV_PROTO", "990"),
+            ("IV_CIPHERS", "AES-256-GCM:CHACHA20-POLY1305"),
+            ("IV_GUI_VER", "foo"),
+            ("common_name", "client1"),
+        ]);
+        let info = PeerInfo::from_env(&env).unwrap();
+        assert_eq!(Some("2.6.0"), info.version.as_deref());
+        assert_eq!(Some("linux"), info.platform.as_deref());
+        assert!(info
+            .proto
+            .contains(ProtoFlags::DATA_V2 | ProtoFlags::REQUEST_PUSH));
+        assert!(!info.proto.contains(ProtoFlags::NCP_P2P));
+        assert_eq!(vec!["AES-256-GCM", "CHACHA20-POLY1305"], info.ciphers);
+        assert_eq!(Some("foo"), info.get("IV_GUI_VER"));
+        assert_eq!(None, info.get("common_name"));
+    }
+
+    #[test]
+    fn invalid_proto() {
+        let env = env(&[("IV_PROTO", "foo")]);
+        assert_eq!(
+            Err(EventArgsError::InvalidValue(
+                "IV_PROTO".to_owned(),
+                "foo".to_owned()
+            )),
+            PeerInfo::from_env(&env)
+        );
+    }
+}
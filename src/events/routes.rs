@@ -0,0 +1,275 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+};
+
+use super::{Env, EventArgsError, IpNetwork};
+use crate::env_keys;
+
+/// A route OpenVPN adds through the tunnel, from `route_network_{n}`, `route_netmask_{n}`,
+/// `route_gateway_{n}` and `route_metric_{n}`, or `route_ipv6_network_{n}` and
+/// `route_ipv6_gateway_{n}`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Route {
+    /// The network routed through the tunnel.
+    pub network: IpNetwork,
+    /// The gateway of the route, if one is set.
+    pub gateway: Option<IpAddr>,
+    /// The metric of the route, if one is set. OpenVPN only gives the metric of IPv4 routes.
+    pub metric: Option<u32>,
+}
+
+impl Route {
+    /// Reads all IPv4 and IPv6 routes from the environment of an event.
+    pub fn all_from_env(env: &HashMap<CString, CString>) -> Result<Vec<Self>, EventArgsError> {
+        let mut routes = Vec::new();
+        for n in 1.. {
+            let network: Option<Ipv4Addr> = parse_numbered(env, env_keys::route_network(n))?;
+            let network = match network {
+                Some(network) => network,
+                None => break,
+            };
+            let netmask_key = env_keys::route_netmask(n);
+            let netmask: Ipv4Addr =
+                parse_numbered(env, netmask_key.clone())?.unwrap_or(Ipv4Addr::BROADCAST);
+            let network = netmask_prefix(netmask)
+                .and_then(|prefix| IpNetwork::new(network.into(), prefix))
+                .ok_or_else(|| EventArgsError::InvalidValue(netmask_key, netmask.to_string()))?;
+            routes.push(Route {
+                network,
+                gateway: parse_numbered(env, env_keys::route_gateway(n))?,
+                metric: parse_numbered(env, env_keys::route_metric(n))?,
+            });
+        }
+        for n in 1.. {
+            let network = match parse_numbered(env, env_keys::route_ipv6_network(n))? {
+                Some(network) => network,
+                None => break,
+            };
+            routes.push(Route {
+                network,
+                gateway: parse_numbered(env, env_keys::route_ipv6_gateway(n))?,
+                metric: None,
+            });
+        }
+        Ok(routes)
+    }
+}
+
+/// The routes and tunnel addresses given in the environment of `EventType::RouteUp` and
+/// `EventType::RoutePredown` events.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub struct Routes {
+    /// The routes through the tunnel, the IPv4 routes first.
+    pub routes: Vec<Route>,
+    /// The default gateway of the routes through the tunnel, from `route_vpn_gateway`.
+    pub vpn_gateway: Option<IpAddr>,
+    /// The default IPv4 gateway before the tunnel came up, from `route_net_gateway`.
+    pub net_gateway: Option<IpAddr>,
+    /// The gateway of the IPv6 routes through the tunnel, from `route_ipv6_gateway`.
+    pub vpn_gateway_ipv6: Option<IpAddr>,
+    /// The default IPv6 gateway before the tunnel came up, from `net_gateway_ipv6`.
+    pub net_gateway_ipv6: Option<IpAddr>,
+    /// The IPv4 address of the tunnel, from `ifconfig_local`, with the prefix of
+    /// `ifconfig_netmask` in subnet topologies.
+    pub local: Option<IpNetwork>,
+    /// The IPv4 address of the remote end of the tunnel in point-to-point topologies, from
+    /// `ifconfig_remote`.
+    pub remote: Option<IpAddr>,
+    /// The IPv6 address of the tunnel, from `ifconfig_ipv6_local`, with the prefix of
+    /// `ifconfig_ipv6_netbits`.
+    pub local_ipv6: Option<IpNetwork>,
+    /// The IPv6 address of the remote end of the tunnel, from `ifconfig_ipv6_remote`.
+    pub remote_ipv6: Option<IpAddr>,
+}
+
+impl Routes {
+    /// Parses the routes and tunnel addresses from the environment of an event. Every variable
+    /// is optional, but an error is returned if one can't be parsed.
+    pub fn from_env(env: &HashMap<CString, CString>) -> Result<Self, EventArgsError> {
+        let routes = Route::all_from_env(env)?;
+        let env = Env(env);
+        let local = match env.parse_opt::<Ipv4Addr>(env_keys::IFCONFIG_LOCAL)? {
+            Some(local) => {
+                let netmask = env
+                    .parse_opt(env_keys::IFCONFIG_NETMASK)?
+                    .unwrap_or(Ipv4Addr::BROADCAST);
+                let network = netmask_prefix(netmask)
+                    .and_then(|prefix| IpNetwork::new(local.into(), prefix))
+                    .ok_or_else(|| {
+                        EventArgsError::InvalidValue(
+                            env_keys::IFCONFIG_NETMASK.to_owned(),
+                            netmask.to_string(),
+                        )
+                    })?;
+                Some(network)
+            }
+            None => None,
+        };
+        let local_ipv6 = match env.parse_opt::<IpAddr>(env_keys::IFCONFIG_IPV6_LOCAL)? {
+            Some(local) => {
+                let netbits = env
+                    .parse_opt(env_keys::IFCONFIG_IPV6_NETBITS)?
+                    .unwrap_or(128);
+                let network = IpNetwork::new(local, netbits).ok_or_else(|| {
+                    EventArgsError::InvalidValue(
+                        env_keys::IFCONFIG_IPV6_NETBITS.to_owned(),
+                        netbits.to_string(),
+                    )
+                })?;
+                Some(network)
+            }
+            None => None,
+        };
+        Ok(Routes {
+            routes,
+            vpn_gateway: env.parse_opt(env_keys::ROUTE_VPN_GATEWAY)?,
+            net_gateway: env.parse_opt(env_keys::ROUTE_NET_GATEWAY)?,
+            vpn_gateway_ipv6: env.parse_opt(env_keys::ROUTE_IPV6_GATEWAY)?,
+            net_gateway_ipv6: env.parse_opt(env_keys::NET_GATEWAY_IPV6)?,
+            local,
+            remote: env.parse_opt(env_keys::IFCONFIG_REMOTE)?,
+            local_ipv6,
+            remote_ipv6: env.parse_opt(env_keys::IFCONFIG_IPV6_REMOTE)?,
+        })
+    }
+
+    /// The IPv4 routes.
+    pub fn ipv4(&self) -> impl Iterator<Item = &Route> {
+        self.routes
+            .iter()
+            .filter(|route| route.network.address().is_ipv4())
+    }
+
+    /// The IPv6 routes.
+    pub fn ipv6(&self) -> impl Iterator<Item = &Route> {
+        self.routes
+            .iter()
+            .filter(|route| route.network.address().is_ipv6())
+    }
+}
+
+/// Parses a numbered variable, which can't use the `Env` accessors since its name isn't static.
+pub(crate) fn parse_numbered<T: FromStr>(
+    env: &HashMap<CString, CString>,
+    name: String,
+) -> Result<Option<T>, EventArgsError> {
+    let value = match env.get(&CString::new(name.as_str()).unwrap()) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let value = value
+        .to_str()
+        .map_err(|e| EventArgsError::InvalidUtf8(name.clone(), e))?;
+    value
+        .parse()
+        .map(Some)
+        .map_err(|_| EventArgsError::InvalidValue(name, value.to_owned()))
+}
+
+/// The prefix length of a netmask, or `None` if its ones are not contiguous.
+pub(crate) fn netmask_prefix(netmask: Ipv4Addr) -> Option<u8> {
+    let bits = u32::from(netmask);
+    let prefix = bits.leading_ones();
+    if prefix + bits.trailing_zeros() >= 32 {
+        Some(prefix as u8)
+    } else {
+        None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::env;
+
+    #[test]
+    fn parse_routes() {
+        let valid = env(&[
+            ("route_network_1", "10.0.0.0"),
+            ("route_netmask_1", "255.255.0.0"),
+            ("route_gateway_1", "10.8.0.1"),
+            ("route_metric_1", "100"),
+            ("route_network_2", "192.0.2.1"),
+            ("route_network_4", "198.51.100.0"),
+            ("route_ipv6_network_1", "2001:db8::/32"),
+        ]);
+        let routes = Route::all_from_env(&valid).unwrap();
+        assert_eq!(
+            vec!["10.0.0.0/16", "192.0.2.1/32", "2001:db8::/32"],
+            routes
+                .iter()
+                .map(|route| route.network.to_string())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some("10.8.0.1".parse().unwrap()), routes[0].gateway);
+        assert_eq!(Some(100), routes[0].metric);
+        assert_eq!(None, routes[1].metric);
+        assert_eq!(None, routes[2].gateway);
+
+        let invalid = env(&[
+            ("route_network_1", "10.0.0.0"),
+            ("route_netmask_1", "255.0.255.0"),
+        ]);
+        assert_eq!(
+            Err(EventArgsError::InvalidValue(
+                "route_netmask_1".to_owned(),
+                "255.0.255.0".to_owned()
+            )),
+            Route::all_from_env(&invalid)
+        );
+    }
+
+    #[test]
+    fn parse_tunnel_addresses() {
+        let routes = Routes::from_env(&env(&[
+            ("route_network_1", "10.0.0.0"),
+            ("route_netmask_1", "255.0.0.0"),
+            ("route_ipv6_network_1", "::/0"),
+            ("route_vpn_gateway", "10.8.0.1"),
+            ("route_net_gateway", "192.168.1.1"),
+            ("route_ipv6_gateway", "fd00::1"),
+            ("net_gateway_ipv6", "2001:db8::1"),
+            ("ifconfig_local", "10.8.0.6"),
+            ("ifconfig_netmask", "255.255.255.0"),
+            ("ifconfig_ipv6_local", "fd00::1000"),
+            ("ifconfig_ipv6_netbits", "64"),
+            ("ifconfig_ipv6_remote", "fd00::1"),
+        ]))
+        .unwrap();
+        assert_eq!(1, routes.ipv4().count());
+        assert_eq!(1, routes.ipv6().count());
+        assert_eq!(Some("10.8.0.1".parse().unwrap()), routes.vpn_gateway);
+        assert_eq!(Some("192.168.1.1".parse().unwrap()), routes.net_gateway);
+        assert_eq!(Some("fd00::1".parse().unwrap()), routes.vpn_gateway_ipv6);
+        assert_eq!(
+            Some("2001:db8::1".parse().unwrap()),
+            routes.net_gateway_ipv6
+        );
+        assert_eq!(Some("10.8.0.6/24".parse().unwrap()), routes.local);
+        assert_eq!(None, routes.remote);
+        assert_eq!(Some("fd00::1000/64".parse().unwrap()), routes.local_ipv6);
+        assert_eq!(Some("fd00::1".parse().unwrap()), routes.remote_ipv6);
+
+        // Without a netmask, as in point-to-point topologies, the address is a single host.
+        let p2p = Routes::from_env(&env(&[
+            ("ifconfig_local", "10.8.0.6"),
+            ("ifconfig_remote", "10.8.0.5"),
+        ]))
+        .unwrap();
+        assert_eq!(Some("10.8.0.6/32".parse().unwrap()), p2p.local);
+        assert_eq!(Some("10.8.0.5".parse().unwrap()), p2p.remote);
+        assert!(p2p.routes.is_empty());
+    }
+}
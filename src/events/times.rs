@@ -0,0 +1,110 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{Env, EventArgsError};
+use crate::env_keys;
+
+/// The time the event happened, from `time_unix`. Only set for some events, such as
+/// client connects.
+pub fn time_unix(env: &HashMap<CString, CString>) -> Result<Option<SystemTime>, EventArgsError> {
+    Env(env).system_time_opt(env_keys::TIME_UNIX)
+}
+
+/// The time OpenVPN started, from `daemon_start_time`.
+pub fn daemon_start_time(
+    env: &HashMap<CString, CString>,
+) -> Result<Option<SystemTime>, EventArgsError> {
+    Env(env).system_time_opt(env_keys::DAEMON_START_TIME)
+}
+
+/// How long the client was connected, from `time_duration`. Set on client disconnect.
+pub fn time_duration(env: &HashMap<CString, CString>) -> Result<Option<Duration>, EventArgsError> {
+    Env(env).duration_opt(env_keys::TIME_DURATION)
+}
+
+impl Env<'_> {
+    /// Reads a time given in seconds since the Unix epoch.
+    pub(crate) fn system_time_opt(
+        &self,
+        key: &'static str,
+    ) -> Result<Option<SystemTime>, EventArgsError> {
+        match self.parse_opt::<u64>(key)? {
+            Some(seconds) => UNIX_EPOCH
+                .checked_add(Duration::from_secs(seconds))
+                .map(Some)
+                .ok_or_else(|| EventArgsError::InvalidValue(key.to_owned(), seconds.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    /// Reads a duration given in seconds.
+    pub(crate) fn duration_opt(
+        &self,
+        key: &'static str,
+    ) -> Result<Option<Duration>, EventArgsError> {
+        Ok(self.parse_opt(key)?.map(Duration::from_secs))
+    }
+
+    /// Reads a duration given in seconds, returning an error if it is not set.
+    pub(crate) fn duration(&self, key: &'static str) -> Result<Duration, EventArgsError> {
+        Ok(Duration::from_secs(self.parse(key)?))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::env;
+
+    #[test]
+    fn times() {
+        let env = env(&[
+            ("time_unix", "1700000000"),
+            ("daemon_start_time", "1699990000"),
+            ("time_duration", "3600"),
+        ]);
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            time_unix(&env).unwrap()
+        );
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::from_secs(1_699_990_000)),
+            daemon_start_time(&env).unwrap()
+        );
+        assert_eq!(
+            Some(Duration::from_secs(3600)),
+            time_duration(&env).unwrap()
+        );
+    }
+
+    #[test]
+    fn invalid_times() {
+        assert_eq!(Ok(None), time_unix(&env(&[])));
+        assert_eq!(
+            Err(EventArgsError::InvalidValue(
+                "time_unix".to_owned(),
+                "-1".to_owned()
+            )),
+            time_unix(&env(&[("time_unix", "-1")]))
+        );
+        assert_eq!(
+            Err(EventArgsError::InvalidValue(
+                "time_duration".to_owned(),
+                "1h".to_owned()
+            )),
+            time_duration(&env(&[("time_duration", "1h")]))
+        );
+    }
+}
@@ -15,8 +15,18 @@ pub mod parse;
 mod structs;
 pub use self::structs::*;
 
+/// Safe access to the arguments of `openvpn_plugin_open_v3`.
+mod open_args;
+pub use self::open_args::{OpenArgs, SslApi};
+
 // Return values. Returned from the plugin to OpenVPN to indicate success or failure. Can also
 // Accept (success) or decline (error) operations, such as incoming client connection attempts.
 pub const OPENVPN_PLUGIN_FUNC_SUCCESS: c_int = 0;
 pub const OPENVPN_PLUGIN_FUNC_ERROR: c_int = 1;
 pub const OPENVPN_PLUGIN_FUNC_DEFERRED: c_int = 2;
+
+/// The oldest version of the v3 plugin structs the plugins can read, the first argument OpenVPN
+/// passes to `openvpn_plugin_open_v3` and `openvpn_plugin_func_v3`. Version 3 added the
+/// `ovpn_version*` fields to the open arguments, the last fields in `openvpn_plugin_args_open_in`.
+/// `openvpn-plugin.h` has no constant for it.
+pub const OPENVPN_PLUGIN_STRUCTVER_MIN: c_int = 3;
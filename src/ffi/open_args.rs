@@ -0,0 +1,239 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    cell::Cell,
+    ffi::CStr,
+    marker::PhantomData,
+    os::raw::{c_int, c_void},
+    ptr,
+};
+
+use super::openvpn_plugin_args_open_in;
+use crate::{raw::RawEvent, EventTypeSet};
+
+thread_local! {
+    /// The arguments of the `openvpn_plugin_open_v3` call running on this thread.
+    static CURRENT: Cell<*const openvpn_plugin_args_open_in> = const { Cell::new(ptr::null()) };
+}
+
+/// The SSL library OpenVPN is built with. The `ovpnSSLAPI` enum in `openvpn-plugin.h`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum SslApi {
+    /// OpenVPN is built without SSL support. `SSLAPI_NONE`.
+    Unavailable,
+    /// OpenSSL. `SSLAPI_OPENSSL`.
+    OpenSsl,
+    /// Mbed TLS. `SSLAPI_MBEDTLS`.
+    MbedTls,
+}
+
+/// All the fields of the arguments OpenVPN passes to `openvpn_plugin_open_v3`, including the
+/// ones the `openvpn_plugin!` macros don't parse, like the version of OpenVPN.
+///
+/// Plugins using the macros can read them from their open callback with [`with_current`]:
+///
+/// ```rust
+/// # use openvpn_plugin::ffi::OpenArgs;
+/// let version = OpenArgs::with_current(|args| {
+///     args.map(|args| (args.openvpn_version_major(), args.openvpn_version_minor()))
+/// });
+/// // Not called from an open callback.
+/// assert_eq!(None, version);
+/// ```
+///
+/// [`with_current`]: #method.with_current
+#[derive(Debug, Clone, Copy)]
+pub struct OpenArgs<'a> {
+    args: *const openvpn_plugin_args_open_in,
+    _lifetime: PhantomData<&'a openvpn_plugin_args_open_in>,
+}
+
+impl<'a> OpenArgs<'a> {
+    /// Wraps the arguments OpenVPN passed to `openvpn_plugin_open_v3`. `None` if `args` is null.
+    ///
+    /// # Safety
+    ///
+    /// `args` must be null or point to arguments with a struct version of at least
+    /// [`OPENVPN_PLUGIN_STRUCTVER_MIN`], with the strings and arrays in them valid for the
+    /// lifetime `'a`, like they are during the call to `openvpn_plugin_open_v3`.
+    ///
+    /// [`OPENVPN_PLUGIN_STRUCTVER_MIN`]: constant.OPENVPN_PLUGIN_STRUCTVER_MIN.html
+    pub unsafe fn new(args: *const openvpn_plugin_args_open_in) -> Option<Self> {
+        if args.is_null() {
+            None
+        } else {
+            Some(OpenArgs {
+                args,
+                _lifetime: PhantomData,
+            })
+        }
+    }
+
+    /// Calls `f` with the arguments of the `openvpn_plugin_open_v3` call running on this thread,
+    /// or `None` if this is not called from within a plugin open callback.
+    pub fn with_current<R>(f: impl FnOnce(Option<OpenArgs<'_>>) -> R) -> R {
+        let args = CURRENT.with(Cell::get);
+        // Safe since `enter` only sets the pointer for the duration of the open call, when the
+        // arguments are valid.
+        f(unsafe { OpenArgs::new(args) })
+    }
+
+    /// Makes these the arguments `with_current` gives on this thread, until the guard is dropped.
+    pub(crate) fn enter(self) -> CurrentGuard {
+        CurrentGuard(CURRENT.with(|current| current.replace(self.args)))
+    }
+
+    fn args(&self) -> &'a openvpn_plugin_args_open_in {
+        // Safe since `new` requires the pointer to be valid for `'a`.
+        unsafe { &*self.args }
+    }
+
+    /// All the events OpenVPN supports, the events the plugin can register for.
+    pub fn type_mask(&self) -> EventTypeSet {
+        EventTypeSet::from_bits_truncate(self.args().type_mask)
+    }
+
+    /// The arguments and environment, unparsed.
+    pub fn raw(&self) -> RawEvent<'a> {
+        let args = self.args();
+        // Safe since `new` requires the arrays to be valid for `'a`.
+        unsafe { RawEvent::new(args.argv, args.envp) }
+    }
+
+    /// The `openvpn_plugin_callbacks` struct with the logging and utility functions OpenVPN
+    /// exports to plugins. Its layout depends on the struct version OpenVPN passed.
+    pub fn callbacks(&self) -> *const c_void {
+        self.args().callbacks
+    }
+
+    /// The SSL library OpenVPN is built with, or `None` for values this crate does not know.
+    pub fn ssl_api(&self) -> Option<SslApi> {
+        match self.args().ssl_api {
+            0 => Some(SslApi::Unavailable),
+            1 => Some(SslApi::OpenSsl),
+            2 => Some(SslApi::MbedTls),
+            _ => None,
+        }
+    }
+
+    /// The raw `ovpnSSLAPI` value of `ssl_api`.
+    pub fn ssl_api_raw(&self) -> c_int {
+        self.args().ssl_api
+    }
+
+    /// The full version string of OpenVPN, such as `OpenVPN 2.6.0 x86_64-pc-linux-gnu [SSL]`.
+    pub fn openvpn_version(&self) -> Option<&'a CStr> {
+        // Safe since `new` requires the strings to be valid for `'a`.
+        unsafe { cstr(self.args().ovpn_version) }
+    }
+
+    /// The major version of OpenVPN, 2 for OpenVPN 2.6.0.
+    pub fn openvpn_version_major(&self) -> u32 {
+        self.args().ovpn_version_major
+    }
+
+    /// The minor version of OpenVPN, 6 for OpenVPN 2.6.0.
+    pub fn openvpn_version_minor(&self) -> u32 {
+        self.args().ovpn_version_minor
+    }
+
+    /// The rest of the version of OpenVPN, `.0` for OpenVPN 2.6.0 and `_git` for development
+    /// builds.
+    pub fn openvpn_version_patch(&self) -> Option<&'a CStr> {
+        // Safe since `new` requires the strings to be valid for `'a`.
+        unsafe { cstr(self.args().ovpn_version_patch) }
+    }
+}
+
+unsafe fn cstr<'a>(ptr: *const std::os::raw::c_char) -> Option<&'a CStr> {
+    if ptr.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(ptr))
+    }
+}
+
+/// Restores the previous current arguments of the thread when dropped.
+pub(crate) struct CurrentGuard(*const openvpn_plugin_args_open_in);
+
+impl Drop for CurrentGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.0));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EventType;
+    use std::ffi::CString;
+
+    #[test]
+    fn fields() {
+        let plugin = CString::new("/plugin.so").unwrap();
+        let argv = [plugin.as_ptr(), ptr::null()];
+        let envp = [ptr::null()];
+        let version = CString::new("OpenVPN 2.6.0").unwrap();
+        let patch = CString::new(".0").unwrap();
+        let mut open_in = openvpn_plugin_args_open_in {
+            type_mask: (EventType::Up | EventType::Down).bits(),
+            argv: argv.as_ptr(),
+            envp: envp.as_ptr(),
+            callbacks: ptr::null(),
+            ssl_api: 1,
+            ovpn_version: version.as_ptr(),
+            ovpn_version_major: 2,
+            ovpn_version_minor: 6,
+            ovpn_version_patch: patch.as_ptr(),
+        };
+        let args = unsafe { OpenArgs::new(&open_in) }.unwrap();
+        assert_eq!(EventType::Up | EventType::Down, args.type_mask());
+        assert_eq!(Some(plugin.as_c_str()), args.raw().arg(0));
+        assert_eq!(Some(SslApi::OpenSsl), args.ssl_api());
+        assert_eq!(Some(version.as_c_str()), args.openvpn_version());
+        assert_eq!(
+            (2, 6),
+            (args.openvpn_version_major(), args.openvpn_version_minor())
+        );
+        assert_eq!(Some(patch.as_c_str()), args.openvpn_version_patch());
+
+        open_in.ssl_api = 7;
+        open_in.ovpn_version = ptr::null();
+        let args = unsafe { OpenArgs::new(&open_in) }.unwrap();
+        assert_eq!(None, args.ssl_api());
+        assert_eq!(7, args.ssl_api_raw());
+        assert_eq!(None, args.openvpn_version());
+        assert!(unsafe { OpenArgs::new(ptr::null()) }.is_none());
+    }
+
+    #[test]
+    fn current() {
+        let envp = [ptr::null()];
+        let open_in = openvpn_plugin_args_open_in {
+            type_mask: 0,
+            argv: envp.as_ptr(),
+            envp: envp.as_ptr(),
+            callbacks: ptr::null(),
+            ssl_api: 0,
+            ovpn_version: ptr::null(),
+            ovpn_version_major: 2,
+            ovpn_version_minor: 5,
+            ovpn_version_patch: ptr::null(),
+        };
+        {
+            let _current = unsafe { OpenArgs::new(&open_in) }.unwrap().enter();
+            assert_eq!(
+                Some(5),
+                OpenArgs::with_current(|args| args.map(|args| args.openvpn_version_minor()))
+            );
+        }
+        assert!(OpenArgs::with_current(|args| args.is_none()));
+    }
+}
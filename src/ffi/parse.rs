@@ -8,11 +8,40 @@
 
 use std::collections::HashMap;
 use std::error::Error;
-use std::ffi::{CStr, CString};
+use std::ffi::{CStr, CString, OsString};
 use std::fmt;
 use std::os::raw::c_char;
+use std::slice;
 use std::str::Utf8Error;
 
+/// Limits on the size of the string arrays the parsing functions accept. OpenVPN never gives a
+/// plugin anywhere near this much, so reaching a limit means the array is not properly null
+/// terminated, from a bug or an incompatible OpenVPN build. With the limits, such an array gives
+/// a `ParseError` instead of the parser reading memory until it crashes or hangs.
+///
+/// The functions without limits in their name use `Limits::default()`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct Limits {
+    /// The maximum number of strings in an array.
+    pub max_entries: usize,
+    /// The maximum length of a string in bytes, not counting the null terminator.
+    pub max_string_len: usize,
+}
+
+impl Limits {
+    /// The default limits. 65536 strings of at most 1 MiB each.
+    pub const DEFAULT: Limits = Limits {
+        max_entries: 1 << 16,
+        max_string_len: 1 << 20,
+    };
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits::DEFAULT
+    }
+}
+
 /// Error type returned by the ffi parsing functions if the input data is invalid in some way.
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum ParseError {
@@ -21,6 +50,10 @@ pub enum ParseError {
     /// A string in the environment has no '=' char in it, and is thus not a valid environment
     /// entry.
     NoEqual(CString),
+    /// The array has more strings than the limit, given here.
+    TooManyEntries(usize),
+    /// A string is longer than the limit, given here.
+    StringTooLong(usize),
 }
 
 impl fmt::Display for ParseError {
@@ -28,32 +61,86 @@ impl fmt::Display for ParseError {
         match *self {
             ParseError::NullPtr => "Input is null pointer".fmt(f),
             ParseError::NoEqual(ref s) => write!(f, "No equal sign in \"{}\"", s.to_string_lossy()),
+            ParseError::TooManyEntries(max) => write!(f, "More than {} strings in array", max),
+            ParseError::StringTooLong(max) => write!(f, "String longer than {} bytes", max),
         }
     }
 }
 
 impl Error for ParseError {}
 
+/// Error type returned by `env_utf8` when a key or value in the environment is not valid UTF-8.
+/// Tells which variable is invalid, with the invalid bytes of the key replaced with U+FFFD.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum EnvUtf8Error {
+    /// The key itself is not valid UTF-8.
+    InvalidKey(String, Utf8Error),
+    /// The value of the key is not valid UTF-8.
+    InvalidValue(String, Utf8Error),
+}
+
+impl EnvUtf8Error {
+    /// The key of the invalid variable.
+    pub fn key(&self) -> &str {
+        match self {
+            EnvUtf8Error::InvalidKey(key, _) | EnvUtf8Error::InvalidValue(key, _) => key,
+        }
+    }
+}
+
+impl fmt::Display for EnvUtf8Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> Result<(), fmt::Error> {
+        match self {
+            EnvUtf8Error::InvalidKey(key, _) => {
+                write!(f, "Invalid UTF-8 in environment key \"{}\"", key)
+            }
+            EnvUtf8Error::InvalidValue(key, _) => {
+                write!(
+                    f,
+                    "Invalid UTF-8 in the value of environment variable \"{}\"",
+                    key
+                )
+            }
+        }
+    }
+}
+
+impl Error for EnvUtf8Error {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EnvUtf8Error::InvalidKey(_, e) | EnvUtf8Error::InvalidValue(_, e) => Some(e),
+        }
+    }
+}
+
 
 /// Parses a null-terminated C string array into a Vec<CString> for safe usage.
 ///
-/// Returns an Err if given a null pointer.
+/// Returns an Err if given a null pointer, or if the array is larger than `Limits::default()`.
 ///
-/// # Segfaults
+/// # Safety
 ///
 /// Can cause the program to crash if the pointer array starting at `ptr` is not correctly null
 /// terminated. Likewise, if any string pointed to is not properly null-terminated it may crash.
-pub unsafe fn string_array(mut ptr: *const *const c_char) -> Result<Vec<CString>, ParseError> {
-    if ptr.is_null() {
-        Err(ParseError::NullPtr)
-    } else {
-        let mut strings = Vec::new();
-        while !(*ptr).is_null() {
-            strings.push(CStr::from_ptr(*ptr).to_owned());
-            ptr = ptr.offset(1);
-        }
-        Ok(strings)
-    }
+/// The limits stop the parser before it reads far past such an array, but reading up to them may
+/// already be invalid.
+pub unsafe fn string_array(ptr: *const *const c_char) -> Result<Vec<CString>, ParseError> {
+    string_array_with_limits(ptr, Limits::default())
+}
+
+/// Version of `string_array` with the given limits on the size of the array.
+///
+/// # Safety
+///
+/// Same requirements as `string_array`.
+pub unsafe fn string_array_with_limits(
+    ptr: *const *const c_char,
+    limits: Limits,
+) -> Result<Vec<CString>, ParseError> {
+    Ok(string_array_borrowed_with_limits(ptr, limits)?
+        .into_iter()
+        .map(CStr::to_owned)
+        .collect())
 }
 
 /// Convenience method for plugins to convert the C string arrays they are given into real Rust
@@ -65,6 +152,24 @@ pub fn string_array_utf8(strings: &[CString]) -> Result<Vec<String>, Utf8Error>
         .collect()
 }
 
+/// Lossy version of `string_array_utf8`. Invalid UTF-8 sequences are replaced with U+FFFD
+/// instead of failing the whole conversion, for plugins that only log or display the strings.
+pub fn string_array_utf8_lossy(strings: &[CString]) -> Vec<String> {
+    strings
+        .iter()
+        .map(|s| s.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// Converts the C string arrays plugins are given into `OsString`s. Unlike `string_array_utf8`
+/// this can't fail, so arguments that are paths or names in other encodings survive intact.
+///
+/// On Unix the bytes are kept as they are. On Windows OpenVPN gives the strings in UTF-8, and any
+/// invalid sequences are replaced with U+FFFD.
+pub fn string_array_os(strings: &[CString]) -> Vec<OsString> {
+    strings.iter().map(|s| os_string(s)).collect()
+}
+
 
 /// Parses a null-terminated array of C strings with "=" delimiters into a key-value map.
 ///
@@ -74,36 +179,218 @@ pub fn string_array_utf8(strings: &[CString]) -> Result<Vec<String>, Utf8Error>
 ///
 /// If multiple entries have the same key, the last one will be in the result map.
 ///
-/// # Segfaults
+/// # Safety
 ///
-/// Uses `string_array` internally and will segfault for the same reasons as that function.
+/// Will segfault for the same reasons as `string_array`.
 pub unsafe fn env(envptr: *const *const c_char) -> Result<HashMap<CString, CString>, ParseError> {
+    env_with_limits(envptr, Limits::default())
+}
+
+/// Version of `env` with the given limits on the size of the environment.
+///
+/// # Safety
+///
+/// Same requirements as `string_array`.
+pub unsafe fn env_with_limits(
+    envptr: *const *const c_char,
+    limits: Limits,
+) -> Result<HashMap<CString, CString>, ParseError> {
+    let mut map = HashMap::new();
+    // Split the borrowed strings directly, so each key and value is only copied once.
+    for string in string_array_borrowed_with_limits(envptr, limits)? {
+        let (key, value) = split_env_entry(string)?;
+        // It's safe to unwrap since the key is a part of a C string and has no null bytes.
+        map.insert(CString::new(key).unwrap(), value.to_owned());
+    }
+    Ok(map)
+}
+
+/// Borrowing version of `string_array`. Returns the strings in the array without copying them.
+///
+/// Returns an Err if given a null pointer.
+///
+/// # Safety
+///
+/// Same requirements as `string_array`. Additionally the strings must stay valid and unmodified
+/// for the lifetime `'a`. For the arrays OpenVPN gives a plugin, that is the duration of the
+/// callback they were given in.
+pub unsafe fn string_array_borrowed<'a>(
+    ptr: *const *const c_char,
+) -> Result<Vec<&'a CStr>, ParseError> {
+    string_array_borrowed_with_limits(ptr, Limits::default())
+}
+
+/// Version of `string_array_borrowed` with the given limits on the size of the array.
+///
+/// # Safety
+///
+/// Same requirements as `string_array_borrowed`.
+pub unsafe fn string_array_borrowed_with_limits<'a>(
+    mut ptr: *const *const c_char,
+    limits: Limits,
+) -> Result<Vec<&'a CStr>, ParseError> {
+    if ptr.is_null() {
+        Err(ParseError::NullPtr)
+    } else {
+        let mut strings = Vec::new();
+        while !(*ptr).is_null() {
+            if strings.len() == limits.max_entries {
+                return Err(ParseError::TooManyEntries(limits.max_entries));
+            }
+            strings.push(cstr_with_max_len(*ptr, limits.max_string_len)?);
+            ptr = ptr.offset(1);
+        }
+        Ok(strings)
+    }
+}
+
+/// Like `CStr::from_ptr`, but gives up after `max_len` bytes without a null terminator.
+///
+/// # Safety
+///
+/// `ptr` must point to a null-terminated string, or at least `max_len + 1` readable bytes, valid
+/// for the lifetime `'a`.
+unsafe fn cstr_with_max_len<'a>(
+    ptr: *const c_char,
+    max_len: usize,
+) -> Result<&'a CStr, ParseError> {
+    let bytes = ptr as *const u8;
+    let mut len = 0;
+    while *bytes.add(len) != 0 {
+        if len == max_len {
+            return Err(ParseError::StringTooLong(max_len));
+        }
+        len += 1;
+    }
+    Ok(CStr::from_bytes_with_nul_unchecked(slice::from_raw_parts(
+        bytes,
+        len + 1,
+    )))
+}
+
+/// Borrowing version of `env`. Returns a map pointing directly into the given strings instead of
+/// copying every key and value.
+///
+/// Keys are returned as byte slices since the key part of an entry is not null-terminated.
+/// Look up values with the bytes of the key, e.g. `env.get(&b"username"[..])` or
+/// `env.get(env_keys::USERNAME.as_bytes())`.
+///
+/// # Safety
+///
+/// Same requirements as `string_array_borrowed`.
+pub unsafe fn env_borrowed<'a>(
+    envptr: *const *const c_char,
+) -> Result<HashMap<&'a [u8], &'a CStr>, ParseError> {
     let mut map = HashMap::new();
-    for string in string_array(envptr)? {
-        let string_bytes = string.as_bytes();
-        let equal_index = string_bytes
-            .iter()
-            .position(|&c| c == b'=')
-            .ok_or_else(|| ParseError::NoEqual(string.clone()))?;
-
-        // It's safe to unwrap since CString guarantees no null bytes.
-        let key = CString::new(&string_bytes[..equal_index]).unwrap();
-        let value = CString::new(&string_bytes[equal_index + 1..]).unwrap();
+    for string in string_array_borrowed(envptr)? {
+        let (key, value) = split_env_entry(string)?;
         map.insert(key, value);
     }
     Ok(map)
 }
 
+/// Looks up a single variable in a null-terminated array of C strings with "=" delimiters,
+/// without building the whole map like `env` does. Returns `None` if the pointer is null or the
+/// key is not present. If the key is present multiple times the last value is returned, same as
+/// with `env`. Entries without an equal sign are ignored. `None` is also returned if the
+/// environment is larger than `Limits::default()`.
+///
+/// ```rust,no_run
+/// # use openvpn_plugin::{env_keys, ffi::parse};
+/// # let envp = std::ptr::null();
+/// let username = unsafe { parse::env_get(envp, env_keys::cstr::USERNAME) };
+/// ```
+///
+/// # Safety
+///
+/// Same requirements as `string_array`.
+pub unsafe fn env_get(mut envptr: *const *const c_char, key: &CStr) -> Option<CString> {
+    if envptr.is_null() {
+        return None;
+    }
+    let key = key.to_bytes();
+    let limits = Limits::default();
+    let mut value = None;
+    let mut entries = 0;
+    while !(*envptr).is_null() {
+        entries += 1;
+        if entries > limits.max_entries {
+            return None;
+        }
+        let entry = cstr_with_max_len(*envptr, limits.max_string_len).ok()?;
+        if let Ok((entry_key, entry_value)) = split_env_entry(entry) {
+            if entry_key == key {
+                value = Some(entry_value);
+            }
+        }
+        envptr = envptr.offset(1);
+    }
+    value.map(CStr::to_owned)
+}
+
+/// Splits an environment entry at the first equal sign.
+pub(crate) fn split_env_entry(entry: &CStr) -> Result<(&[u8], &CStr), ParseError> {
+    let bytes = entry.to_bytes_with_nul();
+    let equal_index = bytes
+        .iter()
+        .position(|&c| c == b'=')
+        .ok_or_else(|| ParseError::NoEqual(entry.to_owned()))?;
+    // The value is the tail of the entry, so it keeps the entry's null terminator.
+    let value = CStr::from_bytes_with_nul(&bytes[equal_index + 1..])
+        .expect("Tail of a CStr is a valid CStr");
+    Ok((&bytes[..equal_index], value))
+}
+
 /// Convenience method for plugins to convert the environments given to them into Rust String based
-/// environments.
-pub fn env_utf8(env: &HashMap<CString, CString>) -> Result<HashMap<String, String>, Utf8Error> {
+/// environments. The error tells which variable is not valid UTF-8.
+pub fn env_utf8(env: &HashMap<CString, CString>) -> Result<HashMap<String, String>, EnvUtf8Error> {
     let mut output_env = HashMap::with_capacity(env.len());
     for (key, value) in env {
-        output_env.insert(key.to_str()?.to_owned(), value.to_str()?.to_owned());
+        let key = key
+            .to_str()
+            .map_err(|e| EnvUtf8Error::InvalidKey(key.to_string_lossy().into_owned(), e))?;
+        let value = value
+            .to_str()
+            .map_err(|e| EnvUtf8Error::InvalidValue(key.to_owned(), e))?;
+        output_env.insert(key.to_owned(), value.to_owned());
     }
     Ok(output_env)
 }
 
+/// Lossy version of `env_utf8`. Invalid UTF-8 sequences in keys and values are replaced with
+/// U+FFFD instead of failing the whole conversion. Keys that only differ in their invalid bytes
+/// become the same key, and only one of their values is kept.
+pub fn env_utf8_lossy(env: &HashMap<CString, CString>) -> HashMap<String, String> {
+    env.iter()
+        .map(|(key, value)| {
+            (
+                key.to_string_lossy().into_owned(),
+                value.to_string_lossy().into_owned(),
+            )
+        })
+        .collect()
+}
+
+/// Converts the environments given to plugins into `OsString` based environments. Unlike
+/// `env_utf8` this can't fail, values such as paths and common names with arbitrary bytes in them
+/// are kept as they are. See `string_array_os` for how the bytes are converted.
+pub fn env_os(env: &HashMap<CString, CString>) -> HashMap<OsString, OsString> {
+    env.iter()
+        .map(|(key, value)| (os_string(key), os_string(value)))
+        .collect()
+}
+
+#[cfg(unix)]
+fn os_string(s: &CStr) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(s.to_bytes().to_vec())
+}
+
+#[cfg(not(unix))]
+fn os_string(s: &CStr) -> OsString {
+    OsString::from(s.to_string_lossy().into_owned())
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -215,6 +502,87 @@ mod tests {
         assert_eq!(Some(&value), env.get(&key));
     }
 
+    #[test]
+    fn env_borrowed_points_into_input() {
+        let test_str1 = "foo=bar=baz\0";
+        let test_str2 = "empty=\0";
+        let ptr_arr = [
+            test_str1 as *const _ as *const c_char,
+            test_str2 as *const _ as *const c_char,
+            ptr::null(),
+        ];
+        let env = unsafe { env_borrowed(&ptr_arr as *const *const c_char).unwrap() };
+        assert_eq!(2, env.len());
+        let value = env[&b"foo"[..]];
+        assert_eq!(b"bar=baz", value.to_bytes());
+        assert_eq!(test_str1[4..].as_ptr(), value.as_ptr() as *const u8);
+        assert_eq!(b"", env[&b"empty"[..]].to_bytes());
+    }
+
+    #[test]
+    fn env_borrowed_no_equal() {
+        let test_str = "foobar\0";
+        let ptr_arr = [test_str as *const _ as *const c_char, ptr::null()];
+        let result = unsafe { env_borrowed(&ptr_arr as *const *const c_char) };
+        assert_eq!(
+            result,
+            Err(ParseError::NoEqual(CString::new("foobar").unwrap()))
+        );
+    }
+
+    #[test]
+    fn env_get_last_value() {
+        let ptr_arr = [
+            "foo=123\0" as *const _ as *const c_char,
+            "bar\0" as *const _ as *const c_char,
+            "foo=abc\0" as *const _ as *const c_char,
+            ptr::null(),
+        ];
+        let ptr = &ptr_arr as *const *const c_char;
+        let foo = CString::new("foo").unwrap();
+        let bar = CString::new("bar").unwrap();
+        assert_eq!(Some(CString::new("abc").unwrap()), unsafe {
+            env_get(ptr, &foo)
+        });
+        assert_eq!(None, unsafe { env_get(ptr, &bar) });
+        assert_eq!(None, unsafe { env_get(ptr::null(), &foo) });
+    }
+
+    #[test]
+    fn string_array_limits() {
+        let strings = [
+            CString::new("foo").unwrap(),
+            CString::new("barbaz").unwrap(),
+        ];
+        let ptr_arr = [strings[0].as_ptr(), strings[1].as_ptr(), ptr::null()];
+        let parse = |max_entries, max_string_len| unsafe {
+            string_array_with_limits(
+                ptr_arr.as_ptr(),
+                Limits {
+                    max_entries,
+                    max_string_len,
+                },
+            )
+        };
+        assert_eq!(Ok(strings.to_vec()), parse(2, 6));
+        assert_eq!(Err(ParseError::TooManyEntries(1)), parse(1, 6));
+        assert_eq!(Err(ParseError::StringTooLong(5)), parse(2, 5));
+    }
+
+    #[test]
+    fn env_limits() {
+        let entry = CString::new("foo=bar").unwrap();
+        let ptr_arr = [entry.as_ptr(), entry.as_ptr(), ptr::null()];
+        let limits = Limits {
+            max_entries: 1,
+            ..Limits::default()
+        };
+        assert_eq!(Err(ParseError::TooManyEntries(1)), unsafe {
+            env_with_limits(ptr_arr.as_ptr(), limits)
+        });
+        assert_eq!(1, unsafe { env(ptr_arr.as_ptr()) }.unwrap().len());
+    }
+
     #[test]
     fn env_utf8_happy_path() {
         let mut env = HashMap::new();
@@ -233,6 +601,128 @@ mod tests {
             CString::new(vec![192]).unwrap(),
             CString::new("bar").unwrap(),
         );
-        assert!(env_utf8(&env).is_err());
+        let error = env_utf8(&env).unwrap_err();
+        assert!(matches!(error, EnvUtf8Error::InvalidKey(..)));
+        assert_eq!("\u{fffd}", error.key());
+
+        let mut env = HashMap::new();
+        env.insert(
+            CString::new("common_name").unwrap(),
+            CString::new(vec![b'a', 192]).unwrap(),
+        );
+        let error = env_utf8(&env).unwrap_err();
+        assert!(matches!(error, EnvUtf8Error::InvalidValue(..)));
+        assert_eq!("common_name", error.key());
+        assert_eq!(
+            "Invalid UTF-8 in the value of environment variable \"common_name\"",
+            error.to_string()
+        );
+    }
+
+    #[test]
+    fn utf8_lossy_replaces_invalid() {
+        let mut env = HashMap::new();
+        env.insert(
+            CString::new("foo").unwrap(),
+            CString::new(vec![b'a', 192]).unwrap(),
+        );
+        env.insert(CString::new("baz").unwrap(), CString::new("123").unwrap());
+        let result = env_utf8_lossy(&env);
+        assert_eq!("a\u{fffd}", result.get("foo").unwrap());
+        assert_eq!("123", result.get("baz").unwrap());
+        assert_eq!(
+            vec!["\u{fffd}".to_owned(), "bar".to_owned()],
+            string_array_utf8_lossy(&[
+                CString::new(vec![192]).unwrap(),
+                CString::new("bar").unwrap()
+            ])
+        );
+    }
+
+    #[test]
+    fn env_os_keeps_invalid_utf8() {
+        let mut env = HashMap::new();
+        env.insert(
+            CString::new("common_name").unwrap(),
+            CString::new(vec![b'a', 192]).unwrap(),
+        );
+        let result = env_os(&env);
+        let value = result.get(&OsString::from("common_name")).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            assert_eq!(&[b'a', 192][..], value.as_bytes());
+        }
+        #[cfg(not(unix))]
+        assert_eq!("a\u{fffd}", value);
+        assert_eq!(
+            vec![OsString::from("foo")],
+            string_array_os(&[CString::new("foo").unwrap()])
+        );
+    }
+
+    mod proptests {
+        use super::*;
+        use proptest::{collection::vec, prelude::*};
+
+        /// Builds a null-terminated pointer array to `strings` and gives it to `f`.
+        fn with_ptr_array<T>(strings: &[CString], f: impl FnOnce(*const *const c_char) -> T) -> T {
+            let mut ptrs: Vec<*const c_char> = strings.iter().map(|s| s.as_ptr()).collect();
+            ptrs.push(ptr::null());
+            f(ptrs.as_ptr())
+        }
+
+        fn key() -> impl Strategy<Value = Vec<u8>> {
+            // Any bytes except null and equal sign. Short, so that duplicate keys are common.
+            vec(prop_oneof![1u8..b'=', b'=' + 1..=255u8], 0..3)
+        }
+
+        fn value() -> impl Strategy<Value = Vec<u8>> {
+            vec(1u8..=255u8, 0..10)
+        }
+
+        fn to_cstrings(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<CString> {
+            entries
+                .iter()
+                .map(|(key, value)| CString::new([&key[..], b"=", &value[..]].concat()).unwrap())
+                .collect()
+        }
+
+        proptest! {
+            #[test]
+            fn env_last_duplicate_wins_and_no_data_loss(entries in vec((key(), value()), 0..20)) {
+                let mut expected = HashMap::new();
+                for (key, value) in &entries {
+                    expected.insert(CString::new(key.clone()).unwrap(), CString::new(value.clone()).unwrap());
+                }
+                let strings = to_cstrings(&entries);
+                let env = with_ptr_array(&strings, |ptr| unsafe { env(ptr) }).unwrap();
+                prop_assert_eq!(expected, env);
+            }
+
+            #[test]
+            fn env_splits_at_first_equal(key in key(), value in value(), tail in value()) {
+                let value = [&value[..], b"=", &tail[..]].concat();
+                let strings = to_cstrings(&[(key.clone(), value.clone())]);
+                let env = with_ptr_array(&strings, |ptr| unsafe { env(ptr) }).unwrap();
+                prop_assert_eq!(Some(&CString::new(value).unwrap()), env.get(&CString::new(key).unwrap()));
+            }
+
+            #[test]
+            fn env_utf8_keeps_all_strings(entries in vec((".*", ".*"), 0..20)) {
+                let entries: Vec<(Vec<u8>, Vec<u8>)> = entries
+                    .into_iter()
+                    .map(|(key, value): (String, String)| (key.replace(['=', '\0'], ""), value.replace('\0', "")))
+                    .map(|(key, value)| (key.into_bytes(), value.into_bytes()))
+                    .collect();
+                let strings = to_cstrings(&entries);
+                let env = with_ptr_array(&strings, |ptr| unsafe { env(ptr) }).unwrap();
+                let utf8 = env_utf8(&env).unwrap();
+                prop_assert_eq!(env.len(), utf8.len());
+                for (key, value) in &env {
+                    prop_assert_eq!(Some(&value.to_str().unwrap().to_owned()), utf8.get(key.to_str().unwrap()));
+                }
+            }
+        }
     }
 }
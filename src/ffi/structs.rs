@@ -14,23 +14,41 @@ use std::os::raw::{c_char, c_int, c_uint, c_void};
 /// Struct sent to `openvpn_plugin_open_v3` containing input values.
 #[repr(C)]
 pub struct openvpn_plugin_args_open_in {
-    type_mask: c_int,
+    pub(crate) type_mask: c_int,
     pub argv: *const *const c_char,
     pub envp: *const *const c_char,
-    callbacks: *const c_void,
-    ssl_api: ovpnSSLAPI,
-    ovpn_version: *const c_char,
-    ovpn_version_major: c_uint,
-    ovpn_version_minor: c_uint,
-    ovpn_version_patch: *const c_char,
+    pub(crate) callbacks: *const c_void,
+    /// An `ovpnSSLAPI` value. Read as an integer, since OpenVPN may pass values not in the enum.
+    pub(crate) ssl_api: c_int,
+    pub(crate) ovpn_version: *const c_char,
+    pub(crate) ovpn_version_major: c_uint,
+    pub(crate) ovpn_version_minor: c_uint,
+    pub(crate) ovpn_version_patch: *const c_char,
 }
 
-#[allow(dead_code)]
-#[repr(C)]
-enum ovpnSSLAPI {
-    None,
-    OpenSsl,
-    MbedTls,
+impl openvpn_plugin_args_open_in {
+    /// Creates the arguments OpenVPN passes to `openvpn_plugin_open_v3`, with the given arguments
+    /// and environment and everything else empty.
+    pub(crate) fn new(
+        argv: *const *const c_char,
+        envp: *const *const c_char,
+        ovpn_version: *const c_char,
+        ovpn_version_major: c_uint,
+        ovpn_version_minor: c_uint,
+        ovpn_version_patch: *const c_char,
+    ) -> Self {
+        openvpn_plugin_args_open_in {
+            type_mask: 0,
+            argv,
+            envp,
+            callbacks: std::ptr::null(),
+            ssl_api: 0,
+            ovpn_version,
+            ovpn_version_major,
+            ovpn_version_minor,
+            ovpn_version_patch,
+        }
+    }
 }
 
 /// Struct used for returning values from `openvpn_plugin_open_v3` to OpenVPN.
@@ -41,6 +59,16 @@ pub struct openvpn_plugin_args_open_return {
     return_list: *const c_void,
 }
 
+impl Default for openvpn_plugin_args_open_return {
+    fn default() -> Self {
+        openvpn_plugin_args_open_return {
+            type_mask: 0,
+            handle: std::ptr::null(),
+            return_list: std::ptr::null(),
+        }
+    }
+}
+
 /// Struct sent to `openvpn_plugin_func_v3` containing input values.
 #[repr(C)]
 pub struct openvpn_plugin_args_func_in {
@@ -48,13 +76,42 @@ pub struct openvpn_plugin_args_func_in {
     pub argv: *const *const c_char,
     pub envp: *const *const c_char,
     pub handle: *const c_void,
-    per_client_context: *const c_void,
+    pub(crate) per_client_context: *const c_void,
     current_cert_depth: c_int,
     current_cert: *const c_void,
 }
 
+impl openvpn_plugin_args_func_in {
+    /// Creates the arguments OpenVPN passes to `openvpn_plugin_func_v3`, with no client context or
+    /// certificate.
+    pub(crate) fn new(
+        event_type: c_int,
+        argv: *const *const c_char,
+        envp: *const *const c_char,
+        handle: *const c_void,
+    ) -> Self {
+        openvpn_plugin_args_func_in {
+            event_type,
+            argv,
+            envp,
+            handle,
+            per_client_context: std::ptr::null(),
+            current_cert_depth: 0,
+            current_cert: std::ptr::null(),
+        }
+    }
+}
+
 /// Struct used for returning values from `openvpn_plugin_func_v3` to OpenVPN.
 #[repr(C)]
 pub struct openvpn_plugin_args_func_return {
     return_list: *const c_void,
 }
+
+impl Default for openvpn_plugin_args_func_return {
+    fn default() -> Self {
+        openvpn_plugin_args_func_return {
+            return_list: std::ptr::null(),
+        }
+    }
+}
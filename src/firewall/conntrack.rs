@@ -0,0 +1,156 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Removal of the connection tracking entries of disconnected clients. Linux only.
+//!
+//! The kernel keeps tracking the connections of a client after it disconnects. When the virtual
+//! address of the client is given to a client connecting later, its traffic matches those stale
+//! entries, which can have it dropped or sent through the NAT mapping of the old connection until
+//! they time out. Flushing the entries when the client disconnects avoids that:
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, ffi::CString};
+//! # use openvpn_plugin::{firewall::conntrack::Conntrack, EventResult};
+//! fn client_disconnect(
+//!     env: HashMap<CString, CString>,
+//! ) -> Result<EventResult, Box<dyn std::error::Error>> {
+//!     Conntrack::new().flush_client(&env)?;
+//!     Ok(EventResult::Success)
+//! }
+//! ```
+//!
+//! The entries are removed with the `conntrack` command of conntrack-tools, which needs the
+//! `CAP_NET_ADMIN` capability.
+
+use std::{
+    collections::HashMap,
+    ffi::{CString, OsString},
+    net::IpAddr,
+};
+
+use super::{run_stderr, FirewallError};
+use crate::{env_keys, events::Env};
+
+/// Removes connection tracking entries with the `conntrack` command.
+#[derive(Debug, Clone)]
+pub struct Conntrack {
+    program: OsString,
+}
+
+impl Default for Conntrack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Conntrack {
+    /// Removes entries with the `conntrack` command.
+    pub fn new() -> Self {
+        Conntrack {
+            program: "conntrack".into(),
+        }
+    }
+
+    /// Sets the `conntrack` command to run, for when it is not in the `PATH` of OpenVPN.
+    pub fn program(mut self, program: impl Into<OsString>) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    /// Removes the entries of connections from and to `address`. Returns the number of entries
+    /// removed.
+    pub fn flush(&self, address: IpAddr) -> Result<u64, FirewallError> {
+        let mut deleted = 0;
+        for direction in &["--orig-src", "--orig-dst"] {
+            deleted += self.delete(direction, address)?;
+        }
+        Ok(deleted)
+    }
+
+    /// Removes the entries of the virtual addresses of the client in `env`, the environment of
+    /// an `EventType::ClientDisconnect` event. The addresses are read from
+    /// `ifconfig_pool_remote_ip` and `ifconfig_pool_remote_ip6`. Returns the number of entries
+    /// removed.
+    pub fn flush_client(&self, env: &HashMap<CString, CString>) -> Result<u64, FirewallError> {
+        let env = Env(env);
+        let addresses = [
+            env.parse_opt(env_keys::IFCONFIG_POOL_REMOTE_IP)?,
+            env.parse_opt(env_keys::IFCONFIG_POOL_REMOTE_IP6)?,
+        ];
+        let mut deleted = 0;
+        for address in addresses.iter().flatten() {
+            deleted += self.flush(*address)?;
+        }
+        Ok(deleted)
+    }
+
+    fn delete(&self, direction: &str, address: IpAddr) -> Result<u64, FirewallError> {
+        match run_stderr(&self.program, &args(direction, address)) {
+            Ok(stderr) => Ok(deleted_count(&stderr).unwrap_or(0)),
+            // `conntrack` fails when there is nothing to remove.
+            Err(FirewallError::Failed { ref stderr, .. }) if deleted_count(stderr) == Some(0) => {
+                Ok(0)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn args(direction: &str, address: IpAddr) -> Vec<String> {
+    let family = match address {
+        IpAddr::V4(_) => "ipv4",
+        IpAddr::V6(_) => "ipv6",
+    };
+    vec![
+        "--delete".to_owned(),
+        "--family".to_owned(),
+        family.to_owned(),
+        direction.to_owned(),
+        address.to_string(),
+    ]
+}
+
+/// Reads the count from the `N flow entries have been deleted.` summary `conntrack` writes to
+/// stderr.
+fn deleted_count(stderr: &str) -> Option<u64> {
+    let summary = stderr.lines().last()?;
+    let before = summary.strip_suffix(" flow entries have been deleted.")?;
+    before.rsplit(' ').next()?.parse().ok()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_args() {
+        assert_eq!(
+            "--delete --family ipv4 --orig-src 10.8.0.6",
+            args("--orig-src", "10.8.0.6".parse().unwrap()).join(" ")
+        );
+        assert_eq!(
+            "--delete --family ipv6 --orig-dst fd00::1000",
+            args("--orig-dst", "fd00::1000".parse().unwrap()).join(" ")
+        );
+    }
+
+    #[test]
+    fn parse_deleted_count() {
+        assert_eq!(
+            Some(3),
+            deleted_count("conntrack v1.4.7 (conntrack-tools): 3 flow entries have been deleted.")
+        );
+        assert_eq!(
+            Some(0),
+            deleted_count("conntrack v1.4.7 (conntrack-tools): 0 flow entries have been deleted.")
+        );
+        assert_eq!(None, deleted_count("conntrack: Operation not permitted"));
+        assert_eq!(None, deleted_count(""));
+    }
+}
@@ -0,0 +1,672 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Firewall set membership of client addresses, from `EventType::LearnAddress` events. Requires
+//! the `firewall` feature.
+//!
+//! OpenVPN reports every address it routes to a client with a `LearnAddress` event. A
+//! [`Firewall`] adds those addresses to a named set of an nftables table, or to an ipset
+//! referenced by iptables rules, so the rules of the set apply to the traffic of the client. Each
+//! client can be put in a set of its own choosing with [`Firewall::classify`], for example one
+//! set per group of users:
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, ffi::CString};
+//! # use openvpn_plugin::{events::EventArgs, firewall::{Firewall, Nftables}, EventResult, EventType};
+//! fn event(
+//!     event: EventType,
+//!     args: Vec<CString>,
+//!     env: HashMap<CString, CString>,
+//!     firewall: &mut Firewall<Nftables>,
+//! ) -> Result<EventResult, Box<dyn std::error::Error>> {
+//!     firewall.handle(&EventArgs::parse(event, &args, &env)?)?;
+//!     Ok(EventResult::Success)
+//! }
+//!
+//! let firewall = Firewall::new(Nftables::new("inet", "filter"), "vpn_clients")
+//!     .classify(|common_name| common_name.ends_with(".admin").then(|| "vpn_admins".to_owned()));
+//! ```
+//!
+//! The commands are run directly, never through a shell, and a failing command is returned as an
+//! error. A failure to add an address fails the event, so OpenVPN does not route traffic to an
+//! address the firewall doesn't know about. The addresses are tracked per client, and the
+//! addresses a client still has when it disconnects are removed from their sets, since OpenVPN
+//! doesn't always report their deletion. An address that could not be removed stays tracked, so
+//! the removal is retried on the next delete or disconnect.
+//!
+//! The sets must exist, and their names may only contain ASCII letters, digits and underscores.
+//! IPv6 addresses are put in the set named like the IPv4 set with `6`
+//! appended, `vpn_clients6` above. Clients with an `iroute` have whole networks routed to them,
+//! which needs sets able to hold networks: nftables sets with `flags interval`, or ipsets of type
+//! `hash:net`. MAC addresses, learned in `dev tap` mode, are ignored.
+//!
+//! Clients are told apart by their certificate common name, so servers with `duplicate-cn` have
+//! the addresses of every client with the same common name removed when one of them disconnects.
+//!
+//! On Linux, the [`conntrack`] module removes the connection tracking entries of disconnected
+//! clients, so a client given the same address later is not affected by them.
+//!
+//! [`Firewall`]: struct.Firewall.html
+//! [`Firewall::classify`]: struct.Firewall.html#method.classify
+//! [`conntrack`]: conntrack/index.html
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    ffi::OsString,
+    fmt, io,
+    panic::UnwindSafe,
+    process::{Command, ExitStatus},
+};
+
+use crate::events::{EventArgs, EventArgsError, IpNetwork, LearnAddressOp, LearnedAddress};
+
+#[cfg(target_os = "linux")]
+pub mod conntrack;
+
+/// Adds and removes addresses of firewall sets.
+pub trait Backend {
+    /// Adds `address` to `set`.
+    fn add(&mut self, set: &str, address: IpNetwork) -> Result<(), FirewallError>;
+
+    /// Removes `address` from `set`.
+    fn remove(&mut self, set: &str, address: IpNetwork) -> Result<(), FirewallError>;
+}
+
+/// Sets in an nftables table, changed with the `nft` command.
+#[derive(Debug, Clone)]
+pub struct Nftables {
+    program: OsString,
+    family: String,
+    table: String,
+}
+
+impl Nftables {
+    /// Changes the sets of `table` in the address family `family`, such as `inet` or `ip`.
+    pub fn new(family: impl Into<String>, table: impl Into<String>) -> Self {
+        Nftables {
+            program: "nft".into(),
+            family: family.into(),
+            table: table.into(),
+        }
+    }
+
+    /// Sets the `nft` command to run, for when it is not in the `PATH` of OpenVPN.
+    pub fn program(mut self, program: impl Into<OsString>) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    /// `nft` joins its arguments and parses them as one command, so the set name is checked to
+    /// not contain anything but a name.
+    fn args(
+        &self,
+        verb: &str,
+        set: &str,
+        address: IpNetwork,
+    ) -> Result<Vec<String>, FirewallError> {
+        if !is_valid_set_name(set) {
+            return Err(FirewallError::InvalidSetName(set.to_owned()));
+        }
+        Ok(vec![
+            verb.to_owned(),
+            "element".to_owned(),
+            self.family.clone(),
+            self.table.clone(),
+            set.to_owned(),
+            "{".to_owned(),
+            element(address),
+            "}".to_owned(),
+        ])
+    }
+}
+
+impl Backend for Nftables {
+    fn add(&mut self, set: &str, address: IpNetwork) -> Result<(), FirewallError> {
+        run(&self.program, &self.args("add", set, address)?)
+    }
+
+    fn remove(&mut self, set: &str, address: IpNetwork) -> Result<(), FirewallError> {
+        run(&self.program, &self.args("delete", set, address)?)
+    }
+}
+
+/// Sets of the `ipset` command, for use in iptables rules with `-m set --match-set`.
+#[derive(Debug, Clone)]
+pub struct Ipset {
+    program: OsString,
+}
+
+impl Default for Ipset {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ipset {
+    /// Changes ipsets with the `ipset` command.
+    pub fn new() -> Self {
+        Ipset {
+            program: "ipset".into(),
+        }
+    }
+
+    /// Sets the `ipset` command to run, for when it is not in the `PATH` of OpenVPN.
+    pub fn program(mut self, program: impl Into<OsString>) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    fn args(verb: &str, set: &str, address: IpNetwork) -> Vec<String> {
+        // `-exist` makes adding an address already in the set, or removing one that isn't, succeed.
+        vec![
+            verb.to_owned(),
+            "-exist".to_owned(),
+            set.to_owned(),
+            element(address),
+        ]
+    }
+}
+
+impl Backend for Ipset {
+    fn add(&mut self, set: &str, address: IpNetwork) -> Result<(), FirewallError> {
+        run(&self.program, &Self::args("add", set, address))
+    }
+
+    fn remove(&mut self, set: &str, address: IpNetwork) -> Result<(), FirewallError> {
+        run(&self.program, &Self::args("del", set, address))
+    }
+}
+
+/// Returns true if `set` is a non-empty name of ASCII letters, digits and underscores.
+fn is_valid_set_name(set: &str) -> bool {
+    !set.is_empty() && set.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+}
+
+/// A single address is written without a prefix, so it also fits in sets of addresses.
+fn element(address: IpNetwork) -> String {
+    if address.is_host() {
+        address.address().to_string()
+    } else {
+        address.to_string()
+    }
+}
+
+fn run(program: &OsString, args: &[String]) -> Result<(), FirewallError> {
+    run_stderr(program, args).map(drop)
+}
+
+/// Runs a command, returning what it wrote to stderr if it succeeded.
+fn run_stderr(program: &OsString, args: &[String]) -> Result<String, FirewallError> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| FirewallError::Spawn(program.to_string_lossy().into_owned(), e))?;
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+    if output.status.success() {
+        Ok(stderr)
+    } else {
+        let mut command = program.to_string_lossy().into_owned();
+        for arg in args {
+            command.push(' ');
+            command.push_str(arg);
+        }
+        Err(FirewallError::Failed {
+            command,
+            status: output.status,
+            stderr,
+        })
+    }
+}
+
+/// Error changing a firewall set.
+#[derive(Debug)]
+pub enum FirewallError {
+    /// The command could not be run.
+    Spawn(String, io::Error),
+    /// The command exited unsuccessfully.
+    Failed {
+        /// The command line that failed.
+        command: String,
+        /// The exit status of the command.
+        status: ExitStatus,
+        /// What the command wrote to stderr.
+        stderr: String,
+    },
+    /// The address of the client in the environment of the event is not valid.
+    InvalidEnv(EventArgsError),
+    /// The set name contains other characters than ASCII letters, digits and underscores.
+    InvalidSetName(String),
+}
+
+impl fmt::Display for FirewallError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FirewallError::Spawn(program, _) => write!(f, "Unable to run {}", program),
+            FirewallError::Failed {
+                command,
+                status,
+                stderr,
+            } => {
+                write!(f, "\"{}\" failed with {}", command, status)?;
+                if !stderr.is_empty() {
+                    write!(f, ": {}", stderr)?;
+                }
+                Ok(())
+            }
+            FirewallError::InvalidEnv(_) => "Invalid client address in env".fmt(f),
+            FirewallError::InvalidSetName(set) => {
+                write!(f, "Invalid firewall set name \"{}\"", set)
+            }
+        }
+    }
+}
+
+impl Error for FirewallError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FirewallError::Spawn(_, e) => Some(e),
+            FirewallError::Failed { .. } | FirewallError::InvalidSetName(_) => None,
+            FirewallError::InvalidEnv(e) => Some(e),
+        }
+    }
+}
+
+impl From<EventArgsError> for FirewallError {
+    fn from(e: EventArgsError) -> Self {
+        FirewallError::InvalidEnv(e)
+    }
+}
+
+/// The set of an address and the client it belongs to.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Member {
+    common_name: String,
+    set: String,
+}
+
+type Classify = Box<dyn Fn(&str) -> Option<String> + Send + UnwindSafe>;
+
+/// Keeps the addresses of clients in firewall sets, following `EventType::LearnAddress` and
+/// `EventType::ClientDisconnect` events.
+pub struct Firewall<B> {
+    backend: B,
+    set: String,
+    classify: Option<Classify>,
+    members: HashMap<IpNetwork, Member>,
+}
+
+impl<B: Backend> Firewall<B> {
+    /// Puts the addresses of every client in `set`, changed through `backend`.
+    pub fn new(backend: B, set: impl Into<String>) -> Self {
+        Firewall {
+            backend,
+            set: set.into(),
+            classify: None,
+            members: HashMap::new(),
+        }
+    }
+
+    /// Sets the function choosing the set of the client with a common name. Its addresses are put
+    /// in the default set if it returns `None`.
+    ///
+    /// The returned names may only contain ASCII letters, digits and underscores. The common name
+    /// is chosen by the client, so it should not be used as a set name without checking it.
+    /// `Nftables` fails to add addresses to sets with other names with
+    /// `FirewallError::InvalidSetName`.
+    pub fn classify<F>(mut self, classify: F) -> Self
+    where
+        F: Fn(&str) -> Option<String> + Send + UnwindSafe + 'static,
+    {
+        self.classify = Some(Box::new(classify));
+        self
+    }
+
+    /// Follows `args` if they are the arguments of a `LearnAddress` or `ClientDisconnect` event.
+    /// Other events are ignored.
+    pub fn handle(&mut self, args: &EventArgs) -> Result<(), FirewallError> {
+        match args {
+            EventArgs::LearnAddress {
+                operation,
+                address,
+                common_name,
+            } => self.learn_address(*operation, address, common_name.as_deref()),
+            EventArgs::ClientDisconnect {
+                common_name: Some(common_name),
+                ..
+            } => self.client_disconnect(common_name),
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds or removes `address` according to `operation`. `common_name` is the client the
+    /// address is added or updated for, and is not given when deleting.
+    ///
+    /// An `LearnAddressOp::Update` moves the address from the set of the client it belonged to,
+    /// to the set of `common_name`.
+    pub fn learn_address(
+        &mut self,
+        operation: LearnAddressOp,
+        address: &LearnedAddress,
+        common_name: Option<&str>,
+    ) -> Result<(), FirewallError> {
+        let address = match address {
+            LearnedAddress::Ip(address) => *address,
+            LearnedAddress::Mac(_) => return Ok(()),
+        };
+        match (operation, common_name) {
+            (LearnAddressOp::Add, Some(common_name))
+            | (LearnAddressOp::Update, Some(common_name)) => self.insert(address, common_name),
+            // Without a client there is no set to add the address to, it is only removed.
+            (LearnAddressOp::Add, None)
+            | (LearnAddressOp::Update, None)
+            | (LearnAddressOp::Delete, _) => self.remove(address),
+        }
+    }
+
+    /// Removes the addresses of the client with `common_name` from their sets.
+    ///
+    /// Every address is tried, and the first error is returned.
+    pub fn client_disconnect(&mut self, common_name: &str) -> Result<(), FirewallError> {
+        let addresses = self
+            .members
+            .iter()
+            .filter(|(_, member)| member.common_name == common_name)
+            .map(|(address, _)| *address)
+            .collect::<Vec<_>>();
+        self.remove_all(addresses)
+    }
+
+    /// Removes every tracked address from its set. Meant for when the plugin is closed.
+    ///
+    /// Every address is tried, and the first error is returned.
+    pub fn clear(&mut self) -> Result<(), FirewallError> {
+        let addresses = self.members.keys().copied().collect::<Vec<_>>();
+        self.remove_all(addresses)
+    }
+
+    /// The addresses of the client with `common_name`.
+    pub fn addresses<'a>(&'a self, common_name: &'a str) -> impl Iterator<Item = IpNetwork> + 'a {
+        self.members
+            .iter()
+            .filter(move |(_, member)| member.common_name == common_name)
+            .map(|(address, _)| *address)
+    }
+
+    /// The set `address` is in, if it is tracked.
+    pub fn set_of(&self, address: IpNetwork) -> Option<&str> {
+        self.members.get(&address).map(|member| member.set.as_str())
+    }
+
+    /// The backend changing the sets.
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    fn insert(&mut self, address: IpNetwork, common_name: &str) -> Result<(), FirewallError> {
+        let member = Member {
+            common_name: common_name.to_owned(),
+            set: self.set_for(common_name, address),
+        };
+        match self.members.get(&address) {
+            Some(old) if *old == member => return Ok(()),
+            Some(old) if old.set == member.set => {
+                // Already in the right set, only the owner changed.
+                self.members.insert(address, member);
+                return Ok(());
+            }
+            Some(_) => self.remove(address)?,
+            None => (),
+        }
+        self.backend.add(&member.set, address)?;
+        self.members.insert(address, member);
+        Ok(())
+    }
+
+    fn remove(&mut self, address: IpNetwork) -> Result<(), FirewallError> {
+        if let Some(member) = self.members.get(&address) {
+            self.backend.remove(&member.set, address)?;
+            self.members.remove(&address);
+        }
+        Ok(())
+    }
+
+    fn remove_all(&mut self, addresses: Vec<IpNetwork>) -> Result<(), FirewallError> {
+        let mut result = Ok(());
+        for address in addresses {
+            if let Err(e) = self.remove(address) {
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    fn set_for(&self, common_name: &str, address: IpNetwork) -> String {
+        let mut set = self
+            .classify
+            .as_ref()
+            .and_then(|classify| classify(common_name))
+            .unwrap_or_else(|| self.set.clone());
+        if address.address().is_ipv6() {
+            set.push('6');
+        }
+        set
+    }
+}
+
+impl<B: fmt::Debug> fmt::Debug for Firewall<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Firewall")
+            .field("backend", &self.backend)
+            .field("set", &self.set)
+            .field("members", &self.members)
+            .finish_non_exhaustive()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records the changes, and fails those to sets named `broken`.
+    #[derive(Debug, Default)]
+    struct Recorder(Vec<String>);
+
+    impl Backend for Recorder {
+        fn add(&mut self, set: &str, address: IpNetwork) -> Result<(), FirewallError> {
+            self.change("add", set, address)
+        }
+
+        fn remove(&mut self, set: &str, address: IpNetwork) -> Result<(), FirewallError> {
+            self.change("remove", set, address)
+        }
+    }
+
+    impl Recorder {
+        fn change(
+            &mut self,
+            verb: &str,
+            set: &str,
+            address: IpNetwork,
+        ) -> Result<(), FirewallError> {
+            if set.starts_with("broken") {
+                return Err(FirewallError::Spawn(
+                    set.to_owned(),
+                    io::ErrorKind::NotFound.into(),
+                ));
+            }
+            self.0.push(format!("{} {} {}", verb, set, address));
+            Ok(())
+        }
+
+        fn take(&mut self) -> Vec<String> {
+            std::mem::take(&mut self.0)
+        }
+    }
+
+    fn ip(address: &str) -> LearnedAddress {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn follows_learn_address() {
+        let mut firewall = Firewall::new(Recorder::default(), "clients").classify(|common_name| {
+            Some(format!("{}_set", common_name)).filter(|_| common_name == "admin")
+        });
+        let learn = |firewall: &mut Firewall<Recorder>, op, address, common_name| {
+            firewall
+                .learn_address(op, &ip(address), common_name)
+                .unwrap();
+            firewall.backend.take()
+        };
+
+        assert_eq!(
+            vec!["add clients 10.8.0.6/32"],
+            learn(
+                &mut firewall,
+                LearnAddressOp::Add,
+                "10.8.0.6",
+                Some("alice")
+            )
+        );
+        assert_eq!(
+            vec!["add clients6 fd00::1000/128"],
+            learn(
+                &mut firewall,
+                LearnAddressOp::Add,
+                "fd00::1000",
+                Some("alice")
+            )
+        );
+        // Learning the same address again changes nothing.
+        assert!(learn(
+            &mut firewall,
+            LearnAddressOp::Update,
+            "10.8.0.6",
+            Some("alice")
+        )
+        .is_empty());
+        // Moving to a client in another set.
+        assert_eq!(
+            vec!["remove clients 10.8.0.6/32", "add admin_set 10.8.0.6/32"],
+            learn(
+                &mut firewall,
+                LearnAddressOp::Update,
+                "10.8.0.6",
+                Some("admin")
+            )
+        );
+        assert_eq!(
+            Some("admin_set"),
+            firewall.set_of("10.8.0.6".parse().unwrap())
+        );
+        assert_eq!(
+            vec!["remove admin_set 10.8.0.6/32"],
+            learn(&mut firewall, LearnAddressOp::Delete, "10.8.0.6", None)
+        );
+        // Unknown addresses and MAC addresses are ignored.
+        assert!(learn(&mut firewall, LearnAddressOp::Delete, "10.8.0.7", None).is_empty());
+        assert!(learn(
+            &mut firewall,
+            LearnAddressOp::Add,
+            "00:ff:01:02:03:04",
+            Some("alice")
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn rolls_back_on_disconnect() {
+        let mut firewall = Firewall::new(Recorder::default(), "clients");
+        for (address, common_name) in &[
+            ("10.8.0.6", "alice"),
+            ("10.9.0.0/24", "alice"),
+            ("10.8.0.10", "bob"),
+        ] {
+            firewall
+                .learn_address(LearnAddressOp::Add, &ip(address), Some(common_name))
+                .unwrap();
+        }
+        firewall.backend.take();
+
+        firewall.client_disconnect("alice").unwrap();
+        let mut removed = firewall.backend.take();
+        removed.sort();
+        assert_eq!(
+            vec!["remove clients 10.8.0.6/32", "remove clients 10.9.0.0/24"],
+            removed
+        );
+        assert_eq!(0, firewall.addresses("alice").count());
+        assert_eq!(1, firewall.addresses("bob").count());
+
+        firewall.clear().unwrap();
+        assert_eq!(vec!["remove clients 10.8.0.10/32"], firewall.backend.take());
+    }
+
+    #[test]
+    fn keeps_addresses_failing_to_change() {
+        let mut firewall = Firewall::new(Recorder::default(), "clients")
+            .classify(|common_name| Some("broken".to_owned()).filter(|_| common_name == "mallory"));
+        assert!(firewall
+            .learn_address(LearnAddressOp::Add, &ip("10.8.0.6"), Some("mallory"))
+            .is_err());
+        assert_eq!(None, firewall.set_of("10.8.0.6".parse().unwrap()));
+
+        firewall
+            .learn_address(LearnAddressOp::Add, &ip("10.8.0.6"), Some("alice"))
+            .unwrap();
+        // The set is broken after the address was added, so removing it fails and is retried.
+        let address = "10.8.0.6".parse().unwrap();
+        firewall.members.get_mut(&address).unwrap().set = "broken".to_owned();
+        assert!(firewall
+            .learn_address(LearnAddressOp::Delete, &ip("10.8.0.6"), None)
+            .is_err());
+        assert!(firewall.client_disconnect("alice").is_err());
+        assert_eq!(
+            vec![address],
+            firewall.addresses("alice").collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn command_args() {
+        let host = "10.8.0.6".parse().unwrap();
+        let network = "fd00::/64".parse().unwrap();
+        assert_eq!(
+            "add element inet filter clients { 10.8.0.6 }",
+            Nftables::new("inet", "filter")
+                .args("add", "clients", host)
+                .unwrap()
+                .join(" ")
+        );
+        assert_eq!(
+            "delete element inet filter clients6 { fd00::/64 }",
+            Nftables::new("inet", "filter")
+                .args("delete", "clients6", network)
+                .unwrap()
+                .join(" ")
+        );
+        for set in &[
+            "",
+            "clients }; flush ruleset; add element inet filter x {",
+            "clients 6",
+        ] {
+            assert!(matches!(
+                Nftables::new("inet", "filter").args("add", set, host),
+                Err(FirewallError::InvalidSetName(_))
+            ));
+        }
+        assert_eq!(
+            "del -exist clients 10.8.0.6",
+            Ipset::args("del", "clients", host).join(" ")
+        );
+    }
+}
@@ -0,0 +1,310 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Handling of events OpenVPN doesn't wait for an answer to on a background thread.
+//!
+//! Events such as `RouteUp` or `ClientDisconnect` only inform the plugin, OpenVPN logs a warning
+//! when they fail and carries on. A plugin exporting them to a slow sink, such as a database or a
+//! remote API, still blocks OpenVPN for every one of them. A [`Forwarder`] instead puts these
+//! events in a bounded queue handled by a worker thread, and answers them with success right away:
+//!
+//! ```rust,no_run
+//! use std::{collections::HashMap, convert::Infallible, ffi::CString, io};
+//! use openvpn_plugin::{
+//!     forward::{Forward, Forwarder},
+//!     layer::{self, BoxService, Event},
+//!     openvpn_plugin, EventResult, EventType, EventTypeSet,
+//! };
+//!
+//! fn export(event: Event) -> Result<(), io::Error> {
+//!     // Sends the event to the slow sink.
+//! #   Ok(())
+//! }
+//!
+//! fn open(
+//!     _args: Vec<CString>,
+//!     _env: HashMap<CString, CString>,
+//! ) -> Result<(EventTypeSet, BoxService), io::Error> {
+//!     let forwarder = Forwarder::spawn(1024, export)?;
+//!     let events = forwarder.events() | EventType::AuthUserPassVerify;
+//!     let service = Forward::new(
+//!         forwarder,
+//!         layer::service_fn(|_event| Ok::<_, Infallible>(EventResult::Success)),
+//!     );
+//!     Ok((events, Box::new(service)))
+//! }
+//!
+//! fn close(_handle: BoxService) {}
+//!
+//! openvpn_plugin!(crate::open, crate::close, layer::event, BoxService);
+//! # fn main() {}
+//! ```
+//!
+//! Errors from the handler are logged as warnings. When the queue is full, new events are logged
+//! and dropped rather than blocking OpenVPN. Events still in the queue when the forwarder is
+//! dropped are handled before the worker stops, within the shutdown timeout of the [`workers`].
+//!
+//! [`Forwarder`]: struct.Forwarder.html
+//! [`workers`]: ../workers/index.html
+
+use std::{
+    error::Error as StdError,
+    fmt, io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, SyncSender, TrySendError},
+        Arc,
+    },
+};
+
+use crate::{
+    layer::{Event, Service},
+    logging,
+    workers::Workers,
+    Error, EventResult, EventType, EventTypeSet,
+};
+
+/// The events forwarded by default, `RouteUp` and `ClientDisconnect`. OpenVPN only logs a warning
+/// when these events fail.
+///
+/// `Up` and `Down` are not included, since OpenVPN exits when they fail, see the
+/// [`error_policy`]. Add them with [`Forwarder::with_events`] if the plugin never wants to fail
+/// them.
+///
+/// [`error_policy`]: ../error_policy/index.html
+/// [`Forwarder::with_events`]: struct.Forwarder.html#method.with_events
+pub fn default_events() -> EventTypeSet {
+    EventType::RouteUp | EventType::ClientDisconnect
+}
+
+/// A bounded queue of events with a worker thread handling them.
+pub struct Forwarder {
+    // Declared before `workers`, so the queue is closed before the worker is joined.
+    tx: SyncSender<Event>,
+    events: EventTypeSet,
+    dropped: Arc<AtomicU64>,
+    _workers: Workers,
+}
+
+impl Forwarder {
+    /// Spawns a worker calling `handler` for every forwarded event, with room for `capacity`
+    /// events waiting to be handled. Forwards the [`default_events`].
+    ///
+    /// [`default_events`]: fn.default_events.html
+    pub fn spawn<F, E>(capacity: usize, mut handler: F) -> io::Result<Self>
+    where
+        F: FnMut(Event) -> Result<(), E> + Send + 'static,
+        E: Into<Box<dyn StdError>>,
+    {
+        let (tx, rx) = mpsc::sync_channel::<Event>(capacity);
+        let mut workers = Workers::new();
+        workers.spawn("event-forwarder", move |_shutdown| {
+            // Stops when the forwarder, holding the only sender, is dropped.
+            for event in rx {
+                let event_type = event.event;
+                if let Err(e) = handler(event) {
+                    logging::log_warning(&Error::ForwardFailed {
+                        event: event_type,
+                        source: e.into(),
+                    });
+                }
+            }
+        })?;
+        Ok(Forwarder {
+            tx,
+            events: default_events(),
+            dropped: Arc::new(AtomicU64::new(0)),
+            _workers: workers,
+        })
+    }
+
+    /// Sets the events to forward, instead of the [`default_events`]. Forwarded events are always
+    /// answered with success, so only forward events the plugin never wants to fail.
+    ///
+    /// [`default_events`]: fn.default_events.html
+    pub fn with_events(mut self, events: impl Into<EventTypeSet>) -> Self {
+        self.events = events.into();
+        self
+    }
+
+    /// The events this forwarder forwards.
+    pub fn events(&self) -> EventTypeSet {
+        self.events
+    }
+
+    /// Returns whether `event` is forwarded.
+    pub fn forwards(&self, event: EventType) -> bool {
+        self.events.contains(event)
+    }
+
+    /// Puts `event` in the queue without waiting. If the queue is full the event is logged as a
+    /// warning and dropped.
+    pub fn forward(&self, event: Event) {
+        match self.tx.try_send(event) {
+            Ok(()) => (),
+            Err(TrySendError::Full(event)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                logging::log_warning(&QueueFull(event.event));
+            }
+            // The worker only stops when the forwarder is dropped, or if the handler panicked.
+            Err(TrySendError::Disconnected(event)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                logging::log_warning(&Error::ForwardFailed {
+                    event: event.event,
+                    source: "The event forwarding worker has stopped".into(),
+                });
+            }
+        }
+    }
+
+    /// The number of events dropped because the queue was full or the worker had stopped.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Debug for Forwarder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Forwarder")
+            .field("events", &self.events)
+            .field("dropped", &self.dropped())
+            .finish_non_exhaustive()
+    }
+}
+
+/// A service forwarding the events of a [`Forwarder`] and answering them with success. Other
+/// events go to the inner service.
+///
+/// [`Forwarder`]: struct.Forwarder.html
+#[derive(Debug)]
+pub struct Forward<S> {
+    forwarder: Forwarder,
+    inner: S,
+}
+
+impl<S> Forward<S> {
+    /// Wraps `inner`, forwarding the events of `forwarder` instead of calling it with them.
+    pub fn new(forwarder: Forwarder, inner: S) -> Self {
+        Forward { forwarder, inner }
+    }
+
+    /// The forwarder.
+    pub fn forwarder(&self) -> &Forwarder {
+        &self.forwarder
+    }
+}
+
+impl<S: Service> Service for Forward<S> {
+    fn call(&mut self, event: Event) -> Result<EventResult, Box<dyn StdError>> {
+        if self.forwarder.forwards(event.event) {
+            self.forwarder.forward(event);
+            Ok(EventResult::Success)
+        } else {
+            self.inner.call(event)
+        }
+    }
+}
+
+/// The queue of a `Forwarder` was full, so an event of the given type was dropped. Logged as a
+/// warning.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct QueueFull(pub EventType);
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The event queue is full, dropping the {} event", self.0)
+    }
+}
+
+impl StdError for QueueFull {}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::service_fn;
+    use std::{collections::HashMap, convert::Infallible, sync::Mutex, time::Duration};
+
+    fn event(event: EventType) -> Event {
+        Event {
+            event,
+            args: Vec::new(),
+            env: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn forwards_in_background() {
+        let (tx, rx) = mpsc::channel();
+        let forwarder = Forwarder::spawn(8, move |event: Event| tx.send(event.event))
+            .unwrap()
+            .with_events(EventType::Up | EventType::ClientDisconnect);
+        let inner_calls = Arc::new(Mutex::new(Vec::new()));
+        let mut service = Forward::new(
+            forwarder,
+            service_fn({
+                let inner_calls = inner_calls.clone();
+                move |event: Event| {
+                    inner_calls.lock().unwrap().push(event.event);
+                    Ok::<_, Infallible>(EventResult::Failure)
+                }
+            }),
+        );
+
+        assert_eq!(
+            EventResult::Success,
+            service.call(event(EventType::Up)).unwrap()
+        );
+        assert_eq!(
+            EventResult::Failure,
+            service.call(event(EventType::AuthUserPassVerify)).unwrap()
+        );
+        assert_eq!(
+            EventResult::Success,
+            service.call(event(EventType::ClientDisconnect)).unwrap()
+        );
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(EventType::Up, rx.recv_timeout(timeout).unwrap());
+        assert_eq!(
+            EventType::ClientDisconnect,
+            rx.recv_timeout(timeout).unwrap()
+        );
+        assert_eq!(
+            vec![EventType::AuthUserPassVerify],
+            *inner_calls.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn drops_when_full() {
+        let (started_tx, started_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let (handled_tx, handled_rx) = mpsc::channel();
+        let forwarder = Forwarder::spawn(1, move |event: Event| {
+            let _ = started_tx.send(());
+            let _ = release_rx.recv();
+            handled_tx.send(event.event)
+        })
+        .unwrap();
+
+        // The worker takes the first event and waits, the second fills the queue.
+        forwarder.forward(event(EventType::Up));
+        started_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        forwarder.forward(event(EventType::Down));
+        forwarder.forward(event(EventType::RouteUp));
+        assert_eq!(1, forwarder.dropped());
+
+        drop(release_tx);
+        drop(forwarder);
+        assert_eq!(
+            vec![EventType::Up, EventType::Down],
+            handled_rx.try_iter().collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Structs populated from the environment of an event.
+//!
+//! With the `derive` feature, [`FromEnv`] can be derived for a struct with named fields. Every
+//! field is read from the environment variable with the same name and parsed with `FromStr`:
+//!
+//! ```rust
+//! # #[cfg(feature = "derive")] {
+//! use std::net::IpAddr;
+//! use openvpn_plugin::from_env::FromEnv;
+//!
+//! #[derive(FromEnv)]
+//! struct ClientInfo {
+//!     common_name: String,
+//!     untrusted_ip: IpAddr,
+//!     trusted_port: u16,
+//!     /// `None` if the variable is not set.
+//!     username: Option<String>,
+//!     #[env(rename = "IV_GUI_VER")]
+//!     gui_version: Option<String>,
+//!     /// `0` if the variable is not set.
+//!     #[env(default)]
+//!     bytes_received: u64,
+//! }
+//! # }
+//! ```
+//!
+//! `ClientInfo::from_env(&env)` then fails with an [`EventArgsError`] naming the variable that is
+//! missing or can't be parsed.
+//!
+//! [`FromEnv`]: trait.FromEnv.html
+//! [`EventArgsError`]: ../events/enum.EventArgsError.html
+
+use std::{collections::HashMap, ffi::CString};
+
+use crate::events::EventArgsError;
+
+#[cfg(feature = "derive")]
+pub use openvpn_plugin_derive::FromEnv;
+
+/// A type that can be read from the environment of an event.
+pub trait FromEnv: Sized {
+    /// Reads the value from `env`.
+    fn from_env(env: &HashMap<CString, CString>) -> Result<Self, EventArgsError>;
+}
+
+impl FromEnv for crate::events::DisconnectStats {
+    fn from_env(env: &HashMap<CString, CString>) -> Result<Self, EventArgsError> {
+        Self::from_env(env)
+    }
+}
+
+impl FromEnv for crate::sessions::SessionKey {
+    fn from_env(env: &HashMap<CString, CString>) -> Result<Self, EventArgsError> {
+        Self::from_env(env)
+    }
+}
+
+/// Used by the code generated by `#[derive(FromEnv)]`. Not public API.
+#[doc(hidden)]
+pub mod __private {
+    use std::{collections::HashMap, ffi::CString, str::FromStr};
+
+    use crate::events::{Env, EventArgsError};
+
+    pub fn parse<T: FromStr>(
+        env: &HashMap<CString, CString>,
+        name: &'static str,
+    ) -> Result<T, EventArgsError> {
+        Env(env).parse(name)
+    }
+
+    pub fn parse_opt<T: FromStr>(
+        env: &HashMap<CString, CString>,
+        name: &'static str,
+    ) -> Result<Option<T>, EventArgsError> {
+        Env(env).parse_opt(name)
+    }
+}
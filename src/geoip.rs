@@ -0,0 +1,356 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The country and autonomous system clients connect from, looked up in MaxMind databases.
+//! Requires the `geoip` feature.
+//!
+//! A [`GeoIp`] reads a country database, such as GeoLite2-Country or GeoLite2-City, and an ASN
+//! database, such as GeoLite2-ASN. Either is optional. The [`Origin`] of a client is looked up
+//! from `untrusted_ip` or `untrusted_ip6`:
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, ffi::CString};
+//! # use openvpn_plugin::{geoip::GeoIp, EventResult};
+//! # fn event(env: HashMap<CString, CString>) -> Result<EventResult, Box<dyn std::error::Error>> {
+//! let geoip = GeoIp::new()
+//!     .country_database("/var/lib/GeoIP/GeoLite2-Country.mmdb")?
+//!     .asn_database("/var/lib/GeoIP/GeoLite2-ASN.mmdb")?;
+//!
+//! // Logs e.g. "Client connected from SE, AS64496 (Example Networks)".
+//! println!("Client connected from {}", geoip.lookup_env(&env)?);
+//! # Ok(EventResult::Success)
+//! # }
+//! ```
+//!
+//! With the `policy` feature, [`GeoIp::locate`] fills in the origin of a `policy::Connection`, so
+//! the `country` and `asn` conditions of policy rules can match it.
+//!
+//! The databases are read into memory when opened. Open them again to pick up updates.
+//!
+//! [`GeoIp`]: struct.GeoIp.html
+//! [`Origin`]: struct.Origin.html
+//! [`GeoIp::locate`]: struct.GeoIp.html#method.locate
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    ffi::CString,
+    fmt,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use maxminddb::{MaxMindDBError, Reader};
+use serde::Deserialize;
+
+use crate::{
+    env_keys,
+    events::{untrusted_ip, EventArgsError},
+};
+
+/// Error returned when a database can't be opened or read.
+#[derive(Debug)]
+pub enum GeoIpError {
+    /// The database at the path could not be opened.
+    Open(PathBuf, MaxMindDBError),
+    /// Looking up the address failed, because the database is corrupt.
+    Lookup(IpAddr, MaxMindDBError),
+    /// The address of the client is missing from the environment or not valid.
+    InvalidEnv(EventArgsError),
+}
+
+impl fmt::Display for GeoIpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeoIpError::Open(path, _) => {
+                write!(f, "Unable to open GeoIP database {}", path.display())
+            }
+            GeoIpError::Lookup(ip, _) => write!(f, "Unable to look up {} in GeoIP database", ip),
+            GeoIpError::InvalidEnv(_) => "Invalid client address in env".fmt(f),
+        }
+    }
+}
+
+impl Error for GeoIpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GeoIpError::Open(_, e) | GeoIpError::Lookup(_, e) => Some(e),
+            GeoIpError::InvalidEnv(e) => Some(e),
+        }
+    }
+}
+
+impl From<EventArgsError> for GeoIpError {
+    fn from(e: EventArgsError) -> Self {
+        GeoIpError::InvalidEnv(e)
+    }
+}
+
+
+/// Where an address is, as far as the databases know. Fields the databases have no data for are
+/// `None`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Default)]
+pub struct Origin {
+    /// The ISO 3166-1 code of the country, e.g. `SE`.
+    pub country: Option<String>,
+    /// The number of the autonomous system.
+    pub asn: Option<u32>,
+    /// The organization running the autonomous system.
+    pub as_organization: Option<String>,
+}
+
+/// Formats as e.g. `SE, AS64496 (Example Networks)`, or `unknown` without any data.
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(country) = &self.country {
+            parts.push(country.clone());
+        }
+        match (self.asn, &self.as_organization) {
+            (Some(asn), Some(organization)) => parts.push(format!("AS{} ({})", asn, organization)),
+            (Some(asn), None) => parts.push(format!("AS{}", asn)),
+            (None, _) => (),
+        }
+        if parts.is_empty() {
+            return "unknown".fmt(f);
+        }
+        parts.join(", ").fmt(f)
+    }
+}
+
+/// The fields of a GeoIP2 or GeoLite2 Country or City record used here.
+#[derive(Deserialize)]
+struct CountryRecord<'a> {
+    #[serde(borrow)]
+    country: Option<Country<'a>>,
+}
+
+#[derive(Deserialize)]
+struct Country<'a> {
+    iso_code: Option<&'a str>,
+}
+
+/// A GeoIP2 or GeoLite2 ASN record.
+#[derive(Deserialize)]
+struct AsnRecord<'a> {
+    autonomous_system_number: Option<u32>,
+    autonomous_system_organization: Option<&'a str>,
+}
+
+/// Country and ASN databases in the MaxMind DB format.
+#[derive(Default)]
+pub struct GeoIp {
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    /// Creates a lookup without any databases, finding nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the country database at `path`, e.g. GeoLite2-Country or GeoLite2-City.
+    pub fn country_database(mut self, path: impl AsRef<Path>) -> Result<Self, GeoIpError> {
+        self.country = Some(open(path.as_ref())?);
+        Ok(self)
+    }
+
+    /// Reads the ASN database at `path`, e.g. GeoLite2-ASN.
+    pub fn asn_database(mut self, path: impl AsRef<Path>) -> Result<Self, GeoIpError> {
+        self.asn = Some(open(path.as_ref())?);
+        Ok(self)
+    }
+
+    /// Looks up where `ip` is.
+    pub fn lookup(&self, ip: IpAddr) -> Result<Origin, GeoIpError> {
+        let mut origin = Origin::default();
+        if let Some(reader) = &self.country {
+            if let Some(record) = lookup::<CountryRecord<'_>>(reader, ip)? {
+                origin.country = record
+                    .country
+                    .and_then(|country| country.iso_code)
+                    .map(str::to_owned);
+            }
+        }
+        if let Some(reader) = &self.asn {
+            if let Some(record) = lookup::<AsnRecord<'_>>(reader, ip)? {
+                origin.asn = record.autonomous_system_number;
+                origin.as_organization = record.autonomous_system_organization.map(str::to_owned);
+            }
+        }
+        Ok(origin)
+    }
+
+    /// Looks up where the client of the event with the environment `env` connects from, by its
+    /// `untrusted_ip` or `untrusted_ip6`.
+    pub fn lookup_env(&self, env: &HashMap<CString, CString>) -> Result<Origin, GeoIpError> {
+        let ip = untrusted_ip(env)?.ok_or(EventArgsError::MissingEnv(env_keys::UNTRUSTED_IP6))?;
+        self.lookup(ip)
+    }
+
+    /// Looks up the origin of the address of `connection`, and fills in its `country` and
+    /// `asn`. Does nothing if the connection has no address.
+    #[cfg(feature = "policy")]
+    pub fn locate(&self, connection: &mut crate::policy::Connection) -> Result<(), GeoIpError> {
+        if let Some(ip) = connection.ip {
+            let origin = self.lookup(ip)?;
+            connection.country = origin.country;
+            connection.asn = origin.asn;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for GeoIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let database_type = |reader: &Option<Reader<Vec<u8>>>| {
+            reader
+                .as_ref()
+                .map(|reader| reader.metadata.database_type.clone())
+        };
+        f.debug_struct("GeoIp")
+            .field("country", &database_type(&self.country))
+            .field("asn", &database_type(&self.asn))
+            .finish()
+    }
+}
+
+fn open(path: &Path) -> Result<Reader<Vec<u8>>, GeoIpError> {
+    Reader::open_readfile(path).map_err(|e| GeoIpError::Open(path.to_owned(), e))
+}
+
+/// Looks up the record of `ip`. Returns `None` if the database has no record for it.
+fn lookup<'de, T: Deserialize<'de>>(
+    reader: &'de Reader<Vec<u8>>,
+    ip: IpAddr,
+) -> Result<Option<T>, GeoIpError> {
+    match reader.lookup(ip) {
+        Ok(record) => Ok(Some(record)),
+        Err(MaxMindDBError::AddressNotFoundError(_)) => Ok(None),
+        Err(e) => Err(GeoIpError::Lookup(ip, e)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{env, temp_path};
+    use std::fs;
+
+    fn string(s: &str) -> Vec<u8> {
+        // Lengths from 29 are given in the byte after the control byte.
+        let mut bytes = match s.len() {
+            len @ 0..=28 => vec![(2 << 5) | len as u8],
+            len => vec![(2 << 5) | 29, (len - 29) as u8],
+        };
+        bytes.extend(s.as_bytes());
+        bytes
+    }
+
+    fn map(len: u8) -> Vec<u8> {
+        vec![(7 << 5) | len]
+    }
+
+    fn uint16(value: u16) -> Vec<u8> {
+        let mut bytes = vec![(5 << 5) | 2];
+        bytes.extend(value.to_be_bytes());
+        bytes
+    }
+
+    fn uint32(value: u32) -> Vec<u8> {
+        let mut bytes = vec![(6 << 5) | 4];
+        bytes.extend(value.to_be_bytes());
+        bytes
+    }
+
+    /// An IPv4 database with one node, where `0.0.0.0/1` has a record with both country and ASN
+    /// data and `128.0.0.0/1` has none.
+    fn database() -> Vec<u8> {
+        let node_count = 1u32;
+        let mut db = Vec::new();
+        // The left record points to the start of the data section, the right one is empty.
+        db.extend(&(node_count + 16).to_be_bytes()[1..]);
+        db.extend(&node_count.to_be_bytes()[1..]);
+        db.extend([0; 16]);
+        for part in [
+            map(3),
+            string("country"),
+            map(1),
+            string("iso_code"),
+            string("SE"),
+            string("autonomous_system_number"),
+            uint32(64496),
+            string("autonomous_system_organization"),
+            string("Example Networks"),
+        ] {
+            db.extend(part);
+        }
+        db.extend(b"\xab\xcd\xefMaxMind.com");
+        for part in [
+            map(9),
+            string("node_count"),
+            uint32(node_count),
+            string("record_size"),
+            uint16(24),
+            string("ip_version"),
+            uint16(4),
+            string("database_type"),
+            string("Test"),
+            string("languages"),
+            vec![0, 4],
+            string("binary_format_major_version"),
+            uint16(2),
+            string("binary_format_minor_version"),
+            uint16(0),
+            string("build_epoch"),
+            vec![8, 2, 0, 0, 0, 0, 0, 0, 0, 0],
+            string("description"),
+            map(0),
+        ] {
+            db.extend(part);
+        }
+        db
+    }
+
+    #[test]
+    fn lookup() {
+        let path = temp_path("geoip").with_extension("mmdb");
+        fs::write(&path, database()).unwrap();
+        let geoip = GeoIp::new()
+            .country_database(&path)
+            .unwrap()
+            .asn_database(&path)
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let origin = geoip.lookup("10.0.0.1".parse().unwrap()).unwrap();
+        assert_eq!(Some("SE"), origin.country.as_deref());
+        assert_eq!(Some(64496), origin.asn);
+        assert_eq!("SE, AS64496 (Example Networks)", origin.to_string());
+
+        let env = env(&[("untrusted_ip", "192.0.2.1")]);
+        let origin = geoip.lookup_env(&env).unwrap();
+        assert_eq!(Origin::default(), origin);
+        assert_eq!("unknown", origin.to_string());
+
+        assert!(GeoIp::new().country_database(&path).is_err());
+
+        #[cfg(feature = "policy")]
+        {
+            let mut connection = crate::policy::Connection {
+                ip: Some("10.0.0.1".parse().unwrap()),
+                ..Default::default()
+            };
+            geoip.locate(&mut connection).unwrap();
+            assert_eq!(Some("SE"), connection.country.as_deref());
+            assert_eq!(Some(64496), connection.asn);
+        }
+    }
+}
@@ -0,0 +1,651 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Streams events to a gRPC service and waits for its decisions on authentication events.
+//! Requires the `grpc` feature.
+//!
+//! The protocol is in `proto/openvpn_plugin.proto` in the crate sources, for generating the
+//! service side in any language. [`GrpcClient`] opens one `StreamEvents` call and sends every event
+//! on it. [`notify`] only queues the event, while [`decide`] waits for the [`Decision`] of the
+//! service:
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, ffi::CString, sync::Arc};
+//! # use openvpn_plugin::{
+//! #     deferred_auth::DeferredAuthPool,
+//! #     grpc::GrpcClient,
+//! #     layer::Event,
+//! #     EventResult, EventType,
+//! # };
+//! # fn event(
+//! #     event: Event,
+//! #     client: &Arc<GrpcClient>,
+//! #     pool: &DeferredAuthPool,
+//! # ) -> Result<EventResult, Box<dyn std::error::Error>> {
+//! if event.event == EventType::AuthUserPassVerify {
+//!     let client = client.clone();
+//!     let env = event.env.clone();
+//!     Ok(pool.defer(&env, move || client.decide(&event).map(EventResult::from))?)
+//! } else {
+//!     client.notify(&event)?;
+//!     Ok(EventResult::Success)
+//! }
+//! # }
+//! ```
+//!
+//! The client runs the connection on a runtime of its own, so it works the same in plugins with
+//! synchronous and `async` callbacks. The call is opened again by the next event after it breaks.
+//! Decisions still outstanding when it breaks fail with [`GrpcError::Disconnected`].
+//!
+//! Arguments and environment values that are not valid UTF-8 have the invalid bytes replaced with
+//! U+FFFD. The values of the variables [`redact`] considers sensitive, such as `password`, are
+//! left out unless [`include_secrets`] is used.
+//!
+//! [`GrpcClient`]: struct.GrpcClient.html
+//! [`notify`]: struct.GrpcClient.html#method.notify
+//! [`decide`]: struct.GrpcClient.html#method.decide
+//! [`include_secrets`]: struct.GrpcClient.html#method.include_secrets
+//! [`Decision`]: enum.Decision.html
+//! [`GrpcError::Disconnected`]: enum.GrpcError.html#variant.Disconnected
+//! [`redact`]: ../redact/index.html
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    error::Error,
+    fmt,
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    runtime::{Builder, Runtime},
+    sync::{
+        mpsc::{self, error::TrySendError},
+        oneshot,
+    },
+};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{
+    codegen::http::uri::PathAndQuery,
+    transport::{Channel, Endpoint},
+    Request, Status,
+};
+use tonic_prost::ProstCodec;
+
+use crate::{layer::Event, logging, redact, EventResult};
+
+/// The default time to wait for the service to decide.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default number of events that can be queued while the service is slow.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+const STREAM_EVENTS: &str = "/openvpn_plugin.v1.PluginEvents/StreamEvents";
+
+/// The messages of `proto/openvpn_plugin.proto`.
+pub mod proto {
+    use std::collections::HashMap;
+
+    /// An event sent to the service.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Event {
+        /// Identifies the event within the stream.
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        /// The name OpenVPN uses for the event type, such as `"PLUGIN_UP"`.
+        #[prost(string, tag = "2")]
+        pub event_type: String,
+        /// The arguments OpenVPN gave with the event, starting with the path to the plugin.
+        #[prost(string, repeated, tag = "3")]
+        pub args: Vec<String>,
+        /// The environment OpenVPN gave with the event.
+        #[prost(map = "string, string", tag = "4")]
+        pub env: HashMap<String, String>,
+        /// Set when the plugin waits for a decision on this event.
+        #[prost(bool, tag = "5")]
+        pub decision_required: bool,
+    }
+
+    /// The answer of the service to an event with `decision_required` set.
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Decision {
+        /// The `id` of the event being answered.
+        #[prost(uint64, tag = "1")]
+        pub id: u64,
+        #[prost(enumeration = "Verdict", tag = "2")]
+        pub verdict: i32,
+        /// The reason given to the client when denying, if any.
+        #[prost(string, tag = "3")]
+        pub reason: String,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+    #[repr(i32)]
+    pub enum Verdict {
+        Unspecified = 0,
+        /// The event succeeds.
+        Allow = 1,
+        /// The event fails, with `reason` given to the client.
+        Deny = 2,
+        /// The service decides later, and writes the result to the `auth_control_file` itself.
+        Pending = 3,
+    }
+}
+
+/// The error type returned by [`GrpcClient`].
+///
+/// [`GrpcClient`]: struct.GrpcClient.html
+#[derive(Debug)]
+pub enum GrpcError {
+    /// The endpoint could not be parsed.
+    InvalidEndpoint(String, tonic::transport::Error),
+    /// The runtime of the client could not be created.
+    Runtime(io::Error),
+    /// The call failed. Only logged, events sent on a broken call fail with `Disconnected`.
+    Status(Status),
+    /// The call broke before the event was sent or answered.
+    Disconnected,
+    /// The queue of events waiting to be sent is full.
+    QueueFull,
+    /// The service did not decide within the timeout.
+    Timeout,
+    /// The service answered with a verdict that has no meaning in the protocol.
+    InvalidVerdict(i32),
+}
+
+impl fmt::Display for GrpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GrpcError::InvalidEndpoint(endpoint, _) => {
+                write!(f, "Invalid gRPC endpoint \"{}\"", endpoint)
+            }
+            GrpcError::Runtime(_) => f.write_str("Unable to create the runtime of the gRPC client"),
+            GrpcError::Status(_) => f.write_str("The gRPC event stream failed"),
+            GrpcError::Disconnected => f.write_str("Not connected to the gRPC service"),
+            GrpcError::QueueFull => f.write_str("Too many events waiting to be sent"),
+            GrpcError::Timeout => f.write_str("The gRPC service did not decide in time"),
+            GrpcError::InvalidVerdict(verdict) => {
+                write!(f, "Invalid verdict {} from the gRPC service", verdict)
+            }
+        }
+    }
+}
+
+impl Error for GrpcError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GrpcError::InvalidEndpoint(_, e) => Some(e),
+            GrpcError::Runtime(e) => Some(e),
+            GrpcError::Status(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The decision of the service on an event.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Decision {
+    /// The event succeeds.
+    Allow,
+    /// The event fails, with the reason to give the client, if any.
+    Deny(Option<String>),
+    /// The service decides later, and writes the result to the control file of the event itself.
+    Pending,
+}
+
+impl From<Decision> for EventResult {
+    fn from(decision: Decision) -> Self {
+        match decision {
+            Decision::Allow => EventResult::Success,
+            Decision::Deny(None) => EventResult::Failure,
+            Decision::Deny(Some(reason)) => EventResult::FailureWithReason(reason),
+            Decision::Pending => EventResult::Deferred,
+        }
+    }
+}
+
+impl TryFrom<proto::Decision> for Decision {
+    type Error = GrpcError;
+
+    fn try_from(decision: proto::Decision) -> Result<Self, GrpcError> {
+        match proto::Verdict::try_from(decision.verdict) {
+            Ok(proto::Verdict::Allow) => Ok(Decision::Allow),
+            Ok(proto::Verdict::Deny) if decision.reason.is_empty() => Ok(Decision::Deny(None)),
+            Ok(proto::Verdict::Deny) => Ok(Decision::Deny(Some(decision.reason))),
+            Ok(proto::Verdict::Pending) => Ok(Decision::Pending),
+            Ok(proto::Verdict::Unspecified) | Err(_) => {
+                Err(GrpcError::InvalidVerdict(decision.verdict))
+            }
+        }
+    }
+}
+
+
+/// The senders waiting for a decision on one call, `None` once the call has ended.
+type Pending = Arc<Mutex<Option<HashMap<u64, oneshot::Sender<proto::Decision>>>>>;
+
+/// The open call.
+struct Stream {
+    events: mpsc::Sender<proto::Event>,
+    pending: Pending,
+}
+
+/// A client streaming events to a gRPC service.
+pub struct GrpcClient {
+    endpoint: String,
+    channel: Channel,
+    runtime: Option<Runtime>,
+    timeout: Duration,
+    capacity: usize,
+    include_secrets: bool,
+    next_id: AtomicU64,
+    stream: Mutex<Option<Stream>>,
+}
+
+impl GrpcClient {
+    /// Creates a client for the service at `endpoint`, such as `http://127.0.0.1:50051`. Connects
+    /// when the first event is sent.
+    pub fn new(endpoint: &str) -> Result<Self, GrpcError> {
+        let parsed = Endpoint::from_shared(endpoint.to_owned())
+            .map_err(|e| GrpcError::InvalidEndpoint(endpoint.to_owned(), e))?;
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("grpc-client")
+            .enable_all()
+            .build()
+            .map_err(GrpcError::Runtime)?;
+        let channel = {
+            let _runtime = runtime.enter();
+            parsed.connect_timeout(DEFAULT_TIMEOUT).connect_lazy()
+        };
+        Ok(GrpcClient {
+            endpoint: endpoint.to_owned(),
+            channel,
+            runtime: Some(runtime),
+            timeout: DEFAULT_TIMEOUT,
+            capacity: DEFAULT_CAPACITY,
+            include_secrets: false,
+            next_id: AtomicU64::new(0),
+            stream: Mutex::new(None),
+        })
+    }
+
+    /// Sets the time to wait for the service to decide. `DEFAULT_TIMEOUT` by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the number of events that can be queued while the service is slow, after which sending
+    /// fails with `GrpcError::QueueFull`. `DEFAULT_CAPACITY` by default.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Sends the values of sensitive variables too, such as `password`, for services that verify
+    /// credentials.
+    pub fn include_secrets(mut self) -> Self {
+        self.include_secrets = true;
+        self
+    }
+
+    /// The endpoint of the service.
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Queues `event` to be sent to the service, without waiting for it to be sent.
+    pub fn notify(&self, event: &Event) -> Result<(), GrpcError> {
+        self.send(event, false).map(|_| ())
+    }
+
+    /// Sends `event` to the service and blocks until it decides. Must not be called from an async
+    /// runtime, use `decide_async` there.
+    pub fn decide(&self, event: &Event) -> Result<Decision, GrpcError> {
+        let decision = self.send(event, true)?;
+        self.runtime().block_on(decision)
+    }
+
+    /// Sends `event` to the service and waits for it to decide.
+    pub async fn decide_async(&self, event: &Event) -> Result<Decision, GrpcError> {
+        let decision = self.send(event, true)?;
+        self.runtime()
+            .spawn(decision)
+            .await
+            .unwrap_or(Err(GrpcError::Disconnected))
+    }
+
+    /// Queues `event`, and returns a future resolving to the decision on it.
+    fn send(
+        &self,
+        event: &Event,
+        decision_required: bool,
+    ) -> Result<impl Future<Output = Result<Decision, GrpcError>>, GrpcError> {
+        let message = self.message(event, decision_required);
+        let id = message.id;
+        let (events, pending) = self.stream();
+        let (decision_tx, decision_rx) = oneshot::channel();
+        if decision_required {
+            match &mut *pending.lock().unwrap_or_else(PoisonError::into_inner) {
+                Some(pending) => pending.insert(id, decision_tx),
+                None => return Err(GrpcError::Disconnected),
+            };
+        }
+        if let Err(e) = events.try_send(message) {
+            forget(&pending, id);
+            return Err(match e {
+                TrySendError::Full(_) => GrpcError::QueueFull,
+                TrySendError::Closed(_) => GrpcError::Disconnected,
+            });
+        }
+        let timeout = self.timeout;
+        Ok(async move {
+            let decision = tokio::time::timeout(timeout, decision_rx).await;
+            forget(&pending, id);
+            match decision {
+                Ok(Ok(decision)) => Decision::try_from(decision),
+                Ok(Err(_)) => Err(GrpcError::Disconnected),
+                Err(_) => Err(GrpcError::Timeout),
+            }
+        })
+    }
+
+    /// Returns the open call, opening a new one if there is none or it has ended.
+    fn stream(&self) -> (mpsc::Sender<proto::Event>, Pending) {
+        let mut stream = self.stream.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(open) = &*stream {
+            let ended = open
+                .pending
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .is_none();
+            if !ended {
+                return (open.events.clone(), open.pending.clone());
+            }
+        }
+        let (events, receiver) = mpsc::channel(self.capacity);
+        let pending = Arc::new(Mutex::new(Some(HashMap::new())));
+        self.runtime()
+            .spawn(run_stream(self.channel.clone(), receiver, pending.clone()));
+        *stream = Some(Stream {
+            events: events.clone(),
+            pending: pending.clone(),
+        });
+        (events, pending)
+    }
+
+    fn message(&self, event: &Event, decision_required: bool) -> proto::Event {
+        proto::Event {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            event_type: event.event.name().to_owned(),
+            args: event
+                .args
+                .iter()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+            env: event
+                .env
+                .iter()
+                .filter(|(key, _)| self.include_secrets || !redact::is_sensitive(key.as_bytes()))
+                .map(|(key, value)| {
+                    (
+                        key.to_string_lossy().into_owned(),
+                        value.to_string_lossy().into_owned(),
+                    )
+                })
+                .collect(),
+            decision_required,
+        }
+    }
+
+    fn runtime(&self) -> &Runtime {
+        self.runtime
+            .as_ref()
+            .expect("Runtime is only taken on drop")
+    }
+}
+
+impl Drop for GrpcClient {
+    fn drop(&mut self) {
+        // Dropping a runtime blocks, which panics if the client is dropped in an async context.
+        if let Some(runtime) = self.runtime.take() {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+impl fmt::Debug for GrpcClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GrpcClient")
+            .field("endpoint", &self.endpoint)
+            .field("timeout", &self.timeout)
+            .field("capacity", &self.capacity)
+            .field("include_secrets", &self.include_secrets)
+            .finish()
+    }
+}
+
+fn forget(pending: &Pending, id: u64) {
+    if let Some(pending) = &mut *pending.lock().unwrap_or_else(PoisonError::into_inner) {
+        pending.remove(&id);
+    }
+}
+
+/// Sends the events from `receiver` on one `StreamEvents` call, and hands the decisions to the
+/// senders in `pending`.
+async fn run_stream(channel: Channel, receiver: mpsc::Receiver<proto::Event>, pending: Pending) {
+    let result = async {
+        let mut client = tonic::client::Grpc::new(channel);
+        client
+            .ready()
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        let mut decisions: tonic::Streaming<proto::Decision> = client
+            .streaming(
+                Request::new(ReceiverStream::new(receiver)),
+                PathAndQuery::from_static(STREAM_EVENTS),
+                ProstCodec::default(),
+            )
+            .await?
+            .into_inner();
+        while let Some(decision) = decisions.message().await? {
+            let sender = pending
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .as_mut()
+                .and_then(|pending| pending.remove(&decision.id));
+            if let Some(sender) = sender {
+                let _ = sender.send(decision);
+            }
+        }
+        Ok::<_, Status>(())
+    }
+    .await;
+    // Fails the outstanding decisions, and makes the next event open a new call.
+    pending
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+    if let Err(status) = result {
+        logging::log_warning(&GrpcError::Status(status));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures;
+    use std::{
+        convert::Infallible,
+        ffi::CString,
+        task::{Context, Poll},
+    };
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::{
+        body::Body,
+        codegen::{http, BoxFuture, BoxStream},
+        server::{Grpc, NamedService, StreamingService},
+        transport::Server,
+        Response, Streaming,
+    };
+
+    /// Records the events it gets, and allows `alice` and denies everyone else.
+    #[derive(Clone, Default)]
+    struct PluginEvents(Arc<Mutex<Vec<proto::Event>>>);
+
+    impl NamedService for PluginEvents {
+        const NAME: &'static str = "openvpn_plugin.v1.PluginEvents";
+    }
+
+    impl tonic::codegen::Service<http::Request<Body>> for PluginEvents {
+        type Response = http::Response<Body>;
+        type Error = Infallible;
+        type Future = BoxFuture<Self::Response, Infallible>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+            let service = self.clone();
+            Box::pin(async move {
+                Ok(Grpc::new(ProstCodec::default())
+                    .streaming(service, request)
+                    .await)
+            })
+        }
+    }
+
+    impl StreamingService<proto::Event> for PluginEvents {
+        type Response = proto::Decision;
+        type ResponseStream = BoxStream<proto::Decision>;
+        type Future = BoxFuture<Response<Self::ResponseStream>, Status>;
+
+        fn call(&mut self, request: Request<Streaming<proto::Event>>) -> Self::Future {
+            let events = self.0.clone();
+            Box::pin(async move {
+                let mut stream = request.into_inner();
+                let (decisions, receiver) = mpsc::channel(16);
+                tokio::spawn(async move {
+                    while let Ok(Some(event)) = stream.message().await {
+                        if event.decision_required {
+                            let allowed =
+                                event.env.get("username").map(String::as_str) == Some("alice");
+                            let decision = proto::Decision {
+                                id: event.id,
+                                verdict: if allowed {
+                                    proto::Verdict::Allow as i32
+                                } else {
+                                    proto::Verdict::Deny as i32
+                                },
+                                reason: if allowed { "" } else { "Unknown user" }.to_owned(),
+                            };
+                            decisions.send(Ok(decision)).await.unwrap();
+                        }
+                        events.lock().unwrap().push(event);
+                    }
+                });
+                Ok(Response::new(
+                    Box::pin(ReceiverStream::new(receiver)) as Self::ResponseStream
+                ))
+            })
+        }
+    }
+
+    fn event(event: crate::EventType, env: &[(&str, &str)]) -> Event {
+        Event {
+            event,
+            args: vec![CString::new("/plugin.so").unwrap()],
+            env: fixtures::env(env),
+        }
+    }
+
+    #[test]
+    fn streams_events_and_decisions() {
+        let server = Builder::new_multi_thread().enable_all().build().unwrap();
+        let service = PluginEvents::default();
+        let listener = server
+            .block_on(tokio::net::TcpListener::bind("127.0.0.1:0"))
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        server.spawn(
+            Server::builder()
+                .add_service(service.clone())
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let client = GrpcClient::new(&format!("http://{}", addr)).unwrap();
+        client
+            .notify(&event(
+                crate::EventType::Up,
+                &[("dev", "tun0"), ("password", "hunter2")],
+            ))
+            .unwrap();
+        assert_eq!(
+            Decision::Allow,
+            client
+                .decide(&event(
+                    crate::EventType::AuthUserPassVerify,
+                    &[("username", "alice")]
+                ))
+                .unwrap()
+        );
+        assert_eq!(
+            Decision::Deny(Some("Unknown user".to_owned())),
+            client
+                .decide(&event(
+                    crate::EventType::AuthUserPassVerify,
+                    &[("username", "mallory")]
+                ))
+                .unwrap()
+        );
+
+        let events = service.0.lock().unwrap();
+        assert_eq!(3, events.len());
+        assert_eq!("PLUGIN_UP", events[0].event_type);
+        assert_eq!(vec!["/plugin.so"], events[0].args);
+        assert_eq!(Some("tun0"), events[0].env.get("dev").map(String::as_str));
+        assert_eq!(None, events[0].env.get("password"));
+        assert!(!events[0].decision_required);
+        assert!(events[1].decision_required);
+    }
+
+    #[test]
+    fn unreachable_service() {
+        let client = GrpcClient::new("http://127.0.0.1:1").unwrap();
+        assert!(matches!(
+            client.decide(&event(crate::EventType::AuthUserPassVerify, &[])),
+            Err(GrpcError::Disconnected)
+        ));
+        assert!(matches!(
+            GrpcClient::new("not a uri"),
+            Err(GrpcError::InvalidEndpoint(..))
+        ));
+    }
+
+    #[test]
+    fn invalid_verdict() {
+        let decision = proto::Decision {
+            id: 0,
+            verdict: proto::Verdict::Unspecified as i32,
+            reason: String::new(),
+        };
+        assert!(matches!(
+            Decision::try_from(decision),
+            Err(GrpcError::InvalidVerdict(0))
+        ));
+    }
+}
@@ -0,0 +1,362 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Verification of credentials against a local password file. Requires the `htpasswd` feature.
+//!
+//! The file has one `username:hash` line per user, like the files made by `htpasswd -B`. Empty
+//! lines and lines starting with `#` are skipped. Hashes must be bcrypt (`$2a$`, `$2b$` or `$2y$`)
+//! or Argon2 in the PHC string format (`$argon2id$`, `$argon2i$` or `$argon2d$`). Older and weaker
+//! formats are rejected when the file is loaded.
+//!
+//! ```text
+//! # Created with `htpasswd -nbB alice hunter2`.
+//! alice:$2y$05$5JSdsGZsJhRmKKTS2d0ueuEdJdy6D/oXKNFpeyjTOzPv0OeSEUJ06
+//! bob:$argon2id$v=19$m=19456,t=2,p=1$c2FsdHNhbHRzYWx0$1J3Xq0iI6R0cNs3m5AgdK5r5E9pZTt3tSpyAvh2m1Ao
+//! ```
+//!
+//! A [`PasswordFile`] keeps the parsed file in memory and checks whether it changed on every
+//! verification, so users can be added without restarting OpenVPN:
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, error::Error, ffi::CString};
+//! # use openvpn_plugin::{auth::Credentials, htpasswd::PasswordFile, EventResult};
+//! # fn event(env: HashMap<CString, CString>) -> Result<EventResult, Box<dyn Error>> {
+//! let users = PasswordFile::open("/etc/openvpn/users.htpasswd")?;
+//!
+//! let credentials = Credentials::from_env(&env)?;
+//! Ok(match users.verify(credentials.username(), credentials.password()) {
+//!     true => EventResult::Success,
+//!     false => EventResult::Failure,
+//! })
+//! # }
+//! ```
+//!
+//! The hashes are compared in constant time, and a password given for an unknown user is still
+//! hashed, so the time taken does not tell whether a user exists. Password hashes are slow by
+//! design, which blocks OpenVPN while they are computed. Verify on a [`DeferredAuthPool`] when
+//! many clients connect at once.
+//!
+//! [`PasswordFile`]: struct.PasswordFile.html
+//! [`DeferredAuthPool`]: ../deferred_auth/struct.DeferredAuthPool.html
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    time::SystemTime,
+};
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordVerifier},
+    Argon2,
+};
+
+use crate::logging;
+
+/// The error type returned by [`PasswordFile`].
+///
+/// [`PasswordFile`]: struct.PasswordFile.html
+#[derive(Debug)]
+pub enum PasswordFileError {
+    /// The file could not be read.
+    Io(PathBuf, io::Error),
+    /// The line with the given number, counted from 1, is not valid.
+    InvalidLine(PathBuf, usize, &'static str),
+}
+
+impl fmt::Display for PasswordFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PasswordFileError::Io(path, _) => {
+                write!(f, "Unable to read password file {}", path.display())
+            }
+            PasswordFileError::InvalidLine(path, line, reason) => {
+                write!(f, "{} line {}: {}", path.display(), line, reason)
+            }
+        }
+    }
+}
+
+impl Error for PasswordFileError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PasswordFileError::Io(_, e) => Some(e),
+            PasswordFileError::InvalidLine(..) => None,
+        }
+    }
+}
+
+
+/// A password hash in one of the supported formats.
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum Hash {
+    Bcrypt(String),
+    Argon2(String),
+}
+
+impl Hash {
+    fn parse(hash: &str) -> Result<Self, &'static str> {
+        if ["$2a$", "$2b$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            Ok(Hash::Bcrypt(hash.to_owned()))
+        } else if hash.starts_with("$argon2") {
+            match PasswordHash::new(hash) {
+                Ok(parsed) if parsed.hash.is_some() => (),
+                _ => return Err("Invalid Argon2 hash"),
+            }
+            Ok(Hash::Argon2(hash.to_owned()))
+        } else {
+            Err("Unsupported hash, only bcrypt and Argon2 are supported")
+        }
+    }
+
+    /// Returns whether `password` matches. Malformed hashes never match.
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Hash::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Hash::Argon2(hash) => match PasswordHash::new(hash) {
+                Ok(hash) => Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok(),
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+type Users = Arc<HashMap<String, Hash>>;
+
+/// What the file looked like when it was loaded, to tell when it changed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct Version {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+impl Version {
+    fn of(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        Ok(Version {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Loaded {
+    users: Users,
+    version: Version,
+}
+
+/// A password file, loaded into memory and loaded again when it changes. See the
+/// [module documentation] for the format.
+///
+/// [module documentation]: index.html
+#[derive(Debug)]
+pub struct PasswordFile {
+    path: PathBuf,
+    loaded: Mutex<Loaded>,
+}
+
+impl PasswordFile {
+    /// Loads the file at `path`.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, PasswordFileError> {
+        let path = path.into();
+        let loaded = load(&path)?;
+        Ok(PasswordFile {
+            path,
+            loaded: Mutex::new(loaded),
+        })
+    }
+
+    /// The path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns whether `password` is the password of `username`. Unknown users are not an error,
+    /// they are never verified.
+    ///
+    /// Loads the file again first if it changed since it was last loaded. If it can no longer be
+    /// read, or is no longer valid, the error is logged and the users loaded before are used.
+    pub fn verify(&self, username: &str, password: &str) -> bool {
+        let users = self.users();
+        match users.get(username) {
+            Some(hash) => hash.verify(password),
+            None => {
+                // Takes as long as verifying a known user with the same kind of hash.
+                if let Some(hash) = users.values().next() {
+                    hash.verify(password);
+                }
+                false
+            }
+        }
+    }
+
+    /// Loads the file again, even if it has not changed.
+    pub fn reload(&self) -> Result<(), PasswordFileError> {
+        let loaded = load(&self.path)?;
+        *self.lock() = loaded;
+        Ok(())
+    }
+
+    /// Returns whether the file has an entry for `username`.
+    pub fn contains(&self, username: &str) -> bool {
+        self.users().contains_key(username)
+    }
+
+    /// The number of users in the file.
+    pub fn len(&self) -> usize {
+        self.users().len()
+    }
+
+    /// Returns true if the file has no users.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The users, loaded again if the file changed.
+    fn users(&self) -> Users {
+        let mut loaded = self.lock();
+        let changed = match Version::of(&self.path) {
+            Ok(version) => version != loaded.version,
+            Err(_) => true,
+        };
+        if changed {
+            match load(&self.path) {
+                Ok(reloaded) => *loaded = reloaded,
+                Err(e) => logging::log_warning(&e),
+            }
+        }
+        loaded.users.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Loaded> {
+        self.loaded.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn load(path: &Path) -> Result<Loaded, PasswordFileError> {
+    let io_error = |e| PasswordFileError::Io(path.to_owned(), e);
+    // Taken before reading, so a change during the read is picked up by the next check.
+    let version = Version::of(path).map_err(io_error)?;
+    let contents = fs::read_to_string(path).map_err(io_error)?;
+    let users = parse(&contents)
+        .map_err(|(line, reason)| PasswordFileError::InvalidLine(path.to_owned(), line, reason))?;
+    Ok(Loaded {
+        users: Arc::new(users),
+        version,
+    })
+}
+
+/// Parses the lines of a password file. Returns the line number and reason of the first invalid
+/// line on error.
+fn parse(contents: &str) -> Result<HashMap<String, Hash>, (usize, &'static str)> {
+    let mut users = HashMap::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |reason| (index + 1, reason);
+        let (username, hash) = line
+            .split_once(':')
+            .ok_or_else(|| invalid("Expected username:hash"))?;
+        if username.is_empty() {
+            return Err(invalid("Empty username"));
+        }
+        let hash = Hash::parse(hash).map_err(invalid)?;
+        if users.insert(username.to_owned(), hash).is_some() {
+            return Err(invalid("Duplicate username"));
+        }
+    }
+    Ok(users)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::temp_path;
+    use argon2::{
+        password_hash::{PasswordHasher, SaltString},
+        Algorithm, Params, Version,
+    };
+
+    /// Hashes with the smallest parameters, to keep the tests fast.
+    fn argon2_hash(password: &str) -> String {
+        let salt = SaltString::encode_b64(b"saltsaltsaltsalt").unwrap();
+        let params = Params::new(8, 1, 1, None).unwrap();
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(password.as_bytes(), &salt)
+            .unwrap()
+            .to_string()
+    }
+
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = temp_path(&format!("htpasswd-{}", name));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn verifies() {
+        let contents = format!(
+            "# Users\nalice:{}\n\n  bob:{}  \n",
+            bcrypt::hash("hunter2", 4).unwrap(),
+            argon2_hash("correct horse")
+        );
+        let path = temp_file("verifies", &contents);
+        let users = PasswordFile::open(&path).unwrap();
+        assert_eq!(2, users.len());
+
+        assert!(users.verify("alice", "hunter2"));
+        assert!(!users.verify("alice", "hunter3"));
+        assert!(users.verify("bob", "correct horse"));
+        assert!(!users.verify("bob", "hunter2"));
+        assert!(!users.verify("carol", "hunter2"));
+
+        // Loaded again when changed.
+        fs::write(&path, format!("carol:{}\n", argon2_hash("hunter2"))).unwrap();
+        assert!(users.verify("carol", "hunter2"));
+        assert!(!users.contains("alice"));
+
+        // Keeps the users if the file becomes invalid.
+        fs::write(&path, "carol:plaintext\n").unwrap();
+        assert!(users.verify("carol", "hunter2"));
+        assert!(users.reload().is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_lines() {
+        let bcrypt = bcrypt::hash("hunter2", 4).unwrap();
+        assert_eq!(
+            Err((1, "Expected username:hash")),
+            parse("alice").map(|_| ())
+        );
+        assert_eq!(
+            Err((2, "Unsupported hash, only bcrypt and Argon2 are supported")),
+            parse("# comment\nalice:{SHA}fEqNCco3Yq9h5ZUglD3CZJT4lBs=").map(|_| ())
+        );
+        assert_eq!(
+            Err((2, "Duplicate username")),
+            parse(&format!("alice:{0}\nalice:{0}", bcrypt)).map(|_| ())
+        );
+        assert_eq!(
+            Err((1, "Invalid Argon2 hash")),
+            parse("alice:$argon2id$nope").map(|_| ())
+        );
+    }
+}
@@ -0,0 +1,517 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Verifies credentials with an HTTP(S) authentication service. Requires the `http-auth` feature.
+//!
+//! [`HttpAuthClient`] POSTs the credentials of an `EventType::AuthUserPassVerify` event as JSON
+//! to a URL and decides the authentication from the status of the response:
+//!
+//! ```json
+//! {
+//!     "username": "alice",
+//!     "password": "hunter2",
+//!     "common_name": "alice-laptop",
+//!     "untrusted_ip": "203.0.113.7",
+//!     "untrusted_port": 51820,
+//!     "peer_info": { "IV_VER": "2.6.8", "IV_PLAT": "linux" },
+//!     "auth_control_file": "/tmp/openvpn_acf_1234.tmp",
+//!     "auth_pending_file": "/tmp/openvpn_apf_1234.tmp"
+//! }
+//! ```
+//!
+//! * `200 OK` or `204 No Content` approves the authentication.
+//! * `202 Accepted` means the service decides later, for example after a second factor. The service
+//!   then takes over the deferred authentication: it writes `1` or `0` to `auth_control_file`
+//!   itself, and may write to `auth_pending_file` first, as described in [`auth`]. The files are
+//!   local paths, so this only works for a service on the same host, with permission to write them.
+//!   `202` is an error for requests without an `auth_control_file`.
+//! * `401 Unauthorized` or `403 Forbidden` denies it. A JSON body with a `reason` string is given
+//!   to the client as the reason.
+//! * Server errors and failed requests are retried. Any other status is an error.
+//!
+//! The request blocks, so run it on a [`DeferredAuthPool`], or use [`authenticate_async`] in an
+//! [`openvpn_plugin_async!`] plugin:
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, ffi::CString, io, time::Duration};
+//! # use openvpn_plugin::{
+//! #     deferred_auth::DeferredAuthPool,
+//! #     http_auth::{AuthRequest, HttpAuthClient},
+//! #     EventResult,
+//! # };
+//! # fn event(
+//! #     env: HashMap<CString, CString>,
+//! #     pool: &DeferredAuthPool,
+//! # ) -> Result<EventResult, Box<dyn std::error::Error>> {
+//! let client = HttpAuthClient::new("https://auth.example.com/openvpn")?
+//!     .timeout(Duration::from_secs(5))
+//!     .retries(2);
+//! let request = AuthRequest::from_env(&env)?;
+//! Ok(pool.defer(&env, move || {
+//!     client.authenticate(&request).map(EventResult::from)
+//! })?)
+//! # }
+//! ```
+//!
+//! [`HttpAuthClient`]: struct.HttpAuthClient.html
+//! [`auth`]: ../auth/index.html
+//! [`DeferredAuthPool`]: ../deferred_auth/struct.DeferredAuthPool.html
+//! [`authenticate_async`]: struct.HttpAuthClient.html#method.authenticate_async
+//! [`openvpn_plugin_async!`]: ../macro.openvpn_plugin_async.html
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    ffi::CString,
+    fmt,
+    net::IpAddr,
+    path::PathBuf,
+    sync::OnceLock,
+    thread,
+    time::Duration,
+};
+
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::{
+    auth::{Credentials, SecretString},
+    env_keys,
+    events::{Env, EventArgsError, PeerInfo},
+    EventResult,
+};
+
+/// The default time to wait for the service to answer one request.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The default number of times to retry a failed request.
+pub const DEFAULT_RETRIES: u32 = 2;
+
+/// The default time to wait before retrying a failed request.
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// The error type returned by [`HttpAuthClient`].
+///
+/// [`HttpAuthClient`]: struct.HttpAuthClient.html
+#[derive(Debug)]
+pub enum HttpAuthError {
+    /// The URL of the service could not be parsed.
+    InvalidUrl(String, String),
+    /// The HTTP client could not be created, or the request failed after all retries.
+    Request(reqwest::Error),
+    /// The service answered with a status that has no meaning in the protocol, or with a server
+    /// error after all retries.
+    UnexpectedStatus(StatusCode),
+}
+
+impl fmt::Display for HttpAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HttpAuthError::InvalidUrl(url, e) => write!(f, "Invalid URL \"{}\": {}", url, e),
+            HttpAuthError::Request(_) => {
+                f.write_str("Request to the authentication service failed")
+            }
+            HttpAuthError::UnexpectedStatus(status) => {
+                write!(
+                    f,
+                    "Unexpected status {} from the authentication service",
+                    status
+                )
+            }
+        }
+    }
+}
+
+impl Error for HttpAuthError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            HttpAuthError::Request(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+
+/// The decision of the authentication service.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum AuthDecision {
+    /// The credentials are valid.
+    Allow,
+    /// The credentials are not valid, with the reason to give the client, if any.
+    Deny(Option<String>),
+    /// The service decides later, and writes the result to the `auth_control_file` of the
+    /// request itself.
+    Pending,
+}
+
+impl From<AuthDecision> for EventResult {
+    fn from(decision: AuthDecision) -> Self {
+        match decision {
+            AuthDecision::Allow => EventResult::Success,
+            AuthDecision::Deny(None) => EventResult::Failure,
+            AuthDecision::Deny(Some(reason)) => EventResult::FailureWithReason(reason),
+            AuthDecision::Pending => EventResult::Deferred,
+        }
+    }
+}
+
+/// The body of the request sent to the authentication service.
+#[derive(Clone, Eq, PartialEq, Serialize)]
+pub struct AuthRequest {
+    /// The username the client gave.
+    pub username: String,
+    /// The password the client gave. Zeroed on drop when the `zeroize` feature is enabled.
+    #[serde(serialize_with = "serialize_secret")]
+    password: SecretString,
+    /// The common name of the client certificate, if any.
+    pub common_name: Option<String>,
+    /// The address the client connects from.
+    pub untrusted_ip: Option<IpAddr>,
+    /// The port the client connects from.
+    pub untrusted_port: Option<u16>,
+    /// The `IV_*` peer info variables the client sent.
+    pub peer_info: BTreeMap<String, String>,
+    /// The file the service writes the result to if it answers `202 Accepted`. Set when the
+    /// authentication can be deferred.
+    pub auth_control_file: Option<PathBuf>,
+    /// The file the service may write pending authentication information to before answering
+    /// `202 Accepted`. OpenVPN 2.5+.
+    pub auth_pending_file: Option<PathBuf>,
+}
+
+impl AuthRequest {
+    /// Collects the request from the environment of an `EventType::AuthUserPassVerify` event.
+    pub fn from_env(env: &HashMap<CString, CString>) -> Result<Self, EventArgsError> {
+        let credentials = Credentials::from_env(env)?;
+        let env_vars = Env(env);
+        let untrusted_ip = env_vars.ip_opt(env_keys::UNTRUSTED_IP, env_keys::UNTRUSTED_IP6)?;
+        Ok(AuthRequest {
+            username: credentials.username().to_owned(),
+            password: SecretString::from(credentials.password().to_owned()),
+            common_name: env_vars.string_opt(env_keys::COMMON_NAME)?,
+            untrusted_ip,
+            untrusted_port: env_vars.parse_opt(env_keys::UNTRUSTED_PORT)?,
+            peer_info: PeerInfo::from_env(env)?.values,
+            auth_control_file: env_vars
+                .string_opt(env_keys::AUTH_CONTROL_FILE)?
+                .map(PathBuf::from),
+            auth_pending_file: env_vars
+                .string_opt(env_keys::AUTH_PENDING_FILE)?
+                .map(PathBuf::from),
+        })
+    }
+
+    /// The password the client gave.
+    pub fn password(&self) -> &str {
+        &self.password
+    }
+}
+
+fn serialize_secret<S: Serializer>(
+    secret: &SecretString,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(secret)
+}
+
+impl fmt::Debug for AuthRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthRequest")
+            .field("username", &self.username)
+            .field("password", &"REDACTED")
+            .field("common_name", &self.common_name)
+            .field("untrusted_ip", &self.untrusted_ip)
+            .field("untrusted_port", &self.untrusted_port)
+            .field("peer_info", &self.peer_info)
+            .field("auth_control_file", &self.auth_control_file)
+            .field("auth_pending_file", &self.auth_pending_file)
+            .finish()
+    }
+}
+
+/// The optional body of a `401` or `403` response.
+#[derive(Deserialize)]
+struct DenyBody {
+    reason: Option<String>,
+}
+
+/// What to do with a response.
+enum Outcome {
+    Done(AuthDecision),
+    /// A denial, with the reason in the body.
+    Deny,
+    Retry(HttpAuthError),
+    Fail(HttpAuthError),
+}
+
+fn outcome(status: StatusCode, request: &AuthRequest) -> Outcome {
+    match status {
+        StatusCode::OK | StatusCode::NO_CONTENT => Outcome::Done(AuthDecision::Allow),
+        // Nothing would ever write the result of the authentication.
+        StatusCode::ACCEPTED if request.auth_control_file.is_none() => {
+            Outcome::Fail(HttpAuthError::UnexpectedStatus(status))
+        }
+        StatusCode::ACCEPTED => Outcome::Done(AuthDecision::Pending),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Outcome::Deny,
+        status if status.is_server_error() => {
+            Outcome::Retry(HttpAuthError::UnexpectedStatus(status))
+        }
+        status => Outcome::Fail(HttpAuthError::UnexpectedStatus(status)),
+    }
+}
+
+fn deny_reason(body: &[u8]) -> Option<String> {
+    serde_json::from_slice::<DenyBody>(body).ok()?.reason
+}
+
+
+/// A client for an HTTP(S) authentication service. HTTPS uses the Mozilla root certificates.
+pub struct HttpAuthClient {
+    url: Url,
+    timeout: Duration,
+    retries: u32,
+    retry_delay: Duration,
+    blocking: OnceLock<reqwest::blocking::Client>,
+    #[cfg(feature = "tokio")]
+    client: OnceLock<reqwest::Client>,
+}
+
+impl HttpAuthClient {
+    /// Creates a client for the service at `url`.
+    pub fn new(url: &str) -> Result<Self, HttpAuthError> {
+        let url = Url::parse(url)
+            .map_err(|e| HttpAuthError::InvalidUrl(url.to_owned(), e.to_string()))?;
+        Ok(HttpAuthClient {
+            url,
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            blocking: OnceLock::new(),
+            #[cfg(feature = "tokio")]
+            client: OnceLock::new(),
+        })
+    }
+
+    /// Sets the time to wait for the service to answer one request. `DEFAULT_TIMEOUT` by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the number of times to retry a request that failed or got a server error.
+    /// `DEFAULT_RETRIES` by default.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Sets the time to wait before retrying. `DEFAULT_RETRY_DELAY` by default.
+    pub fn retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// The URL of the service.
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Asks the service to verify `request`, blocking until it answers. Must not be called from
+    /// an async runtime, use `authenticate_async` there.
+    pub fn authenticate(&self, request: &AuthRequest) -> Result<AuthDecision, HttpAuthError> {
+        let client = match self.blocking.get() {
+            Some(client) => client,
+            None => {
+                let client = reqwest::blocking::Client::builder()
+                    .timeout(self.timeout)
+                    .build()
+                    .map_err(HttpAuthError::Request)?;
+                self.blocking.get_or_init(|| client)
+            }
+        };
+        let mut attempt = 0;
+        loop {
+            let error = match client.post(self.url.clone()).json(request).send() {
+                Ok(response) => match outcome(response.status(), request) {
+                    Outcome::Done(decision) => return Ok(decision),
+                    Outcome::Deny => {
+                        let body = response.bytes().unwrap_or_default();
+                        return Ok(AuthDecision::Deny(deny_reason(&body)));
+                    }
+                    Outcome::Fail(e) => return Err(e),
+                    Outcome::Retry(e) => e,
+                },
+                Err(e) => HttpAuthError::Request(e),
+            };
+            if attempt >= self.retries {
+                return Err(error);
+            }
+            attempt += 1;
+            thread::sleep(self.retry_delay);
+        }
+    }
+
+    /// Asks the service to verify `request`. Requires the `tokio` feature.
+    #[cfg(feature = "tokio")]
+    pub async fn authenticate_async(
+        &self,
+        request: &AuthRequest,
+    ) -> Result<AuthDecision, HttpAuthError> {
+        let client = match self.client.get() {
+            Some(client) => client,
+            None => {
+                let client = reqwest::Client::builder()
+                    .timeout(self.timeout)
+                    .build()
+                    .map_err(HttpAuthError::Request)?;
+                self.client.get_or_init(|| client)
+            }
+        };
+        let mut attempt = 0;
+        loop {
+            let error = match client.post(self.url.clone()).json(request).send().await {
+                Ok(response) => match outcome(response.status(), request) {
+                    Outcome::Done(decision) => return Ok(decision),
+                    Outcome::Deny => {
+                        let body = response.bytes().await.unwrap_or_default();
+                        return Ok(AuthDecision::Deny(deny_reason(&body)));
+                    }
+                    Outcome::Fail(e) => return Err(e),
+                    Outcome::Retry(e) => e,
+                },
+                Err(e) => HttpAuthError::Request(e),
+            };
+            if attempt >= self.retries {
+                return Err(error);
+            }
+            attempt += 1;
+            tokio::time::sleep(self.retry_delay).await;
+        }
+    }
+}
+
+impl fmt::Debug for HttpAuthClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpAuthClient")
+            .field("url", &self.url.as_str())
+            .field("timeout", &self.timeout)
+            .field("retries", &self.retries)
+            .field("retry_delay", &self.retry_delay)
+            .finish()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::env;
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+    };
+
+    /// Answers one request per response in `responses`, and returns the request bodies.
+    fn serve(responses: Vec<&'static str>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/auth", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for response in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(&stream);
+                let mut content_length = 0;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                    line.clear();
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                bodies.push(String::from_utf8(body).unwrap());
+                (&stream).write_all(response.as_bytes()).unwrap();
+            }
+            bodies
+        });
+        (url, server)
+    }
+
+    fn request() -> AuthRequest {
+        let env = env(&[
+            ("username", "alice"),
+            ("password", "hunter2"),
+            ("untrusted_ip", "203.0.113.7"),
+            ("untrusted_port", "51820"),
+            ("IV_PLAT", "linux"),
+            ("auth_control_file", "/tmp/acf"),
+        ]);
+        AuthRequest::from_env(&env).unwrap()
+    }
+
+    #[test]
+    fn decisions() {
+        let (url, server) = serve(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 403 Forbidden\r\nContent-Length: 24\r\n\r\n{\"reason\":\"Locked out\"}\n",
+            "HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n",
+        ]);
+        let client = HttpAuthClient::new(&url)
+            .unwrap()
+            .retry_delay(Duration::from_millis(1));
+        let request = request();
+
+        assert_eq!(AuthDecision::Allow, client.authenticate(&request).unwrap());
+        assert_eq!(
+            AuthDecision::Deny(Some("Locked out".to_owned())),
+            client.authenticate(&request).unwrap()
+        );
+        assert_eq!(
+            AuthDecision::Pending,
+            client.authenticate(&request).unwrap()
+        );
+        match client.authenticate(&request) {
+            Err(HttpAuthError::UnexpectedStatus(StatusCode::NOT_FOUND)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+
+        let bodies = server.join().unwrap();
+        assert_eq!(5, bodies.len());
+        assert_eq!(
+            serde_json::json!({
+                "username": "alice",
+                "password": "hunter2",
+                "common_name": null,
+                "untrusted_ip": "203.0.113.7",
+                "untrusted_port": 51820,
+                "peer_info": { "IV_PLAT": "linux" },
+                "auth_control_file": "/tmp/acf",
+                "auth_pending_file": null,
+            }),
+            serde_json::from_str::<serde_json::Value>(&bodies[0]).unwrap()
+        );
+        assert!(!format!("{:?}", request).contains("hunter2"));
+    }
+
+    #[test]
+    fn pending_requires_control_file() {
+        let (url, server) = serve(vec!["HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\n\r\n"]);
+        let client = HttpAuthClient::new(&url).unwrap();
+        let mut request = request();
+        request.auth_control_file = None;
+
+        match client.authenticate(&request) {
+            Err(HttpAuthError::UnexpectedStatus(StatusCode::ACCEPTED)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Sending events to a companion program over a Unix domain socket, or a named pipe on Windows.
+//! Requires the `ipc` feature.
+//!
+//! The plugin stays small and the companion does the actual work, in any language and without
+//! running inside the OpenVPN process. Each event is sent as one message, a 4 byte big endian
+//! length followed by that many bytes of JSON:
+//!
+//! ```json
+//! {"event":"Up","args":["/usr/lib/openvpn/plugin.so"],"env":{"dev":"tun0"}}
+//! ```
+//!
+//! Arguments and environment values that are not valid UTF-8 have the invalid bytes replaced with
+//! U+FFFD. The values of the variables [`redact`] considers sensitive, such as `password`, are
+//! left out unless `include_secrets` is used on the sender.
+//!
+//! On Unix a [`UnixSocketSender`] connects to a daemon listening on the socket. It connects on
+//! the first event and reconnects after the connection breaks, so the daemon can be started after
+//! OpenVPN and restarted at any time. While the daemon is unreachable events fail without
+//! waiting, and connecting is retried with exponential backoff.
+//!
+//! On Windows a [`NamedPipeSender`] instead creates the pipe, and GUI applications or services
+//! connect to it to receive the events. One client is connected at a time, and a new one can
+//! connect after it disconnects. Events fail without waiting while no client is connected. The
+//! security descriptor of the pipe decides who may connect, only `SYSTEM` and administrators by
+//! default.
+//!
+//! [`forwarder`] puts a sender behind a [`Forwarder`], so OpenVPN never waits for the
+//! companion:
+//!
+//! ```rust,no_run
+//! use std::{collections::HashMap, ffi::CString, io};
+//! use openvpn_plugin::{
+//!     forward::Forward,
+//!     ipc,
+//!     layer::{self, BoxService},
+//!     openvpn_plugin, EventResult, EventTypeSet,
+//! };
+//!
+//! fn open(
+//!     _args: Vec<CString>,
+//!     _env: HashMap<CString, CString>,
+//! ) -> Result<(EventTypeSet, BoxService), io::Error> {
+//!     #[cfg(unix)]
+//!     let forwarder = ipc::forwarder("/run/openvpn-daemon.sock", 1024)?;
+//!     #[cfg(windows)]
+//!     let forwarder = ipc::forwarder(r"\\.\pipe\openvpn-events", 1024)?;
+//!     let events = forwarder.events();
+//!     let service = Forward::new(
+//!         forwarder,
+//!         layer::service_fn(|_event| Ok::<_, io::Error>(EventResult::Success)),
+//!     );
+//!     Ok((events, Box::new(service)))
+//! }
+//!
+//! fn close(_handle: BoxService) {}
+//!
+//! openvpn_plugin!(crate::open, crate::close, layer::event, BoxService);
+//! # fn main() {}
+//! ```
+//!
+//! [`UnixSocketSender`]: struct.UnixSocketSender.html
+//! [`NamedPipeSender`]: struct.NamedPipeSender.html
+//! [`forwarder`]: fn.forwarder.html
+//! [`Forwarder`]: ../forward/struct.Forwarder.html
+//! [`redact`]: ../redact/index.html
+
+#[cfg(windows)]
+use std::ffi::OsString;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::{collections::BTreeMap, convert::TryFrom, error::Error, fmt, io, time::Duration};
+
+use serde::Serialize;
+
+use crate::{forward::Forwarder, layer::Event, redact, EventType};
+
+#[cfg(unix)]
+mod unix;
+#[cfg(unix)]
+pub use self::unix::UnixSocketSender;
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+pub use self::windows::{NamedPipeSender, DEFAULT_SECURITY_DESCRIPTOR};
+
+/// How long to wait before the first reconnect, by default.
+pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+
+/// The longest time to wait between reconnects, by default.
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How long writing a message may block, by default.
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Error sending an event.
+#[derive(Debug)]
+pub enum SendError {
+    /// Connecting failed recently, and the next attempt is not due until the given time has
+    /// passed.
+    Backoff(Duration),
+    /// Connecting to the socket, or creating the pipe, failed.
+    Connect(io::Error),
+    /// No client is connected to the pipe. Only on Windows.
+    NotConnected,
+    /// Writing the message failed. The connection is closed, and a new one made for the next
+    /// event.
+    Write(io::Error),
+    /// The message would be larger than the 4 byte length can describe.
+    TooLarge(usize),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Backoff(remaining) => write!(
+                f,
+                "Not connected to the daemon, retrying in {:?}",
+                remaining
+            ),
+            SendError::Connect(_) => f.write_str("Unable to connect to the daemon"),
+            SendError::NotConnected => f.write_str("No client is connected to the pipe"),
+            SendError::Write(_) => f.write_str("Unable to send the event to the daemon"),
+            SendError::TooLarge(len) => write!(f, "The event is too large to send, {} bytes", len),
+        }
+    }
+}
+
+impl Error for SendError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SendError::Connect(e) | SendError::Write(e) => Some(e),
+            SendError::Backoff(_) | SendError::NotConnected | SendError::TooLarge(_) => None,
+        }
+    }
+}
+
+
+/// Spawns a [`Forwarder`] sending its events to the daemon listening at `path`, with room for
+/// `capacity` events while the daemon is slow. Events that can't be sent are logged as warnings.
+///
+/// [`Forwarder`]: ../forward/struct.Forwarder.html
+#[cfg(unix)]
+pub fn forwarder(path: impl Into<PathBuf>, capacity: usize) -> io::Result<Forwarder> {
+    let mut sender = UnixSocketSender::new(path);
+    Forwarder::spawn(capacity, move |event| sender.send(&event))
+}
+
+/// Spawns a [`Forwarder`] sending its events to the client of the pipe `name`, with room for
+/// `capacity` events while the client is slow. Events that can't be sent are logged as warnings.
+///
+/// [`Forwarder`]: ../forward/struct.Forwarder.html
+#[cfg(windows)]
+pub fn forwarder(name: impl Into<OsString>, capacity: usize) -> io::Result<Forwarder> {
+    let mut sender = NamedPipeSender::new(name);
+    Forwarder::spawn(capacity, move |event| sender.send(&event))
+}
+
+/// The JSON of one event.
+#[derive(Serialize)]
+struct Message {
+    event: EventType,
+    args: Vec<String>,
+    env: BTreeMap<String, String>,
+}
+
+/// Encodes `event` as a length prefixed message.
+fn message(event: &Event, include_secrets: bool) -> Result<Vec<u8>, SendError> {
+    let env = event
+        .env
+        .iter()
+        .filter(|(key, _)| include_secrets || !redact::is_sensitive(key.as_bytes()))
+        .map(|(key, value)| {
+            (
+                key.to_string_lossy().into_owned(),
+                value.to_string_lossy().into_owned(),
+            )
+        })
+        .collect();
+    let message = Message {
+        event: event.event,
+        args: event
+            .args
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect(),
+        env,
+    };
+    // Serializing strings into a vector can't fail.
+    let json = serde_json::to_vec(&message).expect("Unable to serialize event");
+    let len = u32::try_from(json.len()).map_err(|_| SendError::TooLarge(json.len()))?;
+    let mut buf = Vec::with_capacity(4 + json.len());
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(&json);
+    Ok(buf)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::layer_event;
+
+    #[test]
+    fn encodes_messages() {
+        let event = layer_event(EventType::Down);
+
+        let encoded = message(&event, false).unwrap();
+        let json = br#"{"event":"Down","args":["/plugin.so"],"env":{"dev":"tun0"}}"#;
+        assert_eq!(&(json.len() as u32).to_be_bytes(), &encoded[..4]);
+        assert_eq!(&json[..], &encoded[4..]);
+        assert!(String::from_utf8(message(&event, true).unwrap())
+            .unwrap()
+            .contains(r#""password":"hunter2""#));
+    }
+}
@@ -0,0 +1,181 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    io::Write,
+    os::unix::net::UnixStream,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use super::{
+    message, SendError, DEFAULT_INITIAL_BACKOFF, DEFAULT_MAX_BACKOFF, DEFAULT_WRITE_TIMEOUT,
+};
+use crate::layer::Event;
+
+/// Sends events to a daemon listening on a Unix domain socket.
+#[derive(Debug)]
+pub struct UnixSocketSender {
+    path: PathBuf,
+    stream: Option<UnixStream>,
+    write_timeout: Duration,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    /// The current backoff, and when the next connect attempt is due.
+    backoff: Option<(Duration, Instant)>,
+    include_secrets: bool,
+}
+
+impl UnixSocketSender {
+    /// Creates a sender for the socket at `path`. Connects when the first event is sent.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        UnixSocketSender {
+            path: path.into(),
+            stream: None,
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            backoff: None,
+            include_secrets: false,
+        }
+    }
+
+    /// Sets how long writing a message may block before the connection is considered broken.
+    /// [`DEFAULT_WRITE_TIMEOUT`] by default.
+    ///
+    /// [`DEFAULT_WRITE_TIMEOUT`]: constant.DEFAULT_WRITE_TIMEOUT.html
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    /// Sets the wait before the first reconnect, doubled after every failed attempt up to `max`.
+    /// [`DEFAULT_INITIAL_BACKOFF`] and [`DEFAULT_MAX_BACKOFF`] by default.
+    ///
+    /// [`DEFAULT_INITIAL_BACKOFF`]: constant.DEFAULT_INITIAL_BACKOFF.html
+    /// [`DEFAULT_MAX_BACKOFF`]: constant.DEFAULT_MAX_BACKOFF.html
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
+    }
+
+    /// Sends the values of sensitive variables too, such as `password`, for daemons that
+    /// authenticate clients.
+    pub fn include_secrets(mut self) -> Self {
+        self.include_secrets = true;
+        self
+    }
+
+    /// The path of the socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns true if the sender has a connection to the daemon.
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Sends `event` as one message, connecting first if needed.
+    pub fn send(&mut self, event: &Event) -> Result<(), SendError> {
+        let message = message(event, self.include_secrets)?;
+        let stream = self.connect()?;
+        if let Err(e) = stream.write_all(&message) {
+            self.stream = None;
+            return Err(SendError::Write(e));
+        }
+        Ok(())
+    }
+
+    fn connect(&mut self) -> Result<&mut UnixStream, SendError> {
+        if self.stream.is_none() {
+            if let Some((_, next_attempt)) = self.backoff {
+                let now = Instant::now();
+                if now < next_attempt {
+                    return Err(SendError::Backoff(next_attempt - now));
+                }
+            }
+            let stream = UnixStream::connect(&self.path)
+                .and_then(|stream| {
+                    stream.set_write_timeout(Some(self.write_timeout))?;
+                    Ok(stream)
+                })
+                .map_err(|e| {
+                    let backoff = match self.backoff {
+                        Some((backoff, _)) => (backoff * 2).min(self.max_backoff),
+                        None => self.initial_backoff,
+                    };
+                    self.backoff = Some((backoff, Instant::now() + backoff));
+                    SendError::Connect(e)
+                })?;
+            self.backoff = None;
+            self.stream = Some(stream);
+        }
+        Ok(self.stream.as_mut().expect("Connected above"))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::{layer_event, temp_path};
+    use crate::EventType;
+    use std::{io::Read, os::unix::net::UnixListener};
+
+    fn read_message(stream: &mut UnixStream) -> serde_json::Value {
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut json = vec![0; u32::from_be_bytes(len) as usize];
+        stream.read_exact(&mut json).unwrap();
+        serde_json::from_slice(&json).unwrap()
+    }
+
+    #[test]
+    fn sends_messages() {
+        let path = temp_path("ipc");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+
+        let mut sender = UnixSocketSender::new(&path);
+        sender.send(&layer_event(EventType::Up)).unwrap();
+        assert!(sender.is_connected());
+        let (mut stream, _) = listener.accept().unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "event": "Up",
+                "args": ["/plugin.so"],
+                "env": {"dev": "tun0"},
+            }),
+            read_message(&mut stream)
+        );
+
+        let mut sender = UnixSocketSender::new(&path).include_secrets();
+        sender.send(&layer_event(EventType::Up)).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        assert_eq!("hunter2", read_message(&mut stream)["env"]["password"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn backs_off_when_unreachable() {
+        let path = temp_path("ipc-missing");
+        let mut sender =
+            UnixSocketSender::new(path).backoff(Duration::from_secs(60), Duration::from_secs(120));
+        assert!(matches!(
+            sender.send(&layer_event(EventType::Up)),
+            Err(SendError::Connect(_))
+        ));
+        assert!(matches!(
+            sender.send(&layer_event(EventType::Up)),
+            Err(SendError::Backoff(remaining)) if remaining > Duration::from_secs(50)
+        ));
+        assert!(!sender.is_connected());
+    }
+}
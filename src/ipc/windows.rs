@@ -0,0 +1,339 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    convert::TryFrom,
+    ffi::{OsStr, OsString},
+    fmt, io, iter, mem,
+    os::windows::ffi::OsStrExt,
+    ptr,
+    time::Duration,
+};
+
+use windows_sys::Win32::{
+    Foundation::{
+        CloseHandle, GetLastError, LocalFree, ERROR_IO_INCOMPLETE, ERROR_IO_PENDING, ERROR_NO_DATA,
+        ERROR_PIPE_CONNECTED, HANDLE, INVALID_HANDLE_VALUE, WAIT_TIMEOUT,
+    },
+    Security::{
+        Authorization::{ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1},
+        PSECURITY_DESCRIPTOR, SECURITY_ATTRIBUTES,
+    },
+    Storage::FileSystem::{
+        WriteFile, FILE_FLAG_FIRST_PIPE_INSTANCE, FILE_FLAG_OVERLAPPED, PIPE_ACCESS_OUTBOUND,
+    },
+    System::{
+        Pipes::{
+            ConnectNamedPipe, CreateNamedPipeW, DisconnectNamedPipe, PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_TYPE_BYTE, PIPE_WAIT,
+        },
+        Threading::CreateEventW,
+        IO::{CancelIoEx, GetOverlappedResult, GetOverlappedResultEx, OVERLAPPED},
+    },
+};
+
+use super::{message, SendError, DEFAULT_WRITE_TIMEOUT};
+use crate::layer::Event;
+
+/// The security descriptor of the pipe by default, in SDDL. Gives `SYSTEM` and the
+/// administrators full access, and no one else any.
+pub const DEFAULT_SECURITY_DESCRIPTOR: &str = "D:P(A;;GA;;;SY)(A;;GA;;;BA)";
+
+/// The size of the output buffer of the pipe.
+const BUFFER_SIZE: u32 = 64 * 1024;
+
+/// Sends events to the client connected to a named pipe the sender creates.
+pub struct NamedPipeSender {
+    name: OsString,
+    security_descriptor: SecurityDescriptor,
+    write_timeout: Duration,
+    include_secrets: bool,
+    pipe: Option<Pipe>,
+}
+
+impl NamedPipeSender {
+    /// Creates a sender for the pipe `name`, such as `\\.\pipe\openvpn-events`. Creates the pipe
+    /// when the first event is sent.
+    pub fn new(name: impl Into<OsString>) -> Self {
+        NamedPipeSender {
+            name: name.into(),
+            security_descriptor: SecurityDescriptor::from_sddl(DEFAULT_SECURITY_DESCRIPTOR)
+                .expect("Invalid default security descriptor"),
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
+            include_secrets: false,
+            pipe: None,
+        }
+    }
+
+    /// Sets the security descriptor of the pipe, in SDDL, deciding who may connect.
+    /// [`DEFAULT_SECURITY_DESCRIPTOR`] by default. Fails if `sddl` is not a valid security
+    /// descriptor.
+    ///
+    /// Clients need read access. For example `D:P(A;;GA;;;SY)(A;;GA;;;BA)(A;;GR;;;IU)` also lets
+    /// the interactively logged on users read the events.
+    ///
+    /// [`DEFAULT_SECURITY_DESCRIPTOR`]: constant.DEFAULT_SECURITY_DESCRIPTOR.html
+    pub fn security_descriptor(mut self, sddl: &str) -> io::Result<Self> {
+        self.security_descriptor = SecurityDescriptor::from_sddl(sddl)?;
+        Ok(self)
+    }
+
+    /// Sets how long writing a message may block before the client is considered gone.
+    /// [`DEFAULT_WRITE_TIMEOUT`] by default.
+    ///
+    /// [`DEFAULT_WRITE_TIMEOUT`]: constant.DEFAULT_WRITE_TIMEOUT.html
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    /// Sends the values of sensitive variables too, such as `password`, for clients that
+    /// authenticate users.
+    pub fn include_secrets(mut self) -> Self {
+        self.include_secrets = true;
+        self
+    }
+
+    /// The name of the pipe.
+    pub fn name(&self) -> &OsStr {
+        &self.name
+    }
+
+    /// Returns true if a client is connected to the pipe.
+    pub fn is_connected(&self) -> bool {
+        matches!(&self.pipe, Some(pipe) if pipe.state == State::Connected)
+    }
+
+    /// Sends `event` as one message to the connected client. Creates the pipe first if needed, and
+    /// fails with `SendError::NotConnected` if no client is connected.
+    pub fn send(&mut self, event: &Event) -> Result<(), SendError> {
+        let message = message(event, self.include_secrets)?;
+        let pipe = match &mut self.pipe {
+            Some(pipe) => pipe,
+            None => self.pipe.insert(
+                Pipe::create(&self.name, &self.security_descriptor).map_err(SendError::Connect)?,
+            ),
+        };
+        if !pipe.poll_connect().map_err(SendError::Connect)? {
+            return Err(SendError::NotConnected);
+        }
+        if let Err(e) = pipe.write(&message, self.write_timeout) {
+            pipe.disconnect();
+            return Err(SendError::Write(e));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for NamedPipeSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NamedPipeSender")
+            .field("name", &self.name)
+            .field("write_timeout", &self.write_timeout)
+            .field("include_secrets", &self.include_secrets)
+            .field("connected", &self.is_connected())
+            .finish()
+    }
+}
+
+/// A security descriptor converted from SDDL, freed on drop.
+struct SecurityDescriptor(PSECURITY_DESCRIPTOR);
+
+// The descriptor is owned and never changed after conversion.
+unsafe impl Send for SecurityDescriptor {}
+
+impl SecurityDescriptor {
+    fn from_sddl(sddl: &str) -> io::Result<Self> {
+        let sddl = wide(OsStr::new(sddl));
+        let mut descriptor = ptr::null_mut();
+        let converted = unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                sddl.as_ptr(),
+                SDDL_REVISION_1,
+                &mut descriptor,
+                ptr::null_mut(),
+            )
+        };
+        if converted == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SecurityDescriptor(descriptor))
+    }
+}
+
+impl Drop for SecurityDescriptor {
+    fn drop(&mut self) {
+        unsafe { LocalFree(self.0) };
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum State {
+    /// Not waiting for a client.
+    Idle,
+    /// Waiting for a client to connect.
+    Listening,
+    Connected,
+}
+
+/// An instance of the pipe, opened for overlapped I/O so connecting and writing never block for
+/// longer than the write timeout.
+struct Pipe {
+    handle: HANDLE,
+    /// Boxed since the system writes to it until the operation using it completes.
+    overlapped: Box<OVERLAPPED>,
+    state: State,
+}
+
+// The handles are owned, and only used through `&mut self`.
+unsafe impl Send for Pipe {}
+
+impl Pipe {
+    fn create(name: &OsStr, security_descriptor: &SecurityDescriptor) -> io::Result<Self> {
+        let name = wide(name);
+        let attributes = SECURITY_ATTRIBUTES {
+            nLength: mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+            lpSecurityDescriptor: security_descriptor.0,
+            bInheritHandle: 0,
+        };
+        // The first instance flag makes this fail if another process already created the pipe,
+        // instead of sharing a name it controls.
+        let handle = unsafe {
+            CreateNamedPipeW(
+                name.as_ptr(),
+                PIPE_ACCESS_OUTBOUND | FILE_FLAG_OVERLAPPED | FILE_FLAG_FIRST_PIPE_INSTANCE,
+                PIPE_TYPE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                1,
+                BUFFER_SIZE,
+                0,
+                0,
+                &attributes,
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        let mut pipe = Pipe {
+            handle,
+            overlapped: Box::default(),
+            state: State::Idle,
+        };
+        pipe.overlapped.hEvent = unsafe { CreateEventW(ptr::null(), 1, 0, ptr::null()) };
+        if pipe.overlapped.hEvent.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(pipe)
+    }
+
+    /// Returns true if a client is connected, starting to wait for one if not already waiting.
+    fn poll_connect(&mut self) -> io::Result<bool> {
+        match self.state {
+            State::Connected => return Ok(true),
+            State::Idle => {
+                if unsafe { ConnectNamedPipe(self.handle, &mut *self.overlapped) } != 0 {
+                    self.state = State::Connected;
+                    return Ok(true);
+                }
+                match unsafe { GetLastError() } {
+                    ERROR_PIPE_CONNECTED => self.state = State::Connected,
+                    ERROR_IO_PENDING => self.state = State::Listening,
+                    // A client connected and closed its end before this call.
+                    ERROR_NO_DATA => self.disconnect(),
+                    error => return Err(io::Error::from_raw_os_error(error as i32)),
+                }
+            }
+            State::Listening => {
+                let mut transferred = 0;
+                let connected = unsafe {
+                    GetOverlappedResult(self.handle, &*self.overlapped, &mut transferred, 0)
+                };
+                if connected != 0 {
+                    self.state = State::Connected;
+                } else {
+                    let error = unsafe { GetLastError() };
+                    if error != ERROR_IO_INCOMPLETE {
+                        self.state = State::Idle;
+                        return Err(io::Error::from_raw_os_error(error as i32));
+                    }
+                }
+            }
+        }
+        Ok(self.state == State::Connected)
+    }
+
+    fn write(&mut self, buf: &[u8], timeout: Duration) -> io::Result<()> {
+        let len = u32::try_from(buf.len()).map_err(|_| io::ErrorKind::InvalidInput)?;
+        let written = unsafe {
+            WriteFile(
+                self.handle,
+                buf.as_ptr(),
+                len,
+                ptr::null_mut(),
+                &mut *self.overlapped,
+            )
+        };
+        if written == 0 && unsafe { GetLastError() } != ERROR_IO_PENDING {
+            return Err(io::Error::last_os_error());
+        }
+        let timeout = u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX - 1);
+        let mut transferred = 0;
+        let completed = unsafe {
+            GetOverlappedResultEx(self.handle, &*self.overlapped, &mut transferred, timeout, 0)
+        };
+        if completed == 0 {
+            let error = io::Error::last_os_error();
+            if error.raw_os_error() == Some(WAIT_TIMEOUT as i32) {
+                self.cancel();
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            return Err(error);
+        }
+        if transferred != len {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        Ok(())
+    }
+
+    /// Drops the connected client, or stops waiting for one. The next event waits for a new
+    /// client.
+    fn disconnect(&mut self) {
+        if self.state == State::Listening {
+            self.cancel();
+        }
+        unsafe { DisconnectNamedPipe(self.handle) };
+        self.state = State::Idle;
+    }
+
+    /// Cancels the pending operation, and waits until the system no longer uses `overlapped`.
+    fn cancel(&mut self) {
+        let mut transferred = 0;
+        unsafe {
+            CancelIoEx(self.handle, &*self.overlapped);
+            GetOverlappedResult(self.handle, &*self.overlapped, &mut transferred, 1);
+        }
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        if self.state == State::Listening {
+            self.cancel();
+        }
+        unsafe {
+            if !self.overlapped.hEvent.is_null() {
+                CloseHandle(self.overlapped.hEvent);
+            }
+            CloseHandle(self.handle);
+        }
+    }
+}
+
+/// Encodes `s` as a nul terminated UTF-16 string.
+fn wide(s: &OsStr) -> Vec<u16> {
+    s.encode_wide().chain(iter::once(0)).collect()
+}
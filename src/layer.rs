@@ -0,0 +1,429 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Middleware wrapping the event handler, modelled after `tower`.
+//!
+//! The event handler of the plugin is a [`Service`], and a [`Layer`] wraps a service in another
+//! service. Logging, timing or access checks are then written once as a layer and stacked around
+//! any handler with [`ServiceBuilder`], instead of being interleaved in the event callback. The
+//! first layer added is the outermost one, so it sees the event first and the result last:
+//!
+//! ```rust,no_run
+//! use std::{collections::HashMap, convert::Infallible, ffi::CString, time::Instant};
+//! use openvpn_plugin::{
+//!     layer::{self, BoxService, LogLayer, ServiceBuilder},
+//!     openvpn_plugin, EventResult, EventType, EventTypeSet,
+//! };
+//!
+//! fn open(
+//!     _args: Vec<CString>,
+//!     _env: HashMap<CString, CString>,
+//! ) -> Result<(EventTypeSet, BoxService), Infallible> {
+//!     let events = EventType::Up | EventType::ClientConnectV2;
+//!     let service = ServiceBuilder::new()
+//!         .layer(LogLayer)
+//!         .layer(layer::around(|event, inner| {
+//!             let start = Instant::now();
+//!             let result = inner.call(event);
+//!             eprintln!("Handled in {:?}", start.elapsed());
+//!             result
+//!         }))
+//!         .service(layer::service_fn(|_event| Ok::<_, Infallible>(EventResult::Success)));
+//!     Ok((events, Box::new(service)))
+//! }
+//!
+//! fn close(_handle: BoxService) {}
+//!
+//! openvpn_plugin!(crate::open, crate::close, layer::event, BoxService);
+//! # fn main() {}
+//! ```
+//!
+//! An [`EventDispatcher`] is a service too, so handlers registered per event can be wrapped the
+//! same way.
+//!
+//! [`Service`]: trait.Service.html
+//! [`Layer`]: trait.Layer.html
+//! [`ServiceBuilder`]: struct.ServiceBuilder.html
+//! [`EventDispatcher`]: ../dispatch/struct.EventDispatcher.html
+
+use std::{collections::HashMap, error::Error, ffi::CString, fmt, panic::UnwindSafe};
+
+use crate::{dispatch::EventDispatcher, logging, redact::EnvDebug, EventResult, EventType};
+
+/// An event going through the services.
+#[derive(Debug, Clone)]
+pub struct Event {
+    /// The type of the event.
+    pub event: EventType,
+    /// The arguments OpenVPN gave with the event, starting with the path to the plugin.
+    pub args: Vec<CString>,
+    /// The environment OpenVPN gave with the event.
+    pub env: HashMap<CString, CString>,
+}
+
+/// Something handling events. Either the event handler of the plugin or a middleware wrapping
+/// another service.
+pub trait Service {
+    /// Handles `event`.
+    fn call(&mut self, event: Event) -> Result<EventResult, Box<dyn Error>>;
+}
+
+impl<S: Service + ?Sized> Service for Box<S> {
+    fn call(&mut self, event: Event) -> Result<EventResult, Box<dyn Error>> {
+        (**self).call(event)
+    }
+}
+
+impl<S> Service for EventDispatcher<S> {
+    fn call(&mut self, event: Event) -> Result<EventResult, Box<dyn Error>> {
+        self.dispatch(event.event, event.args, event.env)
+    }
+}
+
+/// A boxed service, for using a stack of services as the plugin handle without naming its type.
+pub type BoxService = Box<dyn Service + UnwindSafe>;
+
+/// An event callback calling the service in the handle. Give it as `$event_fn` to
+/// [`openvpn_plugin!`] when the handle is a service.
+///
+/// [`openvpn_plugin!`]: ../macro.openvpn_plugin.html
+pub fn event<S: Service>(
+    event: EventType,
+    args: Vec<CString>,
+    env: HashMap<CString, CString>,
+    handle: &mut S,
+) -> Result<EventResult, Box<dyn Error>> {
+    handle.call(Event { event, args, env })
+}
+
+/// A service calling a closure. Created with [`service_fn`].
+///
+/// [`service_fn`]: fn.service_fn.html
+#[derive(Clone, Copy)]
+pub struct ServiceFn<F>(F);
+
+/// Creates a service calling `f` for every event.
+pub fn service_fn<F, E>(f: F) -> ServiceFn<F>
+where
+    F: FnMut(Event) -> Result<EventResult, E>,
+    E: Into<Box<dyn Error>>,
+{
+    ServiceFn(f)
+}
+
+impl<F, E> Service for ServiceFn<F>
+where
+    F: FnMut(Event) -> Result<EventResult, E>,
+    E: Into<Box<dyn Error>>,
+{
+    fn call(&mut self, event: Event) -> Result<EventResult, Box<dyn Error>> {
+        (self.0)(event).map_err(Into::into)
+    }
+}
+
+impl<F> fmt::Debug for ServiceFn<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ServiceFn")
+    }
+}
+
+
+/// Wraps a service in another service.
+pub trait Layer<S> {
+    /// The wrapping service.
+    type Service;
+
+    /// Wraps `inner`.
+    fn layer(&self, inner: S) -> Self::Service;
+}
+
+/// A layer returning the service unchanged.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Identity;
+
+impl<S> Layer<S> for Identity {
+    type Service = S;
+
+    fn layer(&self, inner: S) -> S {
+        inner
+    }
+}
+
+/// Two layers, `Inner` wrapping the service first and `Outer` wrapping the result.
+#[derive(Debug, Clone, Copy)]
+pub struct Stack<Inner, Outer> {
+    inner: Inner,
+    outer: Outer,
+}
+
+impl<S, Inner, Outer> Layer<S> for Stack<Inner, Outer>
+where
+    Inner: Layer<S>,
+    Outer: Layer<Inner::Service>,
+{
+    type Service = Outer::Service;
+
+    fn layer(&self, service: S) -> Self::Service {
+        self.outer.layer(self.inner.layer(service))
+    }
+}
+
+/// Stacks layers around a service. The first layer added is the outermost.
+#[derive(Debug, Clone)]
+pub struct ServiceBuilder<L = Identity> {
+    layer: L,
+}
+
+impl ServiceBuilder {
+    /// Creates a builder without layers.
+    pub fn new() -> Self {
+        ServiceBuilder { layer: Identity }
+    }
+}
+
+impl Default for ServiceBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<L> ServiceBuilder<L> {
+    /// Adds `layer` inside the layers added so far.
+    pub fn layer<T>(self, layer: T) -> ServiceBuilder<Stack<T, L>> {
+        ServiceBuilder {
+            layer: Stack {
+                inner: layer,
+                outer: self.layer,
+            },
+        }
+    }
+
+    /// Wraps `service` in the layers.
+    pub fn service<S>(&self, service: S) -> L::Service
+    where
+        L: Layer<S>,
+    {
+        self.layer.layer(service)
+    }
+}
+
+
+/// A layer created from a closure turning the inner service into the wrapping service. Created
+/// with [`layer_fn`].
+///
+/// [`layer_fn`]: fn.layer_fn.html
+#[derive(Clone, Copy)]
+pub struct LayerFn<F>(F);
+
+/// Creates a layer calling `f` with the inner service.
+pub fn layer_fn<F>(f: F) -> LayerFn<F> {
+    LayerFn(f)
+}
+
+impl<F, S, Out> Layer<S> for LayerFn<F>
+where
+    F: Fn(S) -> Out,
+{
+    type Service = Out;
+
+    fn layer(&self, inner: S) -> Out {
+        (self.0)(inner)
+    }
+}
+
+impl<F> fmt::Debug for LayerFn<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LayerFn")
+    }
+}
+
+/// A layer running a closure around the inner service. Created with [`around`].
+///
+/// [`around`]: fn.around.html
+#[derive(Clone, Copy)]
+pub struct AroundLayer<F>(F);
+
+/// Creates a layer calling `f` with every event and the inner service. `f` decides whether and
+/// how to call the inner service, and can change the event before and the result after it.
+pub fn around<F>(f: F) -> AroundLayer<F>
+where
+    F: Fn(Event, &mut dyn Service) -> Result<EventResult, Box<dyn Error>>,
+{
+    AroundLayer(f)
+}
+
+impl<F: Clone, S> Layer<S> for AroundLayer<F> {
+    type Service = Around<F, S>;
+
+    fn layer(&self, inner: S) -> Around<F, S> {
+        Around {
+            f: self.0.clone(),
+            inner,
+        }
+    }
+}
+
+impl<F> fmt::Debug for AroundLayer<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AroundLayer")
+    }
+}
+
+/// The service of an [`AroundLayer`].
+///
+/// [`AroundLayer`]: struct.AroundLayer.html
+pub struct Around<F, S> {
+    f: F,
+    inner: S,
+}
+
+impl<F, S> Service for Around<F, S>
+where
+    F: Fn(Event, &mut dyn Service) -> Result<EventResult, Box<dyn Error>>,
+    S: Service,
+{
+    fn call(&mut self, event: Event) -> Result<EventResult, Box<dyn Error>> {
+        (self.f)(event, &mut self.inner)
+    }
+}
+
+impl<F, S: fmt::Debug> fmt::Debug for Around<F, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Around")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+
+/// A layer logging every event with its environment, and the result. The values of sensitive
+/// variables are redacted, see the [`redact`] module.
+///
+/// [`redact`]: ../redact/index.html
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogLayer;
+
+impl<S> Layer<S> for LogLayer {
+    type Service = Log<S>;
+
+    fn layer(&self, inner: S) -> Log<S> {
+        Log { inner }
+    }
+}
+
+/// The service of a [`LogLayer`].
+///
+/// [`LogLayer`]: struct.LogLayer.html
+#[derive(Debug)]
+pub struct Log<S> {
+    inner: S,
+}
+
+impl<S: Service> Service for Log<S> {
+    fn call(&mut self, event: Event) -> Result<EventResult, Box<dyn Error>> {
+        let event_type = event.event;
+        logging::log_info(&format!(
+            "Event {:?} with env {:?}",
+            event_type,
+            EnvDebug(&event.env)
+        ));
+        let result = self.inner.call(event);
+        match &result {
+            Ok(result) => logging::log_info(&format!("Event {:?}: {:?}", event_type, result)),
+            Err(e) => logging::log_info(&format!("Event {:?} failed: {}", event_type, e)),
+        }
+        result
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    /// A middleware recording in `calls` when it is entered and left.
+    fn recording(
+        name: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+    ) -> impl Fn(Event, &mut dyn Service) -> Result<EventResult, Box<dyn Error>> + Clone {
+        move |event, inner| {
+            calls.lock().unwrap().push(format!("{} before", name));
+            let result = inner.call(event);
+            calls.lock().unwrap().push(format!("{} after", name));
+            result
+        }
+    }
+
+    fn event(event: EventType) -> Event {
+        Event {
+            event,
+            args: Vec::new(),
+            env: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn layer_order() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let handler_calls = calls.clone();
+        let mut service = ServiceBuilder::new()
+            .layer(around(recording("outer", calls.clone())))
+            .layer(LogLayer)
+            .layer(around(recording("inner", calls.clone())))
+            .service(service_fn(move |_event| {
+                handler_calls.lock().unwrap().push("handler".to_owned());
+                Ok::<_, io::Error>(EventResult::Success)
+            }));
+
+        assert_eq!(
+            EventResult::Success,
+            service.call(event(EventType::Up)).unwrap()
+        );
+        assert_eq!(
+            vec![
+                "outer before",
+                "inner before",
+                "handler",
+                "inner after",
+                "outer after"
+            ],
+            *calls.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn short_circuit() {
+        let deny_connect = around(|event: Event, inner: &mut dyn Service| match event.event {
+            EventType::ClientConnectV2 => Ok(EventResult::Failure),
+            _ => inner.call(event),
+        });
+        let dispatcher = EventDispatcher::new(0)
+            .on(EventType::ClientConnectV2, |ctx| {
+                *ctx.state += 1;
+                Ok::<_, io::Error>(EventResult::Success)
+            })
+            .on(EventType::Up, |ctx| {
+                *ctx.state += 1;
+                Ok::<_, io::Error>(EventResult::Success)
+            });
+        let mut service: BoxService = Box::new(deny_connect.layer(dispatcher));
+
+        assert_eq!(
+            EventResult::Failure,
+            service.call(event(EventType::ClientConnectV2)).unwrap()
+        );
+        assert_eq!(
+            EventResult::Success,
+            super::event(EventType::Up, Vec::new(), HashMap::new(), &mut service).unwrap()
+        );
+    }
+}
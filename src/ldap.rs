@@ -0,0 +1,474 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Verifies credentials by binding to an LDAP or Active Directory server. Requires the `ldap`
+//! feature.
+//!
+//! An [`LdapAuthenticator`] finds the entry of the user in one of two ways:
+//!
+//! * [`direct_bind`] builds the DN of the entry from a template, such as
+//!   `uid={username},ou=people,dc=example,dc=com`, and binds with it.
+//! * [`search_bind`] binds with a service account, searches for the entry with a filter such as
+//!   `(sAMAccountName={username})` and binds with the DN it finds. Use it when the DN can't be
+//!   derived from the username.
+//!
+//! The groups of the user are read from the `memberOf` attribute of the entry. When groups are
+//! required with [`require_group`], users in none of them are denied. The groups of allowed users
+//! are returned in the [`LdapDecision`], for a policy layer to act on:
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, error::Error, ffi::CString};
+//! # use openvpn_plugin::{auth::Credentials, ldap::{LdapAuthenticator, LdapDecision}, EventResult};
+//! # fn event(env: HashMap<CString, CString>) -> Result<EventResult, Box<dyn Error>> {
+//! let ldap = LdapAuthenticator::search_bind(
+//!     "ldaps://ad.example.com",
+//!     "cn=openvpn,ou=services,dc=example,dc=com",
+//!     "service password",
+//!     "ou=people,dc=example,dc=com",
+//!     "(sAMAccountName={username})",
+//! )
+//! .require_group("cn=vpn-users,ou=groups,dc=example,dc=com");
+//!
+//! let credentials = Credentials::from_env(&env)?;
+//! let decision = ldap.authenticate(credentials.username(), credentials.password())?;
+//! if let LdapDecision::Allow { groups, .. } = &decision {
+//!     println!("{} is in {:?}", credentials.username(), groups);
+//! }
+//! Ok(decision.into())
+//! # }
+//! ```
+//!
+//! Every authentication opens a new connection and blocks until the server has answered, so run
+//! it on a [`DeferredAuthPool`]. It must not be called from within an async runtime.
+//!
+//! [`LdapAuthenticator`]: struct.LdapAuthenticator.html
+//! [`direct_bind`]: struct.LdapAuthenticator.html#method.direct_bind
+//! [`search_bind`]: struct.LdapAuthenticator.html#method.search_bind
+//! [`require_group`]: struct.LdapAuthenticator.html#method.require_group
+//! [`LdapDecision`]: enum.LdapDecision.html
+//! [`DeferredAuthPool`]: ../deferred_auth/struct.DeferredAuthPool.html
+
+use std::{error::Error, fmt, time::Duration};
+
+use ldap3::{dn_escape, ldap_escape, LdapConn, LdapConnSettings, LdapError, Scope, SearchEntry};
+
+use crate::EventResult;
+
+/// The default time to wait for the connection to the server and for every operation.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The attribute the groups of a user are read from by default.
+pub const DEFAULT_GROUP_ATTRIBUTE: &str = "memberOf";
+
+/// The LDAP result code of a bind with the wrong password or an unknown DN.
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// The placeholder replaced by the username in templates and filters.
+const USERNAME_PLACEHOLDER: &str = "{username}";
+
+/// The error type returned by [`LdapAuthenticator`].
+///
+/// [`LdapAuthenticator`]: struct.LdapAuthenticator.html
+#[derive(Debug)]
+pub enum LdapAuthError {
+    /// Connecting to the server failed, or it answered an operation with an error.
+    Ldap(LdapError),
+    /// The search for the user found more than one entry.
+    AmbiguousUser(usize),
+}
+
+impl fmt::Display for LdapAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LdapAuthError::Ldap(_) => f.write_str("LDAP operation failed"),
+            LdapAuthError::AmbiguousUser(count) => {
+                write!(f, "The search for the user found {} entries", count)
+            }
+        }
+    }
+}
+
+impl Error for LdapAuthError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            LdapAuthError::Ldap(e) => Some(e),
+            LdapAuthError::AmbiguousUser(_) => None,
+        }
+    }
+}
+
+impl From<LdapError> for LdapAuthError {
+    fn from(e: LdapError) -> Self {
+        LdapAuthError::Ldap(e)
+    }
+}
+
+
+/// Why a user was denied.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum DenyReason {
+    /// The server rejected the password, or it was empty.
+    InvalidCredentials,
+    /// The search found no entry for the username.
+    UnknownUser,
+    /// The user is in none of the required groups.
+    NotInGroup,
+}
+
+/// The result of an authentication.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum LdapDecision {
+    /// The credentials are valid.
+    Allow {
+        /// The DN of the entry of the user.
+        dn: String,
+        /// The values of the group attribute of the entry, usually group DNs.
+        groups: Vec<String>,
+    },
+    /// The user is denied.
+    Deny(DenyReason),
+}
+
+impl LdapDecision {
+    /// Returns whether the user is allowed.
+    pub fn is_allow(&self) -> bool {
+        matches!(self, LdapDecision::Allow { .. })
+    }
+}
+
+/// `Allow` gives `Success` and `Deny` gives `Failure`. The reason is not given to the client, so
+/// it can't tell unknown users from wrong passwords.
+impl From<LdapDecision> for EventResult {
+    fn from(decision: LdapDecision) -> Self {
+        match decision {
+            LdapDecision::Allow { .. } => EventResult::Success,
+            LdapDecision::Deny(_) => EventResult::Failure,
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Lookup {
+    Direct {
+        dn_template: String,
+    },
+    Search {
+        bind_dn: String,
+        bind_password: String,
+        base: String,
+        filter: String,
+    },
+}
+
+/// Authenticates users against an LDAP server. See the [module documentation] for an example.
+///
+/// [module documentation]: index.html
+#[derive(Clone)]
+pub struct LdapAuthenticator {
+    url: String,
+    lookup: Lookup,
+    timeout: Duration,
+    starttls: bool,
+    group_attribute: String,
+    required_groups: Vec<String>,
+}
+
+impl LdapAuthenticator {
+    /// Binds as the DN built by replacing `{username}` in `dn_template` with the escaped
+    /// username. `url` is an `ldap://` or `ldaps://` URL.
+    pub fn direct_bind(url: impl Into<String>, dn_template: impl Into<String>) -> Self {
+        Self::new(
+            url.into(),
+            Lookup::Direct {
+                dn_template: dn_template.into(),
+            },
+        )
+    }
+
+    /// Binds as `bind_dn` to search `base` and its subtree with `filter`, where `{username}` is
+    /// replaced with the escaped username, then binds as the single entry found.
+    pub fn search_bind(
+        url: impl Into<String>,
+        bind_dn: impl Into<String>,
+        bind_password: impl Into<String>,
+        base: impl Into<String>,
+        filter: impl Into<String>,
+    ) -> Self {
+        Self::new(
+            url.into(),
+            Lookup::Search {
+                bind_dn: bind_dn.into(),
+                bind_password: bind_password.into(),
+                base: base.into(),
+                filter: filter.into(),
+            },
+        )
+    }
+
+    fn new(url: String, lookup: Lookup) -> Self {
+        LdapAuthenticator {
+            url,
+            lookup,
+            timeout: DEFAULT_TIMEOUT,
+            starttls: false,
+            group_attribute: DEFAULT_GROUP_ATTRIBUTE.to_owned(),
+            required_groups: Vec::new(),
+        }
+    }
+
+    /// Sets the time to wait for the connection and for every operation. `DEFAULT_TIMEOUT` by
+    /// default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Upgrades `ldap://` connections to TLS with StartTLS before binding. Off by default.
+    pub fn starttls(mut self, starttls: bool) -> Self {
+        self.starttls = starttls;
+        self
+    }
+
+    /// Sets the attribute the groups of a user are read from. `DEFAULT_GROUP_ATTRIBUTE` by
+    /// default.
+    pub fn group_attribute(mut self, attribute: impl Into<String>) -> Self {
+        self.group_attribute = attribute.into();
+        self
+    }
+
+    /// Requires users to be in `group`. When called several times, users must be in at least one
+    /// of the groups. Groups are compared without regard to ASCII case.
+    pub fn require_group(mut self, group: impl Into<String>) -> Self {
+        self.required_groups.push(group.into());
+        self
+    }
+
+    /// Checks the credentials with the server. Empty passwords are denied without contacting the
+    /// server, since most servers treat a bind with one as an anonymous bind that succeeds.
+    pub fn authenticate(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<LdapDecision, LdapAuthError> {
+        if password.is_empty() {
+            return Ok(LdapDecision::Deny(DenyReason::InvalidCredentials));
+        }
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(self.timeout)
+            .set_starttls(self.starttls);
+        let mut conn = LdapConn::with_settings(settings, &self.url)?;
+        let result = self.authenticate_with(&mut conn, username, password);
+        let _ = conn.unbind();
+        result
+    }
+
+    fn authenticate_with(
+        &self,
+        conn: &mut LdapConn,
+        username: &str,
+        password: &str,
+    ) -> Result<LdapDecision, LdapAuthError> {
+        let entry = match &self.lookup {
+            Lookup::Direct { dn_template } => {
+                let dn = fill(dn_template, &dn_escape(username));
+                if !bind(conn, self.timeout, &dn, password)? {
+                    return Ok(LdapDecision::Deny(DenyReason::InvalidCredentials));
+                }
+                // Read as the user, who may read their own entry.
+                self.find(conn, &dn, Scope::Base, "(objectClass=*)")?
+                    .unwrap_or_else(|| SearchEntry {
+                        dn,
+                        attrs: Default::default(),
+                        bin_attrs: Default::default(),
+                    })
+            }
+            Lookup::Search {
+                bind_dn,
+                bind_password,
+                base,
+                filter,
+            } => {
+                conn.with_timeout(self.timeout)
+                    .simple_bind(bind_dn, bind_password)?
+                    .success()?;
+                let filter = fill(filter, &ldap_escape(username));
+                let entry = match self.find(conn, base, Scope::Subtree, &filter)? {
+                    Some(entry) => entry,
+                    None => return Ok(LdapDecision::Deny(DenyReason::UnknownUser)),
+                };
+                if !bind(conn, self.timeout, &entry.dn, password)? {
+                    return Ok(LdapDecision::Deny(DenyReason::InvalidCredentials));
+                }
+                entry
+            }
+        };
+        Ok(self.decide(entry))
+    }
+
+    /// Searches for the entry of the user. Returns `None` if there is none.
+    fn find(
+        &self,
+        conn: &mut LdapConn,
+        base: &str,
+        scope: Scope,
+        filter: &str,
+    ) -> Result<Option<SearchEntry>, LdapAuthError> {
+        let (mut entries, _) = conn
+            .with_timeout(self.timeout)
+            .search(base, scope, filter, vec![self.group_attribute.as_str()])?
+            .success()?;
+        match entries.len() {
+            0 => Ok(None),
+            1 => Ok(entries.pop().map(SearchEntry::construct)),
+            count => Err(LdapAuthError::AmbiguousUser(count)),
+        }
+    }
+
+    fn decide(&self, mut entry: SearchEntry) -> LdapDecision {
+        let groups = entry
+            .attrs
+            .drain()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&self.group_attribute))
+            .map(|(_, values)| values)
+            .unwrap_or_default();
+        let in_group = self.required_groups.is_empty()
+            || groups.iter().any(|group| {
+                self.required_groups
+                    .iter()
+                    .any(|required| required.eq_ignore_ascii_case(group))
+            });
+        if in_group {
+            LdapDecision::Allow {
+                dn: entry.dn,
+                groups,
+            }
+        } else {
+            LdapDecision::Deny(DenyReason::NotInGroup)
+        }
+    }
+}
+
+impl fmt::Debug for LdapAuthenticator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("LdapAuthenticator");
+        debug.field("url", &self.url);
+        match &self.lookup {
+            Lookup::Direct { dn_template } => debug.field("dn_template", dn_template),
+            Lookup::Search {
+                bind_dn,
+                base,
+                filter,
+                ..
+            } => debug
+                .field("bind_dn", bind_dn)
+                .field("bind_password", &"REDACTED")
+                .field("base", base)
+                .field("filter", filter),
+        };
+        debug
+            .field("timeout", &self.timeout)
+            .field("starttls", &self.starttls)
+            .field("group_attribute", &self.group_attribute)
+            .field("required_groups", &self.required_groups)
+            .finish()
+    }
+}
+
+/// Binds as `dn`. Returns false if the server rejected the credentials.
+fn bind(
+    conn: &mut LdapConn,
+    timeout: Duration,
+    dn: &str,
+    password: &str,
+) -> Result<bool, LdapAuthError> {
+    match conn
+        .with_timeout(timeout)
+        .simple_bind(dn, password)?
+        .success()
+    {
+        Ok(_) => Ok(true),
+        Err(LdapError::LdapResult { result }) if result.rc == INVALID_CREDENTIALS => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replaces `{username}` in `template` with the already escaped username.
+fn fill(template: &str, escaped_username: &str) -> String {
+    template.replace(USERNAME_PLACEHOLDER, escaped_username)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn entry(groups: &[&str]) -> SearchEntry {
+        let mut attrs = HashMap::new();
+        attrs.insert(
+            "memberof".to_owned(),
+            groups.iter().map(|group| group.to_string()).collect(),
+        );
+        SearchEntry {
+            dn: "uid=alice,dc=example".to_owned(),
+            attrs,
+            bin_attrs: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn escapes_username() {
+        assert_eq!(
+            "uid=a\\2cb\\3d,dc=example",
+            fill("uid={username},dc=example", &dn_escape("a,b="))
+        );
+        assert_eq!(
+            "(uid=\\2a\\29\\28uid=\\2a)",
+            fill("(uid={username})", &ldap_escape("*)(uid=*"))
+        );
+    }
+
+    #[test]
+    fn denies_empty_password_offline() {
+        // Nothing listens on the discard port, so contacting the server would fail.
+        let ldap = LdapAuthenticator::direct_bind("ldap://127.0.0.1:9", "uid={username}");
+        assert_eq!(
+            LdapDecision::Deny(DenyReason::InvalidCredentials),
+            ldap.authenticate("alice", "").unwrap()
+        );
+        assert!(ldap.authenticate("alice", "hunter2").is_err());
+    }
+
+    #[test]
+    fn checks_groups() {
+        let ldap = LdapAuthenticator::direct_bind("ldap://localhost", "uid={username}");
+        assert!(ldap.decide(entry(&[])).is_allow());
+
+        let ldap = ldap
+            .require_group("cn=admins,dc=example")
+            .require_group("CN=VPN,DC=example");
+        assert_eq!(
+            LdapDecision::Allow {
+                dn: "uid=alice,dc=example".to_owned(),
+                groups: vec![
+                    "cn=other,dc=example".to_owned(),
+                    "cn=vpn,dc=example".to_owned()
+                ],
+            },
+            ldap.decide(entry(&["cn=other,dc=example", "cn=vpn,dc=example"]))
+        );
+        assert_eq!(
+            LdapDecision::Deny(DenyReason::NotInGroup),
+            ldap.decide(entry(&["cn=other,dc=example"]))
+        );
+        assert_eq!(
+            EventResult::Failure,
+            LdapDecision::Deny(DenyReason::NotInGroup).into()
+        );
+    }
+}
@@ -83,7 +83,26 @@
 //! If [`catch_unwind`] captures a panic it will log it and then return
 //! [`OPENVPN_PLUGIN_FUNC_ERROR`] to OpenVPN.
 //!
-//! Note that this will only work for unwinding panics, not with `panic=abort`.
+//! With the `abort-on-panic` feature the panic is logged and then the whole OpenVPN process is
+//! aborted with [`std::process::abort`]. For deployments that rather have OpenVPN die loudly than
+//! keep running with a plugin in an unknown state.
+//!
+//! Note that this will only work for unwinding panics, not with `panic=abort`. A plugin built with
+//! `panic = "abort"` can enable the `no-unwind` feature to leave out the [`catch_unwind`] calls.
+//! The feature gives a compile error when not building with `panic = "abort"`, so it can't be
+//! enabled by mistake.
+//!
+//! With the `backtrace` feature the backtrace of the panic is logged as well. This installs a
+//! panic hook when the plugin is opened. Any hook installed before that is still called.
+//!
+//! Since the handle is moved into `$close_fn`, it must be [`UnwindSafe`]. Handles holding a `&mut`
+//! reference, or an `Rc`, `Arc` or `&` of a type with interior mutability such as `Cell` or
+//! `RefCell`, are not. Such a handle can be opted in with
+//! `openvpn_plugin!(open, close, event, unwind_unsafe Handle)`. The tradeoff is that a panic can
+//! leave the handle with broken invariants. The handle is used again by later events, and
+//! dropped after a panic in `$close_fn`, without anything checking that it is still consistent. A
+//! handle that is `UnwindSafe` is not better off in the event callback, which always gets the
+//! handle after earlier panics. The opt-in only removes the compile time check for `$close_fn`.
 //!
 //! ## Logging
 //!
@@ -92,21 +111,55 @@
 //! stderr. To activate logging with the `error!` macro in the `log` crate, build this crate with
 //! the `log` feature.
 //!
+//! With the `tracing` feature errors are instead logged as `tracing` events. The callbacks run in
+//! the spans `plugin_open`, `plugin_close` and `plugin_event`. The last one has the fields
+//! `event`, `common_name` and `untrusted_ip`, so errors can be traced back to the client causing
+//! them.
+//!
+//! With the `otel` feature the spans can be exported to an OpenTelemetry collector. See the
+//! [`otel`] module.
+//!
+//! The `json-log` feature takes precedence over both and prints every error and panic as a JSON
+//! object on a single line to stderr, for log collectors such as fluentd or vector:
+//!
+//! ```text
+//! {"causes":["timed out"],"event":"PLUGIN_AUTH_USER_PASS_VERIFY","level":"error","message":"Authentication failed","plugin":"my_plugin","timestamp":1700000000.5}
+//! ```
+//!
+//! With the `journald` feature errors and panics are instead sent to the systemd journal, with the
+//! fields `PRIORITY`, `PLUGIN` and `OPENVPN_EVENT`. The failures of a single event type on an
+//! OpenVPN server can then be shown with, for example:
+//!
+//! ```text
+//! journalctl -u openvpn-server@server OPENVPN_EVENT=PLUGIN_AUTH_USER_PASS_VERIFY
+//! ```
+//!
+//! If journald is not running, the messages are printed to stderr.
+//!
+//! Values of sensitive environment variables, such as `password`, are redacted from everything
+//! logged for an event. See the [`redact`] module for which variables are sensitive.
+//!
+//! [`redact`]: redact/index.html
+//! [`otel`]: otel/index.html
 //! [`openvpn_plugin!`]: macro.openvpn_plugin.html
 //! [`OPENVPN_PLUGIN_FUNC_ERROR`]: ffi/constant.OPENVPN_PLUGIN_FUNC_ERROR.html
 //! [`catch_unwind`]: https://doc.rust-lang.org/std/panic/fn.catch_unwind.html
+//! [`UnwindSafe`]: https://doc.rust-lang.org/std/panic/trait.UnwindSafe.html
 
 #[cfg(feature = "serde")]
 #[cfg_attr(feature = "serde", macro_use)]
 extern crate serde;
 
+#[cfg(all(feature = "no-unwind", not(panic = "abort")))]
+compile_error!("The `no-unwind` feature requires building with `panic = \"abort\"`");
+
 use std::{
     collections::HashMap,
     convert::TryFrom,
     ffi::CString,
-    fmt,
-    os::raw::{c_int, c_void},
-    panic,
+    os::raw::{c_char, c_int, c_uint, c_void},
+    panic, ptr,
+    time::Instant,
 };
 
 /// FFI types and functions used by the plugin to convert between the types OpenVPN pass and expect
@@ -123,7 +176,151 @@ mod types;
 /// Functions for logging errors that occur in plugins.
 mod logging;
 
-pub use crate::types::{EventResult, EventType};
+/// The error type of the errors this crate logs.
+mod error;
+
+pub mod env_keys;
+
+/// Helpers for plugins doing deferred authentication.
+pub mod auth;
+
+/// Helpers for plugins deferring client connect events.
+pub mod client_connect;
+
+pub mod client_context;
+
+pub mod ccd;
+
+/// Typed representations of the arguments and environment passed with each event.
+pub mod events;
+
+/// Parsing and building of the strings used by the OpenVPN challenge/response protocols.
+pub mod cr;
+
+mod base64;
+
+pub mod redact;
+
+/// Support for plugins that want to read the raw event data from OpenVPN on demand instead of
+/// having it all parsed up front. Used by the [`openvpn_plugin_raw!`] macro.
+///
+/// [`openvpn_plugin_raw!`]: macro.openvpn_plugin_raw.html
+pub mod raw;
+
+pub mod callbacks;
+
+pub mod dispatch;
+
+pub mod layer;
+
+pub mod error_policy;
+
+pub mod watchdog;
+
+pub mod workers;
+
+pub mod forward;
+
+pub mod deferred_auth;
+
+pub mod rate_limit;
+
+pub mod sessions;
+
+pub mod tunnel;
+
+pub mod plugin_path;
+
+pub mod args;
+
+pub mod from_env;
+
+pub mod prelude;
+
+#[cfg(feature = "metrics")]
+pub mod metrics;
+
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+
+#[cfg(feature = "statsd")]
+pub mod statsd;
+
+#[cfg(all(feature = "sd-notify", unix))]
+pub mod sd_notify;
+
+#[cfg(feature = "otel")]
+pub mod otel;
+
+#[cfg(any(
+    feature = "config-toml",
+    feature = "config-json",
+    feature = "config-yaml"
+))]
+pub mod config;
+
+#[cfg(feature = "auth-cache")]
+pub mod auth_cache;
+
+#[cfg(feature = "dbus")]
+pub mod dbus;
+
+#[cfg(feature = "firewall")]
+pub mod firewall;
+
+#[cfg(feature = "geoip")]
+pub mod geoip;
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
+#[cfg(feature = "htpasswd")]
+pub mod htpasswd;
+
+#[cfg(feature = "http-auth")]
+pub mod http_auth;
+
+#[cfg(all(feature = "ipc", any(unix, windows)))]
+pub mod ipc;
+
+#[cfg(feature = "ldap")]
+pub mod ldap;
+
+#[cfg(feature = "policy")]
+pub mod policy;
+
+#[cfg(feature = "radius")]
+pub mod radius;
+
+#[cfg(feature = "ipnet")]
+pub mod subnets;
+
+#[cfg(feature = "totp")]
+pub mod totp;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+#[cfg(feature = "recorder")]
+pub mod recorder;
+
+/// Support for plugins with `async` event callbacks executing on a tokio runtime. Used by the
+/// [`openvpn_plugin_async!`] macro.
+///
+/// [`openvpn_plugin_async!`]: macro.openvpn_plugin_async.html
+#[cfg(feature = "tokio")]
+#[doc(hidden)]
+pub mod async_plugin;
+
+pub use crate::{
+    callbacks::PluginHandle,
+    error::Error,
+    events::EventArgs,
+    types::{
+        events_from_bitmask, events_to_bitmask, EventResult, EventType, EventTypeSet,
+        EventTypeSetIter, ParseEventTypeError, UnknownEventBits,
+    },
+};
 
 /// The main part of this crate. The macro generates the public FFI functions that OpenVPN looks
 /// for in a shared library:
@@ -131,6 +328,9 @@ pub use crate::types::{EventResult, EventType};
 /// * `openvpn_plugin_open_v3` - Will call `$open_fn`
 /// * `openvpn_plugin_close_v1` - Will call `$close_fn`
 /// * `openvpn_plugin_func_v3` - Will call `$event_fn`
+/// * `openvpn_plugin_abort_v1` - Stops the background [`workers`] of the plugin
+///
+/// More or fewer functions can be exported with the `exports` option, see below.
 ///
 /// This macro must be called in the crate root of the crate you wish to become an OpenVPN plugin.
 /// That is because the FFI functions must be publicly exported from the shared library for OpenVPN
@@ -139,6 +339,25 @@ pub use crate::types::{EventResult, EventType};
 /// See the top level library documentation and the included `debug-plugin` crate for examples on
 /// how to use this macro.
 ///
+/// The callbacks can be paths to functions or closures. Closures can't capture anything, since
+/// the macro is called outside of any function, but small plugins don't need to define three
+/// top level functions:
+///
+/// ```rust,no_run
+/// # use openvpn_plugin::{openvpn_plugin, EventResult, EventType};
+/// # use std::io;
+/// openvpn_plugin!(
+///     |_args, _env| Ok::<_, io::Error>((vec![EventType::Up], ())),
+///     |_handle| {},
+///     |_event, _args, _env, _handle| Ok::<_, io::Error>(EventResult::Success),
+///     ()
+/// );
+/// # fn main() {}
+/// ```
+///
+/// The callbacks can also be methods on the handle type, by implementing [`PluginHandle`] for it
+/// and giving only the handle type to the macro: `openvpn_plugin!(Handle)`.
+///
 ///
 /// ## `$open_fn` - The plugin load callback
 ///
@@ -163,20 +382,26 @@ pub use crate::types::{EventResult, EventType};
 /// With `foo_open` substituted for a function name of your liking and `Handle` being the
 /// `$handle_ty` handle type you pass.
 ///
-/// The type of the error in the result from this function does not matter, as long as it implements
-/// `std::error::Error`. Any error returned is logged and then [`OPENVPN_PLUGIN_FUNC_ERROR`]
-/// is returned to OpenVPN, which indicates that the plugin failed to load and OpenVPN will abort
-/// and exit.
+/// The type of the error in the result from this function does not matter, as long as it converts
+/// into `Box<dyn std::error::Error>`. That is any type implementing `std::error::Error`, as well as
+/// `Box<dyn std::error::Error>` and `anyhow::Error`. Any error returned is logged and then
+/// [`OPENVPN_PLUGIN_FUNC_ERROR`] is returned to OpenVPN, which indicates that the plugin failed to
+/// load and OpenVPN will abort and exit.
 ///
 /// This function will be called by OpenVPN when the plugin is loaded, just as OpenVPN starts.
 ///
 /// This function has access to the arguments passed to the plugin and the initial
-/// OpenVPN environment. If the plugin deems the open operation successful it should return a vector
-/// with the events it wants to register for and the handle instance that the plugin can use to
-/// keep state (See further down for more on the handle).
+/// OpenVPN environment. If the plugin deems the open operation successful it should return the
+/// events it wants to register for and the handle instance that the plugin can use to keep state
+/// (See further down for more on the handle). The events can be given as a `Vec<EventType>` or
+/// anything else that implements `Into<EventTypeSet>`, such as an `EventTypeSet` built with
+/// `EventType::Up | EventType::Down`.
 ///
 /// The `openvpn_plugin::ffi::parse::{string_array_utf8, env_utf8}` functions can be used to try
-/// to convert the arguments and environment into Rust `String`s.
+/// to convert the arguments and environment into Rust `String`s, their `_lossy` variants to do
+/// so replacing invalid UTF-8, and
+/// `openvpn_plugin::ffi::parse::{string_array_os, env_os}` to convert them into `OsString`s
+/// without losing any bytes.
 ///
 ///
 /// ## `$close_fn` - The plugin unload callback
@@ -199,6 +424,20 @@ pub use crate::types::{EventResult, EventType};
 /// Here the plugin can do any cleaning up that is necessary. Since the handle is passed by value it
 /// will be dropped when this function returns.
 ///
+/// If the cleanup can fail, the function can instead return `Result<(), E>` with any error type
+/// the other callbacks can return. An error is logged. OpenVPN does not care about the outcome
+/// of unloading a plugin, so there is nothing more to report it to.
+///
+/// ```rust,no_run
+/// # use std::io;
+/// # struct Handle {}
+/// fn foo_close(handle: Handle) -> Result<(), io::Error> {
+///     /// ...
+/// #    unimplemented!();
+/// }
+/// # fn main() {}
+/// ```
+///
 ///
 /// ## `$event_fn` - The event callback function
 ///
@@ -225,11 +464,14 @@ pub use crate::types::{EventResult, EventType};
 /// With `foo_event` substituted for a function name of your liking and `Handle` being the
 /// `$handle_ty` handle type you pass.
 ///
-/// The type of the error in the result from this function does not matter, as long as it implements
-/// `std::error::Error`. Any error returned is logged and then [`OPENVPN_PLUGIN_FUNC_ERROR`]
-/// is returned to OpenVPN. [`OPENVPN_PLUGIN_FUNC_ERROR`] indicates different things on different
-/// events. In the case of an authentication request or TLS key verification it means that the
-/// request is denied and the connection is aborted.
+/// The type of the error in the result from this function does not matter, as long as it converts
+/// into `Box<dyn std::error::Error>`. That is any type implementing `std::error::Error`, as well as
+/// `Box<dyn std::error::Error>` and `anyhow::Error`. Any error returned is logged and then
+/// [`OPENVPN_PLUGIN_FUNC_ERROR`] is returned to OpenVPN. [`OPENVPN_PLUGIN_FUNC_ERROR`] indicates
+/// different things on different events. In the case of an authentication request or TLS key
+/// verification it means that the request is denied and the connection is aborted.
+/// Errors from events that only inform the plugin, such as `Up`, can be answered with success
+/// instead. See the [`error_policy`] module.
 ///
 /// This function is being called by OpenVPN each time one of the events that `$open_fn` registered
 /// for happens. This can for example be that a tunnel is established or that a client wants to
@@ -247,11 +489,179 @@ pub use crate::types::{EventResult, EventType};
 /// The handle instance is being dropped upon return from the `$close_fn` function just as the
 /// plugin is being unloaded.
 ///
+/// The handle type must be [`UnwindSafe`], since it is moved into `$close_fn` inside
+/// [`catch_unwind`]. Prefix it with `unwind_unsafe` to opt out of that check, for handles holding
+/// an `Rc<RefCell<_>>`, a `&mut` reference or similar. See the crate docs on panic handling for
+/// the tradeoff.
+///
+/// ```rust,no_run
+/// # use openvpn_plugin::{openvpn_plugin, EventResult, EventType};
+/// # use std::{cell::RefCell, collections::HashMap, ffi::CString, io, rc::Rc};
+/// pub struct Handle {
+///     state: Rc<RefCell<Vec<String>>>,
+/// }
+/// # fn open(_: Vec<CString>, _: HashMap<CString, CString>) -> Result<(Vec<EventType>, Handle), io::Error> {
+/// #     unimplemented!();
+/// # }
+/// # fn close(_: Handle) {}
+/// # fn event(_: EventType, _: Vec<CString>, _: HashMap<CString, CString>, _: &mut Handle) -> Result<EventResult, io::Error> {
+/// #     unimplemented!();
+/// # }
+///
+/// openvpn_plugin!(crate::open, crate::close, crate::event, unwind_unsafe Handle);
+/// # fn main() {}
+/// ```
+///
+///
+/// ## `before_event` and `after_event` - Optional event hooks
+///
+/// Functions called before and after `$event_fn` for every event, for auditing or timing all
+/// events in one place. They are given last, by name, with or without the other:
+///
+/// ```rust,no_run
+/// # use openvpn_plugin::{openvpn_plugin, EventResult, EventType};
+/// # use std::{collections::HashMap, error::Error, ffi::CString, io};
+/// # struct Handle {}
+/// # fn open(_: Vec<CString>, _: HashMap<CString, CString>) -> Result<(Vec<EventType>, Handle), io::Error> {
+/// #     unimplemented!();
+/// # }
+/// # fn close(_: Handle) {}
+/// # fn event(_: EventType, _: Vec<CString>, _: HashMap<CString, CString>, _: &mut Handle) -> Result<EventResult, io::Error> {
+/// #     unimplemented!();
+/// # }
+/// fn before_event(event: EventType) {
+///     eprintln!("Handling {:?}", event);
+/// }
+///
+/// fn after_event(event: EventType, result: Result<&EventResult, &dyn Error>) {
+///     eprintln!("Handled {:?}: {:?}", event, result.map_err(ToString::to_string));
+/// }
+///
+/// openvpn_plugin!(
+///     crate::open,
+///     crate::close,
+///     crate::event,
+///     Handle,
+///     before_event = crate::before_event,
+///     after_event = crate::after_event
+/// );
+/// # fn main() {}
+/// ```
+///
+/// `after_event` gets the result of `$event_fn` as returned, before it is turned into a return
+/// code for OpenVPN. It is not called if `$event_fn` panics.
+///
+///
+/// ## `exports` - Optional selection of the exported functions
+///
+/// The open, close and event functions of the v3 API are always exported. By default
+/// `openvpn_plugin_abort_v1` is too. Giving `exports` last, after any hooks, replaces the default
+/// with a list of:
+///
+/// * `abort` - `openvpn_plugin_abort_v1`.
+/// * `legacy` - `openvpn_plugin_open_v2` and `openvpn_plugin_func_v2`, for OpenVPN versions without
+///   the v3 API. They call the v3 functions, without the OpenVPN version in the open arguments.
+/// * `min_version(n)` - `openvpn_plugin_min_version_required_v1`, returning `n`. OpenVPN refuses to
+///   load the plugin if its plugin API version is older than `n`.
+/// * `client_context(T)` - `openvpn_plugin_client_constructor_v1` and
+///   `openvpn_plugin_client_destructor_v1`, keeping a `T: Default + Send` for each client. See the
+///   [`client_context`] module.
+///
+/// ```rust,no_run
+/// # use openvpn_plugin::{openvpn_plugin, EventResult, EventType};
+/// # use std::{collections::HashMap, ffi::CString, io};
+/// # struct Handle {}
+/// # fn open(_: Vec<CString>, _: HashMap<CString, CString>) -> Result<(Vec<EventType>, Handle), io::Error> {
+/// #     unimplemented!();
+/// # }
+/// # fn close(_: Handle) {}
+/// # fn event(_: EventType, _: Vec<CString>, _: HashMap<CString, CString>, _: &mut Handle) -> Result<EventResult, io::Error> {
+/// #     unimplemented!();
+/// # }
+/// openvpn_plugin!(
+///     crate::open,
+///     crate::close,
+///     crate::event,
+///     Handle,
+///     exports = [abort, min_version(3)]
+/// );
+/// # fn main() {}
+/// ```
+///
 /// [`EventType`]: types/enum.EventType.html
 /// [`OPENVPN_PLUGIN_FUNC_ERROR`]: ffi/constant.OPENVPN_PLUGIN_FUNC_ERROR.html
+/// [`error_policy`]: error_policy/index.html
+/// [`workers`]: workers/index.html
+/// [`client_context`]: client_context/index.html
+/// [`PluginHandle`]: callbacks/trait.PluginHandle.html
+/// [`UnwindSafe`]: https://doc.rust-lang.org/std/panic/trait.UnwindSafe.html
+/// [`catch_unwind`]: https://doc.rust-lang.org/std/panic/fn.catch_unwind.html
 #[macro_export]
 macro_rules! openvpn_plugin {
-    ($open_fn:path, $close_fn:path, $event_fn:path, $handle_ty:ty) => {
+    (
+        $open_fn:expr, $close_fn:expr, $event_fn:expr, unwind_unsafe $handle_ty:ty
+        $(, before_event = $before_event:expr)? $(, after_event = $after_event:expr)?
+        $(, exports = [$($export:tt)*])? $(,)?
+    ) => {
+        $crate::openvpn_plugin!(
+            @exports $open_fn, $close_fn, $event_fn, $handle_ty, openvpn_plugin_close_unwind_unsafe,
+            [$($before_event)?], [$($after_event)?], [$([$($export)*])?]
+        );
+    };
+    (
+        $open_fn:expr, $close_fn:expr, $event_fn:expr, $handle_ty:ty
+        $(, before_event = $before_event:expr)? $(, after_event = $after_event:expr)?
+        $(, exports = [$($export:tt)*])? $(,)?
+    ) => {
+        $crate::openvpn_plugin!(
+            @exports $open_fn, $close_fn, $event_fn, $handle_ty, openvpn_plugin_close,
+            [$($before_event)?], [$($after_event)?], [$([$($export)*])?]
+        );
+    };
+    (
+        unwind_unsafe $handle_ty:ty
+        $(, before_event = $before_event:expr)? $(, after_event = $after_event:expr)?
+        $(, exports = [$($export:tt)*])? $(,)?
+    ) => {
+        $crate::openvpn_plugin!(
+            @handle $handle_ty, unwind_unsafe, [$($before_event)?], [$($after_event)?],
+            [$([$($export)*])?]
+        );
+    };
+    (
+        $handle_ty:ty
+        $(, before_event = $before_event:expr)? $(, after_event = $after_event:expr)?
+        $(, exports = [$($export:tt)*])? $(,)?
+    ) => {
+        $crate::openvpn_plugin!(
+            @handle $handle_ty,, [$($before_event)?], [$($after_event)?], [$([$($export)*])?]
+        );
+    };
+    (
+        @handle $handle_ty:ty, $($unwind_unsafe:ident)?,
+        [$($before_event:expr)?], [$($after_event:expr)?], [$([$($export:tt)*])?]
+    ) => {
+        $crate::openvpn_plugin!(
+            <$handle_ty as $crate::PluginHandle>::open,
+            <$handle_ty as $crate::PluginHandle>::close,
+            |event, args, env, handle: &mut $handle_ty| {
+                <$handle_ty as $crate::PluginHandle>::event(handle, event, args, env)
+            },
+            $($unwind_unsafe)? $handle_ty
+            $(, before_event = $before_event)? $(, after_event = $after_event)?
+            $(, exports = [$($export)*])?
+        );
+    };
+    (@hook) => {
+        ::std::option::Option::None
+    };
+    (@hook $hook:expr) => {
+        ::std::option::Option::Some($hook)
+    };
+    (
+        @exports $open_fn:expr, $close_fn:expr, $event_fn:expr, $handle_ty:ty, $close_helper:ident,
+        [$($before_event:expr)?], [$($after_event:expr)?], [$($exports:tt)?]
+    ) => {
         /// Called by OpenVPN when the plugin is first loaded on OpenVPN start.
         /// Used to register which events the plugin wants to listen to (`args.type_mask`). Can
         /// also set an arbitrary pointer inside `args.handle` that will then be passed to all
@@ -261,18 +671,22 @@ macro_rules! openvpn_plugin {
         /// `openvpn_plugin` macro.
         #[no_mangle]
         pub unsafe extern "C" fn openvpn_plugin_open_v3(
-            _version: ::std::os::raw::c_int,
+            version: ::std::os::raw::c_int,
             args: *const $crate::ffi::openvpn_plugin_args_open_in,
             retptr: *mut $crate::ffi::openvpn_plugin_args_open_return,
         ) -> ::std::os::raw::c_int {
-            unsafe { $crate::openvpn_plugin_open::<$handle_ty, _, _>(args, retptr, $open_fn) }
+            let open_fn: $crate::callbacks::OpenFn<_, $handle_ty, _> = $open_fn;
+            unsafe {
+                $crate::openvpn_plugin_open::<$handle_ty, _, _, _>(version, args, retptr, open_fn)
+            }
         }
 
         /// Called by OpenVPN when the plugin is unloaded, just before OpenVPN shuts down.
         /// Will call the function given as `$event_fn` to the `openvpn_plugin` macro.
         #[no_mangle]
         pub unsafe extern "C" fn openvpn_plugin_close_v1(handle: *const ::std::os::raw::c_void) {
-            unsafe { $crate::openvpn_plugin_close::<$handle_ty, _>(handle, $close_fn) }
+            let close_fn: $crate::callbacks::CloseFn<$handle_ty, _> = $close_fn;
+            unsafe { $crate::$close_helper::<$handle_ty, _, _>(handle, close_fn) }
         }
 
         /// Called by OpenVPN for each `OPENVPN_PLUGIN_*` event that it registered for in
@@ -282,11 +696,99 @@ macro_rules! openvpn_plugin {
         /// `openvpn_plugin` macro.
         #[no_mangle]
         pub unsafe extern "C" fn openvpn_plugin_func_v3(
-            _version: ::std::os::raw::c_int,
+            version: ::std::os::raw::c_int,
             args: *const $crate::ffi::openvpn_plugin_args_func_in,
             _retptr: *const $crate::ffi::openvpn_plugin_args_func_return,
         ) -> ::std::os::raw::c_int {
-            unsafe { $crate::openvpn_plugin_func::<$handle_ty, _, _>(args, $event_fn) }
+            let event_fn: $crate::callbacks::EventFn<$handle_ty, _> = $event_fn;
+            let hooks = $crate::callbacks::EventHooks {
+                before_event: $crate::openvpn_plugin!(@hook $($before_event)?),
+                after_event: $crate::openvpn_plugin!(@hook $($after_event)?),
+            };
+            let event_fn = move |event, args, env, handle: &mut $handle_ty| {
+                hooks.call(event_fn, event, args, env, handle)
+            };
+            unsafe { $crate::openvpn_plugin_func::<$handle_ty, _, _>(version, args, event_fn) }
+        }
+
+        $crate::openvpn_plugin!(@select_exports [$($exports)?]);
+    };
+    // Without `exports`, the abort function is exported as well.
+    (@select_exports []) => {
+        $crate::openvpn_plugin!(@export abort);
+    };
+    (@select_exports [[$($name:ident $(($($arg:tt)*))?),* $(,)?]]) => {
+        $($crate::openvpn_plugin!(@export $name $(($($arg)*))?);)*
+    };
+    (@export abort) => {
+        /// Called by OpenVPN instead of `openvpn_plugin_close_v1` when it exits because of a
+        /// fatal error. Stops the background workers of the plugin.
+        #[no_mangle]
+        pub unsafe extern "C" fn openvpn_plugin_abort_v1(_handle: *const ::std::os::raw::c_void) {
+            $crate::openvpn_plugin_abort()
+        }
+    };
+    (@export legacy) => {
+        /// The v2 plugin open function, for OpenVPN versions without the v3 API. Calls
+        /// `openvpn_plugin_open_v3`.
+        #[no_mangle]
+        pub unsafe extern "C" fn openvpn_plugin_open_v2(
+            type_mask: *mut ::std::os::raw::c_uint,
+            argv: *const *const ::std::os::raw::c_char,
+            envp: *const *const ::std::os::raw::c_char,
+            _return_list: *mut ::std::os::raw::c_void,
+        ) -> *mut ::std::os::raw::c_void {
+            unsafe { $crate::openvpn_plugin_open_v2(type_mask, argv, envp, openvpn_plugin_open_v3) }
+        }
+
+        /// The v2 plugin event function, for OpenVPN versions without the v3 API. Calls
+        /// `openvpn_plugin_func_v3`.
+        #[no_mangle]
+        pub unsafe extern "C" fn openvpn_plugin_func_v2(
+            handle: *const ::std::os::raw::c_void,
+            event_type: ::std::os::raw::c_int,
+            argv: *const *const ::std::os::raw::c_char,
+            envp: *const *const ::std::os::raw::c_char,
+            per_client_context: *const ::std::os::raw::c_void,
+            _return_list: *mut ::std::os::raw::c_void,
+        ) -> ::std::os::raw::c_int {
+            unsafe {
+                $crate::openvpn_plugin_func_v2(
+                    handle,
+                    event_type,
+                    argv,
+                    envp,
+                    per_client_context,
+                    openvpn_plugin_func_v3,
+                )
+            }
+        }
+    };
+    (@export min_version($version:expr)) => {
+        /// Called by OpenVPN before opening the plugin. OpenVPN refuses to load the plugin if its
+        /// plugin API is older than the returned version.
+        #[no_mangle]
+        pub extern "C" fn openvpn_plugin_min_version_required_v1() -> ::std::os::raw::c_int {
+            $version
+        }
+    };
+    (@export client_context($context_ty:ty)) => {
+        /// Called by OpenVPN when a client connects. Creates the context OpenVPN passes with the
+        /// events of the client.
+        #[no_mangle]
+        pub unsafe extern "C" fn openvpn_plugin_client_constructor_v1(
+            _handle: *const ::std::os::raw::c_void,
+        ) -> *mut ::std::os::raw::c_void {
+            $crate::client_context::construct::<$context_ty>()
+        }
+
+        /// Called by OpenVPN when a client is gone. Drops its context.
+        #[no_mangle]
+        pub unsafe extern "C" fn openvpn_plugin_client_destructor_v1(
+            _handle: *const ::std::os::raw::c_void,
+            per_client_context: *mut ::std::os::raw::c_void,
+        ) {
+            unsafe { $crate::client_context::destruct(per_client_context) }
         }
     };
 }
@@ -301,7 +803,7 @@ macro_rules! try_or_return_error {
         match $result {
             Ok(result) => result,
             Err(e) => {
-                logging::log_error(&Error::new($error_msg, e));
+                logging::log_error(&Error::parse_failed($error_msg, e));
                 return ffi::OPENVPN_PLUGIN_FUNC_ERROR;
             }
         }
@@ -314,31 +816,49 @@ macro_rules! try_or_return_error {
 ///
 /// [`openvpn_plugin!`]: macro.openvpn_plugin.html
 #[doc(hidden)]
-pub unsafe fn openvpn_plugin_open<H, E, F>(
+pub unsafe fn openvpn_plugin_open<H, S, E, F>(
+    version: c_int,
     args: *const ffi::openvpn_plugin_args_open_in,
     retptr: *mut ffi::openvpn_plugin_args_open_return,
-    open_fn: F,
+    mut open_fn: F,
 ) -> c_int
 where
-    E: ::std::error::Error,
-    F: panic::RefUnwindSafe,
-    F: Fn(Vec<CString>, HashMap<CString, CString>) -> Result<(Vec<EventType>, H), E>,
+    S: Into<EventTypeSet>,
+    E: Into<Box<dyn ::std::error::Error>>,
+    F: panic::UnwindSafe,
+    F: FnMut(Vec<CString>, HashMap<CString, CString>) -> Result<(S, H), E>,
 {
+    #[cfg(feature = "backtrace")]
+    logging::install_panic_hook();
+    #[cfg(feature = "tracing")]
+    let _span = tracing::error_span!("plugin_open").entered();
+
+    if let Err(e) = check_open_args(version, args, retptr) {
+        logging::log_error(&e);
+        return ffi::OPENVPN_PLUGIN_FUNC_ERROR;
+    }
+    let _open_args = ffi::OpenArgs::new(args).map(ffi::OpenArgs::enter);
     let parsed_args = try_or_return_error!(
         ffi::parse::string_array((*args).argv),
         "Malformed args from OpenVPN"
     );
     let parsed_env =
         try_or_return_error!(ffi::parse::env((*args).envp), "Malformed env from OpenVPN");
+    #[cfg(any(feature = "json-log", feature = "journald"))]
+    if let Some(plugin_path) = parsed_args.first() {
+        logging::set_plugin_name(&plugin_path.to_string_lossy());
+    }
 
-    match panic::catch_unwind(|| open_fn(parsed_args, parsed_env)) {
+    match catch_unwind(move || open_fn(parsed_args, parsed_env)) {
         Ok(Ok((events, handle))) => {
-            (*retptr).type_mask = types::events_to_bitmask(&events);
+            (*retptr).type_mask = events.into().bits();
             (*retptr).handle = Box::into_raw(Box::new(handle)) as *const c_void;
+            #[cfg(all(feature = "sd-notify", unix))]
+            sd_notify::ready();
             ffi::OPENVPN_PLUGIN_FUNC_SUCCESS
         }
         Ok(Err(e)) => {
-            logging::log_error(&e);
+            logging::log_error(&*e.into());
             ffi::OPENVPN_PLUGIN_FUNC_ERROR
         }
         Err(e) => {
@@ -354,16 +874,85 @@ where
 ///
 /// [`openvpn_plugin!`]: macro.openvpn_plugin.html
 #[doc(hidden)]
-pub unsafe fn openvpn_plugin_close<H, F>(handle: *const c_void, close_fn: F)
+pub unsafe fn openvpn_plugin_close<H, R, F>(handle: *const c_void, mut close_fn: F)
 where
     H: panic::UnwindSafe,
-    F: Fn(H) + panic::RefUnwindSafe,
+    R: callbacks::CloseResult,
+    F: FnMut(H) -> R + panic::UnwindSafe,
 {
+    if !check_close_handle(handle) {
+        return;
+    }
     // IMPORTANT: Bring the handle object back from a raw pointer. This will cause the
     // handle object to be properly deallocated when `$close_fn` returns.
     let handle = *Box::from_raw(handle as *mut H);
-    if let Err(e) = panic::catch_unwind(|| close_fn(handle)) {
-        logging::log_panic("plugin close", &e);
+    close_handle(move || close_fn(handle));
+}
+
+
+/// Internal helper function. This function should never be called manually, only by code generated
+/// by the [`openvpn_plugin!`] macro when the handle type is marked `unwind_unsafe`.
+///
+/// [`openvpn_plugin!`]: macro.openvpn_plugin.html
+#[doc(hidden)]
+pub unsafe fn openvpn_plugin_close_unwind_unsafe<H, R, F>(handle: *const c_void, mut close_fn: F)
+where
+    R: callbacks::CloseResult,
+    F: FnMut(H) -> R + panic::UnwindSafe,
+{
+    if !check_close_handle(handle) {
+        return;
+    }
+    // The plugin opted out of the unwind safety check for the handle, see the crate docs.
+    let handle = panic::AssertUnwindSafe(*Box::from_raw(handle as *mut H));
+    close_handle(move || close_fn(handle.0));
+}
+
+/// Checks the handle OpenVPN passes to `openvpn_plugin_close_v1`. Logs and returns `false` if it
+/// is null.
+pub(crate) fn check_close_handle(handle: *const c_void) -> bool {
+    if handle.is_null() {
+        logging::log_error(&Error::NullPointer("plugin handle"));
+        return false;
+    }
+    true
+}
+
+fn close_handle<R: callbacks::CloseResult>(close: impl FnOnce() -> R + panic::UnwindSafe) {
+    #[cfg(feature = "tracing")]
+    let _span = tracing::error_span!("plugin_close").entered();
+    match catch_unwind(close) {
+        Ok(result) => result.log_error(),
+        Err(e) => logging::log_panic("plugin close", &e),
+    }
+    #[cfg(feature = "metrics")]
+    metrics::log_summary();
+}
+
+
+/// Runs `f`, catching any panic. With the `no-unwind` feature panics abort the process instead of
+/// unwinding, so `f` is just called.
+#[cfg(not(feature = "no-unwind"))]
+pub(crate) fn catch_unwind<R>(f: impl FnOnce() -> R + panic::UnwindSafe) -> std::thread::Result<R> {
+    panic::catch_unwind(f)
+}
+
+/// Runs `f`, catching any panic. With the `no-unwind` feature panics abort the process instead of
+/// unwinding, so `f` is just called.
+#[cfg(feature = "no-unwind")]
+pub(crate) fn catch_unwind<R>(f: impl FnOnce() -> R + panic::UnwindSafe) -> std::thread::Result<R> {
+    Ok(f())
+}
+
+
+/// Internal helper function. This function should never be called manually, only by code generated
+/// by the [`openvpn_plugin!`] macro.
+///
+/// [`openvpn_plugin!`]: macro.openvpn_plugin.html
+#[doc(hidden)]
+pub fn openvpn_plugin_abort() {
+    if let Err(e) = catch_unwind(workers::shutdown_all) {
+        logging::log_panic("plugin abort", &e);
     }
 }
 
@@ -374,83 +963,222 @@ where
 /// [`openvpn_plugin!`]: macro.openvpn_plugin.html
 #[doc(hidden)]
 pub unsafe fn openvpn_plugin_func<H, E, F>(
+    version: c_int,
     args: *const ffi::openvpn_plugin_args_func_in,
-    event_fn: F,
+    mut event_fn: F,
 ) -> c_int
 where
-    E: ::std::error::Error,
-    F: panic::RefUnwindSafe,
-    F: Fn(EventType, Vec<CString>, HashMap<CString, CString>, &mut H) -> Result<EventResult, E>,
+    E: Into<Box<dyn ::std::error::Error>>,
+    F: panic::UnwindSafe,
+    F: FnMut(EventType, Vec<CString>, HashMap<CString, CString>, &mut H) -> Result<EventResult, E>,
 {
-    let event_type = (*args).event_type;
-    let event = try_or_return_error!(
-        EventType::try_from(event_type).map_err(|_| InvalidEventType(event_type)),
-        "Invalid event integer"
-    );
+    if let Err(e) = check_func_args(version, args) {
+        logging::log_error(&e);
+        return ffi::OPENVPN_PLUGIN_FUNC_ERROR;
+    }
+    let _client_context = client_context::enter((*args).per_client_context);
+    let event = match parse_event_type((*args).event_type) {
+        Some(event) => event,
+        None => return ffi::OPENVPN_PLUGIN_FUNC_SUCCESS,
+    };
     let parsed_args = try_or_return_error!(
         ffi::parse::string_array((*args).argv),
         "Malformed args from OpenVPN"
     );
     let parsed_env =
         try_or_return_error!(ffi::parse::env((*args).envp), "Malformed env from OpenVPN");
+    #[cfg(feature = "tracing")]
+    let _span = logging::event_span(
+        event,
+        parsed_env
+            .get(env_keys::cstr::COMMON_NAME)
+            .map(CString::as_c_str),
+        parsed_env
+            .get(env_keys::cstr::UNTRUSTED_IP)
+            .map(CString::as_c_str),
+    )
+    .entered();
+    #[cfg(any(feature = "json-log", feature = "journald"))]
+    let _event = logging::enter_event(event);
+    let _secrets = redact::Secrets::from_env(&parsed_env).enter();
 
-    let result = panic::catch_unwind(|| {
+    let started = Instant::now();
+    let result = catch_unwind(move || {
         let handle: &mut H = &mut *((*args).handle as *mut H);
         event_fn(event, parsed_args, parsed_env, handle)
     });
+    let elapsed = started.elapsed();
+    watchdog::check(event, elapsed);
+    let code = event_result_code(event, result, || {
+        auth::FailedReasonFile::from_envp((*args).envp)
+    });
+    observe_event(event, elapsed, code);
+    code
+}
 
-    match result {
-        Ok(Ok(EventResult::Success)) => ffi::OPENVPN_PLUGIN_FUNC_SUCCESS,
-        Ok(Ok(EventResult::Deferred)) => ffi::OPENVPN_PLUGIN_FUNC_DEFERRED,
-        Ok(Ok(EventResult::Failure)) => ffi::OPENVPN_PLUGIN_FUNC_ERROR,
-        Ok(Err(e)) => {
-            logging::log_error(&e);
-            ffi::OPENVPN_PLUGIN_FUNC_ERROR
-        }
-        Err(e) => {
-            logging::log_panic("plugin func", &e);
-            ffi::OPENVPN_PLUGIN_FUNC_ERROR
-        }
+/// Internal helper function. This function should never be called manually, only by code generated
+/// by the [`openvpn_plugin!`] macro. Calls `open_v3` with the arguments of the v2 API, without
+/// the OpenVPN version. Returns the handle, or null if opening failed.
+///
+/// [`openvpn_plugin!`]: macro.openvpn_plugin.html
+#[doc(hidden)]
+pub unsafe fn openvpn_plugin_open_v2(
+    type_mask: *mut c_uint,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+    open_v3: unsafe extern "C" fn(
+        c_int,
+        *const ffi::openvpn_plugin_args_open_in,
+        *mut ffi::openvpn_plugin_args_open_return,
+    ) -> c_int,
+) -> *mut c_void {
+    if type_mask.is_null() {
+        logging::log_error(&Error::NullPointer("type mask"));
+        return ptr::null_mut();
+    }
+    let args = ffi::openvpn_plugin_args_open_in::new(argv, envp, ptr::null(), 0, 0, ptr::null());
+    let mut retptr = ffi::openvpn_plugin_args_open_return::default();
+    if open_v3(ffi::OPENVPN_PLUGIN_STRUCTVER_MIN, &args, &mut retptr)
+        != ffi::OPENVPN_PLUGIN_FUNC_SUCCESS
+    {
+        return ptr::null_mut();
     }
+    *type_mask = retptr.type_mask as c_uint;
+    retptr.handle as *mut c_void
 }
 
+/// Internal helper function. This function should never be called manually, only by code generated
+/// by the [`openvpn_plugin!`] macro. Calls `func_v3` with the arguments of the v2 API.
+///
+/// [`openvpn_plugin!`]: macro.openvpn_plugin.html
+#[doc(hidden)]
+pub unsafe fn openvpn_plugin_func_v2(
+    handle: *const c_void,
+    event_type: c_int,
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+    per_client_context: *const c_void,
+    func_v3: unsafe extern "C" fn(
+        c_int,
+        *const ffi::openvpn_plugin_args_func_in,
+        *const ffi::openvpn_plugin_args_func_return,
+    ) -> c_int,
+) -> c_int {
+    let mut args = ffi::openvpn_plugin_args_func_in::new(event_type, argv, envp, handle);
+    args.per_client_context = per_client_context;
+    func_v3(
+        ffi::OPENVPN_PLUGIN_STRUCTVER_MIN,
+        &args,
+        &ffi::openvpn_plugin_args_func_return::default(),
+    )
+}
 
-/// Internal error type
-#[derive(Debug)]
-struct Error {
-    msg: &'static str,
-    source: Box<dyn (::std::error::Error)>,
+/// Checks the version and pointers OpenVPN passes to `openvpn_plugin_open_v3`, before anything
+/// is read from them.
+unsafe fn check_open_args(
+    version: c_int,
+    args: *const ffi::openvpn_plugin_args_open_in,
+    retptr: *mut ffi::openvpn_plugin_args_open_return,
+) -> Result<(), Error> {
+    check_version(version)?;
+    if args.is_null() {
+        return Err(Error::NullPointer("open arguments"));
+    }
+    if retptr.is_null() {
+        return Err(Error::NullPointer("open return value"));
+    }
+    Ok(())
 }
 
-impl Error {
-    pub(crate) fn new(msg: &'static str, source: impl std::error::Error + 'static) -> Error {
-        Error {
-            msg,
-            source: Box::new(source) as Box<dyn std::error::Error>,
-        }
+/// Checks the version and pointers OpenVPN passes to `openvpn_plugin_func_v3`, before anything
+/// is read from them.
+pub(crate) unsafe fn check_func_args(
+    version: c_int,
+    args: *const ffi::openvpn_plugin_args_func_in,
+) -> Result<(), Error> {
+    check_version(version)?;
+    if args.is_null() {
+        return Err(Error::NullPointer("event arguments"));
+    }
+    if (*args).handle.is_null() {
+        return Err(Error::NullPointer("plugin handle"));
     }
+    Ok(())
 }
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.msg.fmt(f)
+fn check_version(version: c_int) -> Result<(), Error> {
+    if version < ffi::OPENVPN_PLUGIN_STRUCTVER_MIN {
+        return Err(Error::UnsupportedVersion(version));
     }
+    Ok(())
 }
 
-impl std::error::Error for Error {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        Some(self.source.as_ref())
+/// Converts the event type integer from OpenVPN into an `EventType`. Logs a warning and returns
+/// `None` if the event is unknown.
+pub(crate) fn parse_event_type(event_type: c_int) -> Option<EventType> {
+    match EventType::try_from(event_type) {
+        Ok(event) => Some(event),
+        Err(_) => {
+            // The plugin can only register for events in `EventType`. So an unknown event means
+            // OpenVPN is newer than this crate, and the event can't be one the plugin cares about.
+            logging::log_warning(&Error::InvalidEvent(event_type));
+            None
+        }
     }
 }
 
-/// Error thrown when trying to convert from an invalid integer into an `Event`.
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
-struct InvalidEventType(c_int);
+/// Feeds the outcome of an event to the enabled metrics backends.
+#[allow(unused_variables)]
+pub(crate) fn observe_event(event: EventType, elapsed: std::time::Duration, code: c_int) {
+    #[cfg(feature = "metrics")]
+    metrics::record(event, elapsed, code == ffi::OPENVPN_PLUGIN_FUNC_ERROR);
+    #[cfg(feature = "statsd")]
+    statsd::send(event, elapsed, code);
+    #[cfg(all(feature = "sd-notify", unix))]
+    sd_notify::event(event, code);
+}
 
-impl fmt::Display for InvalidEventType {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{} is not a valid OPENVPN_PLUGIN_* constant", self.0)
+/// Converts the outcome of an event callback into the return code OpenVPN expects. Logs errors
+/// and panics, answers errors with success if the `error_policy` says so, and writes the reason of
+/// a `EventResult::FailureWithReason` to the file `failed_reason_file` returns, if any. It is only
+/// called for such results.
+pub(crate) fn event_result_code<E: Into<Box<dyn ::std::error::Error>>>(
+    event: EventType,
+    result: std::thread::Result<Result<EventResult, E>>,
+    failed_reason_file: impl FnOnce() -> Option<auth::FailedReasonFile>,
+) -> c_int {
+    match result {
+        Ok(Ok(EventResult::Success)) => ffi::OPENVPN_PLUGIN_FUNC_SUCCESS,
+        Ok(Ok(EventResult::Deferred)) if event.supports_deferred() => {
+            ffi::OPENVPN_PLUGIN_FUNC_DEFERRED
+        }
+        Ok(Ok(EventResult::Deferred)) => {
+            logging::log_error(&Error::IllegalDeferral(event));
+            ffi::OPENVPN_PLUGIN_FUNC_ERROR
+        }
+        Ok(Ok(EventResult::Failure)) => ffi::OPENVPN_PLUGIN_FUNC_ERROR,
+        Ok(Ok(EventResult::FailureWithReason(reason))) => {
+            if let Some(failed_reason_file) = failed_reason_file() {
+                if let Err(e) = failed_reason_file.write(&reason) {
+                    logging::log_error(&e);
+                }
+            }
+            ffi::OPENVPN_PLUGIN_FUNC_ERROR
+        }
+        Ok(Err(e)) if error_policy::ignores_errors(event) => {
+            logging::log_warning(&Error::callback_failed(
+                "Ignoring error in event callback",
+                e,
+            ));
+            ffi::OPENVPN_PLUGIN_FUNC_SUCCESS
+        }
+        Ok(Err(e)) => {
+            logging::log_error(&*e.into());
+            ffi::OPENVPN_PLUGIN_FUNC_ERROR
+        }
+        Err(e) => {
+            logging::log_panic("plugin func", &e);
+            ffi::OPENVPN_PLUGIN_FUNC_ERROR
+        }
     }
 }
-
-impl std::error::Error for InvalidEventType {}
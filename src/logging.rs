@@ -1,41 +1,517 @@
+#[cfg(feature = "backtrace")]
+use std::backtrace::Backtrace;
+#[cfg(feature = "tracing")]
+use std::ffi::CStr;
 use std::{any::Any, error::Error};
 
 /// Error logging method used by the FFI functions to log if `$open_fn` or `$event_fn` return an
-/// error. It logs to the error log level of the `log` crate if the `log` feature is enabled.
-/// Otherwise it will print the error to stderr.
-pub fn log_error(error: &impl Error) {
-    let error_msg = format_error(error);
-    #[cfg(feature = "log")]
+/// error. With the `json-log` feature it prints the error as a JSON object to stderr, or else with
+/// the `journald` feature it sends it to the systemd journal. Otherwise it logs to the error level
+/// of the `tracing` crate if the `tracing` feature is enabled, or else of the `log` crate if the
+/// `log` feature is enabled. Otherwise it will print the error to stderr.
+pub fn log_error(error: &dyn Error) {
+    let chain = error_chain(error);
+    write(Level::Error, &format_chain(&chain), &chain);
+}
+
+/// Same as `log_error`, but for errors that do not make the callback fail. Logs to the warn
+/// level of `tracing` or `log`.
+pub fn log_warning(error: &dyn Error) {
+    let chain = error_chain(error);
+    write(Level::Warn, &format_chain(&chain), &chain);
+}
+
+/// Logs a message that is not an error, at the info level of `tracing` or `log`.
+pub fn log_info(msg: &str) {
+    write(Level::Info, msg, &[msg.to_owned()]);
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Level {
+    Error,
+    Warn,
+    Info,
+}
+
+/// Writes `msg` to the enabled backend. The JSON backend uses `chain`, the error and its sources,
+/// instead. Secrets of the current event are redacted from both.
+fn write(level: Level, msg: &str, chain: &[String]) {
+    let msg = &crate::redact::redact(msg);
+    let chain = &chain
+        .iter()
+        .map(|part| crate::redact::redact(part))
+        .collect::<Vec<_>>()[..];
+    #[cfg(feature = "json-log")]
+    {
+        let _ = msg;
+        eprintln!(
+            "{}",
+            json::record(level, chain, std::time::SystemTime::now())
+        );
+    }
+    #[cfg(all(feature = "journald", not(feature = "json-log")))]
     {
-        log::error!("{}", error_msg);
+        let _ = chain;
+        if journald::send(level, msg).is_err() {
+            eprintln!("{}", msg);
+        }
     }
-    #[cfg(not(feature = "log"))]
+    #[cfg(all(
+        feature = "tracing",
+        not(any(feature = "json-log", feature = "journald"))
+    ))]
     {
-        eprintln!("{}", error_msg);
+        let _ = chain;
+        match level {
+            Level::Error => tracing::error!("{}", msg),
+            Level::Warn => tracing::warn!("{}", msg),
+            Level::Info => tracing::info!("{}", msg),
+        }
+    }
+    #[cfg(all(
+        feature = "log",
+        not(any(feature = "tracing", feature = "json-log", feature = "journald"))
+    ))]
+    {
+        let _ = chain;
+        match level {
+            Level::Error => log::error!("{}", msg),
+            Level::Warn => log::warn!("{}", msg),
+            Level::Info => log::info!("{}", msg),
+        }
+    }
+    #[cfg(not(any(
+        feature = "log",
+        feature = "tracing",
+        feature = "json-log",
+        feature = "journald"
+    )))]
+    {
+        let _ = (level, chain);
+        eprintln!("{}", msg);
     }
 }
 
-pub fn log_panic(source: &str, panic_payload: &Box<dyn Any + Send + 'static>) {
-    let panic_msg = panic_payload
-        .downcast_ref::<&str>()
-        .unwrap_or(&"No panic message");
+/// Creates the span the event callback runs in, with the fields identifying the client that
+/// caused the event. Errors logged in the span carry these fields. The span has the error level so
+/// it is enabled whenever the errors logged in it are.
+#[cfg(feature = "tracing")]
+pub fn event_span(
+    event: crate::EventType,
+    common_name: Option<&CStr>,
+    untrusted_ip: Option<&CStr>,
+) -> tracing::Span {
+    let common_name = common_name.map(CStr::to_string_lossy);
+    let untrusted_ip = untrusted_ip.map(CStr::to_string_lossy);
+    tracing::error_span!(
+        "plugin_event",
+        event = event.name(),
+        common_name = common_name.as_deref(),
+        untrusted_ip = untrusted_ip.as_deref(),
+    )
+}
 
-    #[cfg(feature = "log")]
+/// Logs a panic caught in one of the callbacks. With the `abort-on-panic` feature the process is
+/// aborted after logging, instead of returning to the caller.
+pub fn log_panic(source: &str, panic_payload: &Box<dyn Any + Send + 'static>) {
+    #[allow(unused_mut)]
+    let mut panic_msg = panic_message(&**panic_payload);
+    #[cfg(feature = "backtrace")]
     {
-        log::error!("Panic in the {} callback: {:?}", source, panic_msg);
+        if let Some(backtrace) = take_backtrace() {
+            panic_msg.push_str(&format!("\nBacktrace:\n{}", backtrace));
+        }
     }
-    #[cfg(not(feature = "log"))]
+
+    let msg = format!("Panic in the {} callback: {}", source, panic_msg);
+    write(Level::Error, &msg, std::slice::from_ref(&msg));
+    #[cfg(feature = "abort-on-panic")]
     {
-        eprintln!("Panic in the {} callback: {:?}", source, panic_msg);
+        #[cfg(all(
+            feature = "log",
+            not(any(feature = "tracing", feature = "json-log", feature = "journald"))
+        ))]
+        log::logger().flush();
+        std::process::abort();
     }
 }
 
-fn format_error<E: ::std::error::Error>(error: &E) -> String {
-    let mut error_string = format!("Error: {}", error);
+/// Installs a panic hook that captures the backtrace of every panic, for `log_panic` to include
+/// in the message. The previously installed hook is still called. Installs the hook only once, no
+/// matter how many times this is called.
+#[cfg(feature = "backtrace")]
+pub fn install_panic_hook() {
+    static INSTALL: std::sync::Once = std::sync::Once::new();
+    INSTALL.call_once(|| {
+        let previous_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let backtrace = Backtrace::force_capture();
+            LAST_BACKTRACE.with(|last| *last.borrow_mut() = Some(backtrace));
+            previous_hook(info);
+        }));
+    });
+}
+
+#[cfg(feature = "backtrace")]
+thread_local! {
+    /// The backtrace of the latest panic on this thread, not yet logged.
+    static LAST_BACKTRACE: std::cell::RefCell<Option<Backtrace>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Returns the backtrace of the latest panic on this thread. Panics caught on another thread,
+/// such as in a spawned task, have no backtrace here.
+#[cfg(feature = "backtrace")]
+fn take_backtrace() -> Option<Backtrace> {
+    LAST_BACKTRACE.with(|last| last.borrow_mut().take())
+}
+
+/// Formats the message of a panic. `panic!` with only a string literal gives a `&str` payload,
+/// with format arguments a `String`. Other payloads, from `panic::panic_any`, can't be formatted
+/// since their type is unknown, and are only described as such.
+fn panic_message(panic_payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = panic_payload.downcast_ref::<&str>() {
+        format!("{:?}", msg)
+    } else if let Some(msg) = panic_payload.downcast_ref::<String>() {
+        format!("{:?}", msg)
+    } else {
+        "panic payload of unknown type".to_owned()
+    }
+}
+
+/// The messages of `error` and all its sources.
+fn error_chain(error: &dyn Error) -> Vec<String> {
+    let mut chain = vec![error.to_string()];
     let mut error_iter = error.source();
     while let Some(e) = error_iter {
-        error_string.push_str(&format!("\nCaused by: {}", e));
+        chain.push(e.to_string());
         error_iter = e.source();
     }
+    chain
+}
+
+fn format_chain(chain: &[String]) -> String {
+    let mut error_string = format!("Error: {}", chain[0]);
+    for cause in &chain[1..] {
+        error_string.push_str(&format!("\nCaused by: {}", cause));
+    }
     error_string
 }
+
+
+/// Context included in the records of the structured backends, `json-log` and `journald`.
+#[cfg(any(feature = "json-log", feature = "journald"))]
+mod context {
+    use std::{cell::Cell, path::Path, sync::OnceLock};
+
+    use crate::EventType;
+
+    static PLUGIN_NAME: OnceLock<String> = OnceLock::new();
+
+    thread_local! {
+        static CURRENT_EVENT: Cell<Option<EventType>> = const { Cell::new(None) };
+    }
+
+    /// Sets the plugin name included in every record, from the path to the plugin OpenVPN gives
+    /// as the first argument. Only the first call has any effect.
+    pub fn set_plugin_name(plugin_path: &str) {
+        let name = Path::new(plugin_path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_else(|| plugin_path.to_owned());
+        let _ = PLUGIN_NAME.set(name);
+    }
+
+    pub fn plugin_name() -> Option<&'static str> {
+        PLUGIN_NAME.get().map(String::as_str)
+    }
+
+    /// Makes records logged on this thread include `event`, until the returned guard is dropped.
+    pub fn enter_event(event: EventType) -> EventGuard {
+        let previous = CURRENT_EVENT.with(|current| current.replace(Some(event)));
+        EventGuard { previous }
+    }
+
+    pub fn current_event() -> Option<EventType> {
+        CURRENT_EVENT.with(Cell::get)
+    }
+
+    pub struct EventGuard {
+        previous: Option<EventType>,
+    }
+
+    impl Drop for EventGuard {
+        fn drop(&mut self) {
+            CURRENT_EVENT.with(|current| current.set(self.previous));
+        }
+    }
+}
+
+#[cfg(any(feature = "json-log", feature = "journald"))]
+use self::context::{current_event, plugin_name};
+#[cfg(any(feature = "json-log", feature = "journald"))]
+pub use self::context::{enter_event, set_plugin_name};
+
+
+/// The JSON logging backend, enabled with the `json-log` feature. Prints one JSON object per line
+/// to stderr, which OpenVPN forwards to its log.
+#[cfg(feature = "json-log")]
+mod json {
+    use super::{current_event, plugin_name, Level};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use crate::EventType;
+
+    pub(super) fn record(level: Level, chain: &[String], time: SystemTime) -> String {
+        let timestamp = time
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs_f64())
+            .unwrap_or(0.0);
+        let level = match level {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+        };
+        serde_json::json!({
+            "timestamp": timestamp,
+            "level": level,
+            "plugin": plugin_name(),
+            "event": current_event().map(EventType::name),
+            "message": chain[0],
+            "causes": &chain[1..],
+        })
+        .to_string()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{super::enter_event, *};
+        use crate::EventType;
+        use std::time::Duration;
+
+        #[test]
+        fn records() {
+            let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+            let chain = ["Authentication failed".to_owned(), "timed out".to_owned()];
+            let json: serde_json::Value =
+                serde_json::from_str(&record(Level::Error, &chain, time)).unwrap();
+            assert_eq!(1_700_000_000.5, json["timestamp"]);
+            assert_eq!("error", json["level"]);
+            assert_eq!(serde_json::Value::Null, json["event"]);
+            assert_eq!("Authentication failed", json["message"]);
+            assert_eq!(serde_json::json!(["timed out"]), json["causes"]);
+
+            {
+                let _event = enter_event(EventType::AuthUserPassVerify);
+                let json: serde_json::Value =
+                    serde_json::from_str(&record(Level::Warn, &chain[..1], time)).unwrap();
+                assert_eq!("PLUGIN_AUTH_USER_PASS_VERIFY", json["event"]);
+                assert_eq!("warn", json["level"]);
+                assert_eq!(serde_json::json!([]), json["causes"]);
+            }
+            assert_eq!(None, current_event());
+        }
+    }
+}
+
+/// The journald backend, enabled with the `journald` feature. Sends every record to the journal
+/// over its native protocol, with the event and plugin name as fields of their own.
+#[cfg(all(feature = "journald", not(feature = "json-log")))]
+mod journald {
+    use super::{current_event, plugin_name, Level};
+    use std::{io, os::unix::net::UnixDatagram};
+
+    const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+    /// Sends a record to the journal. Fails if journald is not running.
+    pub(super) fn send(level: Level, msg: &str) -> io::Result<()> {
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to(&entry(level, msg), JOURNAL_SOCKET)?;
+        Ok(())
+    }
+
+    fn entry(level: Level, msg: &str) -> Vec<u8> {
+        // The syslog priorities err, warning and info.
+        let priority = match level {
+            Level::Error => "3",
+            Level::Warn => "4",
+            Level::Info => "6",
+        };
+        let mut entry = Vec::new();
+        append_field(&mut entry, "MESSAGE", msg);
+        append_field(&mut entry, "PRIORITY", priority);
+        if let Some(plugin) = plugin_name() {
+            append_field(&mut entry, "PLUGIN", plugin);
+        }
+        if let Some(event) = current_event() {
+            append_field(&mut entry, "OPENVPN_EVENT", event.name());
+        }
+        entry
+    }
+
+    /// Appends a field in the format of the journal protocol. Values containing newlines, such
+    /// as the error chain of a message, must be prefixed with their length instead of following
+    /// a `=`.
+    fn append_field(entry: &mut Vec<u8>, key: &str, value: &str) {
+        entry.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            entry.push(b'\n');
+            entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            entry.push(b'=');
+        }
+        entry.extend_from_slice(value.as_bytes());
+        entry.push(b'\n');
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::{super::enter_event, *};
+        use crate::EventType;
+
+        #[test]
+        fn entries() {
+            assert_eq!(
+                &b"MESSAGE=Error: failed\nPRIORITY=3\n"[..],
+                &entry(Level::Error, "Error: failed")[..]
+            );
+
+            let _event = enter_event(EventType::ClientConnectV2);
+            let mut expected = b"MESSAGE\n".to_vec();
+            expected.extend_from_slice(&21u64.to_le_bytes());
+            expected.extend_from_slice(b"Error: a\nCaused by: b\n");
+            expected.extend_from_slice(b"PRIORITY=4\nOPENVPN_EVENT=PLUGIN_CLIENT_CONNECT_V2\n");
+            assert_eq!(expected, entry(Level::Warn, "Error: a\nCaused by: b"));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic;
+
+    fn payload(f: impl FnOnce() + panic::UnwindSafe) -> Box<dyn Any + Send> {
+        panic::catch_unwind(f).unwrap_err()
+    }
+
+    #[test]
+    fn panic_messages() {
+        let literal = payload(|| panic!("literal"));
+        assert_eq!("\"literal\"", panic_message(&*literal));
+
+        let value = 5;
+        let formatted = payload(move || panic!("formatted {}", value));
+        assert_eq!("\"formatted 5\"", panic_message(&*formatted));
+
+        let other = payload(|| panic::panic_any(5u32));
+        assert_eq!("panic payload of unknown type", panic_message(&*other));
+    }
+
+    #[cfg(feature = "backtrace")]
+    #[test]
+    fn captures_backtrace() {
+        install_panic_hook();
+        install_panic_hook();
+        let _ = payload(|| panic!("with backtrace"));
+        assert!(take_backtrace().is_some());
+        assert!(take_backtrace().is_none());
+    }
+
+    #[cfg(all(
+        feature = "tracing",
+        not(any(feature = "json-log", feature = "journald"))
+    ))]
+    #[test]
+    fn errors_in_event_span() {
+        use std::{
+            fmt::{self, Write},
+            io,
+            sync::{
+                atomic::{AtomicU64, Ordering},
+                Arc, Mutex,
+            },
+        };
+        use tracing::{
+            field::{Field, Visit},
+            span, Event, Metadata, Subscriber,
+        };
+
+        /// Formats all fields as `name=value`.
+        struct Fields(String);
+
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                let _ = write!(self.0, " {}={:?}", field.name(), value);
+            }
+        }
+
+        /// Records the fields of the entered spans and of every event.
+        #[derive(Default)]
+        struct Recorder {
+            next_id: AtomicU64,
+            spans: Mutex<Vec<String>>,
+            entered: Mutex<Vec<u64>>,
+            events: Arc<Mutex<Vec<String>>>,
+        }
+
+        impl Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, attributes: &span::Attributes<'_>) -> span::Id {
+                let mut fields = Fields(attributes.metadata().name().to_owned());
+                attributes.record(&mut fields);
+                self.spans.lock().unwrap().push(fields.0);
+                span::Id::from_u64(self.next_id.fetch_add(1, Ordering::SeqCst) + 1)
+            }
+
+            fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = Fields(String::new());
+                event.record(&mut fields);
+                let spans = self.spans.lock().unwrap();
+                let span = self
+                    .entered
+                    .lock()
+                    .unwrap()
+                    .last()
+                    .map(|id| spans[*id as usize - 1].clone());
+                self.events.lock().unwrap().push(format!(
+                    "{}:{}",
+                    span.unwrap_or_default(),
+                    fields.0
+                ));
+            }
+
+            fn enter(&self, id: &span::Id) {
+                self.entered.lock().unwrap().push(id.into_u64());
+            }
+
+            fn exit(&self, _: &span::Id) {
+                self.entered.lock().unwrap().pop();
+            }
+        }
+
+        let recorder = Recorder::default();
+        let events = recorder.events.clone();
+        tracing::subscriber::with_default(recorder, || {
+            let common_name = CStr::from_bytes_with_nul(b"client1\0").unwrap();
+            let _span =
+                event_span(crate::EventType::ClientConnectV2, Some(common_name), None).entered();
+            log_error(&io::Error::other("Connection refused"));
+        });
+        assert_eq!(
+            vec![
+                "plugin_event event=\"PLUGIN_CLIENT_CONNECT_V2\" common_name=\"client1\": \
+                 message=Error: Connection refused"
+                    .to_owned()
+            ],
+            *events.lock().unwrap()
+        );
+    }
+}
@@ -0,0 +1,262 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Counters and latency histograms for the event callbacks. Requires the `metrics` feature.
+//!
+//! Every event OpenVPN sends the plugin is counted, together with whether the plugin failed it
+//! and how long the callback took. The plugin can read the metrics at any time, for example from
+//! its event callback:
+//!
+//! ```rust
+//! use openvpn_plugin::{metrics, EventType};
+//!
+//! let auth = metrics::get(EventType::AuthUserPassVerify);
+//! println!(
+//!     "{} authentications, {} failed, {:?} in total",
+//!     auth.invocations,
+//!     auth.failures,
+//!     auth.latency.sum()
+//! );
+//! ```
+//!
+//! A summary of all events is logged when the plugin is closed.
+
+use std::{
+    convert::TryFrom,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::EventType;
+
+/// The upper bounds of the latency histogram buckets. Callbacks slower than the last bound are
+/// counted in a final bucket without an upper bound.
+pub const BUCKET_BOUNDS: &[Duration] = &[
+    Duration::from_millis(1),
+    Duration::from_millis(5),
+    Duration::from_millis(10),
+    Duration::from_millis(50),
+    Duration::from_millis(100),
+    Duration::from_millis(500),
+    Duration::from_secs(1),
+    Duration::from_secs(5),
+];
+
+const BUCKETS: usize = 9;
+
+/// One more than the largest `EventType` value.
+const EVENT_SLOTS: usize = 17;
+
+static REGISTRY: Registry = Registry::new();
+
+/// The metrics of one event type.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct EventMetrics {
+    /// How many times the event callback has been called.
+    pub invocations: u64,
+    /// How many of the calls returned `OPENVPN_PLUGIN_FUNC_ERROR` to OpenVPN.
+    pub failures: u64,
+    /// How long the callback took.
+    pub latency: Histogram,
+}
+
+/// A histogram of callback durations, with the buckets in `BUCKET_BOUNDS`.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Histogram {
+    counts: [u64; BUCKETS],
+    sum: Duration,
+}
+
+impl Histogram {
+    /// The number of durations in each bucket. Not cumulative, each duration is only counted in
+    /// the first bucket its duration fits in. Has one more element than `BUCKET_BOUNDS`, for
+    /// the durations above the last bound.
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    /// The number of durations in the histogram.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The sum of all durations in the histogram.
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    /// The mean duration, if there are any.
+    pub fn mean(&self) -> Option<Duration> {
+        let count = u32::try_from(self.count())
+            .ok()
+            .filter(|count| *count > 0)?;
+        Some(self.sum / count)
+    }
+}
+
+/// The metrics of every event type that has been called at least once.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Snapshot(pub Vec<(EventType, EventMetrics)>);
+
+/// One line per event, e.g. `PLUGIN_UP: 1 invocations, 0 failures, mean latency 2ms`.
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (event, metrics)) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(
+                f,
+                "{}: {} invocations, {} failures, mean latency {:?}",
+                event,
+                metrics.invocations,
+                metrics.failures,
+                metrics.latency.mean().unwrap_or_default()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the metrics of `event`.
+pub fn get(event: EventType) -> EventMetrics {
+    REGISTRY.get(event)
+}
+
+/// Returns the metrics of every event that has been called at least once.
+pub fn snapshot() -> Snapshot {
+    REGISTRY.snapshot()
+}
+
+/// Records one call to the callback of `event`.
+pub(crate) fn record(event: EventType, elapsed: Duration, failed: bool) {
+    REGISTRY.record(event, elapsed, failed);
+}
+
+/// Logs the metrics of all events, if any event has been called.
+pub(crate) fn log_summary() {
+    let snapshot = snapshot();
+    if !snapshot.0.is_empty() {
+        crate::logging::log_info(&format!("Event metrics:\n{}", snapshot));
+    }
+}
+
+struct Registry {
+    events: [Counters; EVENT_SLOTS],
+}
+
+struct Counters {
+    invocations: AtomicU64,
+    failures: AtomicU64,
+    buckets: [AtomicU64; BUCKETS],
+    sum_micros: AtomicU64,
+}
+
+impl Registry {
+    const fn new() -> Self {
+        // A constant as the repeat operand, since inline const blocks need Rust 1.79. Every
+        // element gets its own copy of the initial value.
+        #[allow(clippy::declare_interior_mutable_const)]
+        const COUNTERS: Counters = Counters::new();
+        Registry {
+            events: [COUNTERS; EVENT_SLOTS],
+        }
+    }
+
+    fn record(&self, event: EventType, elapsed: Duration, failed: bool) {
+        let counters = &self.events[event as usize];
+        counters.invocations.fetch_add(1, Ordering::Relaxed);
+        if failed {
+            counters.failures.fetch_add(1, Ordering::Relaxed);
+        }
+        let bucket = BUCKET_BOUNDS
+            .iter()
+            .position(|bound| elapsed <= *bound)
+            .unwrap_or(BUCKET_BOUNDS.len());
+        counters.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        counters.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn get(&self, event: EventType) -> EventMetrics {
+        let counters = &self.events[event as usize];
+        let mut latency = Histogram {
+            sum: Duration::from_micros(counters.sum_micros.load(Ordering::Relaxed)),
+            ..Histogram::default()
+        };
+        for (count, bucket) in latency.counts.iter_mut().zip(&counters.buckets) {
+            *count = bucket.load(Ordering::Relaxed);
+        }
+        EventMetrics {
+            invocations: counters.invocations.load(Ordering::Relaxed),
+            failures: counters.failures.load(Ordering::Relaxed),
+            latency,
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot(
+            EventType::iter()
+                .map(|event| (event, self.get(event)))
+                .filter(|(_, metrics)| metrics.invocations > 0)
+                .collect(),
+        )
+    }
+}
+
+impl Counters {
+    const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+        Counters {
+            invocations: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            buckets: [ZERO; BUCKETS],
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records() {
+        let registry = Registry::new();
+        assert_eq!(Snapshot::default(), registry.snapshot());
+
+        registry.record(EventType::Up, Duration::from_millis(3), false);
+        registry.record(EventType::Up, Duration::from_millis(5), true);
+        registry.record(EventType::Up, Duration::from_secs(10), false);
+        let up = registry.get(EventType::Up);
+        assert_eq!(3, up.invocations);
+        assert_eq!(1, up.failures);
+        assert_eq!(&[0, 2, 0, 0, 0, 0, 0, 0, 1], up.latency.counts());
+        assert_eq!(Duration::from_millis(10_008), up.latency.sum());
+        assert_eq!(Some(Duration::from_millis(3336)), up.latency.mean());
+
+        registry.record(EventType::Down, Duration::ZERO, false);
+        let snapshot = registry.snapshot();
+        assert_eq!(
+            vec![EventType::Up, EventType::Down],
+            snapshot
+                .0
+                .iter()
+                .map(|(event, _)| *event)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "PLUGIN_UP: 3 invocations, 1 failures, mean latency 3.336s\n\
+             PLUGIN_DOWN: 1 invocations, 0 failures, mean latency 0ns",
+            snapshot.to_string()
+        );
+    }
+}
@@ -0,0 +1,162 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Exports the `tracing` spans of the plugin to an OpenTelemetry collector over OTLP/HTTP.
+//! Requires the `otel` feature.
+//!
+//! The spans this crate creates make up the trace of each event. A deferred authentication in
+//! an [`openvpn_plugin_async!`] plugin is traced from the event received (`plugin_event`), through
+//! the callback (`deferred_auth`), to the result written to the control file
+//! (`write_control_file`). Spans the plugin creates in its callback, for example around calls to
+//! an authentication backend, become part of the same trace. [`trace_headers`] gives the headers
+//! that continue the trace in the backend.
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, ffi::CString};
+//! # use openvpn_plugin::{otel::Otel, EventType};
+//! struct Handle {
+//!     otel: Otel,
+//! }
+//!
+//! fn open(
+//!     _args: Vec<CString>,
+//!     _env: HashMap<CString, CString>,
+//! ) -> Result<(Vec<EventType>, Handle), openvpn_plugin::otel::OtelError> {
+//!     let otel = Otel::new("http://localhost:4318/v1/traces", "openvpn-auth")?;
+//!     otel.install()?;
+//!     Ok((vec![EventType::AuthUserPassVerify], Handle { otel }))
+//! }
+//! ```
+//!
+//! Plugins that set up their own `tracing` subscriber add [`Otel::layer`] to it instead of calling
+//! [`Otel::install`]. Spans that are not yet exported are flushed when the `Otel` is dropped.
+//!
+//! [`openvpn_plugin_async!`]: ../macro.openvpn_plugin_async.html
+//! [`trace_headers`]: fn.trace_headers.html
+//! [`Otel::layer`]: struct.Otel.html#method.layer
+//! [`Otel::install`]: struct.Otel.html#method.install
+
+use std::{collections::HashMap, fmt};
+
+use opentelemetry::{propagation::TextMapPropagator, trace::TracerProvider as _};
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+use tracing::{subscriber::SetGlobalDefaultError, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{layer::SubscriberExt, registry::LookupSpan, Layer};
+
+/// The name of the tracer the spans are exported with.
+const TRACER_NAME: &str = "openvpn-plugin";
+
+/// An OTLP exporter of the spans of the plugin.
+#[derive(Debug)]
+pub struct Otel {
+    provider: SdkTracerProvider,
+}
+
+impl Otel {
+    /// Creates an exporter sending spans to the OTLP/HTTP `endpoint`, such as
+    /// `http://localhost:4318/v1/traces`. `service_name` identifies the plugin in the traces.
+    pub fn new(endpoint: &str, service_name: impl Into<String>) -> Result<Self, OtelError> {
+        let exporter = SpanExporter::builder()
+            .with_http()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(OtelError::Exporter)?;
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(
+                Resource::builder()
+                    .with_service_name(service_name.into())
+                    .build(),
+            )
+            .build();
+        Ok(Otel { provider })
+    }
+
+    /// Returns a `tracing` layer exporting the spans of the subscriber it is added to.
+    pub fn layer<S>(&self) -> impl Layer<S>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        tracing_opentelemetry::layer().with_tracer(self.provider.tracer(TRACER_NAME))
+    }
+
+    /// Sets a subscriber exporting all spans as the global default `tracing` subscriber. Fails if
+    /// there already is one.
+    pub fn install(&self) -> Result<(), OtelError> {
+        let subscriber = tracing_subscriber::registry().with(self.layer());
+        tracing::subscriber::set_global_default(subscriber).map_err(OtelError::Install)
+    }
+}
+
+impl Drop for Otel {
+    fn drop(&mut self) {
+        let _ = self.provider.shutdown();
+    }
+}
+
+/// Returns the W3C trace context headers of the current span, for continuing the trace in a
+/// service the plugin calls.
+pub fn trace_headers() -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    TraceContextPropagator::new().inject_context(&tracing::Span::current().context(), &mut headers);
+    headers
+}
+
+/// Error returned when setting up the span export fails.
+#[derive(Debug)]
+pub enum OtelError {
+    /// The OTLP exporter could not be created.
+    Exporter(ExporterBuildError),
+    /// There already is a global default subscriber.
+    Install(SetGlobalDefaultError),
+}
+
+impl fmt::Display for OtelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OtelError::Exporter(_) => "Unable to create the OTLP exporter".fmt(f),
+            OtelError::Install(_) => "Unable to install the tracing subscriber".fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for OtelError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            OtelError::Exporter(e) => Some(e),
+            OtelError::Install(e) => Some(e),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_of_current_span() {
+        let otel = Otel::new("http://127.0.0.1:9/v1/traces", "test").unwrap();
+        let subscriber = tracing_subscriber::registry().with(otel.layer());
+        tracing::subscriber::with_default(subscriber, || {
+            assert_eq!(None, trace_headers().get("traceparent"));
+
+            let span = tracing::error_span!("plugin_event");
+            let _entered = span.enter();
+            let traceparent = trace_headers().remove("traceparent").unwrap();
+            // version-trace_id-span_id-flags
+            let parts: Vec<_> = traceparent.split('-').collect();
+            assert_eq!(
+                vec![2, 32, 16, 2],
+                parts.iter().map(|p| p.len()).collect::<Vec<_>>()
+            );
+        });
+    }
+}
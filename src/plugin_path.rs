@@ -0,0 +1,118 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The path to the plugin library itself, for finding data files installed next to it.
+//!
+//! OpenVPN gives the path of the plugin, as written in the OpenVPN config, as the first argument
+//! to the open callback and every event callback:
+//!
+//! ```rust
+//! # use std::ffi::CString;
+//! use openvpn_plugin::plugin_path::PluginPath;
+//!
+//! # let args = vec![CString::new("/usr/lib/openvpn/my_plugin.so").unwrap()];
+//! let plugin = PluginPath::from_args(&args).unwrap();
+//! assert_eq!(
+//!     std::path::Path::new("/usr/lib/openvpn/my_plugin.toml"),
+//!     plugin.with_extension("toml")
+//! );
+//! ```
+//!
+//! A relative path is relative to the working directory of OpenVPN, which is changed by the
+//! `--cd` option before plugins are loaded.
+
+use std::{
+    ffi::CString,
+    path::{Path, PathBuf},
+};
+
+/// The path to the plugin library.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct PluginPath(PathBuf);
+
+impl PluginPath {
+    /// Reads the path from the first argument. `args` should be given exactly as received by the
+    /// callback. Returns `None` if `args` is empty.
+    pub fn from_args(args: &[CString]) -> Option<Self> {
+        args.first().map(|arg| PluginPath(arg_to_path(arg)))
+    }
+
+    /// The path to the plugin library.
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// The directory the plugin library is in.
+    pub fn dir(&self) -> &Path {
+        match self.0.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        }
+    }
+
+    /// The path to `file_name` in the same directory as the plugin library.
+    pub fn sibling(&self, file_name: impl AsRef<Path>) -> PathBuf {
+        self.dir().join(file_name)
+    }
+
+    /// The path to the file with the same name as the plugin library but the extension
+    /// `extension`, e.g. `my_plugin.toml` for `my_plugin.so`.
+    pub fn with_extension(&self, extension: &str) -> PathBuf {
+        self.0.with_extension(extension)
+    }
+}
+
+impl AsRef<Path> for PluginPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+/// Converts a plugin argument to a path. Lossless on Unix, where paths are bytes.
+pub(crate) fn arg_to_path(arg: &CString) -> PathBuf {
+    #[cfg(unix)]
+    {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+        PathBuf::from(OsStr::from_bytes(arg.as_bytes()))
+    }
+    #[cfg(not(unix))]
+    {
+        PathBuf::from(String::from_utf8_lossy(arg.as_bytes()).into_owned())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plugin(path: &str) -> PluginPath {
+        PluginPath::from_args(&[CString::new(path).unwrap()]).unwrap()
+    }
+
+    #[test]
+    fn siblings() {
+        let absolute = plugin("/usr/lib/openvpn/my_plugin.so");
+        assert_eq!(Path::new("/usr/lib/openvpn"), absolute.dir());
+        assert_eq!(
+            Path::new("/usr/lib/openvpn/data/users.db"),
+            absolute.sibling("data/users.db")
+        );
+        assert_eq!(
+            Path::new("/usr/lib/openvpn/my_plugin.toml"),
+            absolute.with_extension("toml")
+        );
+
+        let relative = plugin("my_plugin.so");
+        assert_eq!(Path::new("."), relative.dir());
+        assert_eq!(Path::new("./users.db"), relative.sibling("users.db"));
+        assert_eq!(Path::new("my_plugin.toml"), relative.with_extension("toml"));
+
+        assert_eq!(None, PluginPath::from_args(&[]));
+    }
+}
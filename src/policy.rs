@@ -0,0 +1,751 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Rules deciding which clients may connect, loaded from the plugin config. Requires the
+//! `policy` feature.
+//!
+//! A [`Policy`] is a list of rules and a default action. The first rule matching a client decides
+//! whether it is allowed, and clients no rule matches get the default action, which is `deny`
+//! unless set. A rule matches when all of its conditions do, and a rule without conditions matches
+//! every client:
+//!
+//! ```toml
+//! [policy]
+//! default = "deny"
+//!
+//! [[policy.rules]]
+//! name = "revoked"
+//! action = "deny"
+//! common_name = ["test-*", "laptop-4?"]
+//!
+//! [[policy.rules]]
+//! name = "office"
+//! action = "allow"
+//! source = ["192.0.2.0/24", "2001:db8::/32"]
+//! platform = ["win", "mac"]
+//! time = { days = ["mon", "tue", "wed", "thu", "fri"], from = "07:00", to = "19:00" }
+//! ```
+//!
+//! * `common_name` matches the common name of the client certificate against globs, where `*`
+//!   matches any number of characters and `?` matches one.
+//! * `source` matches the real address of the client against networks.
+//! * `platform` matches `IV_PLAT` from the peer info of the client, ignoring case.
+//! * `time` matches the current time in UTC. A window ending before it starts spans midnight.
+//! * `country` matches the ISO 3166-1 code of the country the client connects from, ignoring case,
+//!   and `asn` the number of its autonomous system. Both are looked up with `geoip::GeoIp::locate`,
+//!   which requires the `geoip` feature. They never match otherwise.
+//!
+//! A `Policy` implements `Deserialize`, so it is part of the config struct of the plugin. It is
+//! evaluated against the environment of `EventType::ClientConnect`, or of `EventType::TlsVerify`
+//! at certificate depth 0, where no peer info is known yet:
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, ffi::CString};
+//! # use openvpn_plugin::{policy::Policy, EventResult};
+//! # fn audit_log(message: &str) {}
+//! # fn event(env: HashMap<CString, CString>, policy: &Policy) -> Result<EventResult, Box<dyn std::error::Error>> {
+//! let decision = policy.evaluate(&env)?;
+//! audit_log(&format!("{}", decision));
+//! Ok(decision.into())
+//! # }
+//! ```
+//!
+//! [`Policy`]: struct.Policy.html
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    error::Error,
+    ffi::CString,
+    fmt,
+    net::IpAddr,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    env_keys,
+    events::{Env, EventArgsError},
+    EventResult,
+};
+
+/// The common name of the client certificate in `EventType::TlsVerify` events, where
+/// `common_name` is not set yet.
+const X509_0_CN: &str = "X509_0_CN";
+
+/// Whether to let a client connect.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// The client may connect.
+    Allow,
+    /// The client may not connect.
+    #[default]
+    Deny,
+}
+
+/// The rules deciding which clients may connect. See the [module documentation].
+///
+/// [module documentation]: index.html
+#[derive(Debug, Clone, Eq, PartialEq, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// The action for clients no rule matches.
+    #[serde(default)]
+    pub default: Action,
+    /// The rules, in the order they are tried.
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+/// A rule of a [`Policy`]. Matches the clients matching all of its conditions.
+///
+/// [`Policy`]: struct.Policy.html
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// The name of the rule, for logging.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The action for the clients the rule matches.
+    pub action: Action,
+    /// Globs, one of which the common name of the client certificate must match.
+    #[serde(default)]
+    pub common_name: Option<Vec<String>>,
+    /// Networks, one of which the real address of the client must be in.
+    #[serde(default)]
+    pub source: Option<Vec<Cidr>>,
+    /// Platforms, one of which the client must report in `IV_PLAT`.
+    #[serde(default)]
+    pub platform: Option<Vec<String>>,
+    /// The time the client must connect at.
+    #[serde(default)]
+    pub time: Option<TimeWindow>,
+    /// Countries, one of which the client must connect from.
+    #[serde(default)]
+    pub country: Option<Vec<String>>,
+    /// Autonomous system numbers, one of which the address of the client must belong to.
+    #[serde(default)]
+    pub asn: Option<Vec<u32>>,
+}
+
+/// What the conditions of a rule are matched against.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Default)]
+pub struct Connection {
+    /// The common name of the client certificate, from `common_name` or `X509_0_CN`.
+    pub common_name: Option<String>,
+    /// The real address of the client, from `trusted_ip` or `untrusted_ip`, or their IPv6
+    /// counterparts.
+    pub ip: Option<IpAddr>,
+    /// The platform of the client, from `IV_PLAT`.
+    pub platform: Option<String>,
+    /// The ISO 3166-1 code of the country the client connects from. Not in the environment, see
+    /// `geoip::GeoIp::locate`.
+    pub country: Option<String>,
+    /// The autonomous system the address of the client belongs to. Not in the environment, see
+    /// `geoip::GeoIp::locate`.
+    pub asn: Option<u32>,
+}
+
+impl Connection {
+    /// Reads the connection from the environment of a client event. Missing variables, and the
+    /// origin of the client, are left `None`.
+    pub fn from_env(env: &HashMap<CString, CString>) -> Result<Self, EventArgsError> {
+        let env = Env(env);
+        let common_name = match env.string_opt(env_keys::COMMON_NAME)? {
+            Some(common_name) => Some(common_name),
+            None => env.string_opt(X509_0_CN)?,
+        };
+        let mut ip = None;
+        for name in &[
+            env_keys::TRUSTED_IP,
+            env_keys::TRUSTED_IP6,
+            env_keys::UNTRUSTED_IP,
+            env_keys::UNTRUSTED_IP6,
+        ] {
+            ip = env.parse_opt(name)?;
+            if ip.is_some() {
+                break;
+            }
+        }
+        Ok(Connection {
+            common_name,
+            ip,
+            platform: env.string_opt(env_keys::IV_PLAT)?,
+            country: None,
+            asn: None,
+        })
+    }
+}
+
+/// The outcome of evaluating a [`Policy`], with the rule that decided it. Formats as e.g.
+/// `allowed by rule "office"` or `denied by default`, for audit logs.
+///
+/// [`Policy`]: struct.Policy.html
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Decision<'a> {
+    action: Action,
+    rule: Option<(usize, &'a Rule)>,
+}
+
+impl<'a> Decision<'a> {
+    /// Whether the client may connect.
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    /// Returns true if the client may connect.
+    pub fn is_allowed(&self) -> bool {
+        self.action == Action::Allow
+    }
+
+    /// The rule that matched, or `None` if the default action was taken.
+    pub fn rule(&self) -> Option<&'a Rule> {
+        self.rule.map(|(_, rule)| rule)
+    }
+
+    /// The position in the policy of the rule that matched, counted from 0.
+    pub fn rule_index(&self) -> Option<usize> {
+        self.rule.map(|(index, _)| index)
+    }
+}
+
+impl fmt::Display for Decision<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.action {
+            Action::Allow => "allowed".fmt(f)?,
+            Action::Deny => "denied".fmt(f)?,
+        }
+        match self.rule {
+            Some((
+                _,
+                Rule {
+                    name: Some(name), ..
+                },
+            )) => write!(f, " by rule {:?}", name),
+            Some((index, _)) => write!(f, " by rule #{}", index + 1),
+            None => " by default".fmt(f),
+        }
+    }
+}
+
+/// `Allow` gives `EventResult::Success` and `Deny` gives `EventResult::Failure`.
+impl From<Decision<'_>> for EventResult {
+    fn from(decision: Decision<'_>) -> Self {
+        match decision.action {
+            Action::Allow => EventResult::Success,
+            Action::Deny => EventResult::Failure,
+        }
+    }
+}
+
+impl Policy {
+    /// Decides whether the client of the event with the environment `env` may connect now.
+    pub fn evaluate(
+        &self,
+        env: &HashMap<CString, CString>,
+    ) -> Result<Decision<'_>, EventArgsError> {
+        Ok(self.evaluate_at(&Connection::from_env(env)?, SystemTime::now()))
+    }
+
+    /// Decides whether `connection` may connect at `time`.
+    pub fn evaluate_at(&self, connection: &Connection, time: SystemTime) -> Decision<'_> {
+        let matched = self
+            .rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(connection, time));
+        match matched {
+            Some((index, rule)) => Decision {
+                action: rule.action,
+                rule: Some((index, rule)),
+            },
+            None => Decision {
+                action: self.default,
+                rule: None,
+            },
+        }
+    }
+}
+
+#[cfg(any(
+    feature = "config-toml",
+    feature = "config-json",
+    feature = "config-yaml"
+))]
+impl crate::config::Config for Policy {}
+
+impl Rule {
+    /// Returns whether `connection`, at `time`, matches all conditions of the rule.
+    pub fn matches(&self, connection: &Connection, time: SystemTime) -> bool {
+        fn any<T>(condition: &Option<Vec<T>>, matches: impl Fn(&T) -> bool) -> bool {
+            condition
+                .as_ref()
+                .map_or(true, |values| values.iter().any(matches))
+        }
+        let common_name = connection.common_name.as_deref();
+        let platform = connection.platform.as_deref();
+        let country = connection.country.as_deref();
+        any(&self.common_name, |glob| {
+            common_name.is_some_and(|common_name| glob_matches(glob, common_name))
+        }) && any(&self.source, |cidr| {
+            connection.ip.is_some_and(|ip| cidr.contains(ip))
+        }) && any(&self.platform, |expected| {
+            platform.is_some_and(|platform| platform.eq_ignore_ascii_case(expected))
+        }) && any(&self.country, |expected| {
+            country.is_some_and(|country| country.eq_ignore_ascii_case(expected))
+        }) && any(&self.asn, |expected| connection.asn == Some(*expected))
+            && self
+                .time
+                .as_ref()
+                .map_or(true, |window| window.contains(time))
+    }
+}
+
+/// Returns whether `text` matches `pattern`, where `*` matches any number of characters and `?`
+/// matches one.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // The position of the last `*` and of the text it was tried at, to backtrack to.
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    // Lets the `*` match one more character.
+                    p = star_p + 1;
+                    t = star_t + 1;
+                    star = Some((star_p, star_t + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Error returned when a network, time or day in a policy is not valid.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct InvalidValue {
+    kind: &'static str,
+    value: String,
+}
+
+impl InvalidValue {
+    fn new(kind: &'static str, value: &str) -> Self {
+        InvalidValue {
+            kind,
+            value: value.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for InvalidValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid {} {:?}", self.kind, self.value)
+    }
+}
+
+impl Error for InvalidValue {}
+
+
+/// An IPv4 or IPv6 network, written as `address/prefix`. A plain address is a network of one.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// The network of `address` with the given prefix length. Returns `None` if `prefix` is longer
+    /// than the address.
+    pub fn new(address: IpAddr, prefix: u8) -> Option<Self> {
+        let bits = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix > bits {
+            return None;
+        }
+        Some(Cidr { address, prefix })
+    }
+
+    /// Returns whether `ip` is in the network.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        fn prefix_eq(a: u128, b: u128, bits: u32, prefix: u8) -> bool {
+            let shift = bits - u32::from(prefix);
+            prefix == 0 || (a >> shift) == (b >> shift)
+        }
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => prefix_eq(
+                u32::from(network).into(),
+                u32::from(ip).into(),
+                32,
+                self.prefix,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_eq(network.into(), ip.into(), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = InvalidValue;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidValue::new("network", s);
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address: IpAddr = address.parse().map_err(|_| invalid())?;
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None if address.is_ipv4() => 32,
+            None => 128,
+        };
+        Cidr::new(address, prefix).ok_or_else(invalid)
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = InvalidValue;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+
+/// A day of the week.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[allow(missing_docs)]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+const WEEK: [Weekday; 7] = [
+    Weekday::Mon,
+    Weekday::Tue,
+    Weekday::Wed,
+    Weekday::Thu,
+    Weekday::Fri,
+    Weekday::Sat,
+    Weekday::Sun,
+];
+
+/// A time of day, written as `HH:MM`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeOfDay {
+    minutes: u16,
+}
+
+impl TimeOfDay {
+    /// The time `hour:minute`. Returns `None` if it is not a valid time.
+    pub fn new(hour: u8, minute: u8) -> Option<Self> {
+        if hour > 23 || minute > 59 {
+            return None;
+        }
+        Some(TimeOfDay {
+            minutes: u16::from(hour) * 60 + u16::from(minute),
+        })
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = InvalidValue;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidValue::new("time", s);
+        let (hour, minute) = s.split_once(':').ok_or_else(invalid)?;
+        if hour.len() != 2 || minute.len() != 2 {
+            return Err(invalid());
+        }
+        let hour = hour.parse().map_err(|_| invalid())?;
+        let minute = minute.parse().map_err(|_| invalid())?;
+        TimeOfDay::new(hour, minute).ok_or_else(invalid)
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = InvalidValue;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.minutes / 60, self.minutes % 60)
+    }
+}
+
+/// A daily window of time, in UTC. Includes `from` and excludes `to`.
+#[derive(Debug, Clone, Eq, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeWindow {
+    /// The days the window is on. Every day if not given. A window spanning midnight belongs to
+    /// the day it starts on.
+    #[serde(default = "every_day")]
+    pub days: Vec<Weekday>,
+    /// The start of the window.
+    pub from: TimeOfDay,
+    /// The end of the window.
+    pub to: TimeOfDay,
+}
+
+fn every_day() -> Vec<Weekday> {
+    WEEK.to_vec()
+}
+
+impl TimeWindow {
+    /// Returns whether `time` is in the window.
+    pub fn contains(&self, time: SystemTime) -> bool {
+        let minutes = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since_epoch| since_epoch.as_secs() / 60);
+        let days = minutes / (24 * 60);
+        let now = TimeOfDay {
+            minutes: (minutes % (24 * 60)) as u16,
+        };
+        // 1 January 1970 was a Thursday.
+        let weekday = |days: u64| WEEK[((days + 3) % 7) as usize];
+        if self.from <= self.to {
+            self.from <= now && now < self.to && self.days.contains(&weekday(days))
+        } else if now >= self.from {
+            self.days.contains(&weekday(days))
+        } else {
+            // Past midnight, in the window that started the day before.
+            now < self.to && days > 0 && self.days.contains(&weekday(days - 1))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::env;
+    use std::time::Duration;
+
+    /// Monday 1 January 2024 at `hour`:`minute` UTC.
+    fn monday(hour: u64, minute: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1704067200 + hour * 3600 + minute * 60)
+    }
+
+    fn connection(common_name: &str, ip: &str, platform: &str) -> Connection {
+        Connection {
+            common_name: Some(common_name.to_owned()),
+            ip: Some(ip.parse().unwrap()),
+            platform: Some(platform.to_owned()),
+            country: None,
+            asn: None,
+        }
+    }
+
+    fn rule(action: Action) -> Rule {
+        Rule {
+            name: None,
+            action,
+            common_name: None,
+            source: None,
+            platform: None,
+            time: None,
+            country: None,
+            asn: None,
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let policy = Policy {
+            default: Action::Deny,
+            rules: vec![
+                Rule {
+                    name: Some("revoked".to_owned()),
+                    common_name: Some(vec!["test-*".to_owned()]),
+                    ..rule(Action::Deny)
+                },
+                Rule {
+                    source: Some(vec!["192.0.2.0/24".parse().unwrap()]),
+                    platform: Some(vec!["win".to_owned(), "mac".to_owned()]),
+                    ..rule(Action::Allow)
+                },
+            ],
+        };
+        let at = monday(12, 0);
+
+        let decision = policy.evaluate_at(&connection("test-1", "192.0.2.7", "win"), at);
+        assert!(!decision.is_allowed());
+        assert_eq!(Some(0), decision.rule_index());
+        assert_eq!("denied by rule \"revoked\"", decision.to_string());
+
+        let decision = policy.evaluate_at(&connection("alice", "192.0.2.7", "Mac"), at);
+        assert!(decision.is_allowed());
+        assert_eq!("allowed by rule #2", decision.to_string());
+
+        let decision = policy.evaluate_at(&connection("alice", "198.51.100.1", "win"), at);
+        assert_eq!(None, decision.rule());
+        assert_eq!("denied by default", decision.to_string());
+        assert_eq!(EventResult::Failure, EventResult::from(decision));
+
+        // Conditions on missing values don't match.
+        let decision = policy.evaluate_at(&Connection::default(), at);
+        assert_eq!(None, decision.rule());
+    }
+
+    #[test]
+    fn origin() {
+        let policy = Policy {
+            default: Action::Allow,
+            rules: vec![Rule {
+                country: Some(vec!["se".to_owned()]),
+                asn: Some(vec![64496, 64511]),
+                ..rule(Action::Deny)
+            }],
+        };
+        let mut client = connection("alice", "192.0.2.7", "win");
+        let at = monday(12, 0);
+        assert!(policy.evaluate_at(&client, at).is_allowed());
+        client.country = Some("SE".to_owned());
+        assert!(policy.evaluate_at(&client, at).is_allowed());
+        client.asn = Some(64511);
+        assert!(!policy.evaluate_at(&client, at).is_allowed());
+    }
+
+    #[test]
+    fn connection_from_env() {
+        let env = env(&[
+            ("X509_0_CN", "alice"),
+            ("untrusted_ip6", "2001:db8::1"),
+            ("IV_PLAT", "linux"),
+        ]);
+        assert_eq!(
+            connection("alice", "2001:db8::1", "linux"),
+            Connection::from_env(&env).unwrap()
+        );
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_matches("test-*", "test-"));
+        assert!(glob_matches("test-*", "test-laptop"));
+        assert!(glob_matches("*-laptop-?", "alice-laptop-2"));
+        assert!(glob_matches("a*b*c", "aXbYbZc"));
+        assert!(glob_matches("*", ""));
+        assert!(!glob_matches("test-*", "prod-test-1"));
+        assert!(!glob_matches("laptop-?", "laptop-10"));
+        assert!(!glob_matches("a*b", "aXbYc"));
+    }
+
+    #[test]
+    fn networks() {
+        let network: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(network.contains("10.1.255.3".parse().unwrap()));
+        assert!(!network.contains("10.2.0.1".parse().unwrap()));
+        assert!(!network.contains("::ffff:10.1.0.1".parse().unwrap()));
+        let network: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(network.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!network.contains("2001:db9::1".parse().unwrap()));
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains("203.0.113.9".parse().unwrap()));
+        let single: Cidr = "192.0.2.1".parse().unwrap();
+        assert_eq!("192.0.2.1/32", single.to_string());
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn time_windows() {
+        let office = TimeWindow {
+            days: vec![Weekday::Mon],
+            from: "07:00".parse().unwrap(),
+            to: "19:00".parse().unwrap(),
+        };
+        assert!(office.contains(monday(7, 0)));
+        assert!(office.contains(monday(18, 59)));
+        assert!(!office.contains(monday(19, 0)));
+        assert!(!office.contains(monday(24 + 12, 0)));
+
+        let night = TimeWindow {
+            days: vec![Weekday::Mon],
+            from: "22:00".parse().unwrap(),
+            to: "02:00".parse().unwrap(),
+        };
+        assert!(night.contains(monday(23, 0)));
+        assert!(night.contains(monday(24 + 1, 0)));
+        assert!(!night.contains(monday(1, 0)));
+        assert!(!night.contains(monday(24 + 3, 0)));
+
+        assert!("7:00".parse::<TimeOfDay>().is_err());
+        assert!("24:00".parse::<TimeOfDay>().is_err());
+        assert_eq!("23:59", "23:59".parse::<TimeOfDay>().unwrap().to_string());
+    }
+
+    #[cfg(feature = "config-toml")]
+    #[test]
+    fn from_config() {
+        use crate::config::{self, Format};
+
+        let policy: Policy = config::parse(
+            r#"
+            default = "allow"
+
+            [[rules]]
+            name = "office"
+            action = "deny"
+            source = ["192.0.2.0/24"]
+            time = { days = ["sat", "sun"], from = "00:00", to = "23:59" }
+            "#,
+            Format::Toml,
+        )
+        .unwrap();
+        assert_eq!(Action::Allow, policy.default);
+        assert_eq!(
+            vec![Weekday::Sat, Weekday::Sun],
+            policy.rules[0].time.as_ref().unwrap().days
+        );
+
+        let error = config::parse::<Policy>(
+            "[[rules]]\naction = \"deny\"\nsource = [\"192.0.2.0/99\"]\n",
+            Format::Toml,
+        )
+        .unwrap_err();
+        assert_eq!(Some("rules[0].source[0]"), error.key());
+    }
+}
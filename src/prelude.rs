@@ -0,0 +1,47 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The macros, types and helpers most plugins use, for importing all at once:
+//!
+//! ```rust
+//! use openvpn_plugin::prelude::*;
+//! # use std::{collections::HashMap, ffi::CString, io};
+//!
+//! fn open(
+//!     args: Vec<CString>,
+//!     env: HashMap<CString, CString>,
+//! ) -> Result<(EventTypeSet, ()), io::Error> {
+//!     Ok((EventType::AuthUserPassVerify.into(), ()))
+//! }
+//! # fn close(_handle: ()) {}
+//! # fn event(
+//! #     _event: EventType,
+//! #     _args: Vec<CString>,
+//! #     _env: HashMap<CString, CString>,
+//! #     _handle: &mut (),
+//! # ) -> Result<EventResult, io::Error> {
+//! #     Ok(EventResult::Success)
+//! # }
+//!
+//! openvpn_plugin!(crate::open, crate::close, crate::event, ());
+//! # fn main() {}
+//! ```
+
+#[cfg(feature = "tokio")]
+pub use crate::openvpn_plugin_async;
+pub use crate::{
+    args::PluginArgs,
+    dispatch::{EventContext, EventDispatcher},
+    env_keys,
+    events::{EventArgs, EventArgsError},
+    ffi::parse::{env_utf8, env_utf8_lossy, string_array_utf8, string_array_utf8_lossy},
+    from_env::FromEnv,
+    openvpn_plugin, openvpn_plugin_raw,
+    raw::RawEvent,
+    EventResult, EventType, EventTypeSet, PluginHandle,
+};
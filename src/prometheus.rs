@@ -0,0 +1,264 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Serves the [`metrics`] of the plugin to Prometheus over HTTP. Requires the `prometheus`
+//! feature.
+//!
+//! The [`Exporter`] listens on a background thread and answers every request with the metrics in
+//! the Prometheus text format. The address to listen on is normally given as a plugin argument in
+//! the OpenVPN config:
+//!
+//! ```text
+//! plugin /usr/lib/openvpn/my_plugin.so --prometheus-listen 127.0.0.1:9176
+//! ```
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, ffi::CString};
+//! # use openvpn_plugin::{args::PluginArgs, prometheus::Exporter, EventType};
+//! struct Handle {
+//!     _exporter: Option<Exporter>,
+//! }
+//!
+//! fn open(
+//!     args: Vec<CString>,
+//!     _env: HashMap<CString, CString>,
+//! ) -> Result<(Vec<EventType>, Handle), Box<dyn std::error::Error>> {
+//!     let args = PluginArgs::parse(&args)?;
+//!     let exporter = Exporter::from_args(&args)?;
+//!     Ok((vec![EventType::Up], Handle { _exporter: exporter }))
+//! }
+//! ```
+//!
+//! The listener is stopped when the `Exporter` is dropped.
+//!
+//! [`metrics`]: ../metrics/index.html
+//! [`Exporter`]: struct.Exporter.html
+
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    args::PluginArgs,
+    logging,
+    metrics::{self, Snapshot, BUCKET_BOUNDS},
+    Error,
+};
+
+/// The plugin option `Exporter::from_args` takes the address to listen on from, given as
+/// `--prometheus-listen ADDR`.
+pub const LISTEN_ARG: &str = "prometheus-listen";
+
+/// How long to wait for a scraper to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An HTTP listener serving the metrics of the plugin.
+#[derive(Debug)]
+pub struct Exporter {
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Exporter {
+    /// Starts serving the metrics on `addr`.
+    pub fn start(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = thread::Builder::new()
+            .name("prometheus-exporter".to_owned())
+            .spawn({
+                let stop = stop.clone();
+                move || serve(listener, &stop)
+            })?;
+        Ok(Exporter {
+            local_addr,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Starts serving the metrics on the address given in the `--prometheus-listen ADDR` plugin
+    /// option. Returns `None` if the option is not given.
+    pub fn from_args(args: &PluginArgs) -> io::Result<Option<Self>> {
+        args.get(LISTEN_ARG).map(Self::start).transpose()
+    }
+
+    /// The address the exporter listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wakes the listener thread up from `accept`, so it sees that it should stop.
+        let _ = TcpStream::connect(self.local_addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve(listener: TcpListener, stop: &AtomicBool) {
+    for stream in listener.incoming() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let result = stream.and_then(respond);
+        if let Err(e) = result {
+            logging::log_warning(&Error::Other {
+                msg: "Unable to serve Prometheus metrics",
+                source: Box::new(e),
+            });
+        }
+    }
+}
+
+/// Reads the request head and answers with the current metrics, whatever the request was for.
+fn respond(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 && line != "\r\n" && line != "\n" {
+        line.clear();
+    }
+
+    let body = render(&metrics::snapshot());
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {}",
+        body.len(),
+        body
+    )?;
+    stream.shutdown(Shutdown::Write)
+}
+
+/// Formats `snapshot` in the Prometheus text exposition format.
+pub fn render(snapshot: &Snapshot) -> String {
+    let mut out = String::new();
+    out.push_str(
+        "# HELP openvpn_plugin_event_invocations_total Calls to the event callback.\n\
+         # TYPE openvpn_plugin_event_invocations_total counter\n",
+    );
+    for (event, metrics) in &snapshot.0 {
+        let _ = writeln!(
+            out,
+            "openvpn_plugin_event_invocations_total{{event=\"{}\"}} {}",
+            event, metrics.invocations
+        );
+    }
+    out.push_str(
+        "# HELP openvpn_plugin_event_failures_total Events answered with an error.\n\
+         # TYPE openvpn_plugin_event_failures_total counter\n",
+    );
+    for (event, metrics) in &snapshot.0 {
+        let _ = writeln!(
+            out,
+            "openvpn_plugin_event_failures_total{{event=\"{}\"}} {}",
+            event, metrics.failures
+        );
+    }
+    out.push_str(
+        "# HELP openvpn_plugin_event_duration_seconds Duration of the event callback.\n\
+         # TYPE openvpn_plugin_event_duration_seconds histogram\n",
+    );
+    for (event, metrics) in &snapshot.0 {
+        let counts = metrics.latency.counts();
+        let mut cumulative = 0;
+        for (bound, count) in BUCKET_BOUNDS.iter().zip(counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "openvpn_plugin_event_duration_seconds_bucket{{event=\"{}\",le=\"{}\"}} {}",
+                event,
+                bound.as_secs_f64(),
+                cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "openvpn_plugin_event_duration_seconds_bucket{{event=\"{}\",le=\"+Inf\"}} {}\n\
+             openvpn_plugin_event_duration_seconds_sum{{event=\"{}\"}} {}\n\
+             openvpn_plugin_event_duration_seconds_count{{event=\"{}\"}} {}",
+            event,
+            metrics.latency.count(),
+            event,
+            metrics.latency.sum().as_secs_f64(),
+            event,
+            metrics.latency.count()
+        );
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::EventType;
+    use std::{ffi::CString, io::Read};
+
+    #[test]
+    fn serves_metrics() {
+        metrics::record(EventType::IpChange, Duration::from_millis(20), true);
+        let args = [
+            CString::new("/plugin.so").unwrap(),
+            CString::new("--prometheus-listen").unwrap(),
+            CString::new("127.0.0.1:0").unwrap(),
+        ];
+        let args = PluginArgs::parse(&args).unwrap();
+        let exporter = Exporter::from_args(&args).unwrap().unwrap();
+
+        let mut stream = TcpStream::connect(exporter.local_addr()).unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        for line in &[
+            "openvpn_plugin_event_invocations_total{event=\"PLUGIN_IPCHANGE\"} 1",
+            "openvpn_plugin_event_failures_total{event=\"PLUGIN_IPCHANGE\"} 1",
+            "openvpn_plugin_event_duration_seconds_bucket{event=\"PLUGIN_IPCHANGE\",le=\"0.01\"} 0",
+            "openvpn_plugin_event_duration_seconds_bucket{event=\"PLUGIN_IPCHANGE\",le=\"0.05\"} 1",
+            "openvpn_plugin_event_duration_seconds_bucket{event=\"PLUGIN_IPCHANGE\",le=\"+Inf\"} 1",
+            "openvpn_plugin_event_duration_seconds_sum{event=\"PLUGIN_IPCHANGE\"} 0.02",
+        ] {
+            assert!(
+                response.contains(line),
+                "{} missing in:\n{}",
+                line,
+                response
+            );
+        }
+        drop(exporter);
+    }
+
+    #[test]
+    fn no_listen_arg() {
+        let args = PluginArgs::parse(&[CString::new("/plugin.so").unwrap()]).unwrap();
+        assert!(Exporter::from_args(&args).unwrap().is_none());
+    }
+}
@@ -0,0 +1,1130 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! RADIUS authentication and accounting. Requires the `radius` feature.
+//!
+//! [`RadiusClient::authenticate`] sends an Access-Request with the credentials of an
+//! `EventType::AuthUserPassVerify` event and returns the [`RadiusDecision`] of the server.
+//! [`Accounting`] sends Accounting-Start when a client connects, Accounting-Stop with the traffic
+//! counters when it disconnects and, optionally, Interim-Update for the connected clients at a
+//! fixed interval:
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, error::Error, ffi::CString, time::Duration};
+//! # use openvpn_plugin::{
+//! #     radius::{AccessRequest, Accounting, RadiusClient},
+//! #     EventResult, EventType,
+//! # };
+//! struct Handle {
+//!     client: RadiusClient,
+//!     accounting: Accounting,
+//! }
+//!
+//! fn open() -> Result<Handle, Box<dyn Error>> {
+//!     let server = "192.0.2.10:1812".parse()?;
+//!     let client = RadiusClient::new(server, "s3cret").nas_identifier("vpn-1");
+//!     let accounting =
+//!         Accounting::new(client.clone()).interim_interval(Duration::from_secs(600))?;
+//!     Ok(Handle { client, accounting })
+//! }
+//!
+//! fn event(
+//!     event: EventType,
+//!     env: HashMap<CString, CString>,
+//!     handle: &mut Handle,
+//! ) -> Result<EventResult, Box<dyn Error>> {
+//!     match event {
+//!         EventType::AuthUserPassVerify => {
+//!             let request = AccessRequest::from_env(&env)?;
+//!             Ok(handle.client.authenticate(&request)?.into())
+//!         }
+//!         _ => {
+//!             handle.accounting.handle_event(event, &env)?;
+//!             Ok(EventResult::Success)
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! Requests are sent over UDP and retried when the server does not answer within the timeout.
+//! Responses that do not match the request, or are not signed with the shared secret, are
+//! discarded. Access-Requests carry a Message-Authenticator, and Access-Accept, Access-Reject and
+//! Access-Challenge responses without a valid one are discarded too, as RFC 3579 and the
+//! mitigations for BlastRADIUS (CVE-2024-3596) require.
+//!
+//! Every request blocks until the server answers or all retries time out. Run authentications on
+//! a [`DeferredAuthPool`] and forward `ClientDisconnect` with a [`Forwarder`] to keep a slow
+//! server from stalling OpenVPN.
+//!
+//! [`RadiusClient::authenticate`]: struct.RadiusClient.html#method.authenticate
+//! [`RadiusDecision`]: enum.RadiusDecision.html
+//! [`Accounting`]: struct.Accounting.html
+//! [`DeferredAuthPool`]: ../deferred_auth/struct.DeferredAuthPool.html
+//! [`Forwarder`]: ../forward/struct.Forwarder.html
+
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    error::Error,
+    ffi::CString,
+    fmt, io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    auth::Credentials,
+    env_keys,
+    events::{DisconnectStats, Env, EventArgsError},
+    logging,
+    sessions::SessionKey,
+    workers::Workers,
+    EventResult, EventType,
+};
+
+/// The default time to wait for the server to answer one request.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The default number of times to resend a request the server did not answer.
+pub const DEFAULT_RETRIES: u32 = 2;
+
+/// The default NAS-Identifier sent in every request.
+pub const DEFAULT_NAS_IDENTIFIER: &str = "openvpn";
+
+/// The port accounting requests are sent to by default.
+pub const DEFAULT_ACCOUNTING_PORT: u16 = 1813;
+
+/// The attribute types used by this module.
+pub mod attributes {
+    /// User-Name.
+    pub const USER_NAME: u8 = 1;
+    /// User-Password.
+    pub const USER_PASSWORD: u8 = 2;
+    /// Framed-IP-Address.
+    pub const FRAMED_IP_ADDRESS: u8 = 8;
+    /// Reply-Message.
+    pub const REPLY_MESSAGE: u8 = 18;
+    /// Class.
+    pub const CLASS: u8 = 25;
+    /// Session-Timeout.
+    pub const SESSION_TIMEOUT: u8 = 27;
+    /// Calling-Station-Id.
+    pub const CALLING_STATION_ID: u8 = 31;
+    /// NAS-Identifier.
+    pub const NAS_IDENTIFIER: u8 = 32;
+    /// Acct-Status-Type.
+    pub const ACCT_STATUS_TYPE: u8 = 40;
+    /// Acct-Input-Octets.
+    pub const ACCT_INPUT_OCTETS: u8 = 42;
+    /// Acct-Output-Octets.
+    pub const ACCT_OUTPUT_OCTETS: u8 = 43;
+    /// Acct-Session-Id.
+    pub const ACCT_SESSION_ID: u8 = 44;
+    /// Acct-Session-Time.
+    pub const ACCT_SESSION_TIME: u8 = 46;
+    /// Acct-Input-Gigawords.
+    pub const ACCT_INPUT_GIGAWORDS: u8 = 52;
+    /// Acct-Output-Gigawords.
+    pub const ACCT_OUTPUT_GIGAWORDS: u8 = 53;
+    /// NAS-Port-Type.
+    pub const NAS_PORT_TYPE: u8 = 61;
+    /// Message-Authenticator.
+    pub const MESSAGE_AUTHENTICATOR: u8 = 80;
+}
+
+const ACCESS_REQUEST: u8 = 1;
+const ACCESS_ACCEPT: u8 = 2;
+const ACCESS_REJECT: u8 = 3;
+const ACCOUNTING_REQUEST: u8 = 4;
+const ACCOUNTING_RESPONSE: u8 = 5;
+const ACCESS_CHALLENGE: u8 = 11;
+
+/// The NAS-Port-Type of a VPN connection.
+const NAS_PORT_TYPE_VIRTUAL: u32 = 5;
+
+const HEADER_LEN: usize = 20;
+const MAX_PACKET_LEN: usize = 4096;
+const MAX_ATTRIBUTE_LEN: usize = 253;
+const MAX_PASSWORD_LEN: usize = 128;
+
+/// The error type returned by the RADIUS client.
+#[derive(Debug)]
+pub enum RadiusError {
+    /// Sending a request or receiving the response failed.
+    Io(io::Error),
+    /// The server did not answer after all retries.
+    Timeout(SocketAddr),
+    /// The request could not be built from the environment of the event.
+    InvalidEnv(EventArgsError),
+    /// The attribute with the given type is too long for a RADIUS packet.
+    TooLong(u8),
+}
+
+impl fmt::Display for RadiusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RadiusError::Io(_) => f.write_str("Unable to talk to the RADIUS server"),
+            RadiusError::Timeout(server) => {
+                write!(f, "The RADIUS server at {} did not answer", server)
+            }
+            RadiusError::InvalidEnv(_) => f.write_str("Unable to build the RADIUS request"),
+            RadiusError::TooLong(kind) => write!(f, "RADIUS attribute {} is too long", kind),
+        }
+    }
+}
+
+impl Error for RadiusError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RadiusError::Io(e) => Some(e),
+            RadiusError::InvalidEnv(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for RadiusError {
+    fn from(e: io::Error) -> Self {
+        RadiusError::Io(e)
+    }
+}
+
+impl From<EventArgsError> for RadiusError {
+    fn from(e: EventArgsError) -> Self {
+        RadiusError::InvalidEnv(e)
+    }
+}
+
+
+/// A RADIUS attribute.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Attribute {
+    /// The attribute type, see [`attributes`].
+    ///
+    /// [`attributes`]: attributes/index.html
+    pub kind: u8,
+    /// The value, at most 253 bytes.
+    pub value: Vec<u8>,
+}
+
+impl Attribute {
+    /// Creates an attribute.
+    pub fn new(kind: u8, value: impl Into<Vec<u8>>) -> Self {
+        Attribute {
+            kind,
+            value: value.into(),
+        }
+    }
+
+    /// Creates an attribute holding a 32 bit integer.
+    pub fn integer(kind: u8, value: u32) -> Self {
+        Self::new(kind, value.to_be_bytes())
+    }
+}
+
+/// The attributes of a response.
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub struct Reply {
+    /// All attributes in the order the server sent them.
+    pub attributes: Vec<Attribute>,
+}
+
+impl Reply {
+    /// The value of the first attribute of type `kind`.
+    pub fn get(&self, kind: u8) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|attribute| attribute.kind == kind)
+            .map(|attribute| &attribute.value[..])
+    }
+
+    /// The Reply-Message attributes joined together, to show to the user.
+    pub fn reply_message(&self) -> Option<String> {
+        let message: Vec<u8> = self
+            .attributes
+            .iter()
+            .filter(|attribute| attribute.kind == attributes::REPLY_MESSAGE)
+            .flat_map(|attribute| attribute.value.iter().copied())
+            .collect();
+        if message.is_empty() {
+            None
+        } else {
+            Some(String::from_utf8_lossy(&message).into_owned())
+        }
+    }
+
+    /// The Session-Timeout, the longest the client may stay connected.
+    pub fn session_timeout(&self) -> Option<Duration> {
+        let value = self.get(attributes::SESSION_TIMEOUT)?;
+        let seconds = u32::from_be_bytes(value.try_into().ok()?);
+        Some(Duration::from_secs(seconds.into()))
+    }
+
+    /// The Class, which the server wants back in the accounting requests of the session.
+    pub fn class(&self) -> Option<&[u8]> {
+        self.get(attributes::CLASS)
+    }
+}
+
+/// The answer of the server to an Access-Request.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RadiusDecision {
+    /// Access-Accept.
+    Accept(Reply),
+    /// Access-Reject.
+    Reject(Reply),
+    /// Access-Challenge. OpenVPN can not relay the challenge to the client this way, so it is
+    /// treated as a rejection by the `EventResult` conversion.
+    Challenge(Reply),
+}
+
+impl RadiusDecision {
+    /// Returns whether the server accepted the credentials.
+    pub fn is_accept(&self) -> bool {
+        matches!(self, RadiusDecision::Accept(_))
+    }
+
+    /// The attributes of the response.
+    pub fn reply(&self) -> &Reply {
+        match self {
+            RadiusDecision::Accept(reply)
+            | RadiusDecision::Reject(reply)
+            | RadiusDecision::Challenge(reply) => reply,
+        }
+    }
+}
+
+/// Accepts gives `Success`. Reject and challenge give `FailureWithReason` with the Reply-Message,
+/// or `Failure` if there is none.
+impl From<RadiusDecision> for EventResult {
+    fn from(decision: RadiusDecision) -> Self {
+        match decision {
+            RadiusDecision::Accept(_) => EventResult::Success,
+            RadiusDecision::Reject(reply) | RadiusDecision::Challenge(reply) => {
+                match reply.reply_message() {
+                    Some(message) => EventResult::FailureWithReason(message),
+                    None => EventResult::Failure,
+                }
+            }
+        }
+    }
+}
+
+/// An Access-Request.
+#[derive(Clone, Eq, PartialEq)]
+pub struct AccessRequest {
+    /// Sent as User-Name.
+    pub username: String,
+    /// Sent hidden with the shared secret as User-Password.
+    pub password: String,
+    /// Sent as Calling-Station-Id. The address the client connects from.
+    pub calling_station_id: Option<String>,
+    /// Extra attributes to send.
+    pub attributes: Vec<Attribute>,
+}
+
+impl AccessRequest {
+    /// Builds the request from the environment of an `EventType::AuthUserPassVerify` event.
+    pub fn from_env(env: &HashMap<CString, CString>) -> Result<Self, EventArgsError> {
+        let credentials = Credentials::from_env(env)?;
+        Ok(AccessRequest {
+            username: credentials.username().to_owned(),
+            password: credentials.password().to_owned(),
+            calling_station_id: calling_station_id(&Env(env))?,
+            attributes: Vec::new(),
+        })
+    }
+}
+
+impl fmt::Debug for AccessRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessRequest")
+            .field("username", &self.username)
+            .field("password", &"REDACTED")
+            .field("calling_station_id", &self.calling_station_id)
+            .field("attributes", &self.attributes)
+            .finish()
+    }
+}
+
+fn calling_station_id(env: &Env<'_>) -> Result<Option<String>, EventArgsError> {
+    let ip = env.ip_opt(env_keys::UNTRUSTED_IP, env_keys::UNTRUSTED_IP6)?;
+    Ok(ip.map(|ip| ip.to_string()))
+}
+
+/// The Acct-Status-Type of an accounting request.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[repr(u32)]
+pub enum AccountingStatus {
+    /// The client connected.
+    Start = 1,
+    /// The client disconnected.
+    Stop = 2,
+    /// The client is still connected.
+    InterimUpdate = 3,
+}
+
+/// An Accounting-Request.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct AccountingRequest {
+    /// Sent as Acct-Status-Type.
+    pub status: AccountingStatus,
+    /// Sent as Acct-Session-Id. The same for all requests about one session.
+    pub session_id: String,
+    /// Sent as User-Name.
+    pub username: String,
+    /// Sent as Framed-IP-Address. The tunnel address of the client.
+    pub framed_ip: Option<Ipv4Addr>,
+    /// Sent as Calling-Station-Id. The address the client connects from.
+    pub calling_station_id: Option<String>,
+    /// Sent as Acct-Session-Time.
+    pub session_time: Option<Duration>,
+    /// Sent as Acct-Input-Octets and Acct-Input-Gigawords. The bytes received from the client.
+    pub input_octets: Option<u64>,
+    /// Sent as Acct-Output-Octets and Acct-Output-Gigawords. The bytes sent to the client.
+    pub output_octets: Option<u64>,
+    /// Extra attributes to send.
+    pub attributes: Vec<Attribute>,
+}
+
+impl AccountingRequest {
+    /// Builds an Accounting-Start from the environment of an `EventType::ClientConnect` event.
+    pub fn start(env: &HashMap<CString, CString>) -> Result<Self, EventArgsError> {
+        let key = SessionKey::from_env(env)?;
+        let env = Env(env);
+        let session_id = match env.string_opt(env_keys::TIME_UNIX)? {
+            Some(time) => format!("{}-{}", key, time),
+            None => key.to_string(),
+        };
+        Ok(AccountingRequest {
+            status: AccountingStatus::Start,
+            session_id,
+            username: env
+                .string_opt(env_keys::USERNAME)?
+                .unwrap_or_else(|| key.common_name.clone()),
+            framed_ip: env.parse_opt(env_keys::IFCONFIG_POOL_REMOTE_IP)?,
+            calling_station_id: calling_station_id(&env)?,
+            session_time: None,
+            input_octets: None,
+            output_octets: None,
+            attributes: Vec::new(),
+        })
+    }
+
+    /// Builds an Accounting-Stop from the environment of an `EventType::ClientDisconnect` event.
+    pub fn stop(env: &HashMap<CString, CString>) -> Result<Self, EventArgsError> {
+        let stats = DisconnectStats::from_env(env)?;
+        Ok(AccountingRequest {
+            status: AccountingStatus::Stop,
+            session_time: Some(stats.duration),
+            input_octets: Some(stats.bytes_received),
+            output_octets: Some(stats.bytes_sent),
+            ..Self::start(env)?
+        })
+    }
+
+    fn to_attributes(&self) -> Vec<Attribute> {
+        let mut attrs = vec![
+            Attribute::integer(attributes::ACCT_STATUS_TYPE, self.status as u32),
+            Attribute::new(attributes::ACCT_SESSION_ID, self.session_id.as_bytes()),
+            Attribute::new(attributes::USER_NAME, self.username.as_bytes()),
+        ];
+        if let Some(ip) = self.framed_ip {
+            attrs.push(Attribute::new(attributes::FRAMED_IP_ADDRESS, ip.octets()));
+        }
+        if let Some(id) = &self.calling_station_id {
+            attrs.push(Attribute::new(
+                attributes::CALLING_STATION_ID,
+                id.as_bytes(),
+            ));
+        }
+        if let Some(time) = self.session_time {
+            let seconds = u32::try_from(time.as_secs()).unwrap_or(u32::MAX);
+            attrs.push(Attribute::integer(attributes::ACCT_SESSION_TIME, seconds));
+        }
+        if let Some(octets) = self.input_octets {
+            attrs.push(Attribute::integer(
+                attributes::ACCT_INPUT_OCTETS,
+                octets as u32,
+            ));
+            attrs.push(Attribute::integer(
+                attributes::ACCT_INPUT_GIGAWORDS,
+                (octets >> 32) as u32,
+            ));
+        }
+        if let Some(octets) = self.output_octets {
+            attrs.push(Attribute::integer(
+                attributes::ACCT_OUTPUT_OCTETS,
+                octets as u32,
+            ));
+            attrs.push(Attribute::integer(
+                attributes::ACCT_OUTPUT_GIGAWORDS,
+                (octets >> 32) as u32,
+            ));
+        }
+        attrs.extend(self.attributes.iter().cloned());
+        attrs
+    }
+}
+
+
+/// A client for a RADIUS server. Cloning it gives a client for the same server.
+#[derive(Clone)]
+pub struct RadiusClient {
+    server: SocketAddr,
+    accounting_server: SocketAddr,
+    secret: Arc<[u8]>,
+    nas_identifier: String,
+    timeout: Duration,
+    retries: u32,
+    identifier: Arc<AtomicU8>,
+}
+
+impl RadiusClient {
+    /// Creates a client sending Access-Requests to `server` and accounting requests to the
+    /// same address on `DEFAULT_ACCOUNTING_PORT`, signed with the shared `secret`.
+    pub fn new(server: SocketAddr, secret: impl AsRef<[u8]>) -> Self {
+        let mut identifier = [0];
+        // The identifier only needs to tell outstanding requests apart, any start value works.
+        let _ = getrandom::fill(&mut identifier);
+        RadiusClient {
+            server,
+            accounting_server: SocketAddr::new(server.ip(), DEFAULT_ACCOUNTING_PORT),
+            secret: secret.as_ref().into(),
+            nas_identifier: DEFAULT_NAS_IDENTIFIER.to_owned(),
+            timeout: DEFAULT_TIMEOUT,
+            retries: DEFAULT_RETRIES,
+            identifier: Arc::new(AtomicU8::new(identifier[0])),
+        }
+    }
+
+    /// Sets the address accounting requests are sent to.
+    pub fn accounting_server(mut self, server: SocketAddr) -> Self {
+        self.accounting_server = server;
+        self
+    }
+
+    /// Sets the NAS-Identifier sent in every request. `DEFAULT_NAS_IDENTIFIER` by default.
+    pub fn nas_identifier(mut self, nas_identifier: impl Into<String>) -> Self {
+        self.nas_identifier = nas_identifier.into();
+        self
+    }
+
+    /// Sets the time to wait for the server to answer one request. `DEFAULT_TIMEOUT` by default.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the number of times to resend a request the server did not answer. `DEFAULT_RETRIES`
+    /// by default.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Sends an Access-Request and waits for the answer of the server.
+    pub fn authenticate(&self, request: &AccessRequest) -> Result<RadiusDecision, RadiusError> {
+        let mut authenticator = [0; 16];
+        getrandom::fill(&mut authenticator).map_err(io::Error::from)?;
+
+        let mut attrs = vec![
+            // First, so it is at a known offset when signing.
+            Attribute::new(attributes::MESSAGE_AUTHENTICATOR, [0; 16]),
+            Attribute::new(attributes::USER_NAME, request.username.as_bytes()),
+            Attribute::new(
+                attributes::USER_PASSWORD,
+                hide_password(request.password.as_bytes(), &self.secret, &authenticator)?,
+            ),
+            Attribute::new(attributes::NAS_IDENTIFIER, self.nas_identifier.as_bytes()),
+            Attribute::integer(attributes::NAS_PORT_TYPE, NAS_PORT_TYPE_VIRTUAL),
+        ];
+        if let Some(id) = &request.calling_station_id {
+            attrs.push(Attribute::new(
+                attributes::CALLING_STATION_ID,
+                id.as_bytes(),
+            ));
+        }
+        attrs.extend(request.attributes.iter().cloned());
+
+        let mut packet = encode(
+            ACCESS_REQUEST,
+            self.next_identifier(),
+            &authenticator,
+            &attrs,
+        )?;
+        let signature = hmac_md5(&self.secret, &packet);
+        packet[HEADER_LEN + 2..HEADER_LEN + 18].copy_from_slice(&signature);
+
+        let (code, reply) = self.exchange(
+            self.server,
+            &packet,
+            &[ACCESS_ACCEPT, ACCESS_REJECT, ACCESS_CHALLENGE],
+        )?;
+        Ok(match code {
+            ACCESS_ACCEPT => RadiusDecision::Accept(reply),
+            ACCESS_CHALLENGE => RadiusDecision::Challenge(reply),
+            _ => RadiusDecision::Reject(reply),
+        })
+    }
+
+    /// Sends an Accounting-Request and waits for the server to acknowledge it.
+    pub fn account(&self, request: &AccountingRequest) -> Result<(), RadiusError> {
+        let mut attrs = request.to_attributes();
+        attrs.push(Attribute::new(
+            attributes::NAS_IDENTIFIER,
+            self.nas_identifier.as_bytes(),
+        ));
+        let mut packet = encode(ACCOUNTING_REQUEST, self.next_identifier(), &[0; 16], &attrs)?;
+        let authenticator = md5_of(&[&packet, &self.secret]);
+        packet[4..HEADER_LEN].copy_from_slice(&authenticator);
+
+        self.exchange(self.accounting_server, &packet, &[ACCOUNTING_RESPONSE])
+            .map(|_| ())
+    }
+
+    fn next_identifier(&self) -> u8 {
+        self.identifier.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Sends `request` until a valid response with one of `codes` arrives, or the retries run
+    /// out.
+    fn exchange(
+        &self,
+        server: SocketAddr,
+        request: &[u8],
+        codes: &[u8],
+    ) -> Result<(u8, Reply), RadiusError> {
+        let local: SocketAddr = match server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(server)?;
+        let mut buf = [0; MAX_PACKET_LEN];
+        for _ in 0..=self.retries {
+            socket.send(request)?;
+            let deadline = Instant::now() + self.timeout;
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                socket.set_read_timeout(Some(deadline - now))?;
+                let len = match socket.recv(&mut buf) {
+                    Ok(len) => len,
+                    Err(e)
+                        if e.kind() == io::ErrorKind::WouldBlock
+                            || e.kind() == io::ErrorKind::TimedOut =>
+                    {
+                        break
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                // Anything else is silently discarded, as RFC 2865 requires.
+                if let Some(response) = verify_response(&buf[..len], request, &self.secret) {
+                    if codes.contains(&response.0) {
+                        return Ok(response);
+                    }
+                }
+            }
+        }
+        Err(RadiusError::Timeout(server))
+    }
+}
+
+impl fmt::Debug for RadiusClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RadiusClient")
+            .field("server", &self.server)
+            .field("accounting_server", &self.accounting_server)
+            .field("nas_identifier", &self.nas_identifier)
+            .field("timeout", &self.timeout)
+            .field("retries", &self.retries)
+            .finish_non_exhaustive()
+    }
+}
+
+fn encode(
+    code: u8,
+    identifier: u8,
+    authenticator: &[u8; 16],
+    attrs: &[Attribute],
+) -> Result<Vec<u8>, RadiusError> {
+    let mut packet = vec![code, identifier, 0, 0];
+    packet.extend_from_slice(authenticator);
+    for attribute in attrs {
+        if attribute.value.len() > MAX_ATTRIBUTE_LEN {
+            return Err(RadiusError::TooLong(attribute.kind));
+        }
+        packet.push(attribute.kind);
+        packet.push(attribute.value.len() as u8 + 2);
+        packet.extend_from_slice(&attribute.value);
+        if packet.len() > MAX_PACKET_LEN {
+            return Err(RadiusError::TooLong(attribute.kind));
+        }
+    }
+    let len = packet.len() as u16;
+    packet[2..4].copy_from_slice(&len.to_be_bytes());
+    Ok(packet)
+}
+
+/// Splits the attributes of a packet into their offsets and values.
+fn decode_attributes(mut data: &[u8]) -> Option<Vec<(usize, Attribute)>> {
+    let mut attrs = Vec::new();
+    let mut offset = HEADER_LEN;
+    while !data.is_empty() {
+        let len = usize::from(*data.get(1)?);
+        if len < 2 || len > data.len() {
+            return None;
+        }
+        attrs.push((offset, Attribute::new(data[0], &data[2..len])));
+        data = &data[len..];
+        offset += len;
+    }
+    Some(attrs)
+}
+
+/// Checks that `response` answers `request` and is signed with `secret`, and returns its code and
+/// attributes.
+fn verify_response(response: &[u8], request: &[u8], secret: &[u8]) -> Option<(u8, Reply)> {
+    if response.len() < HEADER_LEN || response[1] != request[1] {
+        return None;
+    }
+    let len = usize::from(u16::from_be_bytes([response[2], response[3]]));
+    if len < HEADER_LEN || len > response.len() {
+        return None;
+    }
+    let response = &response[..len];
+    let request_authenticator = &request[4..HEADER_LEN];
+
+    let expected = md5_of(&[
+        &response[..4],
+        request_authenticator,
+        &response[HEADER_LEN..],
+        secret,
+    ]);
+    if expected[..] != response[4..HEADER_LEN] {
+        return None;
+    }
+
+    let attrs = decode_attributes(&response[HEADER_LEN..])?;
+    match attrs
+        .iter()
+        .find(|(_, attribute)| attribute.kind == attributes::MESSAGE_AUTHENTICATOR)
+    {
+        Some((offset, signature)) => {
+            if signature.value.len() != 16 {
+                return None;
+            }
+            let mut signed = response.to_vec();
+            signed[4..HEADER_LEN].copy_from_slice(request_authenticator);
+            signed[offset + 2..offset + 18].fill(0);
+            if hmac_md5(secret, &signed)[..] != signature.value[..] {
+                return None;
+            }
+        }
+        // Without it, the answer to an Access-Request can be forged (CVE-2024-3596, BlastRADIUS).
+        None if matches!(
+            response[0],
+            ACCESS_ACCEPT | ACCESS_REJECT | ACCESS_CHALLENGE
+        ) =>
+        {
+            return None
+        }
+        None => (),
+    }
+    Some((
+        response[0],
+        Reply {
+            attributes: attrs.into_iter().map(|(_, attribute)| attribute).collect(),
+        },
+    ))
+}
+
+/// Hides a User-Password as described in RFC 2865 section 5.2.
+fn hide_password(
+    password: &[u8],
+    secret: &[u8],
+    authenticator: &[u8; 16],
+) -> Result<Vec<u8>, RadiusError> {
+    if password.len() > MAX_PASSWORD_LEN {
+        return Err(RadiusError::TooLong(attributes::USER_PASSWORD));
+    }
+    let mut hidden = password.to_vec();
+    let padded_len = password.len().div_ceil(16).max(1) * 16;
+    hidden.resize(padded_len, 0);
+    let mut previous = authenticator.to_vec();
+    for chunk in hidden.chunks_mut(16) {
+        let key = md5_of(&[secret, &previous]);
+        for (byte, key) in chunk.iter_mut().zip(key.iter()) {
+            *byte ^= key;
+        }
+        previous = chunk.to_vec();
+    }
+    Ok(hidden)
+}
+
+fn md5_of(parts: &[&[u8]]) -> [u8; 16] {
+    let mut context = md5::Context::new();
+    for part in parts {
+        context.consume(part);
+    }
+    context.finalize().0
+}
+
+fn hmac_md5(key: &[u8], message: &[u8]) -> [u8; 16] {
+    let mut block = [0; 64];
+    if key.len() > block.len() {
+        block[..16].copy_from_slice(&md5_of(&[key]));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let inner_key = block.map(|byte| byte ^ 0x36);
+    let outer_key = block.map(|byte| byte ^ 0x5c);
+    let inner = md5_of(&[&inner_key, message]);
+    md5_of(&[&outer_key, &inner])
+}
+
+
+/// A connected client, for interim updates.
+struct ActiveSession {
+    start: AccountingRequest,
+    connected: Instant,
+}
+
+type ActiveSessions = Arc<Mutex<HashMap<String, ActiveSession>>>;
+
+/// Sends the accounting requests of the client sessions to a RADIUS server.
+pub struct Accounting {
+    client: RadiusClient,
+    sessions: ActiveSessions,
+    workers: Workers,
+}
+
+impl Accounting {
+    /// Creates accounting sending its requests with `client`, without interim updates.
+    pub fn new(client: RadiusClient) -> Self {
+        Accounting {
+            client,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            workers: Workers::new(),
+        }
+    }
+
+    /// Spawns a worker sending an Interim-Update for every connected client each `interval`.
+    /// OpenVPN only reports the traffic of a session when it ends, so the updates carry the
+    /// session time but no traffic counters.
+    pub fn interim_interval(mut self, interval: Duration) -> io::Result<Self> {
+        let client = self.client.clone();
+        let sessions = self.sessions.clone();
+        self.workers.spawn("radius-interim", move |shutdown| {
+            while !shutdown.wait_timeout(interval) {
+                let updates: Vec<AccountingRequest> = lock(&sessions)
+                    .values()
+                    .map(|session| AccountingRequest {
+                        status: AccountingStatus::InterimUpdate,
+                        session_time: Some(session.connected.elapsed()),
+                        ..session.start.clone()
+                    })
+                    .collect();
+                for update in updates {
+                    if let Err(e) = client.account(&update) {
+                        logging::log_warning(&e);
+                    }
+                }
+            }
+        })?;
+        Ok(self)
+    }
+
+    /// Sends Accounting-Start for `ClientConnect` events and Accounting-Stop for `ClientDisconnect`
+    /// events. Other events are ignored.
+    pub fn handle_event(
+        &self,
+        event: EventType,
+        env: &HashMap<CString, CString>,
+    ) -> Result<(), RadiusError> {
+        match event {
+            EventType::ClientConnect | EventType::ClientConnectV2 => self.start(env),
+            #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+            EventType::ClientConnectDefer | EventType::ClientConnectDeferV2 => self.start(env),
+            EventType::ClientDisconnect => {
+                let stop = AccountingRequest::stop(env)?;
+                lock(&self.sessions).remove(&stop.session_id);
+                self.client.account(&stop)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn start(&self, env: &HashMap<CString, CString>) -> Result<(), RadiusError> {
+        let start = AccountingRequest::start(env)?;
+        // Tracked before sending, so the session is stopped even if the start was lost.
+        lock(&self.sessions).insert(
+            start.session_id.clone(),
+            ActiveSession {
+                start: start.clone(),
+                connected: Instant::now(),
+            },
+        );
+        self.client.account(&start)
+    }
+
+    /// The number of connected clients.
+    pub fn active(&self) -> usize {
+        lock(&self.sessions).len()
+    }
+}
+
+fn lock(sessions: &ActiveSessions) -> MutexGuard<'_, HashMap<String, ActiveSession>> {
+    sessions.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl fmt::Debug for Accounting {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Accounting")
+            .field("client", &self.client)
+            .field("active", &self.active())
+            .field("workers", &self.workers)
+            .finish()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::env;
+    use std::{convert::TryInto, sync::mpsc, thread};
+
+    const SECRET: &[u8] = b"s3cret";
+
+    /// Answers requests with `handler` until it returns `None`, signing the responses with
+    /// `secret`.
+    fn serve(
+        secret: &'static [u8],
+        handler: impl FnMut(u8, &[Attribute], &[u8; 16]) -> Option<(u8, Vec<Attribute>)>
+            + Send
+            + 'static,
+    ) -> SocketAddr {
+        serve_with(secret, true, handler)
+    }
+
+    /// Like `serve`, but only adds a Message-Authenticator to Access responses if
+    /// `message_authenticator` is true.
+    fn serve_with(
+        secret: &'static [u8],
+        message_authenticator: bool,
+        mut handler: impl FnMut(u8, &[Attribute], &[u8; 16]) -> Option<(u8, Vec<Attribute>)>
+            + Send
+            + 'static,
+    ) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; MAX_PACKET_LEN];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).unwrap();
+                let request = &buf[..len];
+                let authenticator: [u8; 16] = request[4..HEADER_LEN].try_into().unwrap();
+                let attrs: Vec<Attribute> = decode_attributes(&request[HEADER_LEN..])
+                    .unwrap()
+                    .into_iter()
+                    .map(|(_, attribute)| attribute)
+                    .collect();
+                let (code, mut reply) = match handler(request[0], &attrs, &authenticator) {
+                    Some(response) => response,
+                    None => return,
+                };
+                let signed = message_authenticator && code != ACCOUNTING_RESPONSE;
+                if signed {
+                    reply.insert(
+                        0,
+                        Attribute::new(attributes::MESSAGE_AUTHENTICATOR, [0; 16]),
+                    );
+                }
+                let mut response = encode(code, request[1], &authenticator, &reply).unwrap();
+                if signed {
+                    let signature = hmac_md5(secret, &response);
+                    response[HEADER_LEN + 2..HEADER_LEN + 18].copy_from_slice(&signature);
+                }
+                let signature = md5_of(&[&response, secret]);
+                response[4..HEADER_LEN].copy_from_slice(&signature);
+                socket.send_to(&response, peer).unwrap();
+            }
+        });
+        addr
+    }
+
+    fn get(attrs: &[Attribute], kind: u8) -> &[u8] {
+        &attrs.iter().find(|a| a.kind == kind).unwrap().value
+    }
+
+    fn reveal_password(hidden: &[u8], authenticator: &[u8; 16]) -> Vec<u8> {
+        let mut password = Vec::new();
+        let mut previous = authenticator.to_vec();
+        for chunk in hidden.chunks(16) {
+            let key = md5_of(&[SECRET, &previous]);
+            password.extend(chunk.iter().zip(key.iter()).map(|(c, k)| c ^ k));
+            previous = chunk.to_vec();
+        }
+        while password.last() == Some(&0) {
+            password.pop();
+        }
+        password
+    }
+
+    #[test]
+    fn hmac_md5_rfc2104() {
+        let expected = [
+            0x75, 0x0c, 0x78, 0x3e, 0x6a, 0xb0, 0xb5, 0x03, 0xea, 0xa8, 0x6e, 0x31, 0x0a, 0x5d,
+            0xb7, 0x38,
+        ];
+        assert_eq!(expected, hmac_md5(b"Jefe", b"what do ya want for nothing?"));
+    }
+
+    #[test]
+    fn authenticates() {
+        let server = serve(SECRET, |code, attrs, authenticator| {
+            assert_eq!(ACCESS_REQUEST, code);
+            assert_eq!(b"openvpn", get(attrs, attributes::NAS_IDENTIFIER));
+            assert_eq!(b"192.0.2.1", get(attrs, attributes::CALLING_STATION_ID));
+            let password = reveal_password(get(attrs, attributes::USER_PASSWORD), authenticator);
+            Some(if password == b"a password longer than one block" {
+                (
+                    ACCESS_ACCEPT,
+                    vec![Attribute::integer(attributes::SESSION_TIMEOUT, 3600)],
+                )
+            } else {
+                (
+                    ACCESS_REJECT,
+                    vec![Attribute::new(attributes::REPLY_MESSAGE, "Wrong password")],
+                )
+            })
+        });
+        let client = RadiusClient::new(server, SECRET);
+        let mut request = AccessRequest::from_env(&env(&[
+            ("username", "alice"),
+            ("password", "a password longer than one block"),
+            ("untrusted_ip", "192.0.2.1"),
+        ]))
+        .unwrap();
+
+        let decision = client.authenticate(&request).unwrap();
+        assert!(decision.is_accept());
+        assert_eq!(
+            Some(Duration::from_secs(3600)),
+            decision.reply().session_timeout()
+        );
+
+        request.password = "wrong".to_owned();
+        assert_eq!(
+            EventResult::FailureWithReason("Wrong password".to_owned()),
+            client.authenticate(&request).unwrap().into()
+        );
+    }
+
+    #[test]
+    fn discards_forged_responses() {
+        let server = serve(b"wrong secret", |_, _, _| Some((ACCESS_ACCEPT, Vec::new())));
+        let client = RadiusClient::new(server, SECRET)
+            .timeout(Duration::from_millis(50))
+            .retries(1);
+        let request = AccessRequest {
+            username: "alice".to_owned(),
+            password: "hunter2".to_owned(),
+            calling_station_id: None,
+            attributes: Vec::new(),
+        };
+        match client.authenticate(&request) {
+            Err(RadiusError::Timeout(addr)) => assert_eq!(server, addr),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn discards_responses_without_message_authenticator() {
+        let server = serve_with(SECRET, false, |_, _, _| Some((ACCESS_ACCEPT, Vec::new())));
+        let client = RadiusClient::new(server, SECRET)
+            .timeout(Duration::from_millis(50))
+            .retries(1);
+        let request = AccessRequest {
+            username: "alice".to_owned(),
+            password: "hunter2".to_owned(),
+            calling_station_id: None,
+            attributes: Vec::new(),
+        };
+        match client.authenticate(&request) {
+            Err(RadiusError::Timeout(addr)) => assert_eq!(server, addr),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn accounts_sessions() {
+        let (tx, rx) = mpsc::channel();
+        let server = serve(SECRET, move |code, attrs, _| {
+            assert_eq!(ACCOUNTING_REQUEST, code);
+            let status =
+                u32::from_be_bytes(get(attrs, attributes::ACCT_STATUS_TYPE).try_into().unwrap());
+            let session_id = String::from_utf8(get(attrs, attributes::ACCT_SESSION_ID).to_vec());
+            tx.send((status, session_id.unwrap(), attrs.to_vec()))
+                .unwrap();
+            Some((ACCOUNTING_RESPONSE, Vec::new()))
+        });
+        let client = RadiusClient::new(server, SECRET).accounting_server(server);
+        let accounting = Accounting::new(client)
+            .interim_interval(Duration::from_millis(20))
+            .unwrap();
+        let client_env = env(&[
+            ("common_name", "alice"),
+            ("untrusted_ip", "192.0.2.1"),
+            ("untrusted_port", "51820"),
+            ("time_unix", "1700000000"),
+            ("ifconfig_pool_remote_ip", "10.8.0.2"),
+            ("bytes_received", "5000000000"),
+            ("bytes_sent", "20"),
+            ("time_duration", "30"),
+        ]);
+        let timeout = Duration::from_secs(5);
+
+        accounting
+            .handle_event(EventType::ClientConnect, &client_env)
+            .unwrap();
+        let (status, session_id, attrs) = rx.recv_timeout(timeout).unwrap();
+        assert_eq!(AccountingStatus::Start as u32, status);
+        assert_eq!("alice@192.0.2.1:51820-1700000000", session_id);
+        assert_eq!(&[10, 8, 0, 2], get(&attrs, attributes::FRAMED_IP_ADDRESS));
+        assert_eq!(1, accounting.active());
+
+        let (status, ..) = rx.recv_timeout(timeout).unwrap();
+        assert_eq!(AccountingStatus::InterimUpdate as u32, status);
+
+        accounting
+            .handle_event(EventType::ClientDisconnect, &client_env)
+            .unwrap();
+        assert_eq!(0, accounting.active());
+        let (_, _, attrs) = rx
+            .iter()
+            .find(|(status, ..)| *status == AccountingStatus::Stop as u32)
+            .unwrap();
+        assert_eq!(
+            &1u32.to_be_bytes(),
+            get(&attrs, attributes::ACCT_INPUT_GIGAWORDS)
+        );
+        assert_eq!(
+            &705032704u32.to_be_bytes(),
+            get(&attrs, attributes::ACCT_INPUT_OCTETS)
+        );
+    }
+}
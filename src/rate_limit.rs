@@ -0,0 +1,383 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Protection against guessing passwords, by locking out clients that fail to authenticate.
+//!
+//! A [`RateLimiter`] counts the failed `EventType::AuthUserPassVerify` attempts of every username
+//! from every client address. After [`max_failures`] failures in a row, further attempts from that
+//! address for that username fail without being verified, with a reason telling the client when
+//! to try again. Every further failure doubles the lockout, up to [`max_lockout`]. A successful
+//! authentication resets the count.
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, ffi::CString, io};
+//! # use openvpn_plugin::{rate_limit::RateLimiter, EventResult};
+//! # fn verify(env: &HashMap<CString, CString>) -> Result<bool, io::Error> { unimplemented!() }
+//! fn auth_user_pass_verify(
+//!     env: HashMap<CString, CString>,
+//!     limiter: &RateLimiter,
+//! ) -> Result<EventResult, io::Error> {
+//!     limiter.verify(&env, || match verify(&env)? {
+//!         true => Ok(EventResult::Success),
+//!         false => Ok(EventResult::Failure),
+//!     })
+//! }
+//! ```
+//!
+//! Failures are counted per username and address, so an attacker can't lock a user out from
+//! other addresses. Errors returned by the verification are not counted as failures, since they
+//! are not the fault of the client.
+//!
+//! [`RateLimiter`]: struct.RateLimiter.html
+//! [`max_failures`]: struct.RateLimiter.html#method.max_failures
+//! [`max_lockout`]: struct.RateLimiter.html#method.max_lockout
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    net::IpAddr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    auth::ControlFileError, deferred_auth::DeferredAuthPool, env_keys, events::Env, EventResult,
+};
+
+/// The number of failures in a row allowed before locking out, unless changed with
+/// `RateLimiter::max_failures`.
+pub const DEFAULT_MAX_FAILURES: u32 = 5;
+
+/// The lockout after the first failure too many, unless changed with `RateLimiter::lockout`.
+pub const DEFAULT_LOCKOUT: Duration = Duration::from_secs(30);
+
+/// The longest lockout, unless changed with `RateLimiter::max_lockout`.
+pub const DEFAULT_MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+
+/// How long after the last failure the count is reset, unless changed with
+/// `RateLimiter::forget_after`.
+pub const DEFAULT_FORGET_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+type Key = (String, IpAddr);
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<LockedUntil>,
+}
+
+/// The end of a lockout.
+#[derive(Debug, Clone, Copy)]
+enum LockedUntil {
+    Instant(Instant),
+    /// The lockout is too long to add to the time it started, and lasts until reset.
+    Forever,
+}
+
+impl LockedUntil {
+    /// The time left of the lockout at `now`, or `None` if it has ended.
+    fn remaining(self, now: Instant) -> Option<Duration> {
+        match self {
+            LockedUntil::Instant(until) => {
+                until.checked_duration_since(now).filter(|d| !d.is_zero())
+            }
+            LockedUntil::Forever => Some(Duration::MAX),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    failures: HashMap<Key, Failures>,
+    /// The number of entries at which forgotten entries are removed next.
+    next_prune: usize,
+}
+
+/// Counts failed authentications and locks out the clients failing too often. Clones share the
+/// same counts.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    max_failures: u32,
+    lockout: Duration,
+    max_lockout: Duration,
+    forget_after: Duration,
+    entries: Arc<Mutex<Entries>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateLimiter {
+    /// Creates a limiter with the default limits, and no failures counted.
+    pub fn new() -> Self {
+        RateLimiter {
+            max_failures: DEFAULT_MAX_FAILURES,
+            lockout: DEFAULT_LOCKOUT,
+            max_lockout: DEFAULT_MAX_LOCKOUT,
+            forget_after: DEFAULT_FORGET_AFTER,
+            entries: Arc::default(),
+        }
+    }
+
+    /// Sets the number of failures in a row allowed before locking out.
+    pub fn max_failures(mut self, max_failures: u32) -> Self {
+        self.max_failures = max_failures;
+        self
+    }
+
+    /// Sets the lockout after the first failure too many. It doubles with every further failure.
+    pub fn lockout(mut self, lockout: Duration) -> Self {
+        self.lockout = lockout;
+        self
+    }
+
+    /// Sets the longest lockout. A lockout too long to add to the current time, such as
+    /// `Duration::MAX`, lasts until the count is reset.
+    pub fn max_lockout(mut self, max_lockout: Duration) -> Self {
+        self.max_lockout = max_lockout;
+        self
+    }
+
+    /// Sets how long after the last failure the count is reset.
+    pub fn forget_after(mut self, forget_after: Duration) -> Self {
+        self.forget_after = forget_after;
+        self
+    }
+
+    /// Returns `EventResult::FailureWithReason` if the client in `env`, the environment of an
+    /// `EventType::AuthUserPassVerify` event, is locked out. Returns `None` if it may
+    /// authenticate.
+    pub fn check(&self, env: &HashMap<CString, CString>) -> Option<EventResult> {
+        let key = key(env)?;
+        let remaining = self.remaining_lockout(&key.0, key.1)?;
+        Some(EventResult::FailureWithReason(format!(
+            "Too many failed attempts, try again in {} seconds",
+            // Rounded up, to never tell the client to try again while still locked out.
+            remaining
+                .as_secs()
+                .saturating_add(u64::from(remaining.subsec_nanos() > 0))
+        )))
+    }
+
+    /// Counts `result`, the result of verifying the credentials in `env`. Failures increase the
+    /// count of the client and success resets it. Other results are not counted.
+    pub fn record(&self, env: &HashMap<CString, CString>, result: &EventResult) {
+        if let Some(key) = key(env) {
+            self.record_key(key, result);
+        }
+    }
+
+    /// Runs `verify` unless the client in `env` is locked out, and counts its result.
+    pub fn verify<F, E>(&self, env: &HashMap<CString, CString>, verify: F) -> Result<EventResult, E>
+    where
+        F: FnOnce() -> Result<EventResult, E>,
+    {
+        if let Some(locked) = self.check(env) {
+            return Ok(locked);
+        }
+        let result = verify()?;
+        self.record(env, &result);
+        Ok(result)
+    }
+
+    /// Runs `verify` on `pool` with [`DeferredAuthPool::defer`] unless the client in `env` is
+    /// locked out, and counts its result.
+    ///
+    /// [`DeferredAuthPool::defer`]: ../deferred_auth/struct.DeferredAuthPool.html#method.defer
+    pub fn defer<F, E>(
+        &self,
+        pool: &DeferredAuthPool,
+        env: &HashMap<CString, CString>,
+        verify: F,
+    ) -> Result<EventResult, ControlFileError>
+    where
+        F: FnOnce() -> Result<EventResult, E> + Send + 'static,
+        E: Into<Box<dyn std::error::Error>>,
+    {
+        if let Some(locked) = self.check(env) {
+            return Ok(locked);
+        }
+        let key = key(env);
+        let limiter = self.clone();
+        pool.defer(env, move || {
+            let result = verify()?;
+            if let Some(key) = key {
+                limiter.record_key(key, &result);
+            }
+            Ok::<_, E>(result)
+        })
+    }
+
+    /// The time until `username` may authenticate from `ip` again, or `None` if it is not locked
+    /// out.
+    pub fn remaining_lockout(&self, username: &str, ip: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let entries = self.lock();
+        entries
+            .failures
+            .get(&(username.to_owned(), ip))?
+            .locked_until?
+            .remaining(now)
+    }
+
+    /// Resets the count of `username` from `ip`, lifting any lockout.
+    pub fn reset(&self, username: &str, ip: IpAddr) {
+        self.lock().failures.remove(&(username.to_owned(), ip));
+    }
+
+    /// Resets the counts of `username` from every address.
+    pub fn reset_user(&self, username: &str) {
+        self.lock().failures.retain(|(user, _), _| user != username);
+    }
+
+    /// Resets all counts.
+    pub fn clear(&self) {
+        self.lock().failures.clear();
+    }
+
+    fn record_key(&self, key: Key, result: &EventResult) {
+        let now = Instant::now();
+        let mut entries = self.lock();
+        match result {
+            EventResult::Success => {
+                entries.failures.remove(&key);
+                return;
+            }
+            EventResult::Failure | EventResult::FailureWithReason(_) => (),
+            EventResult::Deferred => return,
+        }
+        let failures = entries.failures.entry(key).or_insert(Failures {
+            count: 0,
+            last: now,
+            locked_until: None,
+        });
+        if now.duration_since(failures.last) > self.forget_after {
+            failures.count = 0;
+        }
+        failures.count = failures.count.saturating_add(1);
+        failures.last = now;
+        if failures.count > self.max_failures {
+            let doublings = (failures.count - self.max_failures - 1).min(31);
+            let lockout = self
+                .lockout
+                .saturating_mul(1 << doublings)
+                .min(self.max_lockout);
+            failures.locked_until = Some(
+                now.checked_add(lockout)
+                    .map_or(LockedUntil::Forever, LockedUntil::Instant),
+            );
+        }
+        // Counts of clients that never come back are removed once the map has doubled in size.
+        if entries.failures.len() >= entries.next_prune {
+            let forget_after = self.forget_after;
+            entries.failures.retain(|_, failures| {
+                now.duration_since(failures.last) <= forget_after
+                    || failures
+                        .locked_until
+                        .is_some_and(|until| until.remaining(now).is_some())
+            });
+            entries.next_prune = (entries.failures.len() * 2).max(64);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The username and client address of an authentication. `None` if either is missing.
+fn key(env: &HashMap<CString, CString>) -> Option<Key> {
+    let env = Env(env);
+    let username = env.string(env_keys::USERNAME).ok()?;
+    let ip = env
+        .ip_opt(env_keys::UNTRUSTED_IP, env_keys::UNTRUSTED_IP6)
+        .ok()??;
+    Some((username, ip))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::env;
+    use std::convert::Infallible;
+
+    fn client(username: &str, ip: &str) -> HashMap<CString, CString> {
+        env(&[("username", username), ("untrusted_ip", ip)])
+    }
+
+    #[test]
+    fn locks_out_after_failures() {
+        let limiter = RateLimiter::new().max_failures(2);
+        let alice = client("alice", "10.0.0.1");
+        let ip = "10.0.0.1".parse().unwrap();
+        let fail = || limiter.verify(&alice, || Ok::<_, Infallible>(EventResult::Failure));
+
+        assert_eq!(Ok(EventResult::Failure), fail());
+        assert_eq!(Ok(EventResult::Failure), fail());
+        assert_eq!(None, limiter.check(&alice));
+        assert_eq!(Ok(EventResult::Failure), fail());
+        assert!(limiter.remaining_lockout("alice", ip).unwrap() > Duration::from_secs(29));
+        assert_eq!(
+            Ok(EventResult::FailureWithReason(
+                "Too many failed attempts, try again in 30 seconds".to_owned()
+            )),
+            limiter.verify(&alice, || -> Result<_, Infallible> {
+                panic!("Verified while locked out")
+            })
+        );
+
+        // Other users and addresses are not locked out.
+        assert_eq!(None, limiter.check(&client("bob", "10.0.0.1")));
+        assert_eq!(None, limiter.check(&client("alice", "10.0.0.2")));
+        assert_eq!(None, limiter.check(&client("alice", "")));
+
+        limiter.reset_user("alice");
+        assert_eq!(None, limiter.check(&alice));
+    }
+
+    #[test]
+    fn doubles_lockout() {
+        let limiter = RateLimiter::new()
+            .max_failures(0)
+            .lockout(Duration::from_secs(10))
+            .max_lockout(Duration::from_secs(25));
+        let alice = client("alice", "::1");
+        let ip = "::1".parse().unwrap();
+        let mut lockouts = Vec::new();
+        for _ in 0..3 {
+            limiter.record(&alice, &EventResult::Failure);
+            let remaining = limiter.remaining_lockout("alice", ip).unwrap();
+            lockouts.push((remaining.as_secs_f64()).round() as u64);
+        }
+        assert_eq!(vec![10, 20, 25], lockouts);
+
+        limiter.record(&alice, &EventResult::Success);
+        assert_eq!(None, limiter.remaining_lockout("alice", ip));
+    }
+
+    #[test]
+    fn overflowing_lockout_lasts_until_reset() {
+        let limiter = RateLimiter::new()
+            .max_failures(0)
+            .lockout(Duration::MAX)
+            .max_lockout(Duration::MAX);
+        let alice = client("alice", "::1");
+        let ip = "::1".parse().unwrap();
+        limiter.record(&alice, &EventResult::Failure);
+        assert_eq!(Some(Duration::MAX), limiter.remaining_lockout("alice", ip));
+        assert!(limiter.check(&alice).is_some());
+
+        limiter.reset("alice", ip);
+        assert_eq!(None, limiter.check(&alice));
+    }
+}
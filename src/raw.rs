@@ -0,0 +1,336 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    marker::PhantomData,
+    os::raw::{c_char, c_int},
+    panic,
+    time::Instant,
+};
+
+use crate::{
+    auth::FailedReasonFile,
+    ffi::{self, parse::ParseError},
+    EventResult, EventType,
+};
+
+/// Generates the same FFI functions as [`openvpn_plugin!`], but gives the event callback a
+/// [`RawEvent`] instead of the parsed arguments and environment.
+///
+/// Parsing the arguments and environment allocates a string for every one of the ~70 variables
+/// OpenVPN passes with each event. A plugin that only reads a few of them can instead look them
+/// up on demand through the `RawEvent`, without allocating at all.
+///
+/// ## `$open_fn` and `$close_fn`
+///
+/// Identical to the ones given to [`openvpn_plugin!`].
+///
+/// ## `$event_fn` - The event callback function
+///
+/// Should be a function with the following signature:
+///
+/// ```rust,no_run
+/// # use openvpn_plugin::{raw::RawEvent, EventResult, EventType};
+/// # struct Handle {}
+/// # struct Error {}
+/// fn foo_event(
+///     event: EventType,
+///     raw: RawEvent<'_>,
+///     handle: &mut Handle,
+/// ) -> Result<EventResult, Error> {
+///     /// ...
+/// #    unimplemented!();
+/// }
+/// # fn main() {}
+/// ```
+///
+/// The strings borrowed from the `RawEvent` are owned by OpenVPN and only valid until the
+/// callback returns. The lifetime on `RawEvent` makes sure they can't be kept longer than that.
+///
+/// [`openvpn_plugin!`]: macro.openvpn_plugin.html
+/// [`RawEvent`]: raw/struct.RawEvent.html
+#[macro_export]
+macro_rules! openvpn_plugin_raw {
+    ($open_fn:expr, $close_fn:expr, $event_fn:expr, $handle_ty:ty) => {
+        /// Called by OpenVPN when the plugin is first loaded on OpenVPN start.
+        /// Used to register which events the plugin wants to listen to (`args.type_mask`). Can
+        /// also set an arbitrary pointer inside `args.handle` that will then be passed to all
+        /// subsequent calls to the plugin.
+        ///
+        /// Will parse the data from OpenVPN and call the function given as `$open_fn` to the
+        /// `openvpn_plugin_raw` macro.
+        #[no_mangle]
+        pub unsafe extern "C" fn openvpn_plugin_open_v3(
+            version: ::std::os::raw::c_int,
+            args: *const $crate::ffi::openvpn_plugin_args_open_in,
+            retptr: *mut $crate::ffi::openvpn_plugin_args_open_return,
+        ) -> ::std::os::raw::c_int {
+            unsafe {
+                $crate::openvpn_plugin_open::<$handle_ty, _, _, _>(version, args, retptr, $open_fn)
+            }
+        }
+
+        /// Called by OpenVPN when the plugin is unloaded, just before OpenVPN shuts down.
+        /// Will call the function given as `$close_fn` to the `openvpn_plugin_raw` macro.
+        #[no_mangle]
+        pub unsafe extern "C" fn openvpn_plugin_close_v1(handle: *const ::std::os::raw::c_void) {
+            unsafe { $crate::openvpn_plugin_close::<$handle_ty, _, _>(handle, $close_fn) }
+        }
+
+        /// Called by OpenVPN for each `OPENVPN_PLUGIN_*` event that it registered for in
+        /// the open function.
+        ///
+        /// Will wrap the data from OpenVPN in a `RawEvent` and call the function given as
+        /// `$event_fn` to the `openvpn_plugin_raw` macro.
+        #[no_mangle]
+        pub unsafe extern "C" fn openvpn_plugin_func_v3(
+            version: ::std::os::raw::c_int,
+            args: *const $crate::ffi::openvpn_plugin_args_func_in,
+            _retptr: *const $crate::ffi::openvpn_plugin_args_func_return,
+        ) -> ::std::os::raw::c_int {
+            unsafe {
+                $crate::raw::openvpn_plugin_func::<$handle_ty, _, _>(version, args, $event_fn)
+            }
+        }
+    };
+}
+
+
+/// The arguments and environment OpenVPN passed with an event, left unparsed. Values are read
+/// on demand straight from OpenVPN's memory.
+#[derive(Debug, Copy, Clone)]
+pub struct RawEvent<'a> {
+    argv: *const *const c_char,
+    envp: *const *const c_char,
+    _lifetime: PhantomData<&'a CStr>,
+}
+
+impl<'a> RawEvent<'a> {
+    /// Wraps the given null-terminated string arrays. Null pointers are treated as empty arrays.
+    ///
+    /// # Safety
+    ///
+    /// The arrays must be null terminated and all strings in them null terminated. The arrays and
+    /// strings must stay valid and unmodified for the lifetime `'a`.
+    pub unsafe fn new(argv: *const *const c_char, envp: *const *const c_char) -> Self {
+        RawEvent {
+            argv,
+            envp,
+            _lifetime: PhantomData,
+        }
+    }
+
+    /// Returns the argument at `index`. Index 0 is the path to the plugin.
+    pub fn arg(&self, index: usize) -> Option<&'a CStr> {
+        self.args().nth(index)
+    }
+
+    /// Iterates over all arguments.
+    pub fn args(&self) -> RawStrings<'a> {
+        RawStrings {
+            ptr: self.argv,
+            _lifetime: PhantomData,
+        }
+    }
+
+    /// Returns the value of the environment variable `key`. If the variable is present multiple
+    /// times the last value is returned, the same as with the parsed environment.
+    ///
+    /// ```rust,no_run
+    /// # use openvpn_plugin::{env_keys, raw::RawEvent};
+    /// # fn event(raw: RawEvent<'_>) {
+    /// let username = raw.env_get(env_keys::cstr::USERNAME);
+    /// # }
+    /// ```
+    pub fn env_get(&self, key: &CStr) -> Option<&'a CStr> {
+        let key = key.to_bytes();
+        self.env()
+            .filter(|(entry_key, _)| *entry_key == key)
+            .map(|(_, value)| value)
+            .last()
+    }
+
+    /// Iterates over all environment variables as key and value pairs. Keys are byte slices since
+    /// they are not null terminated in OpenVPN's memory. Entries without an equal sign are
+    /// skipped.
+    pub fn env(&self) -> RawEnv<'a> {
+        RawEnv {
+            strings: self.env_entries(),
+        }
+    }
+
+    /// Parses all arguments, the same way the [`openvpn_plugin!`] macro does.
+    ///
+    /// [`openvpn_plugin!`]: ../macro.openvpn_plugin.html
+    pub fn parse_args(&self) -> Vec<CString> {
+        self.args().map(CStr::to_owned).collect()
+    }
+
+    /// Parses the entire environment, the same way the [`openvpn_plugin!`] macro does.
+    ///
+    /// [`openvpn_plugin!`]: ../macro.openvpn_plugin.html
+    pub fn parse_env(&self) -> Result<HashMap<CString, CString>, ParseError> {
+        let mut env = HashMap::new();
+        for entry in self.env_entries() {
+            let (key, value) = ffi::parse::split_env_entry(entry)?;
+            // It's safe to unwrap since the key is a part of a C string.
+            env.insert(CString::new(key).unwrap(), value.to_owned());
+        }
+        Ok(env)
+    }
+
+    fn env_entries(&self) -> RawStrings<'a> {
+        RawStrings {
+            ptr: self.envp,
+            _lifetime: PhantomData,
+        }
+    }
+}
+
+/// Iterator over a null-terminated array of C strings. Returned by `RawEvent::args`.
+#[derive(Debug, Clone)]
+pub struct RawStrings<'a> {
+    ptr: *const *const c_char,
+    _lifetime: PhantomData<&'a CStr>,
+}
+
+impl<'a> Iterator for RawStrings<'a> {
+    type Item = &'a CStr;
+
+    fn next(&mut self) -> Option<&'a CStr> {
+        // Safe since `RawEvent::new` requires the array to be valid and null terminated, and
+        // iteration never passes the terminating null.
+        unsafe {
+            if self.ptr.is_null() || (*self.ptr).is_null() {
+                return None;
+            }
+            let string = CStr::from_ptr(*self.ptr);
+            self.ptr = self.ptr.offset(1);
+            Some(string)
+        }
+    }
+}
+
+/// Iterator over the environment variables of an event. Returned by `RawEvent::env`.
+#[derive(Debug, Clone)]
+pub struct RawEnv<'a> {
+    strings: RawStrings<'a>,
+}
+
+impl<'a> Iterator for RawEnv<'a> {
+    type Item = (&'a [u8], &'a CStr);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.strings
+            .by_ref()
+            .find_map(|entry| ffi::parse::split_env_entry(entry).ok())
+    }
+}
+
+
+/// Internal helper function. This function should never be called manually, only by code generated
+/// by the [`openvpn_plugin_raw!`] macro.
+///
+/// # Safety
+///
+/// Same requirements as for [`crate::openvpn_plugin_func`].
+///
+/// [`openvpn_plugin_raw!`]: ../macro.openvpn_plugin_raw.html
+#[doc(hidden)]
+pub unsafe fn openvpn_plugin_func<H, E, F>(
+    version: c_int,
+    args: *const ffi::openvpn_plugin_args_func_in,
+    mut event_fn: F,
+) -> c_int
+where
+    E: Into<Box<dyn std::error::Error>>,
+    F: panic::UnwindSafe,
+    F: for<'a> FnMut(EventType, RawEvent<'a>, &mut H) -> Result<EventResult, E>,
+{
+    if let Err(e) = crate::check_func_args(version, args) {
+        crate::logging::log_error(&e);
+        return ffi::OPENVPN_PLUGIN_FUNC_ERROR;
+    }
+    let _client_context = crate::client_context::enter((*args).per_client_context);
+    let event = match crate::parse_event_type((*args).event_type) {
+        Some(event) => event,
+        None => return ffi::OPENVPN_PLUGIN_FUNC_SUCCESS,
+    };
+    let raw = RawEvent::new((*args).argv, (*args).envp);
+
+    #[cfg(feature = "tracing")]
+    let _span = crate::logging::event_span(
+        event,
+        raw.env_get(crate::env_keys::cstr::COMMON_NAME),
+        raw.env_get(crate::env_keys::cstr::UNTRUSTED_IP),
+    )
+    .entered();
+    #[cfg(any(feature = "json-log", feature = "journald"))]
+    let _event = crate::logging::enter_event(event);
+    let _secrets =
+        crate::redact::Secrets::collect(raw.env().map(|(key, value)| (key, value.to_bytes())))
+            .enter();
+
+    let started = Instant::now();
+    let result = crate::catch_unwind(move || {
+        let handle: &mut H = &mut *((*args).handle as *mut H);
+        event_fn(event, raw, handle)
+    });
+    let elapsed = started.elapsed();
+    crate::watchdog::check(event, elapsed);
+    let code =
+        crate::event_result_code(event, result, || FailedReasonFile::from_envp((*args).envp));
+    crate::observe_event(event, elapsed, code);
+    code
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env_keys;
+    use std::ptr;
+
+    #[test]
+    fn null_arrays_are_empty() {
+        let raw = unsafe { RawEvent::new(ptr::null(), ptr::null()) };
+        assert_eq!(None, raw.arg(0));
+        assert_eq!(None, raw.env_get(env_keys::cstr::USERNAME));
+        assert_eq!(Ok(HashMap::new()), raw.parse_env());
+    }
+
+    #[test]
+    fn lookup() {
+        let argv = [
+            "/plugin.so\0".as_ptr() as *const c_char,
+            "arg1\0".as_ptr() as *const c_char,
+            ptr::null(),
+        ];
+        let envp = [
+            "username=foo\0".as_ptr() as *const c_char,
+            "garbage\0".as_ptr() as *const c_char,
+            "username=bar\0".as_ptr() as *const c_char,
+            ptr::null(),
+        ];
+        let raw = unsafe { RawEvent::new(argv.as_ptr(), envp.as_ptr()) };
+        assert_eq!(Some(&b"arg1"[..]), raw.arg(1).map(CStr::to_bytes));
+        assert_eq!(None, raw.arg(2));
+        assert_eq!(
+            Some(&b"bar"[..]),
+            raw.env_get(env_keys::cstr::USERNAME).map(CStr::to_bytes)
+        );
+        assert_eq!(None, raw.env_get(env_keys::cstr::PASSWORD));
+        assert_eq!(2, raw.env().count());
+        assert_eq!(
+            Err(ParseError::NoEqual(CString::new("garbage").unwrap())),
+            raw.parse_env()
+        );
+    }
+}
@@ -0,0 +1,436 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Records the events a plugin receives to a file, and replays them through the plugin's event
+//! callback later. Requires the `recorder` feature.
+//!
+//! A session is stored as JSON lines, one event per line with its arguments and environment.
+//! Strings that are valid UTF-8 are stored as JSON strings, others as arrays of bytes, so the
+//! replayed events are identical to the recorded ones.
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, ffi::CString, io};
+//! # use openvpn_plugin::{recorder::Recorder, EventResult, EventType};
+//! struct Handle {
+//!     recorder: Recorder<io::BufWriter<std::fs::File>>,
+//! }
+//!
+//! fn event(
+//!     event: EventType,
+//!     args: Vec<CString>,
+//!     env: HashMap<CString, CString>,
+//!     handle: &mut Handle,
+//! ) -> Result<EventResult, io::Error> {
+//!     handle.recorder.record(event, &args, &env)?;
+//!     // ...
+//! #   Ok(EventResult::Success)
+//! }
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! // Later, offline:
+//! let session = openvpn_plugin::recorder::load_session("/var/log/openvpn/events.jsonl")?;
+//! # let mut handle = Handle { recorder: Recorder::create("/dev/null")? };
+//! let results = openvpn_plugin::recorder::replay(&session, &mut handle, event);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The variables [`redact::is_sensitive`] reports, such as `password` and `auth_token`, are
+//! redacted by default, since they would otherwise be written to disk in plain text. See
+//! [`Recorder::redact`] and [`Recorder::keep`].
+//!
+//! [`redact::is_sensitive`]: ../redact/fn.is_sensitive.html
+//! [`Recorder::redact`]: struct.Recorder.html#method.redact
+//! [`Recorder::keep`]: struct.Recorder.html#method.keep
+
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    ffi::CString,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use crate::{
+    redact::{self, REDACTED},
+    EventResult, EventType,
+};
+
+
+/// Writes every event given to [`record`] as one line of JSON.
+///
+/// [`record`]: #method.record
+#[derive(Debug)]
+pub struct Recorder<W: Write> {
+    writer: Mutex<W>,
+    redacted: HashSet<Vec<u8>>,
+    kept: HashSet<Vec<u8>>,
+}
+
+impl Recorder<BufWriter<File>> {
+    /// Creates a recorder appending to the file at `path`. The file is created if it does not
+    /// exist.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(BufWriter::new(file)))
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    /// Creates a recorder writing to `writer`. Redacts the sensitive variables.
+    pub fn new(writer: W) -> Self {
+        Recorder {
+            writer: Mutex::new(writer),
+            redacted: HashSet::new(),
+            kept: HashSet::new(),
+        }
+    }
+
+    /// Records the environment variable `key` as [`REDACTED`] instead of its value.
+    ///
+    /// [`REDACTED`]: ../redact/constant.REDACTED.html
+    pub fn redact(mut self, key: impl Into<Vec<u8>>) -> Self {
+        let key = key.into();
+        self.kept.remove(&key);
+        self.redacted.insert(key);
+        self
+    }
+
+    /// Records the real value of the environment variable `key`, even if it is sensitive or was
+    /// given to [`redact`]. Use `keep("password")` to record passwords.
+    ///
+    /// [`redact`]: #method.redact
+    pub fn keep(mut self, key: impl Into<Vec<u8>>) -> Self {
+        let key = key.into();
+        self.redacted.remove(&key);
+        self.kept.insert(key);
+        self
+    }
+
+    fn is_redacted(&self, key: &[u8]) -> bool {
+        (self.redacted.contains(key) || redact::is_sensitive(key)) && !self.kept.contains(key)
+    }
+
+    /// Writes the event as one line and flushes the writer.
+    pub fn record(
+        &self,
+        event: EventType,
+        args: &[CString],
+        env: &HashMap<CString, CString>,
+    ) -> io::Result<()> {
+        let mut env: Vec<(Value, Value)> = env
+            .iter()
+            .map(|(key, value)| {
+                let value = if self.is_redacted(key.as_bytes()) {
+                    Value::Utf8(REDACTED.to_owned())
+                } else {
+                    Value::from(value)
+                };
+                (Value::from(key), value)
+            })
+            .collect();
+        env.sort();
+        let line = Line {
+            event,
+            args: args.iter().map(Value::from).collect(),
+            env,
+        };
+
+        let mut json = serde_json::to_vec(&line)?;
+        json.push(b'\n');
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writer.write_all(&json)?;
+        writer.flush()
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+
+/// One recorded event.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RecordedEvent {
+    /// The type of the event.
+    pub event: EventType,
+    /// The arguments, as given to the event callback.
+    pub args: Vec<CString>,
+    /// The environment, as given to the event callback.
+    pub env: HashMap<CString, CString>,
+}
+
+/// Error returned when a recorded session can't be read.
+#[derive(Debug)]
+pub enum ReplayError {
+    /// Reading the session failed.
+    Read(io::Error),
+    /// The line with the given number, counting from 1, is not a recorded event.
+    InvalidLine(usize, serde_json::Error),
+    /// The line with the given number, counting from 1, contains a string with a null byte.
+    NullByte(usize),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Read(_) => f.write_str("Unable to read the recorded session"),
+            ReplayError::InvalidLine(line, _) => write!(f, "Line {} is not a recorded event", line),
+            ReplayError::NullByte(line) => write!(f, "Line {} contains a null byte", line),
+        }
+    }
+}
+
+impl Error for ReplayError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ReplayError::Read(e) => Some(e),
+            ReplayError::InvalidLine(_, e) => Some(e),
+            ReplayError::NullByte(_) => None,
+        }
+    }
+}
+
+/// Reads a session written by a [`Recorder`] from the file at `path`.
+///
+/// [`Recorder`]: struct.Recorder.html
+pub fn load_session(path: impl AsRef<Path>) -> Result<Vec<RecordedEvent>, ReplayError> {
+    let file = File::open(path).map_err(ReplayError::Read)?;
+    read_session(BufReader::new(file))
+}
+
+/// Reads a session written by a [`Recorder`]. Empty lines are skipped.
+///
+/// [`Recorder`]: struct.Recorder.html
+pub fn read_session(reader: impl BufRead) -> Result<Vec<RecordedEvent>, ReplayError> {
+    let mut session = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.map_err(ReplayError::Read)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line: Line =
+            serde_json::from_str(&line).map_err(|e| ReplayError::InvalidLine(line_number, e))?;
+        session.push(
+            line.into_event()
+                .ok_or(ReplayError::NullByte(line_number))?,
+        );
+    }
+    Ok(session)
+}
+
+/// Calls `event_fn` with every event in `session`, in order, the same way the
+/// [`openvpn_plugin!`] macro calls the event callback. Returns the result of every call.
+///
+/// [`openvpn_plugin!`]: ../macro.openvpn_plugin.html
+pub fn replay<H, E, F>(
+    session: &[RecordedEvent],
+    handle: &mut H,
+    mut event_fn: F,
+) -> Vec<Result<EventResult, E>>
+where
+    F: FnMut(EventType, Vec<CString>, HashMap<CString, CString>, &mut H) -> Result<EventResult, E>,
+{
+    session
+        .iter()
+        .map(|recorded| {
+            event_fn(
+                recorded.event,
+                recorded.args.clone(),
+                recorded.env.clone(),
+                handle,
+            )
+        })
+        .collect()
+}
+
+
+/// The format of one line in a recorded session.
+#[derive(Serialize, Deserialize)]
+struct Line {
+    event: EventType,
+    args: Vec<Value>,
+    env: Vec<(Value, Value)>,
+}
+
+impl Line {
+    fn into_event(self) -> Option<RecordedEvent> {
+        let args = self
+            .args
+            .into_iter()
+            .map(Value::into_cstring)
+            .collect::<Option<_>>()?;
+        let env = self
+            .env
+            .into_iter()
+            .map(|(key, value)| Some((key.into_cstring()?, value.into_cstring()?)))
+            .collect::<Option<_>>()?;
+        Some(RecordedEvent {
+            event: self.event,
+            args,
+            env,
+        })
+    }
+}
+
+/// A string from OpenVPN. Stored as a JSON string when possible, so the file is readable.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Ord, PartialOrd)]
+#[serde(untagged)]
+enum Value {
+    Utf8(String),
+    Bytes(Vec<u8>),
+}
+
+impl Value {
+    fn into_cstring(self) -> Option<CString> {
+        let bytes = match self {
+            Value::Utf8(string) => string.into_bytes(),
+            Value::Bytes(bytes) => bytes,
+        };
+        CString::new(bytes).ok()
+    }
+}
+
+impl From<&CString> for Value {
+    fn from(string: &CString) -> Self {
+        match string.to_str() {
+            Ok(utf8) => Value::Utf8(utf8.to_owned()),
+            Err(_) => Value::Bytes(string.as_bytes().to_vec()),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cstring(s: impl Into<Vec<u8>>) -> CString {
+        CString::new(s).unwrap()
+    }
+
+    fn env(env: &[(&[u8], &[u8])]) -> HashMap<CString, CString> {
+        env.iter()
+            .map(|(k, v)| (cstring(*k), cstring(*v)))
+            .collect()
+    }
+
+    #[test]
+    fn record_and_read() {
+        let recorder = Recorder::new(Vec::new());
+        let args = vec![cstring("/plugin.so"), cstring(&b"\xff\xfe"[..])];
+        let up_env = env(&[(b"dev", b"tun0"), (b"bad_utf8", b"\xc3\x28")]);
+        recorder.record(EventType::Up, &args, &up_env).unwrap();
+        recorder
+            .record(EventType::RouteUp, &[], &HashMap::new())
+            .unwrap();
+
+        let output = recorder.into_inner();
+        assert_eq!(2, output.iter().filter(|&&b| b == b'\n').count());
+        let session = read_session(&output[..]).unwrap();
+        assert_eq!(
+            vec![
+                RecordedEvent {
+                    event: EventType::Up,
+                    args,
+                    env: up_env,
+                },
+                RecordedEvent {
+                    event: EventType::RouteUp,
+                    args: vec![],
+                    env: HashMap::new(),
+                },
+            ],
+            session
+        );
+    }
+
+    #[test]
+    fn redaction() {
+        let credentials = env(&[
+            (b"username", b"foo"),
+            (b"password", b"hunter2"),
+            (b"auth_token", b"token"),
+        ]);
+        let recorder = Recorder::new(Vec::new());
+        recorder
+            .record(EventType::AuthUserPassVerify, &[], &credentials)
+            .unwrap();
+        let recorder = Recorder::new(recorder.into_inner())
+            .redact("username")
+            .keep("password");
+        recorder
+            .record(EventType::AuthUserPassVerify, &[], &credentials)
+            .unwrap();
+
+        let session = read_session(&recorder.into_inner()[..]).unwrap();
+        assert_eq!(
+            env(&[
+                (b"username", b"foo"),
+                (b"password", REDACTED.as_bytes()),
+                (b"auth_token", REDACTED.as_bytes()),
+            ]),
+            session[0].env
+        );
+        assert_eq!(
+            env(&[
+                (b"username", REDACTED.as_bytes()),
+                (b"password", b"hunter2"),
+                (b"auth_token", REDACTED.as_bytes()),
+            ]),
+            session[1].env
+        );
+    }
+
+    #[test]
+    fn invalid_lines() {
+        let input = "\n{\"event\":\"Up\",\"args\":[],\"env\":[]}\nnot json\n";
+        match read_session(input.as_bytes()) {
+            Err(ReplayError::InvalidLine(3, _)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        let input = "{\"event\":\"Up\",\"args\":[[102,0]],\"env\":[]}";
+        match read_session(input.as_bytes()) {
+            Err(ReplayError::NullByte(1)) => (),
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn replay_calls_event_fn() {
+        let session = vec![
+            RecordedEvent {
+                event: EventType::Up,
+                args: vec![],
+                env: env(&[(b"dev", b"tun0")]),
+            },
+            RecordedEvent {
+                event: EventType::Down,
+                args: vec![],
+                env: HashMap::new(),
+            },
+        ];
+        let mut seen = Vec::new();
+        let results = replay(&session, &mut seen, |event, _args, env, seen| {
+            seen.push(event);
+            if env.is_empty() {
+                Err(io::Error::other("no env"))
+            } else {
+                Ok(EventResult::Success)
+            }
+        });
+        assert_eq!(vec![EventType::Up, EventType::Down], seen);
+        assert!(matches!(results[0], Ok(EventResult::Success)));
+        assert!(results[1].is_err());
+    }
+}
@@ -0,0 +1,216 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Redaction of secrets in the errors this crate logs.
+//!
+//! Error messages returned by the callbacks sometimes embed values from the environment, and with
+//! them the password of a client. Before logging an error or panic from an event callback, this
+//! crate replaces the values of all sensitive environment variables of that event with
+//! [`REDACTED`]. The variables in [`SENSITIVE_KEYS`] are always sensitive, and plugins can add
+//! their own with [`add_sensitive_key`]:
+//!
+//! ```rust
+//! openvpn_plugin::redact::add_sensitive_key("otp_secret");
+//! assert!(openvpn_plugin::redact::is_sensitive(b"otp_secret"));
+//! ```
+//!
+//! [`EnvDebug`] prints an environment with the values of the sensitive variables masked the same
+//! way, for plugins that debug print the environment they are given:
+//!
+//! ```rust
+//! # use std::{collections::HashMap, ffi::CString};
+//! use openvpn_plugin::redact::EnvDebug;
+//!
+//! let mut env = HashMap::new();
+//! env.insert(CString::new("username").unwrap(), CString::new("alice").unwrap());
+//! env.insert(CString::new("password").unwrap(), CString::new("hunter2").unwrap());
+//! assert_eq!(
+//!     r#"{"password": <redacted>, "username": "alice"}"#,
+//!     format!("{:?}", EnvDebug(&env))
+//! );
+//! ```
+//!
+//! [`EnvDebug`]: struct.EnvDebug.html
+//! [`REDACTED`]: constant.REDACTED.html
+//! [`SENSITIVE_KEYS`]: constant.SENSITIVE_KEYS.html
+//! [`add_sensitive_key`]: fn.add_sensitive_key.html
+
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    ffi::CString,
+    fmt,
+    sync::{PoisonError, RwLock},
+};
+
+use crate::env_keys;
+
+/// What the values of sensitive variables are replaced with.
+pub const REDACTED: &str = "<redacted>";
+
+/// The environment variables that are always sensitive.
+pub const SENSITIVE_KEYS: &[&str] = &[env_keys::PASSWORD, "auth_token", "pkcs11_pin"];
+
+/// Sensitive variables added by the plugin, in addition to `SENSITIVE_KEYS`.
+static ADDED_KEYS: RwLock<Vec<Vec<u8>>> = RwLock::new(Vec::new());
+
+thread_local! {
+    /// The values of the sensitive variables of the event handled on this thread.
+    static SECRETS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Makes the environment variable `key` sensitive, in addition to the ones in `SENSITIVE_KEYS`.
+pub fn add_sensitive_key(key: impl Into<Vec<u8>>) {
+    let key = key.into();
+    let mut added_keys = ADDED_KEYS.write().unwrap_or_else(PoisonError::into_inner);
+    if !added_keys.contains(&key) {
+        added_keys.push(key);
+    }
+}
+
+/// Returns true if the value of the environment variable `key` is redacted from logs.
+pub fn is_sensitive(key: &[u8]) -> bool {
+    SENSITIVE_KEYS.iter().any(|k| k.as_bytes() == key)
+        || ADDED_KEYS
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .any(|k| &k[..] == key)
+}
+
+/// Debug formats an environment with the values of sensitive variables replaced by `REDACTED`.
+/// The variables are sorted by name.
+#[derive(Clone, Copy)]
+pub struct EnvDebug<'a>(pub &'a HashMap<CString, CString>);
+
+impl fmt::Debug for EnvDebug<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sorted: BTreeMap<_, _> = self.0.iter().collect();
+        f.debug_map()
+            .entries(sorted.into_iter().map(|(key, value)| {
+                let value: &dyn fmt::Debug = if is_sensitive(key.as_bytes()) {
+                    &Redacted
+                } else {
+                    value
+                };
+                (key, value)
+            }))
+            .finish()
+    }
+}
+
+/// Debug formats as `REDACTED`, without quotes.
+struct Redacted;
+
+impl fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+/// The values of the sensitive variables in the environment of an event.
+#[derive(Clone, Default)]
+pub(crate) struct Secrets(Vec<String>);
+
+impl Secrets {
+    /// Picks out the values of the sensitive variables among `env`. Empty values are skipped,
+    /// since there is nothing to redact.
+    pub(crate) fn collect<'a>(env: impl IntoIterator<Item = (&'a [u8], &'a [u8])>) -> Self {
+        Secrets(
+            env.into_iter()
+                .filter(|(key, value)| !value.is_empty() && is_sensitive(key))
+                .map(|(_, value)| String::from_utf8_lossy(value).into_owned())
+                .collect(),
+        )
+    }
+
+    pub(crate) fn from_env(env: &HashMap<CString, CString>) -> Self {
+        Self::collect(
+            env.iter()
+                .map(|(key, value)| (key.as_bytes(), value.as_bytes())),
+        )
+    }
+
+    /// Redacts these secrets from everything logged on this thread, until the returned guard is
+    /// dropped.
+    pub(crate) fn enter(&self) -> SecretsGuard {
+        let previous = SECRETS.with(|secrets| secrets.replace(self.0.clone()));
+        SecretsGuard { previous }
+    }
+}
+
+pub(crate) struct SecretsGuard {
+    previous: Vec<String>,
+}
+
+impl Drop for SecretsGuard {
+    fn drop(&mut self) {
+        SECRETS.with(|secrets| *secrets.borrow_mut() = std::mem::take(&mut self.previous));
+    }
+}
+
+/// Replaces every secret entered on this thread in `msg` with `REDACTED`.
+pub(crate) fn redact(msg: &str) -> String {
+    SECRETS.with(|secrets| {
+        secrets
+            .borrow()
+            .iter()
+            .fold(msg.to_owned(), |msg, secret| msg.replace(secret, REDACTED))
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_entered_secrets() {
+        add_sensitive_key("test_secret");
+        let env: &[(&[u8], &[u8])] = &[
+            (b"username", b"alice"),
+            (b"password", b"hunter2"),
+            (b"test_secret", b"s3cr3t"),
+            (b"auth_token", b""),
+        ];
+        let secrets = Secrets::collect(env.iter().copied());
+        let msg = "alice failed with hunter2 and s3cr3t";
+
+        assert_eq!(msg, redact(msg));
+        {
+            let _secrets = secrets.enter();
+            assert_eq!("alice failed with <redacted> and <redacted>", redact(msg));
+        }
+        assert_eq!(msg, redact(msg));
+    }
+
+    #[test]
+    fn env_debug() {
+        let env: HashMap<CString, CString> =
+            [("untrusted_ip", "203.0.113.17"), ("pkcs11_pin", "1234")]
+                .iter()
+                .map(|(key, value)| (CString::new(*key).unwrap(), CString::new(*value).unwrap()))
+                .collect();
+        assert_eq!(
+            r#"{"pkcs11_pin": <redacted>, "untrusted_ip": "203.0.113.17"}"#,
+            format!("{:?}", EnvDebug(&env))
+        );
+        assert_eq!("{}", format!("{:?}", EnvDebug(&HashMap::new())));
+    }
+
+    #[test]
+    fn sensitive_keys() {
+        assert!(is_sensitive(b"password"));
+        assert!(is_sensitive(b"pkcs11_pin"));
+        assert!(!is_sensitive(b"username"));
+        assert!(!is_sensitive(b"added_in_test"));
+        add_sensitive_key("added_in_test");
+        add_sensitive_key("added_in_test");
+        assert!(is_sensitive(b"added_in_test"));
+    }
+}
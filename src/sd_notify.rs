@@ -0,0 +1,166 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Reports the state of the plugin and the tunnel to systemd. Requires the `sd-notify` feature,
+//! and is only available on Unix.
+//!
+//! When OpenVPN runs as a systemd service with `NotifyAccess=main` or `all`, this crate sends
+//! `STATUS=Tunnel up` after a successful `Up` event, and `STATUS=Tunnel down` after a `Down`
+//! event. The status is shown by `systemctl status`.
+//!
+//! OpenVPN built with systemd support, as in the `openvpn-server@.service` unit with
+//! `Type=notify`, sends `READY=1` itself once the initialization sequence has completed, and
+//! updates the status of the unit too. Its status and the one of the plugin replace each other, so
+//! `systemctl status` shows whichever was sent last. A plugin must not send `READY=1` there, since
+//! the plugin is opened before OpenVPN is initialized and systemd would consider the service
+//! started too early. Only when OpenVPN does not notify systemd itself, a plugin can call
+//! [`set_send_ready`] from its open callback to have `READY=1` sent once that callback succeeds:
+//!
+//! ```rust
+//! openvpn_plugin::sd_notify::set_send_ready(true);
+//! ```
+//!
+//! Nothing is sent when OpenVPN is not started by systemd, that is when `NOTIFY_SOCKET` is not
+//! set. Plugins can send states of their own with [`notify`]:
+//!
+//! ```rust,no_run
+//! # fn main() -> std::io::Result<()> {
+//! openvpn_plugin::sd_notify::notify("STATUS=Waiting for the authentication service")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`set_send_ready`]: fn.set_send_ready.html
+//! [`notify`]: fn.notify.html
+
+use std::{
+    env,
+    ffi::OsStr,
+    io,
+    os::{raw::c_int, unix::net::UnixDatagram},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{ffi, logging, EventType};
+
+/// The environment variable systemd gives the notification socket in.
+pub const NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+
+/// Whether `READY=1` is sent when the open callback succeeds.
+static SEND_READY: AtomicBool = AtomicBool::new(false);
+
+/// Sets whether `READY=1` is sent to systemd when the open callback of the plugin succeeds. Off by
+/// default, since OpenVPN built with systemd support sends it itself.
+pub fn set_send_ready(send_ready: bool) {
+    SEND_READY.store(send_ready, Ordering::Relaxed);
+}
+
+/// Sends `state`, such as `STATUS=Reloading`, to systemd. Returns `Ok(false)` without sending
+/// anything if OpenVPN was not started by systemd.
+pub fn notify(state: &str) -> io::Result<bool> {
+    match env::var_os(NOTIFY_SOCKET) {
+        Some(socket) => notify_socket(&socket, state).map(|()| true),
+        None => Ok(false),
+    }
+}
+
+/// Sends `state` to the notification socket `socket`. A leading `@` means an abstract socket.
+fn notify_socket(socket: &OsStr, state: &str) -> io::Result<()> {
+    let datagram = UnixDatagram::unbound()?;
+    match abstract_name(socket) {
+        Some(name) => send_abstract(&datagram, name, state),
+        None => datagram.send_to(state.as_bytes(), socket).map(|_| ()),
+    }
+}
+
+fn abstract_name(socket: &OsStr) -> Option<&OsStr> {
+    use std::os::unix::ffi::OsStrExt;
+    match socket.as_bytes() {
+        [b'@', name @ ..] => Some(OsStr::from_bytes(name)),
+        _ => None,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn send_abstract(datagram: &UnixDatagram, name: &OsStr, state: &str) -> io::Result<()> {
+    use std::os::{
+        linux::net::SocketAddrExt,
+        unix::{ffi::OsStrExt, net::SocketAddr},
+    };
+
+    let addr = SocketAddr::from_abstract_name(name.as_bytes())?;
+    datagram.send_to_addr(state.as_bytes(), &addr).map(|_| ())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_abstract(_datagram: &UnixDatagram, _name: &OsStr, _state: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Abstract sockets are only supported on Linux",
+    ))
+}
+
+/// Sends `state` if running under systemd, logging failures as warnings.
+fn send(state: &str) {
+    if let Err(e) = notify(state) {
+        logging::log_warning(&e);
+    }
+}
+
+/// Tells systemd the plugin is ready if enabled with `set_send_ready`. Called when the open
+/// callback succeeds.
+pub(crate) fn ready() {
+    if SEND_READY.load(Ordering::Relaxed) {
+        send("READY=1");
+    }
+}
+
+/// Updates the status of the unit after the tunnel went up or down.
+pub(crate) fn event(event: EventType, code: c_int) {
+    match event {
+        EventType::Up if code == ffi::OPENVPN_PLUGIN_FUNC_SUCCESS => send("STATUS=Tunnel up"),
+        EventType::Down => send("STATUS=Tunnel down"),
+        _ => (),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::temp_path;
+
+    #[test]
+    fn sends_to_socket() {
+        let path = temp_path("notify");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixDatagram::bind(&path).unwrap();
+
+        notify_socket(path.as_os_str(), "STATUS=Tunnel up").unwrap();
+        let mut buf = [0; 64];
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(b"STATUS=Tunnel up", &buf[..len]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn sends_to_abstract_socket() {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::SocketAddr;
+
+        let name = format!("openvpn-plugin-notify-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let listener = UnixDatagram::bind_addr(&addr).unwrap();
+
+        notify_socket(OsStr::new(&format!("@{}", name)), "READY=1").unwrap();
+        let mut buf = [0; 64];
+        let len = listener.recv(&mut buf).unwrap();
+        assert_eq!(b"READY=1", &buf[..len]);
+    }
+}
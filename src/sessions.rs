@@ -0,0 +1,492 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Tracking of the client sessions of an OpenVPN server.
+//!
+//! A [`SessionTracker`] follows the `AuthUserPassVerify`, `ClientConnect` and `ClientDisconnect`
+//! events of every client and keeps a map of the sessions that are active. Sessions are keyed by
+//! the common name of the client together with its real address and port, so several clients
+//! sharing a certificate are tracked separately.
+//!
+//! ```rust
+//! use std::{collections::HashMap, ffi::CString};
+//! use openvpn_plugin::{events::EventArgsError, sessions::SessionTracker, EventResult, EventType};
+//!
+//! struct Handle {
+//!     sessions: SessionTracker,
+//! }
+//!
+//! fn event(
+//!     event: EventType,
+//!     _args: Vec<CString>,
+//!     env: HashMap<CString, CString>,
+//!     handle: &mut Handle,
+//! ) -> Result<EventResult, EventArgsError> {
+//!     handle.sessions.handle_event(event, &env)?;
+//!     Ok(EventResult::Success)
+//! }
+//!
+//! let sessions = SessionTracker::new()
+//!     .on_start(|session| println!("{} connected", session.key))
+//!     .on_end(|session, reason| println!("{} ended: {:?}", session.key, reason));
+//! ```
+//!
+//! A session starts when the client connects. Clients that authenticate but never connect, for
+//! example because the authentication failed, are forgotten after [`pending_timeout`]. Connected
+//! sessions are only ended by `ClientDisconnect`, unless an [`idle_timeout`] is set. OpenVPN
+//! authenticates connected clients again on every renegotiation, so with the default
+//! `reneg-sec 3600` an idle timeout somewhat above one hour catches sessions whose disconnect was
+//! never reported.
+//!
+//! [`SessionTracker`]: struct.SessionTracker.html
+//! [`pending_timeout`]: struct.SessionTracker.html#method.pending_timeout
+//! [`idle_timeout`]: struct.SessionTracker.html#method.idle_timeout
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fmt,
+    net::SocketAddr,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    env_keys,
+    events::{DisconnectStats, Env, EventArgsError},
+    EventType,
+};
+
+/// How long a client may stay authenticated without connecting, unless changed with
+/// `SessionTracker::pending_timeout`.
+pub const DEFAULT_PENDING_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+type StartHook = Box<dyn FnMut(&Session) + Send>;
+type EndHook = Box<dyn FnMut(&Session, &EndReason) + Send>;
+
+/// Identifies one client session.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct SessionKey {
+    /// The common name of the client certificate, from `common_name`.
+    pub common_name: String,
+    /// The real address and port of the client, from `untrusted_ip` or `untrusted_ip6` and
+    /// `untrusted_port`.
+    pub endpoint: SocketAddr,
+}
+
+impl SessionKey {
+    /// Reads the key from the environment of a client event.
+    pub fn from_env(env: &HashMap<CString, CString>) -> Result<Self, EventArgsError> {
+        let env = Env(env);
+        let endpoint = env
+            .socket_addr_opt(
+                env_keys::UNTRUSTED_IP,
+                env_keys::UNTRUSTED_IP6,
+                env_keys::UNTRUSTED_PORT,
+            )?
+            .ok_or(EventArgsError::MissingEnv(env_keys::UNTRUSTED_IP6))?;
+        Ok(SessionKey {
+            common_name: env.string(env_keys::COMMON_NAME)?,
+            endpoint,
+        })
+    }
+}
+
+/// Formats the key as `common_name@address:port`.
+impl fmt::Display for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}", self.common_name, self.endpoint)
+    }
+}
+
+/// The state of a tracked session.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum SessionState {
+    /// The client has authenticated but not yet connected.
+    Pending,
+    /// The client is connected.
+    Connected,
+}
+
+/// A client session.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Session {
+    /// The key the session is tracked under.
+    pub key: SessionKey,
+    /// The username the client last authenticated with, if it authenticated with one.
+    pub username: Option<String>,
+    /// Whether the client has connected yet.
+    pub state: SessionState,
+    /// When the first event of the session was seen.
+    pub started: SystemTime,
+    last_seen: Instant,
+}
+
+impl Session {
+    /// When the last event of the session was seen.
+    pub fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+}
+
+/// Why a session ended.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum EndReason {
+    /// OpenVPN reported that the client disconnected. Contains the statistics of the session, if
+    /// they could be parsed from the environment.
+    Disconnected(Option<DisconnectStats>),
+    /// No event was seen for the session within the idle timeout.
+    Idle,
+}
+
+/// Keeps track of the active client sessions. See the [module documentation] for an example.
+///
+/// [module documentation]: index.html
+pub struct SessionTracker {
+    sessions: HashMap<SessionKey, Session>,
+    pending_timeout: Duration,
+    idle_timeout: Option<Duration>,
+    on_start: Option<StartHook>,
+    on_end: Option<EndHook>,
+}
+
+impl SessionTracker {
+    /// Creates a tracker without any sessions or hooks.
+    pub fn new() -> Self {
+        SessionTracker {
+            sessions: HashMap::new(),
+            pending_timeout: DEFAULT_PENDING_TIMEOUT,
+            idle_timeout: None,
+            on_start: None,
+            on_end: None,
+        }
+    }
+
+    /// Sets how long a client may stay authenticated without connecting before it is forgotten.
+    /// Defaults to [`DEFAULT_PENDING_TIMEOUT`].
+    ///
+    /// [`DEFAULT_PENDING_TIMEOUT`]: constant.DEFAULT_PENDING_TIMEOUT.html
+    pub fn pending_timeout(mut self, timeout: Duration) -> Self {
+        self.pending_timeout = timeout;
+        self
+    }
+
+    /// Ends connected sessions that have not been seen in any event for `timeout`. Disabled by
+    /// default.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets a function to call when a client connects.
+    pub fn on_start(mut self, hook: impl FnMut(&Session) + Send + 'static) -> Self {
+        self.on_start = Some(Box::new(hook));
+        self
+    }
+
+    /// Sets a function to call when a session ends. Only called for sessions that were started.
+    pub fn on_end(mut self, hook: impl FnMut(&Session, &EndReason) + Send + 'static) -> Self {
+        self.on_end = Some(Box::new(hook));
+        self
+    }
+
+    /// Updates the sessions from an event. Events that are not about client sessions are ignored.
+    /// Stale sessions are removed on every call.
+    pub fn handle_event(
+        &mut self,
+        event: EventType,
+        env: &HashMap<CString, CString>,
+    ) -> Result<(), EventArgsError> {
+        self.handle_event_at(event, env, Instant::now())
+    }
+
+    fn handle_event_at(
+        &mut self,
+        event: EventType,
+        env: &HashMap<CString, CString>,
+        now: Instant,
+    ) -> Result<(), EventArgsError> {
+        self.remove_stale_at(now);
+        match event {
+            EventType::AuthUserPassVerify => {
+                let key = SessionKey::from_env(env)?;
+                let username = Env(env).string_opt(env_keys::USERNAME)?;
+                let session = touch(&mut self.sessions, key, now);
+                session.username = username;
+            }
+            EventType::ClientConnect | EventType::ClientConnectV2 => self.connect(env, now)?,
+            #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+            EventType::ClientConnectDefer | EventType::ClientConnectDeferV2 => {
+                self.connect(env, now)?
+            }
+            EventType::ClientDisconnect => {
+                let key = SessionKey::from_env(env)?;
+                if let Some(session) = self.sessions.remove(&key) {
+                    let stats = DisconnectStats::from_env(env).ok();
+                    self.end(&session, &EndReason::Disconnected(stats));
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn connect(
+        &mut self,
+        env: &HashMap<CString, CString>,
+        now: Instant,
+    ) -> Result<(), EventArgsError> {
+        let session = touch(&mut self.sessions, SessionKey::from_env(env)?, now);
+        if session.state == SessionState::Pending {
+            session.state = SessionState::Connected;
+            if let Some(hook) = &mut self.on_start {
+                hook(session);
+            }
+        }
+        Ok(())
+    }
+
+    fn end(&mut self, session: &Session, reason: &EndReason) {
+        if session.state == SessionState::Connected {
+            if let Some(hook) = &mut self.on_end {
+                hook(session, reason);
+            }
+        }
+    }
+
+    /// Removes the pending sessions older than the pending timeout and the connected sessions
+    /// idle for longer than the idle timeout. Returns how many sessions were removed.
+    pub fn remove_stale(&mut self) -> usize {
+        self.remove_stale_at(Instant::now())
+    }
+
+    fn remove_stale_at(&mut self, now: Instant) -> usize {
+        let pending_timeout = self.pending_timeout;
+        let idle_timeout = self.idle_timeout;
+        let stale: Vec<SessionKey> = self
+            .sessions
+            .values()
+            .filter(|session| {
+                let timeout = match session.state {
+                    SessionState::Pending => Some(pending_timeout),
+                    SessionState::Connected => idle_timeout,
+                };
+                timeout.is_some_and(|timeout| {
+                    now.saturating_duration_since(session.last_seen) > timeout
+                })
+            })
+            .map(|session| session.key.clone())
+            .collect();
+        for key in &stale {
+            if let Some(session) = self.sessions.remove(key) {
+                self.end(&session, &EndReason::Idle);
+            }
+        }
+        stale.len()
+    }
+
+    /// Returns the session with `key`, if it is tracked.
+    pub fn get(&self, key: &SessionKey) -> Option<&Session> {
+        self.sessions.get(key)
+    }
+
+    /// Returns all tracked sessions, pending and connected, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = &Session> {
+        self.sessions.values()
+    }
+
+    /// Returns the number of connected sessions.
+    pub fn connected(&self) -> usize {
+        self.iter()
+            .filter(|session| session.state == SessionState::Connected)
+            .count()
+    }
+
+    /// Returns the number of tracked sessions, pending and connected.
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Returns true if no sessions are tracked.
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+}
+
+impl Default for SessionTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns the session with `key`, created as pending if it is not tracked yet, and marks it
+/// as seen.
+fn touch(
+    sessions: &mut HashMap<SessionKey, Session>,
+    key: SessionKey,
+    now: Instant,
+) -> &mut Session {
+    let session = sessions.entry(key.clone()).or_insert_with(|| Session {
+        key,
+        username: None,
+        state: SessionState::Pending,
+        started: SystemTime::now(),
+        last_seen: now,
+    });
+    session.last_seen = now;
+    session
+}
+
+impl fmt::Debug for SessionTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionTracker")
+            .field("sessions", &self.sessions)
+            .field("pending_timeout", &self.pending_timeout)
+            .field("idle_timeout", &self.idle_timeout)
+            .finish()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::env;
+    use std::sync::{Arc, Mutex};
+
+    fn client(common_name: &str, port: &str) -> HashMap<CString, CString> {
+        env(&[
+            ("common_name", common_name),
+            ("untrusted_ip", "192.0.2.1"),
+            ("untrusted_port", port),
+            ("username", "alice"),
+            ("bytes_received", "10"),
+            ("bytes_sent", "20"),
+            ("time_duration", "30"),
+        ])
+    }
+
+    fn key(common_name: &str, port: u16) -> SessionKey {
+        SessionKey {
+            common_name: common_name.to_owned(),
+            endpoint: SocketAddr::new([192, 0, 2, 1].into(), port),
+        }
+    }
+
+    fn recording_tracker() -> (SessionTracker, Arc<Mutex<Vec<String>>>) {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let start_log = log.clone();
+        let end_log = log.clone();
+        let tracker = SessionTracker::new()
+            .on_start(move |session| {
+                start_log
+                    .lock()
+                    .unwrap()
+                    .push(format!("start {}", session.key))
+            })
+            .on_end(move |session, reason| {
+                end_log
+                    .lock()
+                    .unwrap()
+                    .push(format!("end {} {:?}", session.key, reason))
+            });
+        (tracker, log)
+    }
+
+    #[test]
+    fn session_lifecycle() {
+        let (mut tracker, log) = recording_tracker();
+        let now = Instant::now();
+        let alice = client("alice", "1194");
+
+        tracker
+            .handle_event_at(EventType::AuthUserPassVerify, &alice, now)
+            .unwrap();
+        let session = tracker.get(&key("alice", 1194)).unwrap();
+        assert_eq!(SessionState::Pending, session.state);
+        assert_eq!(Some("alice"), session.username.as_deref());
+        assert_eq!(0, tracker.connected());
+
+        tracker
+            .handle_event_at(EventType::ClientConnect, &alice, now)
+            .unwrap();
+        tracker
+            .handle_event_at(EventType::ClientConnectV2, &alice, now)
+            .unwrap();
+        tracker
+            .handle_event_at(EventType::ClientConnect, &client("alice", "1195"), now)
+            .unwrap();
+        assert_eq!(2, tracker.connected());
+
+        tracker
+            .handle_event_at(EventType::ClientDisconnect, &alice, now)
+            .unwrap();
+        assert_eq!(1, tracker.len());
+        assert_eq!(
+            vec![
+                "start alice@192.0.2.1:1194".to_owned(),
+                "start alice@192.0.2.1:1195".to_owned(),
+                "end alice@192.0.2.1:1194 Disconnected(Some(DisconnectStats { bytes_received: 10, \
+                 bytes_sent: 20, duration: 30s, trusted_ip: None, trusted_port: None }))"
+                    .to_owned(),
+            ],
+            *log.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn stale_sessions() {
+        let (tracker, log) = recording_tracker();
+        let mut tracker = tracker.idle_timeout(Duration::from_secs(3600));
+        let now = Instant::now();
+
+        tracker
+            .handle_event_at(EventType::AuthUserPassVerify, &client("a", "1"), now)
+            .unwrap();
+        tracker
+            .handle_event_at(EventType::ClientConnect, &client("b", "2"), now)
+            .unwrap();
+        assert_eq!(0, tracker.remove_stale_at(now + DEFAULT_PENDING_TIMEOUT));
+        assert_eq!(
+            1,
+            tracker.remove_stale_at(now + DEFAULT_PENDING_TIMEOUT + Duration::from_secs(1))
+        );
+        assert!(tracker.get(&key("a", 1)).is_none());
+        assert_eq!(vec!["start b@192.0.2.1:2"], *log.lock().unwrap());
+
+        tracker
+            .handle_event_at(
+                EventType::AuthUserPassVerify,
+                &client("b", "2"),
+                now + Duration::from_secs(3000),
+            )
+            .unwrap();
+        assert_eq!(0, tracker.remove_stale_at(now + Duration::from_secs(3601)));
+        tracker
+            .handle_event_at(
+                EventType::Up,
+                &HashMap::new(),
+                now + Duration::from_secs(6601),
+            )
+            .unwrap();
+        assert!(tracker.is_empty());
+        assert_eq!(
+            vec!["start b@192.0.2.1:2", "end b@192.0.2.1:2 Idle"],
+            *log.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn missing_endpoint() {
+        let mut tracker = SessionTracker::new();
+        assert_eq!(
+            Err(EventArgsError::MissingEnv("untrusted_ip6")),
+            tracker.handle_event(EventType::ClientConnect, &env(&[("common_name", "a")]))
+        );
+        assert!(tracker.is_empty());
+    }
+}
@@ -0,0 +1,197 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Sends metrics about every event to a StatsD server over UDP. Requires the `statsd` feature.
+//!
+//! Once a [`Statsd`] client is installed, this crate sends one packet per event with:
+//!
+//! * `<prefix>.events.<event>` - a counter of the event.
+//! * `<prefix>.failures.<event>` - a counter of events answered with an error.
+//! * `<prefix>.latency.<event>` - the duration of the callback, as a timer.
+//! * `<prefix>.auth.accept`, `<prefix>.auth.deny` and `<prefix>.auth.deferred` - counters of the
+//!   outcomes of `AuthUserPassVerify` events.
+//!
+//! `<event>` is the OpenVPN name of the event in lower case, without the `PLUGIN_` prefix, e.g.
+//! `auth_user_pass_verify`. In the DogStatsD format the event is a tag instead of part of the
+//! metric name.
+//!
+//! ```rust,no_run
+//! use openvpn_plugin::statsd::Statsd;
+//!
+//! # fn main() -> std::io::Result<()> {
+//! Statsd::new("127.0.0.1:8125", "openvpn.plugin")?.install();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Packets are sent without waiting for the server. Packets that can't be sent are dropped.
+//!
+//! [`Statsd`]: struct.Statsd.html
+
+use std::{
+    fmt::Write as _,
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    os::raw::c_int,
+    sync::{PoisonError, RwLock},
+    time::Duration,
+};
+
+use crate::{ffi, EventType};
+
+static CLIENT: RwLock<Option<Statsd>> = RwLock::new(None);
+
+/// A client sending event metrics to a StatsD server.
+#[derive(Debug)]
+pub struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+    dogstatsd: bool,
+}
+
+impl Statsd {
+    /// Creates a client sending to the StatsD server at `addr`, with all metric names starting
+    /// with `prefix`.
+    pub fn new(addr: impl ToSocketAddrs, prefix: impl Into<String>) -> io::Result<Self> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "No address to send to"))?;
+        let bind_addr: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(bind_addr)?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Statsd {
+            socket,
+            prefix: prefix.into(),
+            dogstatsd: false,
+        })
+    }
+
+    /// Uses the DogStatsD format, with the event as a tag.
+    pub fn dogstatsd(mut self) -> Self {
+        self.dogstatsd = true;
+        self
+    }
+
+    /// Makes this the client that metrics are sent with. Replaces any previously installed client.
+    pub fn install(self) {
+        *CLIENT.write().unwrap_or_else(PoisonError::into_inner) = Some(self);
+    }
+
+    fn send(&self, event: EventType, elapsed: Duration, code: c_int) {
+        let _ = self
+            .socket
+            .send(self.packet(event, elapsed, code).as_bytes());
+    }
+
+    fn packet(&self, event: EventType, elapsed: Duration, code: c_int) -> String {
+        let name = event.name().trim_start_matches("PLUGIN_").to_lowercase();
+        let mut metrics = vec![("events", "1|c".to_owned())];
+        if code == ffi::OPENVPN_PLUGIN_FUNC_ERROR {
+            metrics.push(("failures", "1|c".to_owned()));
+        }
+        metrics.push(("latency", format!("{}|ms", elapsed.as_secs_f64() * 1000.0)));
+
+        let mut packet = String::new();
+        for (metric, value) in metrics {
+            if self.dogstatsd {
+                let _ = writeln!(
+                    packet,
+                    "{}.{}:{}|#event:{}",
+                    self.prefix, metric, value, name
+                );
+            } else {
+                let _ = writeln!(packet, "{}.{}.{}:{}", self.prefix, metric, name, value);
+            }
+        }
+        if event == EventType::AuthUserPassVerify {
+            let outcome = match code {
+                ffi::OPENVPN_PLUGIN_FUNC_SUCCESS => "accept",
+                ffi::OPENVPN_PLUGIN_FUNC_DEFERRED => "deferred",
+                _ => "deny",
+            };
+            let _ = writeln!(packet, "{}.auth.{}:1|c", self.prefix, outcome);
+        }
+        packet.pop();
+        packet
+    }
+}
+
+/// Stops sending metrics, dropping the installed client.
+pub fn uninstall() {
+    CLIENT
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+}
+
+/// Sends the metrics of one event with the installed client, if there is one.
+pub(crate) fn send(event: EventType, elapsed: Duration, code: c_int) {
+    if let Some(client) = &*CLIENT.read().unwrap_or_else(PoisonError::into_inner) {
+        client.send(event, elapsed, code);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = Statsd::new(server.local_addr().unwrap(), "vpn").unwrap();
+        assert_eq!(
+            "vpn.events.up:1|c\nvpn.latency.up:1.5|ms",
+            client.packet(
+                EventType::Up,
+                Duration::from_micros(1500),
+                ffi::OPENVPN_PLUGIN_FUNC_SUCCESS
+            )
+        );
+        assert_eq!(
+            "vpn.events.auth_user_pass_verify:1|c\n\
+             vpn.failures.auth_user_pass_verify:1|c\n\
+             vpn.latency.auth_user_pass_verify:2|ms\n\
+             vpn.auth.deny:1|c",
+            client.packet(
+                EventType::AuthUserPassVerify,
+                Duration::from_millis(2),
+                ffi::OPENVPN_PLUGIN_FUNC_ERROR
+            )
+        );
+
+        let client = client.dogstatsd();
+        assert_eq!(
+            "vpn.events:1|c|#event:auth_user_pass_verify\n\
+             vpn.latency:0|ms|#event:auth_user_pass_verify\n\
+             vpn.auth.deferred:1|c",
+            client.packet(
+                EventType::AuthUserPassVerify,
+                Duration::ZERO,
+                ffi::OPENVPN_PLUGIN_FUNC_DEFERRED
+            )
+        );
+
+        client.send(
+            EventType::Down,
+            Duration::ZERO,
+            ffi::OPENVPN_PLUGIN_FUNC_SUCCESS,
+        );
+        let mut buf = [0; 512];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            &b"vpn.events:1|c|#event:down\nvpn.latency:0|ms|#event:down"[..],
+            &buf[..len]
+        );
+    }
+}
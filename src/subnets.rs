@@ -0,0 +1,239 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Subnets in the environment as [`ipnet`] types. Requires the `ipnet` feature.
+//!
+//! OpenVPN gives IPv4 subnets as an address and a netmask in two variables, and IPv6 subnets as
+//! an address and a prefix length. These functions combine them into one `Ipv4Net` or `Ipv6Net`,
+//! so the prefix arithmetic of `ipnet` can be used on them:
+//!
+//! ```rust
+//! # use std::{collections::HashMap, ffi::CString, net::Ipv4Addr};
+//! # use openvpn_plugin::subnets;
+//! # let env: HashMap<CString, CString> = [("ifconfig_local", "10.8.0.1"), ("ifconfig_netmask", "255.255.255.0")]
+//! #     .iter()
+//! #     .map(|(k, v)| (CString::new(*k).unwrap(), CString::new(*v).unwrap()))
+//! #     .collect();
+//! let tunnel = subnets::ifconfig_net(&env).unwrap().unwrap();
+//! assert_eq!("10.8.0.0/24", tunnel.trunc().to_string());
+//! assert!(tunnel.contains(&Ipv4Addr::new(10, 8, 0, 6)));
+//! ```
+//!
+//! The address of a subnet is kept as OpenVPN gave it, so the tunnel subnet above is
+//! `10.8.0.1/24`. Use `trunc` for the network itself. With the feature, `events::IpNetwork` also
+//! converts to and from `IpNet`.
+//!
+//! [`ipnet`]: https://docs.rs/ipnet
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+use ipnet::{IpNet, Ipv4Net, Ipv6Net};
+
+use crate::{
+    env_keys,
+    events::{netmask_prefix, parse_numbered, Env, EventArgsError},
+};
+
+/// The IPv4 subnet of the tunnel, from `ifconfig_local` and `ifconfig_netmask`. A single address
+/// in point-to-point topologies, where there is no netmask.
+pub fn ifconfig_net(env: &HashMap<CString, CString>) -> Result<Option<Ipv4Net>, EventArgsError> {
+    let env = Env(env);
+    let address = env.parse_opt(env_keys::IFCONFIG_LOCAL)?;
+    let netmask = env.parse_opt(env_keys::IFCONFIG_NETMASK)?;
+    ipv4_net(address, netmask, env_keys::IFCONFIG_NETMASK)
+}
+
+/// The IPv6 subnet of the tunnel, from `ifconfig_ipv6_local` and `ifconfig_ipv6_netbits`.
+pub fn ifconfig_ipv6_net(
+    env: &HashMap<CString, CString>,
+) -> Result<Option<Ipv6Net>, EventArgsError> {
+    let env = Env(env);
+    let address = env.parse_opt(env_keys::IFCONFIG_IPV6_LOCAL)?;
+    let prefix = env.parse_opt(env_keys::IFCONFIG_IPV6_NETBITS)?;
+    ipv6_net(address, prefix, env_keys::IFCONFIG_IPV6_NETBITS)
+}
+
+/// The IPv4 address given to a client from the pool, with the netmask of the pool, from
+/// `ifconfig_pool_remote_ip` and `ifconfig_pool_netmask`.
+pub fn ifconfig_pool_net(
+    env: &HashMap<CString, CString>,
+) -> Result<Option<Ipv4Net>, EventArgsError> {
+    let env = Env(env);
+    let address = env.parse_opt(env_keys::IFCONFIG_POOL_REMOTE_IP)?;
+    let netmask = env.parse_opt(env_keys::IFCONFIG_POOL_NETMASK)?;
+    ipv4_net(address, netmask, env_keys::IFCONFIG_POOL_NETMASK)
+}
+
+/// The IPv6 address given to a client from the pool, with the prefix length of the pool, from
+/// `ifconfig_pool_remote_ip6` and `ifconfig_pool_ip6_netbits`.
+pub fn ifconfig_pool_ipv6_net(
+    env: &HashMap<CString, CString>,
+) -> Result<Option<Ipv6Net>, EventArgsError> {
+    let env = Env(env);
+    let address = env.parse_opt(env_keys::IFCONFIG_POOL_REMOTE_IP6)?;
+    let prefix = env.parse_opt(env_keys::IFCONFIG_POOL_IP6_NETBITS)?;
+    ipv6_net(address, prefix, env_keys::IFCONFIG_POOL_IP6_NETBITS)
+}
+
+/// The network of the `n`:th `--route`, counting from 1, from `route_network_{n}` and
+/// `route_netmask_{n}`.
+pub fn route_net(
+    env: &HashMap<CString, CString>,
+    n: usize,
+) -> Result<Option<Ipv4Net>, EventArgsError> {
+    let address = parse_numbered(env, env_keys::route_network(n))?;
+    let netmask_key = env_keys::route_netmask(n);
+    let netmask: Option<Ipv4Addr> = parse_numbered(env, netmask_key.clone())?;
+    let prefix = match netmask {
+        Some(netmask) => Some(
+            netmask_prefix(netmask)
+                .ok_or_else(|| EventArgsError::InvalidValue(netmask_key, netmask.to_string()))?,
+        ),
+        None => None,
+    };
+    Ok(address.map(|address| Ipv4Net::new(address, prefix.unwrap_or(32)).unwrap()))
+}
+
+/// The network of the `n`:th `--route-ipv6`, counting from 1, from `route_ipv6_network_{n}`.
+pub fn route_ipv6_net(
+    env: &HashMap<CString, CString>,
+    n: usize,
+) -> Result<Option<Ipv6Net>, EventArgsError> {
+    parse_numbered(env, env_keys::route_ipv6_network(n))
+}
+
+/// The networks of all `--route` and `--route-ipv6` options, the IPv4 networks first.
+pub fn route_nets(env: &HashMap<CString, CString>) -> Result<Vec<IpNet>, EventArgsError> {
+    let mut nets = Vec::new();
+    for n in 1.. {
+        match route_net(env, n)? {
+            Some(net) => nets.push(net.into()),
+            None => break,
+        }
+    }
+    for n in 1.. {
+        match route_ipv6_net(env, n)? {
+            Some(net) => nets.push(net.into()),
+            None => break,
+        }
+    }
+    Ok(nets)
+}
+
+fn ipv4_net(
+    address: Option<Ipv4Addr>,
+    netmask: Option<Ipv4Addr>,
+    netmask_key: &'static str,
+) -> Result<Option<Ipv4Net>, EventArgsError> {
+    let address = match address {
+        Some(address) => address,
+        None => return Ok(None),
+    };
+    let prefix = match netmask {
+        Some(netmask) => netmask_prefix(netmask).ok_or_else(|| {
+            EventArgsError::InvalidValue(netmask_key.to_owned(), netmask.to_string())
+        })?,
+        None => 32,
+    };
+    Ok(Some(Ipv4Net::new(address, prefix).unwrap()))
+}
+
+fn ipv6_net(
+    address: Option<Ipv6Addr>,
+    prefix: Option<u8>,
+    prefix_key: &'static str,
+) -> Result<Option<Ipv6Net>, EventArgsError> {
+    let address = match address {
+        Some(address) => address,
+        None => return Ok(None),
+    };
+    let prefix = prefix.unwrap_or(128);
+    Ipv6Net::new(address, prefix)
+        .map(Some)
+        .map_err(|_| EventArgsError::InvalidValue(prefix_key.to_owned(), prefix.to_string()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::env;
+
+    #[test]
+    fn tunnel_subnets() {
+        let env = env(&[
+            ("ifconfig_local", "10.8.0.1"),
+            ("ifconfig_netmask", "255.255.255.0"),
+            ("ifconfig_ipv6_local", "fd00::1"),
+            ("ifconfig_ipv6_netbits", "64"),
+            ("ifconfig_pool_remote_ip", "10.8.0.6"),
+            ("ifconfig_pool_netmask", "255.255.255.0"),
+            ("ifconfig_pool_remote_ip6", "fd00::1000"),
+        ]);
+        assert_eq!(
+            Some("10.8.0.1/24".parse().unwrap()),
+            ifconfig_net(&env).unwrap()
+        );
+        assert_eq!(
+            Some("fd00::1/64".parse().unwrap()),
+            ifconfig_ipv6_net(&env).unwrap()
+        );
+        assert_eq!(
+            Some("10.8.0.6/24".parse().unwrap()),
+            ifconfig_pool_net(&env).unwrap()
+        );
+        assert_eq!(
+            Some("fd00::1000/128".parse().unwrap()),
+            ifconfig_pool_ipv6_net(&env).unwrap()
+        );
+    }
+
+    #[test]
+    fn route_subnets() {
+        let valid = env(&[
+            ("route_network_1", "10.0.0.0"),
+            ("route_netmask_1", "255.0.0.0"),
+            ("route_network_2", "192.0.2.1"),
+            ("route_ipv6_network_1", "2001:db8::/32"),
+        ]);
+        assert_eq!(
+            vec!["10.0.0.0/8", "192.0.2.1/32", "2001:db8::/32"],
+            route_nets(&valid)
+                .unwrap()
+                .iter()
+                .map(IpNet::to_string)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(None, route_net(&valid, 3).unwrap());
+
+        let invalid = env(&[
+            ("ifconfig_local", "10.8.0.1"),
+            ("ifconfig_netmask", "255.0.255.0"),
+            ("ifconfig_ipv6_local", "fd00::1"),
+            ("ifconfig_ipv6_netbits", "129"),
+        ]);
+        assert_eq!(
+            Err(EventArgsError::InvalidValue(
+                "ifconfig_netmask".to_owned(),
+                "255.0.255.0".to_owned()
+            )),
+            ifconfig_net(&invalid)
+        );
+        assert_eq!(
+            Err(EventArgsError::InvalidValue(
+                "ifconfig_ipv6_netbits".to_owned(),
+                "129".to_owned()
+            )),
+            ifconfig_ipv6_net(&invalid)
+        );
+    }
+}
@@ -0,0 +1,594 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Realistic arguments and environments for every event, modeled on what an OpenVPN 2.6 server
+//! with a `tun` device passes to a plugin.
+//!
+//! The values describe one client, `client1`, connecting from `203.0.113.17:51820` and getting
+//! `10.8.0.6` assigned from the pool. Paths to control files point into the system temporary
+//! directory, so they pass validation but are not created.
+//!
+//! ```rust
+//! use openvpn_plugin::{testing::fixtures, EventArgs, EventType};
+//!
+//! let fixture = fixtures::for_event(EventType::AuthUserPassVerify)
+//!     .with_env("username", "alice")
+//!     .without_env("auth_control_file");
+//! let args = EventArgs::parse(fixture.event, &fixture.args, &fixture.env).unwrap();
+//! # assert_eq!(EventType::AuthUserPassVerify, args.event_type());
+//! ```
+
+use std::{collections::HashMap, env, ffi::CString};
+
+use crate::{env_keys, EventType};
+
+/// Path OpenVPN passes as the first argument, the plugin itself.
+pub const PLUGIN_PATH: &str = "/usr/lib/openvpn/plugins/openvpn-plugin.so";
+
+/// The common name of the client in the fixtures.
+pub const COMMON_NAME: &str = "client1";
+
+/// The real address of the client in the fixtures.
+pub const CLIENT_IP: &str = "203.0.113.17";
+
+/// The real port of the client in the fixtures.
+pub const CLIENT_PORT: &str = "51820";
+
+/// The tunnel address assigned to the client in the fixtures.
+pub const CLIENT_VPN_IP: &str = "10.8.0.6";
+
+
+/// The arguments and environment of one event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fixture {
+    /// The event the arguments and environment belong to.
+    pub event: EventType,
+    /// The arguments. The first one is the path to the plugin.
+    pub args: Vec<CString>,
+    /// The environment variables.
+    pub env: HashMap<CString, CString>,
+}
+
+impl Fixture {
+    /// Sets the environment variable `key` to `value`, replacing any existing value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` or `value` contain a null byte.
+    pub fn with_env(mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Self {
+        self.env.insert(cstring(key), cstring(value));
+        self
+    }
+
+    /// Removes the environment variable `key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` contains a null byte.
+    pub fn without_env(mut self, key: impl Into<Vec<u8>>) -> Self {
+        self.env.remove(&cstring(key));
+        self
+    }
+
+    /// Replaces the argument at `index`. Index 0 is the path to the plugin.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is out of bounds or `value` contains a null byte.
+    pub fn with_arg(mut self, index: usize, value: impl Into<Vec<u8>>) -> Self {
+        self.args[index] = cstring(value);
+        self
+    }
+}
+
+/// Returns the fixture for `event`.
+pub fn for_event(event: EventType) -> Fixture {
+    match event {
+        EventType::Up => up(),
+        EventType::Down => down(),
+        EventType::RouteUp => route_up(),
+        EventType::IpChange => ip_change(),
+        EventType::TlsVerify => tls_verify(),
+        EventType::AuthUserPassVerify => auth_user_pass_verify(),
+        EventType::ClientConnect => client_connect(),
+        EventType::ClientDisconnect => client_disconnect(),
+        EventType::LearnAddress => learn_address(),
+        EventType::ClientConnectV2 => client_connect_v2(),
+        EventType::TlsFinal => tls_final(),
+        #[cfg(any(feature = "openvpn-2-4", feature = "openvpn-2-5"))]
+        EventType::EnablePf => enable_pf(),
+        EventType::RoutePredown => route_predown(),
+        #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+        EventType::ClientConnectDefer => client_connect_defer(),
+        #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+        EventType::ClientConnectDeferV2 => client_connect_defer_v2(),
+        #[cfg(feature = "openvpn-2-6")]
+        EventType::ClientCrresponse => client_crresponse(),
+        #[cfg(feature = "auth-failed-event")]
+        EventType::AuthFailed => auth_failed(),
+    }
+}
+
+/// The environment OpenVPN passes to `openvpn_plugin_open_v3`.
+pub fn open_env() -> HashMap<CString, CString> {
+    let mut env = HashMap::new();
+    set(&mut env, env_keys::CONFIG, "/etc/openvpn/server.conf");
+    set(&mut env, env_keys::DAEMON, "0");
+    set(&mut env, env_keys::DAEMON_LOG_REDIRECT, "0");
+    set(&mut env, env_keys::DAEMON_PID, "4711");
+    set(&mut env, env_keys::DAEMON_START_TIME, "1700000000");
+    set(&mut env, env_keys::VERB, "3");
+    set(&mut env, env_keys::LOCAL_PORT, "1194");
+    set(&mut env, env_keys::PROTO, "udp");
+    set(&mut env, "local_port_1", "1194");
+    set(&mut env, "proto_1", "udp");
+    env
+}
+
+/// The arguments OpenVPN passes to `openvpn_plugin_open_v3` for
+/// `--plugin openvpn-plugin.so verb 3`.
+pub fn open_args() -> Vec<CString> {
+    args(&[PLUGIN_PATH, "verb", "3"])
+}
+
+/// `EventType::Up`, when the server brought up `tun0`.
+pub fn up() -> Fixture {
+    let mut env = tunnel_env();
+    set(&mut env, env_keys::SCRIPT_TYPE, "up");
+    set(&mut env, env_keys::SCRIPT_CONTEXT, "init");
+    Fixture {
+        event: EventType::Up,
+        args: args(&[
+            PLUGIN_PATH,
+            "tun0",
+            "1500",
+            "0",
+            "10.8.0.1",
+            "255.255.255.0",
+            "init",
+        ]),
+        env,
+    }
+}
+
+/// `EventType::Down`, when the server shuts down on `SIGTERM`.
+pub fn down() -> Fixture {
+    let mut env = tunnel_env();
+    set(&mut env, env_keys::SCRIPT_TYPE, "down");
+    set(&mut env, env_keys::SCRIPT_CONTEXT, "init");
+    set(&mut env, env_keys::SIGNAL, "sigterm");
+    Fixture {
+        event: EventType::Down,
+        args: args(&[
+            PLUGIN_PATH,
+            "tun0",
+            "1500",
+            "0",
+            "10.8.0.1",
+            "255.255.255.0",
+            "init",
+        ]),
+        env,
+    }
+}
+
+/// `EventType::RouteUp`, after the server added a route to the client subnet.
+pub fn route_up() -> Fixture {
+    let mut env = routes_env();
+    set(&mut env, env_keys::SCRIPT_TYPE, "route-up");
+    Fixture {
+        event: EventType::RouteUp,
+        args: args(&[PLUGIN_PATH]),
+        env,
+    }
+}
+
+/// `EventType::IpChange`, when `client1` was authenticated from its address.
+pub fn ip_change() -> Fixture {
+    let mut env = client_env();
+    set(&mut env, env_keys::SCRIPT_TYPE, "ipchange");
+    Fixture {
+        event: EventType::IpChange,
+        args: args(&[PLUGIN_PATH, CLIENT_IP, CLIENT_PORT]),
+        env,
+    }
+}
+
+/// `EventType::TlsVerify`, for the certificate of `client1` at depth 0.
+pub fn tls_verify() -> Fixture {
+    let mut env = base_env();
+    set(&mut env, env_keys::SCRIPT_TYPE, "tls-verify");
+    set(&mut env, env_keys::UNTRUSTED_IP, CLIENT_IP);
+    set(&mut env, env_keys::UNTRUSTED_PORT, CLIENT_PORT);
+    set_certificates(&mut env);
+    Fixture {
+        event: EventType::TlsVerify,
+        args: args(&[PLUGIN_PATH, "0", "CN=client1"]),
+        env,
+    }
+}
+
+/// `EventType::AuthUserPassVerify`, with the username `client1` and the password `secret`.
+/// Includes the control, pending and failed reason files used for deferred authentication.
+pub fn auth_user_pass_verify() -> Fixture {
+    let mut env = base_env();
+    set(&mut env, env_keys::SCRIPT_TYPE, "user-pass-verify");
+    set(&mut env, env_keys::UNTRUSTED_IP, CLIENT_IP);
+    set(&mut env, env_keys::UNTRUSTED_PORT, CLIENT_PORT);
+    set(&mut env, env_keys::COMMON_NAME, COMMON_NAME);
+    set(&mut env, env_keys::USERNAME, COMMON_NAME);
+    set(&mut env, env_keys::PASSWORD, "secret");
+    set(
+        &mut env,
+        env_keys::AUTH_CONTROL_FILE,
+        &temp_file("openvpn_acf"),
+    );
+    set(
+        &mut env,
+        env_keys::AUTH_PENDING_FILE,
+        &temp_file("openvpn_apf"),
+    );
+    set(
+        &mut env,
+        env_keys::AUTH_FAILED_REASON_FILE,
+        &temp_file("openvpn_afr"),
+    );
+    set_certificates(&mut env);
+    set_peer_info(&mut env);
+    Fixture {
+        event: EventType::AuthUserPassVerify,
+        args: args(&[PLUGIN_PATH]),
+        env,
+    }
+}
+
+/// `EventType::ClientConnect`. The second argument is the file client specific config can be
+/// written to. With OpenVPN 2.5 and later it includes the files the result of a deferred connect
+/// is written to.
+pub fn client_connect() -> Fixture {
+    Fixture {
+        event: EventType::ClientConnect,
+        args: args(&[PLUGIN_PATH, &temp_file("openvpn_cc")]),
+        env: client_connect_env(),
+    }
+}
+
+/// `EventType::ClientDisconnect`, after `client1` was connected for an hour.
+pub fn client_disconnect() -> Fixture {
+    let mut env = client_env();
+    set(&mut env, env_keys::SCRIPT_TYPE, "client-disconnect");
+    set(&mut env, env_keys::BYTES_RECEIVED, "1843264");
+    set(&mut env, env_keys::BYTES_SENT, "24117248");
+    set(&mut env, env_keys::TIME_DURATION, "3600");
+    Fixture {
+        event: EventType::ClientDisconnect,
+        args: args(&[PLUGIN_PATH]),
+        env,
+    }
+}
+
+/// `EventType::LearnAddress`, when the tunnel address of `client1` is added.
+pub fn learn_address() -> Fixture {
+    let mut env = client_env();
+    set(&mut env, env_keys::SCRIPT_TYPE, "learn-address");
+    Fixture {
+        event: EventType::LearnAddress,
+        args: args(&[PLUGIN_PATH, "add", CLIENT_VPN_IP, COMMON_NAME]),
+        env,
+    }
+}
+
+/// `EventType::ClientConnectV2`.
+pub fn client_connect_v2() -> Fixture {
+    Fixture {
+        event: EventType::ClientConnectV2,
+        args: args(&[PLUGIN_PATH]),
+        env: client_connect_env(),
+    }
+}
+
+/// `EventType::TlsFinal`, after the TLS handshake with `client1` completed.
+pub fn tls_final() -> Fixture {
+    let mut env = base_env();
+    set(&mut env, env_keys::UNTRUSTED_IP, CLIENT_IP);
+    set(&mut env, env_keys::UNTRUSTED_PORT, CLIENT_PORT);
+    set(&mut env, env_keys::COMMON_NAME, COMMON_NAME);
+    set_certificates(&mut env);
+    set_peer_info(&mut env);
+    Fixture {
+        event: EventType::TlsFinal,
+        args: args(&[PLUGIN_PATH]),
+        env,
+    }
+}
+
+/// `EventType::EnablePf`.
+#[cfg(any(feature = "openvpn-2-4", feature = "openvpn-2-5"))]
+pub fn enable_pf() -> Fixture {
+    Fixture {
+        event: EventType::EnablePf,
+        args: args(&[PLUGIN_PATH]),
+        env: base_env(),
+    }
+}
+
+/// `EventType::RoutePredown`, before the server removes its routes.
+pub fn route_predown() -> Fixture {
+    let mut env = routes_env();
+    set(&mut env, env_keys::SCRIPT_TYPE, "route-pre-down");
+    set(&mut env, env_keys::SIGNAL, "sigterm");
+    Fixture {
+        event: EventType::RoutePredown,
+        args: args(&[PLUGIN_PATH]),
+        env,
+    }
+}
+
+/// `EventType::ClientConnectDefer`, including the files the result is written to.
+#[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+pub fn client_connect_defer() -> Fixture {
+    Fixture {
+        event: EventType::ClientConnectDefer,
+        args: args(&[PLUGIN_PATH]),
+        env: client_connect_env(),
+    }
+}
+
+/// `EventType::ClientConnectDeferV2`, including the files the result is written to.
+#[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+pub fn client_connect_defer_v2() -> Fixture {
+    Fixture {
+        event: EventType::ClientConnectDeferV2,
+        args: args(&[PLUGIN_PATH]),
+        env: client_connect_env(),
+    }
+}
+
+/// `EventType::ClientCrresponse`, where `client1` answered a challenge with `123456`.
+#[cfg(feature = "openvpn-2-6")]
+pub fn client_crresponse() -> Fixture {
+    let mut env = client_env();
+    set(&mut env, env_keys::CRRESPONSE, "MTIzNDU2");
+    Fixture {
+        event: EventType::ClientCrresponse,
+        args: args(&[PLUGIN_PATH]),
+        env,
+    }
+}
+
+/// `EventType::AuthFailed`, after the authentication of `client1` failed.
+#[cfg(feature = "auth-failed-event")]
+pub fn auth_failed() -> Fixture {
+    let mut env = client_env();
+    set(&mut env, env_keys::USERNAME, COMMON_NAME);
+    Fixture {
+        event: EventType::AuthFailed,
+        args: args(&[PLUGIN_PATH]),
+        env,
+    }
+}
+
+
+/// Variables present in every event.
+fn base_env() -> HashMap<CString, CString> {
+    let mut env = open_env();
+    set(&mut env, env_keys::DEV, "tun0");
+    set(&mut env, env_keys::DEV_TYPE, "tun");
+    set(&mut env, env_keys::LINK_MTU, "1621");
+    set(&mut env, env_keys::TUN_MTU, "1500");
+    set(&mut env, env_keys::IFCONFIG_LOCAL, "10.8.0.1");
+    set(&mut env, env_keys::IFCONFIG_NETMASK, "255.255.255.0");
+    set(&mut env, env_keys::IFCONFIG_IPV6_LOCAL, "fd00:8::1");
+    set(&mut env, env_keys::IFCONFIG_IPV6_NETBITS, "64");
+    set(&mut env, env_keys::ROUTE_NET_GATEWAY, "192.0.2.1");
+    env
+}
+
+/// Variables describing the tunnel device, in `Up` and `Down`.
+fn tunnel_env() -> HashMap<CString, CString> {
+    let mut env = base_env();
+    set(&mut env, env_keys::IFCONFIG_BROADCAST, "10.8.0.255");
+    env
+}
+
+/// Variables describing the routes, in `RouteUp` and `RoutePredown`.
+fn routes_env() -> HashMap<CString, CString> {
+    let mut env = base_env();
+    set(&mut env, env_keys::ROUTE_VPN_GATEWAY, "10.8.0.2");
+    set(&mut env, &env_keys::route_network(1), "10.8.0.0");
+    set(&mut env, &env_keys::route_netmask(1), "255.255.255.0");
+    set(&mut env, &env_keys::route_gateway(1), "10.8.0.2");
+    env
+}
+
+/// Variables describing an authenticated and connected client.
+fn client_env() -> HashMap<CString, CString> {
+    let mut env = base_env();
+    set(&mut env, env_keys::COMMON_NAME, COMMON_NAME);
+    set(&mut env, env_keys::TRUSTED_IP, CLIENT_IP);
+    set(&mut env, env_keys::TRUSTED_PORT, CLIENT_PORT);
+    set(&mut env, env_keys::UNTRUSTED_IP, CLIENT_IP);
+    set(&mut env, env_keys::UNTRUSTED_PORT, CLIENT_PORT);
+    set(&mut env, env_keys::IFCONFIG_POOL_LOCAL_IP, "10.8.0.1");
+    set(&mut env, env_keys::IFCONFIG_POOL_NETMASK, "255.255.255.0");
+    set(&mut env, env_keys::IFCONFIG_POOL_REMOTE_IP, CLIENT_VPN_IP);
+    set(&mut env, env_keys::IFCONFIG_POOL_REMOTE_IP6, "fd00:8::1000");
+    set(&mut env, env_keys::IFCONFIG_POOL_IP6_NETBITS, "64");
+    set(&mut env, env_keys::TIME_ASCII, "2023-11-14 22:13:20");
+    set(&mut env, env_keys::TIME_UNIX, "1700000000");
+    set(&mut env, env_keys::SESSION_ID, "dGhpc2lzYXNlc3Npb24");
+    set(&mut env, env_keys::SESSION_STATE, "Initial");
+    set_certificates(&mut env);
+    set_peer_info(&mut env);
+    env
+}
+
+fn client_connect_env() -> HashMap<CString, CString> {
+    let mut env = client_env();
+    set(&mut env, env_keys::SCRIPT_TYPE, "client-connect");
+    #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+    {
+        set(
+            &mut env,
+            env_keys::CLIENT_CONNECT_CONFIG_FILE,
+            &temp_file("openvpn_cc"),
+        );
+        set(
+            &mut env,
+            env_keys::CLIENT_CONNECT_DEFERRED_FILE,
+            &temp_file("openvpn_ccr"),
+        );
+    }
+    env
+}
+
+/// The verified certificate chain, `client1` signed by `Example CA`.
+fn set_certificates(env: &mut HashMap<CString, CString>) {
+    let chain = [
+        (
+            COMMON_NAME,
+            "2",
+            "02",
+            "3F:1A:9C:00:4B:77:E2:58:6D:10:2A:C4:91:0E:B3:55:68:DA:F1:07",
+            "5C:0E:71:A8:22:9B:F4:03:6D:E1:58:B7:0A:3C:94:2F:C6:11:8D:7E:45:B9:E0:23:6F:A4:1D:88:52:C7:0B:39",
+        ),
+        (
+            "Example CA",
+            "1",
+            "01",
+            "8B:43:C2:19:7E:05:DA:61:F0:3C:27:B8:9A:14:5E:C6:D2:80:33:4F",
+            "E7:20:5B:9C:04:D1:6A:38:F2:8E:13:C5:77:4A:B0:69:2D:F8:51:0C:A3:96:3E:7B:C4:1F:60:D5:88:27:EA:14",
+        ),
+    ];
+    for (depth, (cn, serial, serial_hex, digest, digest_sha256)) in chain.iter().enumerate() {
+        set(env, &env_keys::tls_id(depth), &format!("CN={}", cn));
+        set(env, &env_keys::tls_serial(depth), serial);
+        set(env, &env_keys::tls_serial_hex(depth), serial_hex);
+        set(env, &env_keys::tls_digest(depth), digest);
+        set(env, &env_keys::tls_digest_sha256(depth), digest_sha256);
+        set(env, &env_keys::x509_field(depth, "CN"), cn);
+    }
+}
+
+/// Peer info sent by an OpenVPN 2.6 client on Linux.
+fn set_peer_info(env: &mut HashMap<CString, CString>) {
+    set(env, env_keys::IV_VER, "2.6.8");
+    set(env, env_keys::IV_PLAT, "linux");
+    set(env, env_keys::IV_PROTO, "990");
+    set(
+        env,
+        env_keys::IV_CIPHERS,
+        "AES-256-GCM:AES-128-GCM:CHACHA20-POLY1305",
+    );
+    set(env, "IV_LZ4", "1");
+    set(env, "IV_LZ4v2", "1");
+    set(env, "IV_LZO", "1");
+    set(env, "IV_COMP_STUB", "1");
+    set(env, "IV_COMP_STUBv2", "1");
+    set(env, "IV_TCPNL", "1");
+    set(env, "IV_SSO", "webauth,openurl,crtext");
+}
+
+/// A path in the temporary directory, named the way OpenVPN names its temporary files.
+fn temp_file(prefix: &str) -> String {
+    env::temp_dir()
+        .join(format!("{}_3a5f0c1e9d2b7a8f4c6e1d0b9a8c7f6e.tmp", prefix))
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn set(env: &mut HashMap<CString, CString>, key: &str, value: &str) {
+    env.insert(cstring(key), cstring(value));
+}
+
+fn args(args: &[&str]) -> Vec<CString> {
+    args.iter().map(|arg| cstring(*arg)).collect()
+}
+
+fn cstring(value: impl Into<Vec<u8>>) -> CString {
+    CString::new(value).expect("Fixture value contains a null byte")
+}
+
+/// Builds an environment out of `(key, value)` pairs, for the unit tests of the crate.
+#[cfg(test)]
+pub(crate) fn env(env: &[(&str, &str)]) -> HashMap<CString, CString> {
+    env.iter()
+        .map(|(key, value)| (cstring(*key), cstring(*value)))
+        .collect()
+}
+
+/// A path in the system temporary directory, unique to `name` and the test process. Nothing is
+/// created.
+#[cfg(test)]
+pub(crate) fn temp_path(name: &str) -> std::path::PathBuf {
+    env::temp_dir().join(format!("openvpn-plugin-{}-{}", name, std::process::id()))
+}
+
+/// A `layer::Event` of the type `event`, with a device and a password in its environment.
+#[cfg(all(test, feature = "ipc"))]
+pub(crate) fn layer_event(event: EventType) -> crate::layer::Event {
+    crate::layer::Event {
+        event,
+        args: args(&["/plugin.so"]),
+        env: env(&[("dev", "tun0"), ("password", "hunter2")]),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::ControlFile,
+        events::{EventArgs, PeerInfo},
+    };
+
+    #[test]
+    fn every_event_parses() {
+        for event in EventType::iter() {
+            let fixture = for_event(event);
+            assert_eq!(event, fixture.event);
+            assert_eq!(PLUGIN_PATH.as_bytes(), fixture.args[0].as_bytes());
+            let args = EventArgs::parse(fixture.event, &fixture.args, &fixture.env)
+                .unwrap_or_else(|e| panic!("Fixture for {:?} does not parse: {}", event, e));
+            assert_eq!(event, args.event_type());
+        }
+    }
+
+    #[test]
+    fn control_files_are_valid() {
+        let fixture = auth_user_pass_verify();
+        assert!(ControlFile::from_env(&fixture.env).is_ok());
+        #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+        assert!(
+            crate::client_connect::DeferredClientConnect::from_env(&client_connect_v2().env)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn peer_info_parses() {
+        let peer_info = PeerInfo::from_env(&client_connect_v2().env).unwrap();
+        assert_eq!(Some("2.6.8"), peer_info.version.as_deref());
+        assert_eq!(3, peer_info.ciphers.len());
+    }
+
+    #[test]
+    fn overrides() {
+        let fixture = up()
+            .with_env(env_keys::DEV, "tun1")
+            .without_env(env_keys::IFCONFIG_LOCAL)
+            .with_arg(1, "tun1");
+        assert_eq!(
+            Some(&cstring("tun1")),
+            fixture.env.get(&cstring(env_keys::DEV))
+        );
+        assert_eq!(None, fixture.env.get(&cstring(env_keys::IFCONFIG_LOCAL)));
+        assert_eq!(cstring("tun1"), fixture.args[1]);
+    }
+}
@@ -0,0 +1,455 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Drives the FFI functions generated by the [`openvpn_plugin!`] macro the same way OpenVPN
+//! does, so a plugin can be tested without a real OpenVPN. Requires the `testing` feature.
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, ffi::CString, io};
+//! # use openvpn_plugin::{openvpn_plugin, EventResult, EventType};
+//! # fn open(_: Vec<CString>, _: HashMap<CString, CString>) -> Result<(Vec<EventType>, ()), io::Error> {
+//! #     Ok((vec![EventType::Up], ()))
+//! # }
+//! # fn close(_: ()) {}
+//! # fn event(_: EventType, _: Vec<CString>, _: HashMap<CString, CString>, _: &mut ()) -> Result<EventResult, io::Error> {
+//! #     Ok(EventResult::Success)
+//! # }
+//! openvpn_plugin!(crate::open, crate::close, crate::event, ());
+//!
+//! # fn main() {
+//! use openvpn_plugin::testing::{Plugin, PluginExports};
+//!
+//! let exports = PluginExports {
+//!     open: openvpn_plugin_open_v3,
+//!     func: openvpn_plugin_func_v3,
+//!     close: openvpn_plugin_close_v1,
+//! };
+//! let mut plugin = Plugin::open(exports, vec!["/plugin.so"], vec![("verb", "3")]).unwrap();
+//! let result = plugin.event(EventType::Up, vec!["/plugin.so", "tun0"], vec![("dev", "tun0")]);
+//! assert_eq!(EventResult::Success, result);
+//! plugin.close();
+//! # }
+//! ```
+//!
+//! [`openvpn_plugin!`]: ../macro.openvpn_plugin.html
+
+use std::{
+    error::Error,
+    ffi::CString,
+    fmt,
+    os::raw::{c_char, c_int, c_void},
+    ptr,
+};
+
+use crate::{ffi, EventResult, EventType, EventTypeSet};
+
+pub mod fixtures;
+
+/// The version of the v3 plugin structs OpenVPN 2.6 passes as the first argument to the
+/// functions. Named `OPENVPN_PLUGINv3_STRUCTVER` in `openvpn-plugin.h`.
+const STRUCT_VERSION: c_int = 5;
+
+const OPENVPN_VERSION: &[u8] = b"2.6.0\0";
+const OPENVPN_VERSION_MAJOR: u32 = 2;
+const OPENVPN_VERSION_MINOR: u32 = 6;
+const OPENVPN_VERSION_PATCH: &[u8] = b".0\0";
+
+/// Signature of the generated `openvpn_plugin_open_v3` function.
+pub type OpenFn = unsafe extern "C" fn(
+    c_int,
+    *const ffi::openvpn_plugin_args_open_in,
+    *mut ffi::openvpn_plugin_args_open_return,
+) -> c_int;
+
+/// Signature of the generated `openvpn_plugin_func_v3` function.
+pub type FuncFn = unsafe extern "C" fn(
+    c_int,
+    *const ffi::openvpn_plugin_args_func_in,
+    *const ffi::openvpn_plugin_args_func_return,
+) -> c_int;
+
+/// Signature of the generated `openvpn_plugin_close_v1` function.
+pub type CloseFn = unsafe extern "C" fn(*const c_void);
+
+/// The three functions exported by a plugin.
+#[derive(Debug, Copy, Clone)]
+pub struct PluginExports {
+    pub open: OpenFn,
+    pub func: FuncFn,
+    pub close: CloseFn,
+}
+
+
+/// Error returned by `Plugin::open` when the plugin's open function fails.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct OpenFailed(pub c_int);
+
+impl fmt::Display for OpenFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Plugin open function returned {}", self.0)
+    }
+}
+
+impl Error for OpenFailed {}
+
+
+/// A loaded plugin. Calls the plugin's close function when dropped, unless `close` has already
+/// been called.
+#[derive(Debug)]
+pub struct Plugin {
+    exports: PluginExports,
+    handle: *const c_void,
+    events: EventTypeSet,
+    closed: bool,
+}
+
+impl Plugin {
+    /// Loads the plugin by calling its open function with the given arguments and environment.
+    /// The first argument should be the path to the plugin, as with a real OpenVPN.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any string contains a null byte.
+    pub fn open<A, K, V>(
+        exports: PluginExports,
+        args: impl IntoIterator<Item = A>,
+        env: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<Self, OpenFailed>
+    where
+        A: Into<Vec<u8>>,
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        let args = StringArray::new(args);
+        let env = StringArray::env(env);
+        let open_in = ffi::openvpn_plugin_args_open_in::new(
+            args.as_ptr(),
+            env.as_ptr(),
+            OPENVPN_VERSION.as_ptr() as *const c_char,
+            OPENVPN_VERSION_MAJOR,
+            OPENVPN_VERSION_MINOR,
+            OPENVPN_VERSION_PATCH.as_ptr() as *const c_char,
+        );
+        let mut open_return = ffi::openvpn_plugin_args_open_return::default();
+
+        let result = unsafe { (exports.open)(STRUCT_VERSION, &open_in, &mut open_return) };
+        if result != ffi::OPENVPN_PLUGIN_FUNC_SUCCESS {
+            return Err(OpenFailed(result));
+        }
+        Ok(Plugin {
+            exports,
+            handle: open_return.handle,
+            events: EventTypeSet::from_bits_truncate(open_return.type_mask),
+            closed: false,
+        })
+    }
+
+    /// Returns the events the plugin registered for.
+    pub fn events(&self) -> EventTypeSet {
+        self.events
+    }
+
+    /// Calls the plugin's event function with the given event, arguments and environment and
+    /// returns the result the plugin gave back to OpenVPN. `OPENVPN_PLUGIN_FUNC_ERROR` is
+    /// returned as `EventResult::Failure`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the plugin did not register for `event`, since OpenVPN would never call it, or if
+    /// any string contains a null byte.
+    pub fn event<A, K, V>(
+        &mut self,
+        event: EventType,
+        args: impl IntoIterator<Item = A>,
+        env: impl IntoIterator<Item = (K, V)>,
+    ) -> EventResult
+    where
+        A: Into<Vec<u8>>,
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        assert!(
+            self.events.contains(event),
+            "Plugin did not register for {}",
+            event
+        );
+        let args = StringArray::new(args);
+        let env = StringArray::env(env);
+        let func_in = ffi::openvpn_plugin_args_func_in::new(
+            event as c_int,
+            args.as_ptr(),
+            env.as_ptr(),
+            self.handle,
+        );
+        let func_return = ffi::openvpn_plugin_args_func_return::default();
+
+        match unsafe { (self.exports.func)(STRUCT_VERSION, &func_in, &func_return) } {
+            ffi::OPENVPN_PLUGIN_FUNC_SUCCESS => EventResult::Success,
+            ffi::OPENVPN_PLUGIN_FUNC_DEFERRED => EventResult::Deferred,
+            _ => EventResult::Failure,
+        }
+    }
+
+    /// Unloads the plugin by calling its close function.
+    pub fn close(mut self) {
+        self.close_inner();
+    }
+
+    fn close_inner(&mut self) {
+        if !self.closed {
+            self.closed = true;
+            unsafe { (self.exports.close)(self.handle) };
+        }
+    }
+}
+
+impl Drop for Plugin {
+    fn drop(&mut self) {
+        self.close_inner();
+    }
+}
+
+
+/// Owns a null-terminated array of C strings and the strings it points to.
+struct StringArray {
+    _strings: Vec<CString>,
+    ptrs: Vec<*const c_char>,
+}
+
+impl StringArray {
+    fn new<S: Into<Vec<u8>>>(strings: impl IntoIterator<Item = S>) -> Self {
+        let strings: Vec<CString> = strings
+            .into_iter()
+            .map(|s| CString::new(s).expect("Null byte in string"))
+            .collect();
+        let mut ptrs: Vec<*const c_char> = strings.iter().map(|s| s.as_ptr()).collect();
+        ptrs.push(ptr::null());
+        StringArray {
+            _strings: strings,
+            ptrs,
+        }
+    }
+
+    fn env<K, V>(env: impl IntoIterator<Item = (K, V)>) -> Self
+    where
+        K: Into<Vec<u8>>,
+        V: Into<Vec<u8>>,
+    {
+        Self::new(env.into_iter().map(|(key, value)| {
+            let mut entry = key.into();
+            entry.push(b'=');
+            entry.extend(value.into());
+            entry
+        }))
+    }
+
+    fn as_ptr(&self) -> *const *const c_char {
+        self.ptrs.as_ptr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, io};
+
+    struct Handle;
+
+    fn open(
+        args: Vec<CString>,
+        _env: HashMap<CString, CString>,
+    ) -> Result<(EventTypeSet, Handle), io::Error> {
+        if args.len() > 1 {
+            return Err(io::Error::other("Unexpected argument"));
+        }
+        Ok((EventType::Up | EventType::AuthUserPassVerify, Handle))
+    }
+
+    fn close(_handle: Handle) {}
+
+    fn event(
+        event: EventType,
+        args: Vec<CString>,
+        env: HashMap<CString, CString>,
+        _handle: &mut Handle,
+    ) -> Result<EventResult, io::Error> {
+        let dev = CString::new("tun0").unwrap();
+        match event {
+            EventType::AuthUserPassVerify => Ok(EventResult::Deferred),
+            EventType::Up if args.get(1) == Some(&dev) && env.values().any(|v| *v == dev) => {
+                Ok(EventResult::Success)
+            }
+            _ => Ok(EventResult::Failure),
+        }
+    }
+
+    crate::openvpn_plugin!(
+        crate::testing::tests::open,
+        crate::testing::tests::close,
+        crate::testing::tests::event,
+        Handle,
+        exports = [abort, legacy, min_version(3), client_context(u32)]
+    );
+
+    const EXPORTS: PluginExports = PluginExports {
+        open: openvpn_plugin_open_v3,
+        func: openvpn_plugin_func_v3,
+        close: openvpn_plugin_close_v1,
+    };
+
+    #[test]
+    fn open_failure() {
+        let result = Plugin::open(
+            EXPORTS,
+            vec!["/plugin.so", "foo"],
+            Vec::<(&str, &str)>::new(),
+        );
+        assert_eq!(
+            Some(OpenFailed(ffi::OPENVPN_PLUGIN_FUNC_ERROR)),
+            result.err()
+        );
+    }
+
+    #[test]
+    fn events() {
+        let mut plugin = Plugin::open(EXPORTS, vec!["/plugin.so"], vec![("verb", "3")]).unwrap();
+        assert_eq!(
+            EventType::Up | EventType::AuthUserPassVerify,
+            plugin.events()
+        );
+        assert_eq!(
+            EventResult::Success,
+            plugin.event(
+                EventType::Up,
+                vec!["/plugin.so", "tun0"],
+                vec![("dev", "tun0")]
+            )
+        );
+        assert_eq!(
+            EventResult::Failure,
+            plugin.event(EventType::Up, vec!["/plugin.so"], vec![("dev", "tun0")])
+        );
+        assert_eq!(
+            EventResult::Deferred,
+            plugin.event(
+                EventType::AuthUserPassVerify,
+                vec!["/plugin.so"],
+                vec![("username", "foo"), ("password", "bar")]
+            )
+        );
+        plugin.close();
+    }
+
+    #[test]
+    fn selected_exports() {
+        assert_eq!(3, openvpn_plugin_min_version_required_v1());
+
+        let plugin = CString::new("/plugin.so").unwrap();
+        let dev = CString::new("tun0").unwrap();
+        let dev_env = CString::new("dev=tun0").unwrap();
+        let argv = [plugin.as_ptr(), dev.as_ptr(), ptr::null()];
+        let envp = [dev_env.as_ptr(), ptr::null()];
+        let mut type_mask = 0;
+        unsafe {
+            let handle = openvpn_plugin_open_v2(
+                &mut type_mask,
+                argv[2..].as_ptr(),
+                envp.as_ptr(),
+                ptr::null_mut(),
+            );
+            assert!(!handle.is_null());
+            assert_eq!(
+                (EventType::Up | EventType::AuthUserPassVerify).bits(),
+                type_mask as c_int
+            );
+
+            let context = openvpn_plugin_client_constructor_v1(handle);
+            assert!(!context.is_null());
+            assert_eq!(
+                ffi::OPENVPN_PLUGIN_FUNC_SUCCESS,
+                openvpn_plugin_func_v2(
+                    handle,
+                    EventType::Up as c_int,
+                    argv.as_ptr(),
+                    envp.as_ptr(),
+                    context,
+                    ptr::null_mut(),
+                )
+            );
+            openvpn_plugin_client_destructor_v1(handle, context);
+            openvpn_plugin_close_v1(handle);
+
+            // The open callback fails with an argument.
+            assert!(openvpn_plugin_open_v2(
+                &mut type_mask,
+                argv.as_ptr(),
+                envp.as_ptr(),
+                ptr::null_mut()
+            )
+            .is_null());
+        }
+    }
+
+    #[test]
+    fn invalid_calls() {
+        let mut open_return = ffi::openvpn_plugin_args_open_return::default();
+        let argv = [ptr::null()];
+        let open_in = ffi::openvpn_plugin_args_open_in::new(
+            argv.as_ptr(),
+            argv.as_ptr(),
+            ptr::null(),
+            0,
+            0,
+            ptr::null(),
+        );
+        unsafe {
+            assert_eq!(
+                ffi::OPENVPN_PLUGIN_FUNC_ERROR,
+                openvpn_plugin_open_v3(2, &open_in, &mut open_return)
+            );
+            assert_eq!(
+                ffi::OPENVPN_PLUGIN_FUNC_ERROR,
+                openvpn_plugin_open_v3(STRUCT_VERSION, ptr::null(), &mut open_return)
+            );
+            assert_eq!(
+                ffi::OPENVPN_PLUGIN_FUNC_ERROR,
+                openvpn_plugin_open_v3(STRUCT_VERSION, &open_in, ptr::null_mut())
+            );
+            assert!(open_return.handle.is_null());
+
+            let func_in = ffi::openvpn_plugin_args_func_in::new(
+                EventType::Up as c_int,
+                argv.as_ptr(),
+                argv.as_ptr(),
+                ptr::null(),
+            );
+            let func_return = ffi::openvpn_plugin_args_func_return::default();
+            assert_eq!(
+                ffi::OPENVPN_PLUGIN_FUNC_ERROR,
+                openvpn_plugin_func_v3(STRUCT_VERSION, &func_in, &func_return)
+            );
+            assert_eq!(
+                ffi::OPENVPN_PLUGIN_FUNC_ERROR,
+                openvpn_plugin_func_v3(STRUCT_VERSION, ptr::null(), &func_return)
+            );
+            openvpn_plugin_close_v1(ptr::null());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Plugin did not register for PLUGIN_DOWN")]
+    fn unregistered_event() {
+        let mut plugin =
+            Plugin::open(EXPORTS, vec!["/plugin.so"], Vec::<(&str, &str)>::new()).unwrap();
+        plugin.event(
+            EventType::Down,
+            Vec::<&str>::new(),
+            Vec::<(&str, &str)>::new(),
+        );
+    }
+}
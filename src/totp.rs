@@ -0,0 +1,406 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Time-based one-time passwords (RFC 6238), for a second factor. Requires the `totp` feature.
+//!
+//! With `--static-challenge` in the client config, OpenVPN clients ask for a code next to the
+//! password and send both as a `cr::Password::StaticChallenge`. The code is checked with the
+//! [`Totp`] of the user, and a [`ReplayGuard`] makes sure every code is only used once:
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, error::Error, ffi::CString};
+//! # use openvpn_plugin::{auth::Credentials, cr::Password, totp::{ReplayGuard, Totp}, EventResult};
+//! # fn check_password(username: &str, password: &str) -> bool { unimplemented!() }
+//! # fn totp_secret(username: &str) -> String { unimplemented!() }
+//! fn verify(
+//!     env: &HashMap<CString, CString>,
+//!     used_codes: &ReplayGuard,
+//! ) -> Result<EventResult, Box<dyn Error>> {
+//!     let credentials = Credentials::from_env(env)?;
+//!     let username = credentials.username();
+//!     let (password, code) = match Password::parse(credentials.password())? {
+//!         Password::StaticChallenge { password, response } => (password, response),
+//!         _ => return Ok(EventResult::Failure),
+//!     };
+//!     let totp = Totp::from_base32(&totp_secret(username))?;
+//!     let valid = check_password(username, &password)
+//!         && totp
+//!             .verify(&code)
+//!             .map_or(false, |step| used_codes.accept(username, step));
+//!     Ok(if valid { EventResult::Success } else { EventResult::Failure })
+//! }
+//! ```
+//!
+//! Codes from one time step before or after the current one are accepted by default, to allow for
+//! clocks that drift and codes typed in at the end of a step. See [`Totp::skew`].
+//!
+//! [`Totp`]: struct.Totp.html
+//! [`ReplayGuard`]: struct.ReplayGuard.html
+//! [`Totp::skew`]: struct.Totp.html#method.skew
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use hmac::{
+    digest::{core_api::BlockSizeUser, Digest},
+    Mac, SimpleHmac,
+};
+
+/// The default number of digits in a code.
+pub const DEFAULT_DIGITS: u32 = 6;
+
+/// The default length of a time step.
+pub const DEFAULT_STEP: Duration = Duration::from_secs(30);
+
+/// The default number of steps before and after the current one to accept codes from.
+pub const DEFAULT_SKEW: u32 = 1;
+
+/// Error returned when a secret can't be decoded.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum TotpError {
+    /// The secret is not valid base32.
+    InvalidBase32,
+    /// The secret is empty.
+    EmptySecret,
+}
+
+impl fmt::Display for TotpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TotpError::InvalidBase32 => "Invalid base32 in TOTP secret".fmt(f),
+            TotpError::EmptySecret => "Empty TOTP secret".fmt(f),
+        }
+    }
+}
+
+impl Error for TotpError {}
+
+
+/// The HMAC hash function codes are computed with.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Default)]
+pub enum Algorithm {
+    /// HMAC-SHA-1. Used by almost all authenticator apps.
+    #[default]
+    Sha1,
+    /// HMAC-SHA-256.
+    Sha256,
+    /// HMAC-SHA-512.
+    Sha512,
+}
+
+/// The secret and parameters of the codes of one user.
+#[derive(Clone, Eq, PartialEq)]
+pub struct Totp {
+    secret: Vec<u8>,
+    digits: u32,
+    step: Duration,
+    skew: u32,
+    algorithm: Algorithm,
+}
+
+impl Totp {
+    /// Creates a generator for `secret` with 6 digit HMAC-SHA-1 codes and 30 second steps, the
+    /// parameters authenticator apps use by default.
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Totp {
+            secret: secret.into(),
+            digits: DEFAULT_DIGITS,
+            step: DEFAULT_STEP,
+            skew: DEFAULT_SKEW,
+            algorithm: Algorithm::default(),
+        }
+    }
+
+    /// Creates a generator for a base32 encoded secret, the format authenticator apps are given
+    /// the secret in. Case, spaces and padding are ignored.
+    pub fn from_base32(secret: &str) -> Result<Self, TotpError> {
+        let secret = decode_base32(secret).ok_or(TotpError::InvalidBase32)?;
+        if secret.is_empty() {
+            return Err(TotpError::EmptySecret);
+        }
+        Ok(Self::new(secret))
+    }
+
+    /// Sets the number of digits in a code.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `digits` is not between 1 and 9.
+    pub fn digits(mut self, digits: u32) -> Self {
+        assert!((1..=9).contains(&digits), "TOTP codes have 1 to 9 digits");
+        self.digits = digits;
+        self
+    }
+
+    /// Sets the length of a time step.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `step` is less than a second.
+    pub fn step(mut self, step: Duration) -> Self {
+        assert!(step.as_secs() > 0, "TOTP steps are at least a second");
+        self.step = step;
+        self
+    }
+
+    /// Sets how many steps before and after the current one codes are accepted from.
+    pub fn skew(mut self, skew: u32) -> Self {
+        self.skew = skew;
+        self
+    }
+
+    /// Sets the hash function.
+    pub fn algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// The time step `time` falls in.
+    pub fn step_at(&self, time: SystemTime) -> u64 {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        since_epoch.as_secs() / self.step.as_secs()
+    }
+
+    /// The code for the time step `step`.
+    pub fn generate(&self, step: u64) -> String {
+        let mac = match self.algorithm {
+            Algorithm::Sha1 => hmac::<sha1::Sha1>(&self.secret, step),
+            Algorithm::Sha256 => hmac::<sha2::Sha256>(&self.secret, step),
+            Algorithm::Sha512 => hmac::<sha2::Sha512>(&self.secret, step),
+        };
+        // Dynamic truncation, RFC 4226 section 5.3.
+        let offset = usize::from(mac[mac.len() - 1] & 0x0f);
+        let value = u32::from_be_bytes([
+            mac[offset] & 0x7f,
+            mac[offset + 1],
+            mac[offset + 2],
+            mac[offset + 3],
+        ]);
+        format!(
+            "{:0width$}",
+            value % 10u32.pow(self.digits),
+            width = self.digits as usize
+        )
+    }
+
+    /// Checks `code` against the current time. Returns the time step of the code if it is valid.
+    pub fn verify(&self, code: &str) -> Option<u64> {
+        self.verify_at(code, SystemTime::now())
+    }
+
+    /// Checks `code` against the time `time`. Returns the time step of the code if it is valid.
+    pub fn verify_at(&self, code: &str, time: SystemTime) -> Option<u64> {
+        let code = code.trim();
+        if code.len() != self.digits as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let current = self.step_at(time);
+        let first = current.saturating_sub(u64::from(self.skew));
+        let last = current.saturating_add(u64::from(self.skew));
+        // Every step in the window is checked, so the time taken does not depend on which matched.
+        let mut matched = None;
+        for step in first..=last {
+            if constant_time_eq(self.generate(step).as_bytes(), code.as_bytes()) {
+                matched = Some(step);
+            }
+        }
+        matched
+    }
+}
+
+/// Never prints the secret.
+impl fmt::Debug for Totp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Totp")
+            .field("secret", &"REDACTED")
+            .field("digits", &self.digits)
+            .field("step", &self.step)
+            .field("skew", &self.skew)
+            .field("algorithm", &self.algorithm)
+            .finish()
+    }
+}
+
+/// Remembers the last time step each user authenticated with, so an intercepted code can't be
+/// used again.
+#[derive(Debug, Default)]
+pub struct ReplayGuard {
+    last_steps: Mutex<HashMap<String, u64>>,
+}
+
+impl ReplayGuard {
+    /// Creates a guard that has seen no codes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that `user` authenticated with a code from time step `step`. Returns false,
+    /// rejecting the code, if the user already authenticated with a code from this step or a
+    /// later one.
+    pub fn accept(&self, user: &str, step: u64) -> bool {
+        let mut last_steps = self
+            .last_steps
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match last_steps.get_mut(user) {
+            Some(last) if *last >= step => false,
+            Some(last) => {
+                *last = step;
+                true
+            }
+            None => {
+                last_steps.insert(user.to_owned(), step);
+                true
+            }
+        }
+    }
+
+    /// Forgets the steps recorded before `step`, which can no longer be replayed once `step` is
+    /// outside the skew window. Call it now and then to bound the memory used.
+    pub fn forget_before(&self, step: u64) {
+        self.last_steps
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, last| *last >= step);
+    }
+
+    /// The number of users with a recorded step.
+    pub fn len(&self) -> usize {
+        self.last_steps
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .len()
+    }
+
+    /// Returns true if no steps are recorded.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+fn hmac<D: Digest + BlockSizeUser>(key: &[u8], counter: u64) -> Vec<u8> {
+    let mut mac = SimpleHmac::<D>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(&counter.to_be_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Decodes RFC 4648 base32, ignoring case, spaces and padding.
+fn decode_base32(input: &str) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in input.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            b' ' | b'=' => continue,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | u32::from(value);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+        }
+    }
+    Some(output)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn rfc6238_vectors() {
+        let sha1 = Totp::new(&b"12345678901234567890"[..]).digits(8);
+        let sha256 = Totp::new(&b"12345678901234567890123456789012"[..])
+            .digits(8)
+            .algorithm(Algorithm::Sha256);
+        let sha512 =
+            Totp::new(&b"1234567890123456789012345678901234567890123456789012345678901234"[..])
+                .digits(8)
+                .algorithm(Algorithm::Sha512);
+        let vectors = [
+            (59, "94287082", "46119246", "90693936"),
+            (1111111109, "07081804", "68084774", "25091201"),
+            (1234567890, "89005924", "91819424", "93441116"),
+            (20000000000, "65353130", "77737706", "47863826"),
+        ];
+        for (time, code_sha1, code_sha256, code_sha512) in vectors.iter() {
+            assert_eq!(*code_sha1, sha1.generate(sha1.step_at(at(*time))));
+            assert_eq!(*code_sha256, sha256.generate(sha256.step_at(at(*time))));
+            assert_eq!(*code_sha512, sha512.generate(sha512.step_at(at(*time))));
+        }
+    }
+
+    #[test]
+    fn verifies_within_window() {
+        let totp = Totp::from_base32("gezd gnbv gy3t qojq gezd gnbv gy3t qojq").unwrap();
+        assert_eq!(Totp::new(&b"12345678901234567890"[..]), totp);
+
+        let now = at(1111111109);
+        let step = totp.step_at(now);
+        assert_eq!(Some(step), totp.verify_at("081804", now));
+        assert_eq!(
+            Some(step - 1),
+            totp.verify_at(&totp.generate(step - 1), now)
+        );
+        assert_eq!(
+            Some(step + 1),
+            totp.verify_at(&totp.generate(step + 1), now)
+        );
+        assert_eq!(None, totp.verify_at(&totp.generate(step + 2), now));
+        assert_eq!(
+            None,
+            totp.clone()
+                .skew(0)
+                .verify_at(&totp.generate(step - 1), now)
+        );
+        assert_eq!(None, totp.verify_at("81804", now));
+        assert_eq!(None, totp.verify_at("08180x", now));
+    }
+
+    #[test]
+    fn rejects_replays() {
+        let guard = ReplayGuard::new();
+        assert!(guard.accept("alice", 10));
+        assert!(!guard.accept("alice", 10));
+        assert!(!guard.accept("alice", 9));
+        assert!(guard.accept("bob", 10));
+        assert!(guard.accept("alice", 11));
+
+        guard.forget_before(11);
+        assert_eq!(1, guard.len());
+        assert!(guard.accept("bob", 10));
+    }
+
+    #[test]
+    fn base32() {
+        assert_eq!(
+            Some(b"Hello!\xde\xad\xbe\xef".to_vec()),
+            decode_base32("JBSWY3DPEHPK3PXP")
+        );
+        assert_eq!(Some(b"f".to_vec()), decode_base32("MY======"));
+        assert_eq!(None, decode_base32("MY1"));
+        assert_eq!(Err(TotpError::EmptySecret), Totp::from_base32("===="));
+    }
+}
@@ -0,0 +1,321 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Tracking of the tunnel of an OpenVPN client.
+//!
+//! A [`TunnelLifecycle`] follows the `Up`, `RouteUp`, `RoutePredown` and `Down` events and records
+//! when the tunnel came up, the addresses it was assigned and every change to its routes. When the
+//! tunnel goes down, a [`TunnelSummary`] of its whole lifetime is returned:
+//!
+//! ```rust
+//! use std::{collections::HashMap, ffi::CString};
+//! use openvpn_plugin::{events::EventArgsError, tunnel::TunnelLifecycle, EventResult, EventType};
+//!
+//! struct Handle {
+//!     tunnel: TunnelLifecycle,
+//! }
+//!
+//! fn event(
+//!     event: EventType,
+//!     _args: Vec<CString>,
+//!     env: HashMap<CString, CString>,
+//!     handle: &mut Handle,
+//! ) -> Result<EventResult, EventArgsError> {
+//!     if let Some(summary) = handle.tunnel.handle_event(event, &env)? {
+//!         println!("{}", summary);
+//!     }
+//!     Ok(EventResult::Success)
+//! }
+//! ```
+//!
+//! [`TunnelLifecycle`]: struct.TunnelLifecycle.html
+//! [`TunnelSummary`]: struct.TunnelSummary.html
+
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fmt,
+    net::IpAddr,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    env_keys,
+    events::{Env, EventArgsError},
+    EventType,
+};
+
+pub use crate::events::Route;
+
+/// Whether a change added or removed routes.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum RouteChangeKind {
+    /// The routes were added, from a `RouteUp` event.
+    Added,
+    /// The routes are about to be removed, from a `RoutePredown` event.
+    Removed,
+}
+
+/// A change to the routes of the tunnel.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct RouteChange {
+    /// Whether the routes were added or removed.
+    pub kind: RouteChangeKind,
+    /// When the event was seen.
+    pub at: SystemTime,
+    /// The routes that were added or removed.
+    pub routes: Vec<Route>,
+}
+
+/// A tunnel that is up.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Tunnel {
+    /// The name of the tunnel device, from `dev`.
+    pub device: String,
+    /// The IPv4 address of the tunnel, from `ifconfig_local`.
+    pub local_ip: Option<IpAddr>,
+    /// The IPv4 address of the remote end of the tunnel in point-to-point topologies, from
+    /// `ifconfig_remote`.
+    pub remote_ip: Option<IpAddr>,
+    /// The IPv6 address of the tunnel, from `ifconfig_ipv6_local`.
+    pub local_ipv6: Option<IpAddr>,
+    /// The MTU of the tunnel device, from `tun_mtu`.
+    pub mtu: Option<u32>,
+    /// When the `Up` event was seen.
+    pub connected: SystemTime,
+    /// The routes that are currently set.
+    pub routes: Vec<Route>,
+    /// Every change to the routes since the tunnel came up, oldest first.
+    pub route_changes: Vec<RouteChange>,
+}
+
+impl Tunnel {
+    fn from_env(env: &HashMap<CString, CString>, now: SystemTime) -> Result<Self, EventArgsError> {
+        let env = Env(env);
+        Ok(Tunnel {
+            device: env.string(env_keys::DEV)?,
+            local_ip: env.parse_opt(env_keys::IFCONFIG_LOCAL)?,
+            remote_ip: env.parse_opt(env_keys::IFCONFIG_REMOTE)?,
+            local_ipv6: env.parse_opt(env_keys::IFCONFIG_IPV6_LOCAL)?,
+            mtu: env.parse_opt(env_keys::TUN_MTU)?,
+            connected: now,
+            routes: Vec::new(),
+            route_changes: Vec::new(),
+        })
+    }
+}
+
+/// The whole lifetime of a tunnel, returned when it goes down.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TunnelSummary {
+    /// The tunnel as it was just before it went down.
+    pub tunnel: Tunnel,
+    /// When the `Down` event was seen.
+    pub disconnected: SystemTime,
+    /// Bytes received through the tunnel, from `bytes_received`, if OpenVPN set it.
+    pub bytes_received: Option<u64>,
+    /// Bytes sent through the tunnel, from `bytes_sent`, if OpenVPN set it.
+    pub bytes_sent: Option<u64>,
+}
+
+impl TunnelSummary {
+    /// How long the tunnel was up.
+    pub fn duration(&self) -> Duration {
+        self.disconnected
+            .duration_since(self.tunnel.connected)
+            .unwrap_or_default()
+    }
+}
+
+/// Formats the summary as one line, e.g.
+/// `tun0 was up for 3600s with 10.8.0.6, 2 route changes`.
+impl fmt::Display for TunnelSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} was up for {}s",
+            self.tunnel.device,
+            self.duration().as_secs()
+        )?;
+        let addresses: Vec<String> = self
+            .tunnel
+            .local_ip
+            .iter()
+            .chain(&self.tunnel.local_ipv6)
+            .map(IpAddr::to_string)
+            .collect();
+        if !addresses.is_empty() {
+            write!(f, " with {}", addresses.join(" and "))?;
+        }
+        write!(f, ", {} route changes", self.tunnel.route_changes.len())?;
+        if let (Some(received), Some(sent)) = (self.bytes_received, self.bytes_sent) {
+            write!(f, ", {} bytes received, {} bytes sent", received, sent)?;
+        }
+        Ok(())
+    }
+}
+
+/// Correlates the tunnel events of a client. See the [module documentation] for an example.
+///
+/// [module documentation]: index.html
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct TunnelLifecycle {
+    current: Option<Tunnel>,
+    last: Option<TunnelSummary>,
+}
+
+impl TunnelLifecycle {
+    /// Creates a lifecycle without any tunnel.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Updates the tunnel from an event. Returns the summary of the tunnel if the event was
+    /// `Down`. Events that are not about the tunnel, and route events while no tunnel is up, are
+    /// ignored.
+    pub fn handle_event(
+        &mut self,
+        event: EventType,
+        env: &HashMap<CString, CString>,
+    ) -> Result<Option<TunnelSummary>, EventArgsError> {
+        self.handle_event_at(event, env, SystemTime::now())
+    }
+
+    fn handle_event_at(
+        &mut self,
+        event: EventType,
+        env: &HashMap<CString, CString>,
+        now: SystemTime,
+    ) -> Result<Option<TunnelSummary>, EventArgsError> {
+        match event {
+            EventType::Up => self.current = Some(Tunnel::from_env(env, now)?),
+            EventType::RouteUp | EventType::RoutePredown => {
+                if let Some(tunnel) = &mut self.current {
+                    let routes = Route::all_from_env(env)?;
+                    let kind = if event == EventType::RouteUp {
+                        tunnel.routes = routes.clone();
+                        RouteChangeKind::Added
+                    } else {
+                        tunnel.routes.clear();
+                        RouteChangeKind::Removed
+                    };
+                    tunnel.route_changes.push(RouteChange {
+                        kind,
+                        at: now,
+                        routes,
+                    });
+                }
+            }
+            EventType::Down => {
+                if let Some(tunnel) = self.current.take() {
+                    let env = Env(env);
+                    let summary = TunnelSummary {
+                        tunnel,
+                        disconnected: now,
+                        bytes_received: env.parse_opt(env_keys::BYTES_RECEIVED)?,
+                        bytes_sent: env.parse_opt(env_keys::BYTES_SENT)?,
+                    };
+                    self.last = Some(summary.clone());
+                    return Ok(Some(summary));
+                }
+            }
+            _ => (),
+        }
+        Ok(None)
+    }
+
+    /// Returns the tunnel, if it is up.
+    pub fn current(&self) -> Option<&Tunnel> {
+        self.current.as_ref()
+    }
+
+    /// Returns the summary of the last tunnel that went down, if any.
+    pub fn last_summary(&self) -> Option<&TunnelSummary> {
+        self.last.as_ref()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::fixtures::env;
+
+    #[test]
+    fn lifecycle() {
+        let mut lifecycle = TunnelLifecycle::new();
+        let start = SystemTime::UNIX_EPOCH;
+        let routes = env(&[
+            ("route_network_1", "0.0.0.0"),
+            ("route_netmask_1", "0.0.0.0"),
+        ]);
+
+        assert_eq!(
+            None,
+            lifecycle
+                .handle_event_at(EventType::RouteUp, &routes, start)
+                .unwrap()
+        );
+        assert!(lifecycle.current().is_none());
+
+        let up = env(&[
+            ("dev", "tun0"),
+            ("ifconfig_local", "10.8.0.6"),
+            ("ifconfig_ipv6_local", "fd00::6"),
+            ("tun_mtu", "1500"),
+        ]);
+        lifecycle
+            .handle_event_at(EventType::Up, &up, start)
+            .unwrap();
+        lifecycle
+            .handle_event_at(EventType::RouteUp, &routes, start)
+            .unwrap();
+        let tunnel = lifecycle.current().unwrap();
+        assert_eq!("tun0", tunnel.device);
+        assert_eq!(Some(1500), tunnel.mtu);
+        assert_eq!(1, tunnel.routes.len());
+
+        lifecycle
+            .handle_event_at(
+                EventType::RoutePredown,
+                &routes,
+                start + Duration::from_secs(59),
+            )
+            .unwrap();
+        assert!(lifecycle.current().unwrap().routes.is_empty());
+
+        let down = env(&[("bytes_received", "100"), ("bytes_sent", "200")]);
+        let summary = lifecycle
+            .handle_event_at(EventType::Down, &down, start + Duration::from_secs(60))
+            .unwrap()
+            .unwrap();
+        assert_eq!(Duration::from_secs(60), summary.duration());
+        assert_eq!(
+            vec![RouteChangeKind::Added, RouteChangeKind::Removed],
+            summary
+                .tunnel
+                .route_changes
+                .iter()
+                .map(|change| change.kind)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "tun0 was up for 60s with 10.8.0.6 and fd00::6, 2 route changes, 100 bytes received, \
+             200 bytes sent",
+            summary.to_string()
+        );
+        assert!(lifecycle.current().is_none());
+        assert_eq!(Some(&summary), lifecycle.last_summary());
+        assert_eq!(
+            None,
+            lifecycle
+                .handle_event_at(EventType::Down, &down, start)
+                .unwrap()
+        );
+    }
+}
@@ -9,13 +9,24 @@
 //! Constants for OpenVPN. Taken from include/openvpn-plugin.h in the OpenVPN repository:
 //! https://github.com/OpenVPN/openvpn/blob/master/include/openvpn-plugin.h.in
 
-use std::os::raw::c_int;
+use std::{
+    convert::TryFrom,
+    fmt,
+    iter::FromIterator,
+    ops::{BitOr, BitOrAssign},
+    os::raw::c_int,
+    str::FromStr,
+};
 
 use derive_try_from_primitive::TryFromPrimitive;
 
 
 /// All the events that an OpenVPN plugin can register for and get notified about.
 /// This is a Rust representation of the constants named `OPENVPN_PLUGIN_*` in `openvpn-plugin.h`.
+///
+/// Events that only exist in some OpenVPN versions are gated behind the `openvpn-2-4`,
+/// `openvpn-2-5` and `openvpn-2-6` crate features. Each feature adds the events that the
+/// corresponding OpenVPN version has, and all of them are enabled by default.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, TryFromPrimitive)]
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[non_exhaustive]
@@ -32,15 +43,347 @@ pub enum EventType {
     LearnAddress = 8,
     ClientConnectV2 = 9,
     TlsFinal = 10,
+    #[cfg(any(feature = "openvpn-2-4", feature = "openvpn-2-5"))]
     EnablePf = 11, // NOTE: feature has been removed as of OpenVPN 2.6
     RoutePredown = 12,
+    #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
     ClientConnectDefer = 13,
+    #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
     ClientConnectDeferV2 = 14,
+    #[cfg(feature = "openvpn-2-6")]
     ClientCrresponse = 15,
     #[cfg(feature = "auth-failed-event")]
     AuthFailed = 16,
 }
 
+impl EventType {
+    /// Every event that exists with the enabled crate features, ordered by event number.
+    const ALL: &'static [EventType] = &[
+        EventType::Up,
+        EventType::Down,
+        EventType::RouteUp,
+        EventType::IpChange,
+        EventType::TlsVerify,
+        EventType::AuthUserPassVerify,
+        EventType::ClientConnect,
+        EventType::ClientDisconnect,
+        EventType::LearnAddress,
+        EventType::ClientConnectV2,
+        EventType::TlsFinal,
+        #[cfg(any(feature = "openvpn-2-4", feature = "openvpn-2-5"))]
+        EventType::EnablePf,
+        EventType::RoutePredown,
+        #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+        EventType::ClientConnectDefer,
+        #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+        EventType::ClientConnectDeferV2,
+        #[cfg(feature = "openvpn-2-6")]
+        EventType::ClientCrresponse,
+        #[cfg(feature = "auth-failed-event")]
+        EventType::AuthFailed,
+    ];
+
+    /// Returns every event that exists with the enabled crate features, ordered by event number.
+    pub fn all() -> &'static [EventType] {
+        Self::ALL
+    }
+
+    /// Iterates over every event that exists with the enabled crate features, ordered by event
+    /// number.
+    ///
+    /// ```rust
+    /// use openvpn_plugin::{EventType, EventTypeSet};
+    ///
+    /// // Register for everything except the events that need a real answer.
+    /// let events: EventTypeSet = EventType::iter()
+    ///     .filter(|event| *event != EventType::AuthUserPassVerify)
+    ///     .collect();
+    /// ```
+    pub fn iter() -> impl Iterator<Item = EventType> {
+        Self::ALL.iter().copied()
+    }
+
+    /// Returns the name OpenVPN uses for this event in its logs, e.g. `PLUGIN_UP`.
+    pub fn name(self) -> &'static str {
+        match self {
+            EventType::Up => "PLUGIN_UP",
+            EventType::Down => "PLUGIN_DOWN",
+            EventType::RouteUp => "PLUGIN_ROUTE_UP",
+            EventType::IpChange => "PLUGIN_IPCHANGE",
+            EventType::TlsVerify => "PLUGIN_TLS_VERIFY",
+            EventType::AuthUserPassVerify => "PLUGIN_AUTH_USER_PASS_VERIFY",
+            EventType::ClientConnect => "PLUGIN_CLIENT_CONNECT",
+            EventType::ClientDisconnect => "PLUGIN_CLIENT_DISCONNECT",
+            EventType::LearnAddress => "PLUGIN_LEARN_ADDRESS",
+            EventType::ClientConnectV2 => "PLUGIN_CLIENT_CONNECT_V2",
+            EventType::TlsFinal => "PLUGIN_TLS_FINAL",
+            #[cfg(any(feature = "openvpn-2-4", feature = "openvpn-2-5"))]
+            EventType::EnablePf => "PLUGIN_ENABLE_PF",
+            EventType::RoutePredown => "PLUGIN_ROUTE_PREDOWN",
+            #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+            EventType::ClientConnectDefer => "PLUGIN_CLIENT_CONNECT_DEFER",
+            #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+            EventType::ClientConnectDeferV2 => "PLUGIN_CLIENT_CONNECT_DEFER_V2",
+            #[cfg(feature = "openvpn-2-6")]
+            EventType::ClientCrresponse => "PLUGIN_CLIENT_CRRESPONSE",
+            #[cfg(feature = "auth-failed-event")]
+            EventType::AuthFailed => "PLUGIN_AUTH_FAILED",
+        }
+    }
+
+    /// Returns true if OpenVPN accepts `EventResult::Deferred` as the result of this event.
+    /// Returning a deferred result from any other event is illegal.
+    pub fn supports_deferred(self) -> bool {
+        match self {
+            EventType::AuthUserPassVerify => true,
+            #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+            EventType::ClientConnect | EventType::ClientConnectV2 => true,
+            _ => false,
+        }
+    }
+}
+
+/// Formats the event with the name OpenVPN uses for it, e.g. `PLUGIN_UP`.
+impl fmt::Display for EventType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.name().fmt(f)
+    }
+}
+
+/// Parses the names produced by the `Display` implementation, e.g. `PLUGIN_UP`.
+impl FromStr for EventType {
+    type Err = ParseEventTypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "PLUGIN_UP" => EventType::Up,
+            "PLUGIN_DOWN" => EventType::Down,
+            "PLUGIN_ROUTE_UP" => EventType::RouteUp,
+            "PLUGIN_IPCHANGE" => EventType::IpChange,
+            "PLUGIN_TLS_VERIFY" => EventType::TlsVerify,
+            "PLUGIN_AUTH_USER_PASS_VERIFY" => EventType::AuthUserPassVerify,
+            "PLUGIN_CLIENT_CONNECT" => EventType::ClientConnect,
+            "PLUGIN_CLIENT_DISCONNECT" => EventType::ClientDisconnect,
+            "PLUGIN_LEARN_ADDRESS" => EventType::LearnAddress,
+            "PLUGIN_CLIENT_CONNECT_V2" => EventType::ClientConnectV2,
+            "PLUGIN_TLS_FINAL" => EventType::TlsFinal,
+            #[cfg(any(feature = "openvpn-2-4", feature = "openvpn-2-5"))]
+            "PLUGIN_ENABLE_PF" => EventType::EnablePf,
+            "PLUGIN_ROUTE_PREDOWN" => EventType::RoutePredown,
+            #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+            "PLUGIN_CLIENT_CONNECT_DEFER" => EventType::ClientConnectDefer,
+            #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+            "PLUGIN_CLIENT_CONNECT_DEFER_V2" => EventType::ClientConnectDeferV2,
+            #[cfg(feature = "openvpn-2-6")]
+            "PLUGIN_CLIENT_CRRESPONSE" => EventType::ClientCrresponse,
+            #[cfg(feature = "auth-failed-event")]
+            "PLUGIN_AUTH_FAILED" => EventType::AuthFailed,
+            _ => return Err(ParseEventTypeError(s.to_owned())),
+        })
+    }
+}
+
+/// Error returned when parsing a string that is not an event name into an `EventType`.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ParseEventTypeError(String);
+
+impl fmt::Display for ParseEventTypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\" is not a valid OpenVPN plugin event name", self.0)
+    }
+}
+
+impl std::error::Error for ParseEventTypeError {}
+
+/// A set of `EventType`s. Stored as a bitmask in the same format OpenVPN uses for `type_mask`,
+/// so an event can never be registered twice.
+///
+/// ```rust
+/// use openvpn_plugin::{EventType, EventTypeSet};
+///
+/// let events = EventType::Up | EventType::Down;
+/// assert!(events.contains(EventType::Up));
+/// assert_eq!(0b11, events.bits());
+/// ```
+#[derive(Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct EventTypeSet(c_int);
+
+impl EventTypeSet {
+    /// Creates an empty set.
+    pub fn new() -> Self {
+        EventTypeSet(0)
+    }
+
+    /// Creates a set from a `type_mask` bitmask. Fails if any bit does not correspond to an
+    /// `EventType`.
+    pub fn from_bits(bits: c_int) -> Result<Self, UnknownEventBits> {
+        let set = Self::from_bits_truncate(bits);
+        if set.0 == bits {
+            Ok(set)
+        } else {
+            Err(UnknownEventBits(bits & !set.0))
+        }
+    }
+
+    /// Creates a set from a `type_mask` bitmask, ignoring bits that don't correspond to an
+    /// `EventType`.
+    pub fn from_bits_truncate(bits: c_int) -> Self {
+        (0..c_int::BITS as i32)
+            .filter(|bit| bits & (1 << bit) != 0)
+            .filter_map(|bit| EventType::try_from(bit).ok())
+            .collect()
+    }
+
+    /// Returns the set as a bitmask in the format OpenVPN expects in `type_mask`.
+    pub fn bits(self) -> c_int {
+        self.0
+    }
+
+    /// Adds `event` to the set. Returns true if it was not already present.
+    pub fn insert(&mut self, event: EventType) -> bool {
+        let present = self.contains(event);
+        self.0 |= event_bit(event);
+        !present
+    }
+
+    /// Removes `event` from the set. Returns true if it was present.
+    pub fn remove(&mut self, event: EventType) -> bool {
+        let present = self.contains(event);
+        self.0 &= !event_bit(event);
+        present
+    }
+
+    /// Returns true if `event` is in the set.
+    pub fn contains(self, event: EventType) -> bool {
+        self.0 & event_bit(event) != 0
+    }
+
+    /// Returns true if the set contains no events.
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns the number of events in the set.
+    pub fn len(self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    /// Iterates over the events in the set, ordered by event number.
+    pub fn iter(self) -> EventTypeSetIter {
+        EventTypeSetIter { bits: self.0 }
+    }
+}
+
+fn event_bit(event: EventType) -> c_int {
+    1 << (event as i32)
+}
+
+impl fmt::Debug for EventTypeSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+impl From<EventType> for EventTypeSet {
+    fn from(event: EventType) -> Self {
+        EventTypeSet(event_bit(event))
+    }
+}
+
+impl From<&[EventType]> for EventTypeSet {
+    fn from(events: &[EventType]) -> Self {
+        events.iter().copied().collect()
+    }
+}
+
+impl From<Vec<EventType>> for EventTypeSet {
+    fn from(events: Vec<EventType>) -> Self {
+        events.into_iter().collect()
+    }
+}
+
+impl FromIterator<EventType> for EventTypeSet {
+    fn from_iter<I: IntoIterator<Item = EventType>>(iter: I) -> Self {
+        let mut set = EventTypeSet::new();
+        set.extend(iter);
+        set
+    }
+}
+
+impl Extend<EventType> for EventTypeSet {
+    fn extend<I: IntoIterator<Item = EventType>>(&mut self, iter: I) {
+        for event in iter {
+            self.insert(event);
+        }
+    }
+}
+
+impl IntoIterator for EventTypeSet {
+    type Item = EventType;
+    type IntoIter = EventTypeSetIter;
+
+    fn into_iter(self) -> EventTypeSetIter {
+        self.iter()
+    }
+}
+
+impl BitOr for EventTypeSet {
+    type Output = EventTypeSet;
+
+    fn bitor(self, other: EventTypeSet) -> EventTypeSet {
+        EventTypeSet(self.0 | other.0)
+    }
+}
+
+impl BitOr<EventType> for EventTypeSet {
+    type Output = EventTypeSet;
+
+    fn bitor(self, event: EventType) -> EventTypeSet {
+        EventTypeSet(self.0 | event_bit(event))
+    }
+}
+
+impl BitOr for EventType {
+    type Output = EventTypeSet;
+
+    fn bitor(self, other: EventType) -> EventTypeSet {
+        EventTypeSet(event_bit(self) | event_bit(other))
+    }
+}
+
+impl BitOrAssign<EventType> for EventTypeSet {
+    fn bitor_assign(&mut self, event: EventType) {
+        self.insert(event);
+    }
+}
+
+impl BitOrAssign for EventTypeSet {
+    fn bitor_assign(&mut self, other: EventTypeSet) {
+        self.0 |= other.0;
+    }
+}
+
+/// Iterator over the events in an `EventTypeSet`.
+#[derive(Debug, Clone)]
+pub struct EventTypeSetIter {
+    bits: c_int,
+}
+
+impl Iterator for EventTypeSetIter {
+    type Item = EventType;
+
+    fn next(&mut self) -> Option<EventType> {
+        while self.bits != 0 {
+            let bit = self.bits.trailing_zeros() as i32;
+            self.bits &= !(1 << bit);
+            if let Ok(event) = EventType::try_from(bit) {
+                return Some(event);
+            }
+        }
+        None
+    }
+}
+
 /// Translates a collection of `EventType` instances into a bitmask in the format OpenVPN
 /// expects it in `type_mask`.
 pub fn events_to_bitmask(events: &[EventType]) -> c_int {
@@ -51,10 +394,50 @@ pub fn events_to_bitmask(events: &[EventType]) -> c_int {
     bitmask
 }
 
+/// Translates a bitmask in the format OpenVPN uses in `type_mask` back into the events it
+/// contains, ordered by event number. The inverse of [`events_to_bitmask`].
+///
+/// Returns an error holding the bits that don't correspond to any `EventType` if there are any.
+///
+/// [`events_to_bitmask`]: fn.events_to_bitmask.html
+pub fn events_from_bitmask(bitmask: c_int) -> Result<Vec<EventType>, UnknownEventBits> {
+    let mut events = Vec::new();
+    let mut unknown: c_int = 0;
+    for bit in 0..c_int::BITS as i32 {
+        if bitmask & (1 << bit) == 0 {
+            continue;
+        }
+        match EventType::try_from(bit) {
+            Ok(event) => events.push(event),
+            Err(_) => unknown |= 1 << bit,
+        }
+    }
+    if unknown == 0 {
+        Ok(events)
+    } else {
+        Err(UnknownEventBits(unknown))
+    }
+}
+
+/// Error returned by [`events_from_bitmask`] when the bitmask has bits set that don't correspond
+/// to any `EventType`. Holds the unknown bits.
+///
+/// [`events_from_bitmask`]: fn.events_from_bitmask.html
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct UnknownEventBits(pub c_int);
+
+impl fmt::Display for UnknownEventBits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown event bits in bitmask: {:#x}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownEventBits {}
+
 
 /// Enum representing the results an OpenVPN plugin can return from an event callback.
 #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum EventResult {
     /// Will return `OPENVPN_PLUGIN_FUNC_SUCCESS` to OpenVPN.
     /// Indicates that the plugin marks the event as a success. This means an auth is approved
@@ -62,11 +445,14 @@ pub enum EventResult {
     Success,
 
     /// Will return `OPENVPN_PLUGIN_FUNC_DEFERRED` to OpenVPN.
-    /// WARNING: Can only be returned from the `EventType::AuthUserPassVerify`
-    /// (`OPENVPN_PLUGIN_AUTH_USER_PASS_VERIFY`) event. No other events may return this variant.
+    /// WARNING: Can only be returned from the events where `EventType::supports_deferred` is
+    /// true: `AuthUserPassVerify`, and `ClientConnect` and `ClientConnectV2` with OpenVPN 2.5 and
+    /// later. If returned from any other event it is logged as an error and
+    /// `OPENVPN_PLUGIN_FUNC_ERROR` is returned to OpenVPN instead.
     /// Returning this tells OpenVPN to continue its normal work and that the decision on if the
-    /// authentication is accepted or not will be delivered later, via writing to the path under
-    /// the `auth_control_file` environment variable.
+    /// authentication or connection is accepted or not will be delivered later, via writing to
+    /// the path under the `auth_control_file` or `client_connect_deferred_file` environment
+    /// variable.
     Deferred,
 
     /// Will return `OPENVPN_PLUGIN_FUNC_ERROR` to OpenVPN.
@@ -76,13 +462,18 @@ pub enum EventResult {
     /// not encounter an error, but the event is a failure or is to be declined. Intended to be
     /// used to decline an authentication request and similar.
     Failure,
+
+    /// Same as `Failure`, but also writes the reason to the path under the
+    /// `auth_failed_reason_file` environment variable, if OpenVPN provided one. OpenVPN 2.6 and
+    /// later forwards the reason to the client, so it can tell the user why the authentication
+    /// was rejected.
+    FailureWithReason(String),
 }
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::convert::TryFrom;
 
     #[test]
     fn event_enum_to_str() {
@@ -90,6 +481,19 @@ mod tests {
         assert_eq!("Up", result);
     }
 
+    #[test]
+    fn event_display_from_str() {
+        assert_eq!(
+            "PLUGIN_CLIENT_CONNECT_V2",
+            EventType::ClientConnectV2.to_string()
+        );
+        assert_eq!(
+            Ok(EventType::AuthUserPassVerify),
+            "PLUGIN_AUTH_USER_PASS_VERIFY".parse()
+        );
+        assert!("Up".parse::<EventType>().is_err());
+    }
+
     #[test]
     fn events_to_bitmask_no_events() {
         let result = events_to_bitmask(&[]);
@@ -114,14 +518,96 @@ mod tests {
         assert_eq!((1 << 12) | (1 << 2), result);
     }
 
+    #[test]
+    fn supports_deferred() {
+        assert!(EventType::AuthUserPassVerify.supports_deferred());
+        #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+        {
+            assert!(EventType::ClientConnect.supports_deferred());
+            assert!(EventType::ClientConnectV2.supports_deferred());
+            assert!(!EventType::ClientConnectDefer.supports_deferred());
+            assert!(!EventType::ClientConnectDeferV2.supports_deferred());
+        }
+        assert!(!EventType::Up.supports_deferred());
+    }
+
+    #[cfg(any(feature = "openvpn-2-5", feature = "openvpn-2-6"))]
+    #[test]
+    fn deferred_client_connect_result_code() {
+        let code = |event| {
+            crate::event_result_code(event, Ok(Ok::<_, String>(EventResult::Deferred)), || None)
+        };
+        assert_eq!(
+            crate::ffi::OPENVPN_PLUGIN_FUNC_DEFERRED,
+            code(EventType::ClientConnectV2)
+        );
+        assert_eq!(
+            crate::ffi::OPENVPN_PLUGIN_FUNC_ERROR,
+            code(EventType::ClientConnectDeferV2)
+        );
+    }
+
+    #[test]
+    fn events_from_bitmask_round_trip() {
+        let events = [EventType::RouteUp, EventType::RoutePredown];
+        assert_eq!(
+            Ok(events.to_vec()),
+            events_from_bitmask(events_to_bitmask(&events))
+        );
+        assert_eq!(Ok(vec![]), events_from_bitmask(0));
+    }
+
+    #[test]
+    fn events_from_bitmask_unknown() {
+        let bitmask = events_to_bitmask(&[EventType::Up]) | (1 << 30);
+        assert_eq!(Err(UnknownEventBits(1 << 30)), events_from_bitmask(bitmask));
+    }
+
+    #[test]
+    fn all_events() {
+        let all = EventType::all();
+        let valid: Vec<EventType> = (0..32)
+            .filter_map(|i| EventType::try_from(i).ok())
+            .collect();
+        assert_eq!(valid, all);
+    }
+
+    #[test]
+    fn event_type_set() {
+        let mut set = EventType::Up | EventType::RouteUp;
+        assert!(!set.insert(EventType::Up));
+        assert!(set.insert(EventType::LearnAddress));
+        assert_eq!(3, set.len());
+        assert!(set.remove(EventType::Up));
+        assert!(!set.contains(EventType::Up));
+        assert_eq!(
+            vec![EventType::RouteUp, EventType::LearnAddress],
+            set.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(Ok(set), EventTypeSet::from_bits(set.bits()));
+    }
+
+    #[test]
+    fn event_type_set_from_unknown_bits() {
+        let bits = EventTypeSet::from(EventType::Down).bits() | (1 << 30);
+        assert_eq!(
+            Err(UnknownEventBits(1 << 30)),
+            EventTypeSet::from_bits(bits)
+        );
+        assert_eq!(
+            EventTypeSet::from(EventType::Down),
+            EventTypeSet::from_bits_truncate(bits)
+        );
+    }
+
     #[test]
     fn events_max_value() {
-        let auth_failed = EventType::try_from(15);
+        let auth_failed = EventType::try_from(16);
         #[cfg(feature = "auth-failed-event")]
         assert_eq!(auth_failed.unwrap(), EventType::AuthFailed);
         #[cfg(not(feature = "auth-failed-event"))]
-        assert_eq!(auth_failed, Err(15));
+        assert_eq!(auth_failed, Err(16));
 
-        assert_eq!(EventType::try_from(16), Err(16));
+        assert_eq!(EventType::try_from(17), Err(17));
     }
 }
@@ -0,0 +1,122 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Warnings about slow event callbacks.
+//!
+//! OpenVPN is single-threaded and waits for the plugin to return from every event. While an event
+//! callback runs, no other client is served. With a threshold set, every event callback that runs
+//! longer than it is logged as a warning with the event type and the elapsed time:
+//!
+//! ```rust
+//! use std::time::Duration;
+//!
+//! openvpn_plugin::watchdog::set_threshold(Some(Duration::from_millis(100)));
+//! ```
+//!
+//! The watchdog is disabled by default. Slow callbacks are only logged after they return, they are
+//! never interrupted.
+
+use std::{
+    convert::TryFrom,
+    error::Error,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{logging, EventType};
+
+/// The threshold in microseconds. Zero disables the watchdog.
+static THRESHOLD_MICROS: AtomicU64 = AtomicU64::new(0);
+
+/// Sets the duration after which an event callback is logged as slow. `None`, or a zero duration,
+/// disables the watchdog.
+pub fn set_threshold(threshold: Option<Duration>) {
+    let micros = threshold.map_or(0, |threshold| {
+        u64::try_from(threshold.as_micros()).unwrap_or(u64::MAX)
+    });
+    THRESHOLD_MICROS.store(micros, Ordering::Relaxed);
+}
+
+/// Returns the duration after which an event callback is logged as slow, if the watchdog is
+/// enabled.
+pub fn threshold() -> Option<Duration> {
+    match THRESHOLD_MICROS.load(Ordering::Relaxed) {
+        0 => None,
+        micros => Some(Duration::from_micros(micros)),
+    }
+}
+
+/// Logs a warning if the callback for `event` ran for longer than the threshold.
+pub(crate) fn check(event: EventType, elapsed: Duration) {
+    if let Some(error) = slow_callback(event, elapsed) {
+        logging::log_warning(&error);
+    }
+}
+
+fn slow_callback(event: EventType, elapsed: Duration) -> Option<SlowCallback> {
+    let threshold = threshold()?;
+    if elapsed > threshold {
+        Some(SlowCallback {
+            event,
+            elapsed,
+            threshold,
+        })
+    } else {
+        None
+    }
+}
+
+/// An event callback ran for longer than the threshold. Logged as a warning.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct SlowCallback {
+    /// The event the callback handled.
+    pub event: EventType,
+    /// How long the callback ran.
+    pub elapsed: Duration,
+    /// The threshold it exceeded.
+    pub threshold: Duration,
+}
+
+impl fmt::Display for SlowCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The {} callback took {:?}, more than the watchdog threshold of {:?}",
+            self.event, self.elapsed, self.threshold
+        )
+    }
+}
+
+impl Error for SlowCallback {}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thresholds() {
+        let elapsed = Duration::from_millis(250);
+        assert!(slow_callback(EventType::Up, elapsed).is_none());
+
+        set_threshold(Some(Duration::from_millis(100)));
+        assert_eq!(Some(Duration::from_millis(100)), threshold());
+        let error = slow_callback(EventType::Up, elapsed).unwrap();
+        assert_eq!(
+            "The PLUGIN_UP callback took 250ms, more than the watchdog threshold of 100ms",
+            error.to_string()
+        );
+        assert!(slow_callback(EventType::Up, Duration::from_millis(100)).is_none());
+
+        set_threshold(Some(Duration::ZERO));
+        assert_eq!(None, threshold());
+        set_threshold(None);
+        assert!(slow_callback(EventType::Up, elapsed).is_none());
+    }
+}
@@ -0,0 +1,390 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Background threads that are stopped when the plugin is unloaded.
+//!
+//! Threads spawned in the open callback and left running keep OpenVPN from exiting, or are killed
+//! in the middle of their work. Spawning them on a [`Workers`] kept in the handle instead ties them
+//! to the lifetime of the plugin. When the handle is dropped after the close callback, every
+//! worker is told to stop and joined:
+//!
+//! ```rust,no_run
+//! # use std::{collections::HashMap, ffi::CString, io, time::Duration};
+//! # use openvpn_plugin::{workers::Workers, EventType};
+//! struct Handle {
+//!     workers: Workers,
+//! }
+//!
+//! fn open(
+//!     _args: Vec<CString>,
+//!     _env: HashMap<CString, CString>,
+//! ) -> Result<(Vec<EventType>, Handle), io::Error> {
+//!     let mut workers = Workers::new();
+//!     workers.spawn("stats-flush", |shutdown| {
+//!         while !shutdown.wait_timeout(Duration::from_secs(10)) {
+//!             // Flush the collected stats.
+//!         }
+//!     })?;
+//!     Ok((vec![EventType::Up], Handle { workers }))
+//! }
+//! ```
+//!
+//! Workers must check the [`Shutdown`] signal they are given and return when it is set. Workers
+//! still running after the shutdown timeout are logged and left behind, so a stuck worker can't
+//! hang OpenVPN.
+//!
+//! When OpenVPN exits because of a fatal error it calls the abort callback of the plugin instead of
+//! the close callback. The handle is not dropped then, but all workers are still told to stop and
+//! waited for, with the same timeout.
+//!
+//! [`Workers`]: struct.Workers.html
+//! [`Shutdown`]: struct.Shutdown.html
+
+use std::{
+    error::Error,
+    fmt, io,
+    panic::{RefUnwindSafe, UnwindSafe},
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, Weak},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::logging;
+
+/// How long to wait for the workers to stop by default.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The worker sets that are alive, for stopping them when OpenVPN aborts.
+static LIVE: Mutex<Vec<Weak<Shared>>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+#[derive(Debug)]
+struct State {
+    stop: bool,
+    running: usize,
+    timeout: Duration,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Tells the workers to stop and waits until they have, or the timeout has passed. Returns
+    /// whether all workers stopped.
+    fn stop(&self) -> bool {
+        let mut state = self.lock();
+        state.stop = true;
+        self.changed.notify_all();
+        let deadline = Instant::now() + state.timeout;
+        while state.running > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self
+                .changed
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        true
+    }
+}
+
+/// Counts a worker as running until dropped, also when the worker panics.
+struct Running(Arc<Shared>);
+
+impl Running {
+    fn new(shared: Arc<Shared>) -> Self {
+        shared.lock().running += 1;
+        Running(shared)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.lock().running -= 1;
+        self.0.changed.notify_all();
+    }
+}
+
+/// The signal telling a worker to stop. Given to every worker when spawned.
+#[derive(Clone)]
+pub struct Shutdown(Arc<Shared>);
+
+impl Shutdown {
+    /// Returns whether the worker should stop.
+    pub fn is_requested(&self) -> bool {
+        self.0.lock().stop
+    }
+
+    /// Waits until the worker should stop.
+    pub fn wait(&self) {
+        let mut state = self.0.lock();
+        while !state.stop {
+            state = self
+                .0
+                .changed
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Waits until the worker should stop or `timeout` has passed, whichever comes first. Returns
+    /// whether the worker should stop. Use it instead of sleeping between rounds of work.
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.0.lock();
+        while !state.stop {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self
+                .0
+                .changed
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        true
+    }
+}
+
+impl fmt::Debug for Shutdown {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Shutdown")
+            .field("requested", &self.is_requested())
+            .finish()
+    }
+}
+
+/// A set of background threads, stopped and joined when dropped.
+pub struct Workers {
+    shared: Arc<Shared>,
+    threads: Vec<(String, thread::JoinHandle<()>)>,
+}
+
+impl Workers {
+    /// Creates an empty set of workers with the default shutdown timeout.
+    pub fn new() -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                stop: false,
+                running: 0,
+                timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            }),
+            changed: Condvar::new(),
+        });
+        let mut live = LIVE.lock().unwrap_or_else(PoisonError::into_inner);
+        live.retain(|shared| shared.strong_count() > 0);
+        live.push(Arc::downgrade(&shared));
+        Workers {
+            shared,
+            threads: Vec::new(),
+        }
+    }
+
+    /// Sets how long to wait for the workers to stop before leaving them behind.
+    pub fn shutdown_timeout(self, timeout: Duration) -> Self {
+        self.shared.lock().timeout = timeout;
+        self
+    }
+
+    /// Spawns a thread named `name` running `worker`. The worker should return when the
+    /// [`Shutdown`] signal it is given is set.
+    ///
+    /// [`Shutdown`]: struct.Shutdown.html
+    pub fn spawn<F>(&mut self, name: impl Into<String>, worker: F) -> io::Result<()>
+    where
+        F: FnOnce(Shutdown) + Send + 'static,
+    {
+        let name = name.into();
+        let shutdown = Shutdown(self.shared.clone());
+        let running = Running::new(self.shared.clone());
+        let thread = thread::Builder::new().name(name.clone()).spawn(move || {
+            let _running = running;
+            worker(shutdown)
+        })?;
+        self.threads.push((name, thread));
+        Ok(())
+    }
+
+    /// The number of workers spawned and not yet joined.
+    pub fn len(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Returns whether there are no workers.
+    pub fn is_empty(&self) -> bool {
+        self.threads.is_empty()
+    }
+
+    /// Tells all workers to stop and joins them. Workers still running after the shutdown timeout
+    /// are logged as a warning and detached. Called when the `Workers` is dropped.
+    pub fn shutdown(&mut self) {
+        let stopped = self.shared.stop();
+        let timeout = self.shared.lock().timeout;
+        for (name, thread) in self.threads.drain(..) {
+            if stopped || thread.is_finished() {
+                let _ = thread.join();
+            } else {
+                logging::log_warning(&WorkerTimeout { name, timeout });
+            }
+        }
+    }
+}
+
+// A `JoinHandle` is not `RefUnwindSafe`, but the workers have no state that a panic in a callback
+// could leave broken. This makes handles holding a `Workers` usable with `openvpn_plugin!`.
+impl UnwindSafe for Workers {}
+impl RefUnwindSafe for Workers {}
+
+impl Default for Workers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl fmt::Debug for Workers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Workers")
+            .field(
+                "threads",
+                &self
+                    .threads
+                    .iter()
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>(),
+            )
+            .field("shutdown", &Shutdown(self.shared.clone()))
+            .finish()
+    }
+}
+
+/// A worker did not stop within the shutdown timeout of its `Workers`, and was left running.
+/// Logged as a warning.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct WorkerTimeout {
+    /// The name of the worker thread.
+    pub name: String,
+    /// The timeout it exceeded.
+    pub timeout: Duration,
+}
+
+impl fmt::Display for WorkerTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The background worker {:?} did not stop within {:?}, leaving it running",
+            self.name, self.timeout
+        )
+    }
+}
+
+impl Error for WorkerTimeout {}
+
+/// Tells the workers of all live `Workers` to stop and waits for them. Called when OpenVPN aborts,
+/// since the handle is not dropped then.
+pub(crate) fn shutdown_all() {
+    let live: Vec<_> = LIVE
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect();
+    for shared in live {
+        if !shared.stop() {
+            logging::log_info("Background workers did not stop in time on abort");
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn stops_and_joins() {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let mut workers = Workers::new();
+        workers
+            .spawn("waiting", {
+                let stopped = stopped.clone();
+                move |shutdown| {
+                    while !shutdown.wait_timeout(Duration::from_secs(60)) {}
+                    stopped.store(true, Ordering::SeqCst);
+                }
+            })
+            .unwrap();
+        workers.spawn("done", |_shutdown| ()).unwrap();
+        assert_eq!(2, workers.len());
+
+        let start = Instant::now();
+        drop(workers);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(stopped.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn leaves_stragglers() {
+        let release = Arc::new(AtomicBool::new(false));
+        let mut workers = Workers::new().shutdown_timeout(Duration::from_millis(50));
+        workers
+            .spawn("stuck", {
+                let release = release.clone();
+                move |_shutdown| {
+                    while !release.load(Ordering::SeqCst) {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                }
+            })
+            .unwrap();
+
+        let start = Instant::now();
+        workers.shutdown();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(workers.is_empty());
+        release.store(true, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn unwind_safe() {
+        fn assert_unwind_safe<T: UnwindSafe>() {}
+        assert_unwind_safe::<Workers>();
+    }
+
+    #[test]
+    fn abort_stops_live_workers() {
+        let mut workers = Workers::new();
+        let (tx, rx) = std::sync::mpsc::channel();
+        workers
+            .spawn("waiting", move |shutdown| {
+                shutdown.wait();
+                tx.send(()).unwrap();
+            })
+            .unwrap();
+
+        shutdown_all();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+#![cfg(feature = "derive")]
+
+use std::{collections::HashMap, ffi::CString, net::IpAddr};
+
+use openvpn_plugin::{events::EventArgsError, from_env::FromEnv};
+
+#[derive(Debug, PartialEq, FromEnv)]
+struct ClientInfo {
+    common_name: String,
+    untrusted_ip: IpAddr,
+    trusted_port: u16,
+    username: Option<String>,
+    #[env(rename = "IV_GUI_VER")]
+    gui_version: Option<String>,
+    #[env(default)]
+    bytes_received: u64,
+}
+
+fn env(env: &[(&str, &str)]) -> HashMap<CString, CString> {
+    env.iter()
+        .map(|(k, v)| (CString::new(*k).unwrap(), CString::new(*v).unwrap()))
+        .collect()
+}
+
+#[test]
+fn derived() {
+    let mut vars = vec![
+        ("common_name", "client"),
+        ("untrusted_ip", "192.0.2.1"),
+        ("trusted_port", "1194"),
+        ("IV_GUI_VER", "app 1.0"),
+    ];
+    assert_eq!(
+        Ok(ClientInfo {
+            common_name: "client".to_owned(),
+            untrusted_ip: "192.0.2.1".parse().unwrap(),
+            trusted_port: 1194,
+            username: None,
+            gui_version: Some("app 1.0".to_owned()),
+            bytes_received: 0,
+        }),
+        ClientInfo::from_env(&env(&vars))
+    );
+
+    vars.push(("bytes_received", "many"));
+    assert_eq!(
+        Err(EventArgsError::InvalidValue(
+            "bytes_received".to_owned(),
+            "many".to_owned()
+        )),
+        ClientInfo::from_env(&env(&vars))
+    );
+    assert_eq!(
+        Err(EventArgsError::MissingEnv("untrusted_ip")),
+        ClientInfo::from_env(&env(&[("common_name", "client")]))
+    );
+}
@@ -0,0 +1,18 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Checks the compile errors `openvpn_plugin!` gives for callbacks with the wrong signature. The
+//! expected errors are in the `.stderr` files next to the test cases. Update them with
+//! `TRYBUILD=overwrite cargo test --test macro_diagnostics` after verifying the new output.
+
+#[test]
+fn macro_diagnostics() {
+    let t = trybuild::TestCases::new();
+    t.pass("tests/ui/valid_*.rs");
+    t.compile_fail("tests/ui/wrong_*.rs");
+}
@@ -0,0 +1,36 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use openvpn_plugin::{openvpn_plugin, EventResult, EventType};
+use std::{collections::HashMap, ffi::CString, io};
+
+pub struct Handle;
+
+fn open(
+    _args: Vec<CString>,
+    _env: HashMap<CString, CString>,
+) -> Result<(Vec<EventType>, Handle), io::Error> {
+    Ok((vec![EventType::Up], Handle))
+}
+
+fn close(_handle: Handle) -> Result<(), io::Error> {
+    Ok(())
+}
+
+fn event(
+    _event: EventType,
+    _args: Vec<CString>,
+    _env: HashMap<CString, CString>,
+    _handle: &mut Handle,
+) -> Result<EventResult, io::Error> {
+    Ok(EventResult::Success)
+}
+
+openvpn_plugin!(crate::open, crate::close, crate::event, Handle);
+
+fn main() {}
@@ -0,0 +1,21 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use openvpn_plugin::{openvpn_plugin, EventResult, EventType};
+use std::io;
+
+pub struct Handle;
+
+openvpn_plugin!(
+    |_args, _env| Ok::<_, io::Error>((vec![EventType::Up], Handle)),
+    |_handle: Handle| -> Result<(), io::Error> { Ok(()) },
+    |_event, _args, _env, _handle| Ok::<_, io::Error>(EventResult::Success),
+    Handle
+);
+
+fn main() {}
@@ -0,0 +1,46 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use openvpn_plugin::{openvpn_plugin, EventResult, EventType, EventTypeSet, PluginHandle};
+use std::{collections::HashMap, error::Error, ffi::CString, io};
+
+pub struct Handle;
+
+impl PluginHandle for Handle {
+    type Error = io::Error;
+
+    fn open(
+        _args: Vec<CString>,
+        _env: HashMap<CString, CString>,
+    ) -> Result<(EventTypeSet, Self), io::Error> {
+        Ok((EventType::Up.into(), Handle))
+    }
+
+    fn event(
+        &mut self,
+        _event: EventType,
+        _args: Vec<CString>,
+        _env: HashMap<CString, CString>,
+    ) -> Result<EventResult, io::Error> {
+        Ok(EventResult::Success)
+    }
+}
+
+fn after_event(event: EventType, result: Result<&EventResult, &dyn Error>) {
+    if let Err(e) = result {
+        eprintln!("{:?} failed: {}", event, e);
+    }
+}
+
+openvpn_plugin!(
+    Handle,
+    before_event = |event| eprintln!("Handling {:?}", event),
+    after_event = crate::after_event,
+);
+
+fn main() {}
@@ -0,0 +1,50 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use openvpn_plugin::{openvpn_plugin, EventResult, EventType};
+use std::{collections::HashMap, ffi::CString, io};
+
+pub struct Handle;
+
+#[derive(Default)]
+pub struct Client {
+    pub events: u64,
+}
+
+fn open(
+    _args: Vec<CString>,
+    _env: HashMap<CString, CString>,
+) -> Result<(Vec<EventType>, Handle), io::Error> {
+    Ok((vec![EventType::ClientConnectV2], Handle))
+}
+
+fn close(_handle: Handle) {}
+
+fn event(
+    _event: EventType,
+    _args: Vec<CString>,
+    _env: HashMap<CString, CString>,
+    _handle: &mut Handle,
+) -> Result<EventResult, io::Error> {
+    Ok(EventResult::Success)
+}
+
+openvpn_plugin!(
+    crate::open,
+    crate::close,
+    crate::event,
+    Handle,
+    before_event = |event| eprintln!("Handling {:?}", event),
+    exports = [legacy, min_version(3), client_context(crate::Client)],
+);
+
+fn main() {
+    let _: unsafe extern "C" fn(_, _, _, _) -> _ = openvpn_plugin_open_v2;
+    let _: extern "C" fn() -> _ = openvpn_plugin_min_version_required_v1;
+    let _: unsafe extern "C" fn(_) -> _ = openvpn_plugin_client_constructor_v1;
+}
@@ -0,0 +1,39 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use openvpn_plugin::{openvpn_plugin, EventResult, EventType, EventTypeSet, PluginHandle};
+use std::{collections::HashMap, ffi::CString, io};
+
+pub struct Handle {
+    events: usize,
+}
+
+impl PluginHandle for Handle {
+    type Error = io::Error;
+
+    fn open(
+        _args: Vec<CString>,
+        _env: HashMap<CString, CString>,
+    ) -> Result<(EventTypeSet, Self), io::Error> {
+        Ok((EventType::Up.into(), Handle { events: 0 }))
+    }
+
+    fn event(
+        &mut self,
+        _event: EventType,
+        _args: Vec<CString>,
+        _env: HashMap<CString, CString>,
+    ) -> Result<EventResult, io::Error> {
+        self.events += 1;
+        Ok(EventResult::Success)
+    }
+}
+
+openvpn_plugin!(Handle);
+
+fn main() {}
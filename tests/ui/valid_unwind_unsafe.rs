@@ -0,0 +1,38 @@
+// Copyright 2023 Mullvad VPN AB.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use openvpn_plugin::{openvpn_plugin, EventResult, EventType};
+use std::{cell::RefCell, collections::HashMap, ffi::CString, io, rc::Rc};
+
+pub struct Handle {
+    state: Rc<RefCell<Vec<EventType>>>,
+}
+
+fn open(
+    _args: Vec<CString>,
+    _env: HashMap<CString, CString>,
+) -> Result<(Vec<EventType>, Handle), io::Error> {
+    let state = Rc::new(RefCell::new(Vec::new()));
+    Ok((vec![EventType::Up], Handle { state }))
+}
+
+fn close(_handle: Handle) {}
+
+fn event(
+    event: EventType,
+    _args: Vec<CString>,
+    _env: HashMap<CString, CString>,
+    handle: &mut Handle,
+) -> Result<EventResult, io::Error> {
+    handle.state.borrow_mut().push(event);
+    Ok(EventResult::Success)
+}
+
+openvpn_plugin!(crate::open, crate::close, crate::event, unwind_unsafe Handle);
+
+fn main() {}